@@ -238,6 +238,27 @@ impl PacketManager {
             on,
         ));
 
+        all_codecs.push(packet!(
+            &["Generic:Light"],
+            SetDeviceBrightness,
+            SetDeviceBrightness,
+            0x33,
+            0x04,
+            brightness,
+        ));
+
+        all_codecs.push(packet!(
+            &["Generic:Light"],
+            SetDeviceColorRgb,
+            SetDeviceColorRgb,
+            0x33,
+            0x05,
+            0x02,
+            r,
+            g,
+            b,
+        ));
+
         Self {
             codec_by_sku: Mutex::new(HashMap::new()),
             all_codecs: all_codecs.into_iter().map(Arc::new).collect(),
@@ -422,12 +443,28 @@ pub struct SetDevicePower {
     pub on: bool,
 }
 
+/// Brightness is a percentage in the range 0-100
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetDeviceBrightness {
+    pub brightness: u8,
+}
+
+/// Sets the color in manual mode (0x02)
+#[derive(Clone, Default, Debug, PartialEq, Eq)]
+pub struct SetDeviceColorRgb {
+    pub r: u8,
+    pub g: u8,
+    pub b: u8,
+}
+
 #[derive(Clone, Debug, PartialEq, Eq)]
 pub enum GoveeBlePacket {
     Generic(HexBytes),
     #[allow(unused)] // can remove if/when SetSceneCode::decode has an impl
     SetSceneCode(SetSceneCode),
     SetDevicePower(SetDevicePower),
+    SetDeviceBrightness(SetDeviceBrightness),
+    SetDeviceColorRgb(SetDeviceColorRgb),
     SetHumidifierNightlight(SetHumidifierNightlightParams),
     NotifyHumidifierMode(NotifyHumidifierMode),
     SetHumidifierMode(SetHumidifierMode),
@@ -448,6 +485,12 @@ impl Base64HexBytes {
             .map(|bytes| Base64HexBytes(HexBytes(bytes)))
     }
 
+    /// The packets as they are written to the device over bluetooth
+    #[cfg(feature = "ble")]
+    pub fn packets(&self) -> std::slice::Chunks<'_, u8> {
+        self.0 .0.chunks(20)
+    }
+
     pub fn base64(&self) -> Vec<String> {
         let mut result = vec![];
         for chunk in self.0 .0.chunks(20) {
@@ -560,6 +603,24 @@ mod test {
             &SetDevicePower { on: true },
             GoveeBlePacket::SetDevicePower(SetDevicePower { on: true }),
         );
+        round_trip(
+            "Generic:Light",
+            &SetDeviceBrightness { brightness: 42 },
+            GoveeBlePacket::SetDeviceBrightness(SetDeviceBrightness { brightness: 42 }),
+        );
+        round_trip(
+            "Generic:Light",
+            &SetDeviceColorRgb {
+                r: 255,
+                g: 69,
+                b: 42,
+            },
+            GoveeBlePacket::SetDeviceColorRgb(SetDeviceColorRgb {
+                r: 255,
+                g: 69,
+                b: 42,
+            }),
+        );
         round_trip(
             "H7160",
             &SetHumidifierNightlightParams {
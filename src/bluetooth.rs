@@ -0,0 +1,699 @@
+//! Talks to Govee devices directly over bluetooth, for devices
+//! such as thermometers that have no WiFi.
+//! This is only built with the `ble` feature, as it requires
+//! bluez and dbus on the host.
+use crate::ble::{Base64HexBytes, SetDeviceBrightness, SetDeviceColorRgb, SetDevicePower};
+use crate::opt_env_var;
+use crate::platform_api::{
+    DeviceCapability, DeviceCapabilityKind, DeviceCapabilityState, DeviceType, HttpDeviceInfo,
+    HttpDeviceState,
+};
+use crate::service::device::Device;
+use crate::service::quirks::HumidityUnits;
+use crate::service::state::StateHandle;
+use crate::temperature::{TemperatureUnits, TemperatureValue};
+use anyhow::Context;
+use btleplug::api::{
+    BDAddr, Central, CentralEvent, Characteristic, Manager as _, Peripheral as _, ScanFilter,
+    WriteType,
+};
+use btleplug::platform::{Adapter, Manager, Peripheral};
+use futures::StreamExt;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use tokio::time::Instant;
+use uuid::Uuid;
+
+const DEFAULT_SCAN_DURATION_SECS: u64 = 5;
+/// How long to wait between scans
+const SCAN_INTERVAL: Duration = Duration::from_secs(60);
+
+/// The GATT service and characteristic through which Govee devices
+/// accept the packets produced by the codecs in crate::ble
+const GOVEE_GATT_SERVICE_UUID: Uuid = Uuid::from_u128(0x00010203_0405_0607_0809_0a0b0c0d1910);
+const GOVEE_GATT_CONTROL_CHARACTERISTIC_UUID: Uuid =
+    Uuid::from_u128(0x00010203_0405_0607_0809_0a0b0c0d2b11);
+
+#[derive(clap::Parser, Debug)]
+pub struct BleArguments {
+    /// How long each bluetooth scan runs, in seconds.
+    /// You may also set GOVEE_BLE_SCAN_DURATION_SECS via the environment.
+    #[arg(long, global = true)]
+    pub ble_scan_duration_secs: Option<u64>,
+}
+
+impl BleArguments {
+    pub fn scan_duration(&self) -> anyhow::Result<Duration> {
+        let secs = match self.ble_scan_duration_secs {
+            Some(secs) => secs,
+            None => {
+                opt_env_var("GOVEE_BLE_SCAN_DURATION_SECS")?.unwrap_or(DEFAULT_SCAN_DURATION_SECS)
+            }
+        };
+        Ok(Duration::from_secs(secs))
+    }
+}
+
+/// The company identifier that Govee thermometer/hygrometer
+/// devices (such as the H5075) use in their advertisements
+pub const GOVEE_THERMOMETER_MANUFACTURER_ID: u16 = 0xec88;
+
+/// Sensor readings that are broadcast in the manufacturer data of
+/// the BLE advertisements from Govee thermometer/hygrometers
+#[derive(Clone, Copy, Debug, PartialEq, Serialize)]
+pub struct ThermometerAdvertisement {
+    pub temperature_c: f64,
+    pub humidity: f64,
+}
+
+impl ThermometerAdvertisement {
+    /// Decode the manufacturer data from an advertisement.
+    /// `data` is the payload that follows the company identifier.
+    /// Returns None if this isn't a thermometer advertisement.
+    pub fn parse(manufacturer_id: u16, data: &[u8]) -> Option<Self> {
+        if manufacturer_id != GOVEE_THERMOMETER_MANUFACTURER_ID {
+            return None;
+        }
+        let [_, b1, b2, b3, ..] = *data else {
+            return None;
+        };
+
+        // Temperature and humidity are packed together into a 24-bit
+        // big endian value as TTTHHH, where TTT is the temperature in
+        // tenths of a degree and HHH is the humidity in tenths of a
+        // percent. The top bit indicates a negative temperature.
+        let mut packed = u32::from_be_bytes([0, b1, b2, b3]);
+        let negative = packed & 0x80_0000 != 0;
+        packed &= 0x7f_ffff;
+
+        let mut temperature_c = (packed / 1000) as f64 / 10.;
+        if negative {
+            temperature_c = -temperature_c;
+        }
+        let humidity = (packed % 1000) as f64 / 10.;
+
+        Some(Self {
+            temperature_c,
+            humidity,
+        })
+    }
+}
+
+/// Extracts the SKU from the name that a device advertises,
+/// such as GVH5075_1A2B or Govee_H5074_1A2B
+pub fn sku_from_local_name(name: &str) -> Option<String> {
+    let name = ["Govee_", "ihoment_", "GBK_", "GV"]
+        .iter()
+        .find_map(|prefix| name.strip_prefix(prefix))?;
+    let sku = name.split('_').next()?;
+    if sku.len() > 1 && sku.starts_with('H') && sku.chars().all(|c| c.is_ascii_alphanumeric()) {
+        Some(sku.to_string())
+    } else {
+        None
+    }
+}
+
+pub async fn default_adapter() -> anyhow::Result<Adapter> {
+    let manager = Manager::new().await.context("connecting to bluez")?;
+    manager
+        .adapters()
+        .await
+        .context("listing bluetooth adapters")?
+        .into_iter()
+        .next()
+        .ok_or_else(|| anyhow::anyhow!("no bluetooth adapters found"))
+}
+
+/// A Govee device that was heard advertising
+#[derive(Debug, Serialize)]
+pub struct DiscoveredDevice {
+    pub address: String,
+    pub name: String,
+    pub sku: String,
+    pub rssi: Option<i16>,
+    pub reading: Option<ThermometerAdvertisement>,
+}
+
+/// Scans for the specified duration, returning the Govee
+/// devices that were heard
+pub async fn discover_devices(duration: Duration) -> anyhow::Result<Vec<DiscoveredDevice>> {
+    let adapter = default_adapter().await?;
+    adapter.start_scan(ScanFilter::default()).await?;
+    tokio::time::sleep(duration).await;
+    adapter.stop_scan().await?;
+
+    let mut devices = vec![];
+    for peripheral in adapter.peripherals().await? {
+        let Some(props) = peripheral.properties().await? else {
+            continue;
+        };
+        let Some(name) = props.local_name else {
+            continue;
+        };
+        let Some(sku) = sku_from_local_name(&name) else {
+            continue;
+        };
+        let reading = props
+            .manufacturer_data
+            .iter()
+            .find_map(|(mfr, data)| ThermometerAdvertisement::parse(*mfr, data));
+        devices.push(DiscoveredDevice {
+            address: props.address.to_string(),
+            name,
+            sku,
+            rssi: props.rssi,
+            reading,
+        });
+    }
+    devices.sort_by(|a, b| a.address.cmp(&b.address));
+    Ok(devices)
+}
+
+/// The resolved control characteristic of a device
+#[derive(Clone)]
+struct DeviceHandle {
+    peripheral: Peripheral,
+    characteristic: Characteristic,
+}
+
+/// What we remember about a device handle across restarts
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+struct CachedHandle {
+    adapter: String,
+    service: Uuid,
+    characteristic: Uuid,
+}
+
+fn handle_cache_file() -> PathBuf {
+    crate::cache::cache_dir().join("govee2mqtt-ble-handles.json")
+}
+
+fn load_cached_handles(file: &Path) -> HashMap<String, CachedHandle> {
+    std::fs::read(file)
+        .ok()
+        .and_then(|data| serde_json::from_slice(&data).ok())
+        .unwrap_or_default()
+}
+
+fn save_cached_handles(file: &Path, handles: &HashMap<String, CachedHandle>) {
+    let result = serde_json::to_vec_pretty(handles)
+        .map_err(anyhow::Error::from)
+        .and_then(|data| Ok(std::fs::write(file, data)?));
+    if let Err(err) = result {
+        log::warn!("Unable to save BLE handles to {file:?}: {err:#}");
+    }
+}
+
+/// Sends commands to devices over GATT. The handles of the devices
+/// that it has connected to are kept, so that subsequent commands
+/// don't need to scan for the device or discover its services again.
+/// The handles are also persisted in GOVEE_CACHE_DIR, which allows
+/// a device that bluez already knows about to be used without
+/// scanning for it after a restart.
+pub struct BleClient {
+    adapter: Adapter,
+    adapter_info: String,
+    scan_duration: Duration,
+    handles: Mutex<HashMap<BDAddr, DeviceHandle>>,
+    cache_file: PathBuf,
+    cached_handles: Mutex<HashMap<String, CachedHandle>>,
+}
+
+impl BleClient {
+    pub async fn new(scan_duration: Duration) -> anyhow::Result<Self> {
+        let adapter = default_adapter().await?;
+        let adapter_info = adapter.adapter_info().await?;
+        let cache_file = handle_cache_file();
+        Ok(Self {
+            adapter,
+            adapter_info,
+            scan_duration,
+            handles: Mutex::new(HashMap::new()),
+            cached_handles: Mutex::new(load_cached_handles(&cache_file)),
+            cache_file,
+        })
+    }
+
+    async fn known_peripheral(&self, address: BDAddr) -> anyhow::Result<Option<Peripheral>> {
+        Ok(self
+            .adapter
+            .peripherals()
+            .await?
+            .into_iter()
+            .find(|p| p.address() == address))
+    }
+
+    /// Scans until the device with the specified address is found
+    async fn find_peripheral(&self, address: BDAddr) -> anyhow::Result<Peripheral> {
+        self.adapter.start_scan(ScanFilter::default()).await?;
+        let deadline = Instant::now() + self.scan_duration;
+        let result = loop {
+            if let Some(peripheral) = self.known_peripheral(address).await? {
+                break Ok(peripheral);
+            }
+            if Instant::now() >= deadline {
+                break Err(anyhow::anyhow!("{address} was not found"));
+            }
+            tokio::time::sleep(Duration::from_millis(250)).await;
+        };
+        self.adapter.stop_scan().await?;
+        result
+    }
+
+    async fn resolve_handle(&self, address: BDAddr) -> anyhow::Result<DeviceHandle> {
+        let key = address.to_string();
+        let cached = self
+            .cached_handles
+            .lock()
+            .await
+            .get(&key)
+            .filter(|cached| cached.adapter == self.adapter_info)
+            .cloned();
+
+        let peripheral = match &cached {
+            Some(_) => match self.known_peripheral(address).await? {
+                Some(peripheral) => peripheral,
+                None => self.find_peripheral(address).await?,
+            },
+            None => self.find_peripheral(address).await?,
+        };
+        peripheral
+            .connect()
+            .await
+            .with_context(|| format!("connecting to {address}"))?;
+        peripheral.discover_services().await?;
+
+        let (service, uuid) = match &cached {
+            Some(cached) => (cached.service, cached.characteristic),
+            None => (
+                GOVEE_GATT_SERVICE_UUID,
+                GOVEE_GATT_CONTROL_CHARACTERISTIC_UUID,
+            ),
+        };
+        let characteristic = peripheral
+            .characteristics()
+            .into_iter()
+            .find(|c| c.service_uuid == service && c.uuid == uuid)
+            .ok_or_else(|| anyhow::anyhow!("{address} has no Govee control characteristic"))?;
+
+        if cached.is_none() {
+            let mut cached_handles = self.cached_handles.lock().await;
+            cached_handles.insert(
+                key,
+                CachedHandle {
+                    adapter: self.adapter_info.clone(),
+                    service,
+                    characteristic: uuid,
+                },
+            );
+            save_cached_handles(&self.cache_file, &cached_handles);
+        }
+
+        Ok(DeviceHandle {
+            peripheral,
+            characteristic,
+        })
+    }
+
+    async fn invalidate_handle(&self, address: BDAddr) {
+        self.handles.lock().await.remove(&address);
+        let mut cached_handles = self.cached_handles.lock().await;
+        if cached_handles.remove(&address.to_string()).is_some() {
+            save_cached_handles(&self.cache_file, &cached_handles);
+        }
+    }
+
+    async fn write_with_handle(
+        &self,
+        handle: &DeviceHandle,
+        command: &Base64HexBytes,
+    ) -> anyhow::Result<()> {
+        if !handle.peripheral.is_connected().await? {
+            handle.peripheral.connect().await?;
+        }
+        for packet in command.packets() {
+            handle
+                .peripheral
+                .write(&handle.characteristic, packet, WriteType::WithoutResponse)
+                .await?;
+        }
+        Ok(())
+    }
+
+    /// Connects to the device and writes the packets to its
+    /// control characteristic
+    async fn write_packets(&self, address: BDAddr, command: Base64HexBytes) -> anyhow::Result<()> {
+        if crate::is_dry_run() {
+            log::info!("dry-run: BLE write to {address} {command:?}");
+            return Ok(());
+        }
+
+        let existing = self.handles.lock().await.get(&address).cloned();
+        if let Some(handle) = existing {
+            match self.write_with_handle(&handle, &command).await {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    log::warn!("Writing to {address} failed, will reconnect: {err:#}");
+                    self.invalidate_handle(address).await;
+                }
+            }
+        }
+
+        let result = async {
+            let handle = self.resolve_handle(address).await?;
+            self.write_with_handle(&handle, &command).await?;
+            Ok(handle)
+        }
+        .await;
+        match result {
+            Ok(handle) => {
+                self.handles.lock().await.insert(address, handle);
+                Ok(())
+            }
+            Err(err) => {
+                self.invalidate_handle(address).await;
+                Err(err)
+            }
+        }
+    }
+
+    pub async fn set_power(&self, device_addr: BDAddr, on: bool) -> anyhow::Result<()> {
+        let command = Base64HexBytes::encode_for_sku("Generic:Light", &SetDevicePower { on })?;
+        self.write_packets(device_addr, command).await
+    }
+
+    pub async fn set_brightness(&self, device_addr: BDAddr, percent: u8) -> anyhow::Result<()> {
+        let command = Base64HexBytes::encode_for_sku(
+            "Generic:Light",
+            &SetDeviceBrightness {
+                brightness: percent.min(100),
+            },
+        )?;
+        self.write_packets(device_addr, command).await
+    }
+
+    pub async fn set_color_rgb(
+        &self,
+        device_addr: BDAddr,
+        r: u8,
+        g: u8,
+        b: u8,
+    ) -> anyhow::Result<()> {
+        let command =
+            Base64HexBytes::encode_for_sku("Generic:Light", &SetDeviceColorRgb { r, g, b })?;
+        self.write_packets(device_addr, command).await
+    }
+
+    /// Disconnects from the devices that we connected to
+    pub async fn disconnect(&self) {
+        for (address, handle) in self.handles.lock().await.drain() {
+            if let Err(err) = handle.peripheral.disconnect().await {
+                log::debug!("disconnecting from {address}: {err:#}");
+            }
+        }
+    }
+}
+
+/// Returns true if the error suggests that the device needs to be
+/// paired with this host before it will accept commands
+pub fn is_pairing_error(err: &anyhow::Error) -> bool {
+    if let Some(btleplug::Error::PermissionDenied) = err.downcast_ref() {
+        return true;
+    }
+    let message = format!("{err:#}");
+    [
+        "NotPermitted",
+        "NotAuthorized",
+        "AuthenticationFailed",
+        "Insufficient Authentication",
+        "Insufficient Encryption",
+    ]
+    .iter()
+    .any(|reason| message.contains(reason))
+}
+
+/// Describes the readings of a thermometer using the same
+/// capabilities that the Platform API uses for thermometers
+/// that it knows about, so that the same entities are used
+fn thermometer_info(device: &Device) -> HttpDeviceInfo {
+    let sensor = |instance: &str| DeviceCapability {
+        kind: DeviceCapabilityKind::Property,
+        instance: instance.to_string(),
+        parameters: None,
+        alarm_type: None,
+        event_state: None,
+    };
+    HttpDeviceInfo {
+        sku: device.sku.to_string(),
+        device: device.id.to_string(),
+        device_name: device
+            .undoc_device_info
+            .as_ref()
+            .map(|info| info.entry.device_name.to_string())
+            .unwrap_or_default(),
+        device_type: DeviceType::Thermometer,
+        capabilities: vec![sensor("sensorTemperature"), sensor("sensorHumidity")],
+    }
+}
+
+/// Encodes the readings in the units that the sensor entities
+/// expect to find in the Platform API state for this device
+fn thermometer_state(device: &Device, reading: &ThermometerAdvertisement) -> HttpDeviceState {
+    let quirk = device.resolve_quirk();
+    let temperature_units = quirk
+        .as_ref()
+        .and_then(|q| q.platform_temperature_sensor_units)
+        .unwrap_or(TemperatureUnits::Fahrenheit);
+    let humidity = match quirk.and_then(|q| q.platform_humidity_sensor_units) {
+        Some(HumidityUnits::RelativePercentTimes100) => reading.humidity * 100.,
+        Some(HumidityUnits::RelativePercent) | None => reading.humidity,
+    };
+    let temperature = TemperatureValue::with_celsius(reading.temperature_c)
+        .as_unit(temperature_units)
+        .value();
+
+    HttpDeviceState {
+        sku: device.sku.to_string(),
+        device: device.id.to_string(),
+        capabilities: vec![
+            DeviceCapabilityState {
+                kind: DeviceCapabilityKind::Property,
+                instance: "sensorTemperature".to_string(),
+                state: serde_json::json!({"value": temperature}),
+            },
+            DeviceCapabilityState {
+                kind: DeviceCapabilityKind::Property,
+                instance: "sensorHumidity".to_string(),
+                state: serde_json::json!({"value": {"currentHumidity": humidity}}),
+            },
+        ],
+    }
+}
+
+/// Resolves the device that is advertising from address.
+/// For some devices the id is a superset of the bluetooth address,
+/// so prefer a device that we already know about from the Govee
+/// APIs, otherwise identify it by its address.
+async fn resolve_device_id(state: &StateHandle, address: &str) -> String {
+    for device in state.devices().await {
+        if device.id.to_ascii_uppercase().ends_with(address) {
+            return device.id;
+        }
+    }
+    address.to_string()
+}
+
+async fn handle_advertisement(
+    state: &StateHandle,
+    adapter: &Adapter,
+    event: CentralEvent,
+) -> anyhow::Result<()> {
+    let CentralEvent::ManufacturerDataAdvertisement {
+        id,
+        manufacturer_data,
+    } = event
+    else {
+        return Ok(());
+    };
+    let Some(reading) = manufacturer_data
+        .iter()
+        .find_map(|(mfr, data)| ThermometerAdvertisement::parse(*mfr, data))
+    else {
+        return Ok(());
+    };
+
+    let peripheral = adapter.peripheral(&id).await?;
+    let Some(sku) = peripheral
+        .properties()
+        .await?
+        .and_then(|props| props.local_name)
+        .and_then(|name| sku_from_local_name(&name))
+    else {
+        return Ok(());
+    };
+
+    let address = peripheral.address().to_string();
+    let id = resolve_device_id(state, &address).await;
+    log::trace!("BLE: {sku} {id} {reading:?}");
+    {
+        let mut device = state.device_mut(&sku, &id).await;
+        device.set_ble_address(address);
+        if device.http_device_info.is_none() {
+            let info = thermometer_info(&device);
+            device.set_http_device_info(info);
+        }
+        let http_state = thermometer_state(&device, &reading);
+        device.set_http_device_state(http_state);
+    }
+    state.notify_of_state_change(&id).await
+}
+
+/// Periodically scans for advertisements from thermometers,
+/// reporting their readings as the state of those devices
+async fn run_thermometer_scan(
+    state: StateHandle,
+    adapter: Adapter,
+    scan_duration: Duration,
+) -> anyhow::Result<()> {
+    let mut events = adapter.events().await?;
+    loop {
+        adapter.start_scan(ScanFilter::default()).await?;
+        let deadline = Instant::now() + scan_duration;
+        while let Ok(Some(event)) = tokio::time::timeout_at(deadline, events.next()).await {
+            if let Err(err) = handle_advertisement(&state, &adapter, event).await {
+                log::error!("BLE advertisement: {err:#}");
+            }
+        }
+        adapter.stop_scan().await?;
+        tokio::time::sleep(SCAN_INTERVAL).await;
+    }
+}
+
+/// Starts scanning for thermometers, waiting for the first scan
+/// to complete so that the devices that it finds are registered
+/// with hass along with the rest
+pub async fn start_thermometer_scan(state: &StateHandle, scan_duration: Duration) {
+    let adapter = match default_adapter().await {
+        Ok(adapter) => adapter,
+        Err(err) => {
+            log::warn!("BLE scanning is disabled: {err:#}");
+            return;
+        }
+    };
+
+    log::info!("Starting BLE scan");
+    let state = state.clone();
+    tokio::spawn(async move {
+        if let Err(err) = run_thermometer_scan(state, adapter, scan_duration).await {
+            log::error!("BLE scan: {err:#}");
+        }
+    });
+    tokio::time::sleep(scan_duration).await;
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn thermometer_advertisement() {
+        assert_eq!(
+            ThermometerAdvertisement::parse(0xec88, &[0x00, 0x03, 0x49, 0x63, 0x64, 0x00]),
+            Some(ThermometerAdvertisement {
+                temperature_c: 21.5,
+                humidity: 39.5,
+            })
+        );
+        // Below freezing
+        assert_eq!(
+            ThermometerAdvertisement::parse(0xec88, &[0x00, 0x80, 0x13, 0x88, 0x32, 0x00]),
+            Some(ThermometerAdvertisement {
+                temperature_c: -0.5,
+                humidity: 0.0,
+            })
+        );
+        assert_eq!(
+            ThermometerAdvertisement::parse(0x004c, &[0x00, 0x03, 0x4a, 0x8b, 0x64]),
+            None
+        );
+        assert_eq!(ThermometerAdvertisement::parse(0xec88, &[0x00, 0x03]), None);
+    }
+
+    #[test]
+    fn pairing_error() {
+        assert!(is_pairing_error(
+            &anyhow::Error::new(btleplug::Error::PermissionDenied).context("writing")
+        ));
+        assert!(is_pairing_error(&anyhow::anyhow!(
+            "org.bluez.Error.NotPermitted: Write not permitted"
+        )));
+        assert!(!is_pairing_error(&anyhow::anyhow!(
+            "A4:C1:38:12:34:56 was not found"
+        )));
+    }
+
+    #[test]
+    fn cached_handles_round_trip() {
+        let file = std::env::temp_dir().join(format!(
+            "govee2mqtt-ble-handles-test-{}.json",
+            std::process::id()
+        ));
+        k9::assert_equal!(load_cached_handles(&file), HashMap::new());
+
+        let handles = HashMap::from([(
+            "A4:C1:38:12:34:56".to_string(),
+            CachedHandle {
+                adapter: "hci0 (usb:v1D6Bp0246d0540)".to_string(),
+                service: GOVEE_GATT_SERVICE_UUID,
+                characteristic: GOVEE_GATT_CONTROL_CHARACTERISTIC_UUID,
+            },
+        )]);
+        save_cached_handles(&file, &handles);
+        k9::assert_equal!(load_cached_handles(&file), handles);
+
+        std::fs::write(&file, "garbage").unwrap();
+        k9::assert_equal!(load_cached_handles(&file), HashMap::new());
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn local_name_sku() {
+        k9::assert_equal!(
+            sku_from_local_name("GVH5075_1A2B"),
+            Some("H5075".to_string())
+        );
+        k9::assert_equal!(
+            sku_from_local_name("Govee_H5074_1A2B"),
+            Some("H5074".to_string())
+        );
+        k9::assert_equal!(sku_from_local_name("GVH"), None);
+        k9::assert_equal!(sku_from_local_name("Pixel 7"), None);
+    }
+
+    #[test]
+    fn thermometer_readings() {
+        let device = Device::new("H5075", "A4:C1:38:12:34:56");
+        let reading = ThermometerAdvertisement {
+            temperature_c: 20.0,
+            humidity: 52.3,
+        };
+        let state = thermometer_state(&device, &reading);
+        k9::assert_equal!(
+            state
+                .capability_by_instance("sensorTemperature")
+                .unwrap()
+                .state,
+            serde_json::json!({"value": 68.0})
+        );
+        k9::assert_equal!(
+            state
+                .capability_by_instance("sensorHumidity")
+                .unwrap()
+                .state,
+            serde_json::json!({"value": {"currentHumidity": 52.3}})
+        );
+    }
+}
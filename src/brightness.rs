@@ -0,0 +1,132 @@
+use std::str::FromStr;
+
+pub const DEFAULT_GAMMA: f64 = 2.2;
+
+/// Controls how a brightness percentage requested by the user
+/// maps to the brightness percentage sent to the device.
+/// Many devices have a linear response, which makes the lower end
+/// of the brightness slider feel too bright; applying a gamma curve
+/// makes 50% look like 50% perceived brightness.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum BrightnessCurve {
+    #[default]
+    Linear,
+    Gamma(f64),
+}
+
+impl std::fmt::Display for BrightnessCurve {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Linear => fmt.write_str("linear"),
+            Self::Gamma(gamma) => write!(fmt, "gamma:{gamma}"),
+        }
+    }
+}
+
+impl FromStr for BrightnessCurve {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<BrightnessCurve> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("linear") {
+            return Ok(Self::Linear);
+        }
+        if s.eq_ignore_ascii_case("gamma") {
+            return Ok(Self::Gamma(DEFAULT_GAMMA));
+        }
+        if let Some((kind, gamma)) = s.split_once(':') {
+            if kind.eq_ignore_ascii_case("gamma") {
+                let gamma: f64 = gamma
+                    .trim()
+                    .parse()
+                    .map_err(|err| anyhow::anyhow!("Invalid gamma value in {s}: {err}"))?;
+                if !gamma.is_finite() || gamma <= 0. {
+                    anyhow::bail!("Gamma value in {s} must be a positive number");
+                }
+                return Ok(Self::Gamma(gamma));
+            }
+        }
+        anyhow::bail!("Unknown brightness curve {s}. Use linear, gamma or gamma:VALUE")
+    }
+}
+
+impl BrightnessCurve {
+    /// Map a user-facing brightness percentage to the percentage
+    /// that should be sent to the device
+    pub fn apply(self, percent: u8) -> u8 {
+        match self {
+            Self::Linear => percent,
+            Self::Gamma(gamma) => apply_exponent(percent, gamma),
+        }
+    }
+
+    /// Map a brightness percentage reported by the device back
+    /// to the user-facing percentage; the inverse of apply
+    pub fn invert(self, percent: u8) -> u8 {
+        match self {
+            Self::Linear => percent,
+            Self::Gamma(gamma) => apply_exponent(percent, 1. / gamma),
+        }
+    }
+}
+
+fn apply_exponent(percent: u8, exponent: f64) -> u8 {
+    let percent = percent.min(100);
+    if percent == 0 {
+        return 0;
+    }
+    let scaled = (100. * (percent as f64 / 100.).powf(exponent)).round();
+    // Don't allow a non-zero brightness to round down to zero,
+    // as that would turn the light off
+    scaled.clamp(1., 100.) as u8
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn parsing() {
+        assert_eq!(
+            "linear".parse::<BrightnessCurve>().unwrap(),
+            BrightnessCurve::Linear
+        );
+        assert_eq!(
+            "gamma".parse::<BrightnessCurve>().unwrap(),
+            BrightnessCurve::Gamma(DEFAULT_GAMMA)
+        );
+        assert_eq!(
+            "Gamma:1.8".parse::<BrightnessCurve>().unwrap(),
+            BrightnessCurve::Gamma(1.8)
+        );
+        assert!("gamma:0".parse::<BrightnessCurve>().is_err());
+        assert!("gamma:bogus".parse::<BrightnessCurve>().is_err());
+        assert!("log".parse::<BrightnessCurve>().is_err());
+    }
+
+    #[test]
+    fn mapping() {
+        let linear = BrightnessCurve::Linear;
+        for percent in 0..=100 {
+            assert_eq!(linear.apply(percent), percent);
+            assert_eq!(linear.invert(percent), percent);
+        }
+
+        let gamma = BrightnessCurve::Gamma(2.2);
+        assert_eq!(gamma.apply(0), 0);
+        assert_eq!(gamma.apply(1), 1);
+        assert_eq!(gamma.apply(50), 22);
+        assert_eq!(gamma.apply(100), 100);
+        assert_eq!(gamma.invert(0), 0);
+        assert_eq!(gamma.invert(22), 50);
+        assert_eq!(gamma.invert(100), 100);
+
+        // The slider position should be stable across a round trip
+        for percent in [25, 50, 75, 90, 100] {
+            let round_trip = gamma.invert(gamma.apply(percent));
+            assert!(
+                round_trip.abs_diff(percent) <= 1,
+                "{percent} -> {round_trip}"
+            );
+        }
+    }
+}
@@ -1,6 +1,7 @@
 use anyhow::Context;
 use arc_swap::ArcSwap;
 use chrono::{DateTime, Utc};
+use data_encoding::BASE32_NOPAD;
 use once_cell::sync::Lazy;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
@@ -10,17 +11,34 @@ use std::path::PathBuf;
 use std::sync::Arc;
 use std::time::Duration;
 
+/// The cache is shared via ArcSwap, so readers never block one another
+/// on a lock; sqlite-cache serializes concurrent updates to the same key
+/// via get_for_update, which also prevents a thundering herd of requests
+/// for the same key from all invoking the underlying computation.
 pub static CACHE: Lazy<ArcSwap<Cache>> =
     Lazy::new(|| open_cache().expect("failed to initialize cache").into());
 
-fn cache_file_name() -> PathBuf {
-    let cache_dir = std::env::var("GOVEE_CACHE_DIR")
+#[cfg(not(test))]
+pub fn cache_dir() -> PathBuf {
+    std::env::var("GOVEE_CACHE_DIR")
         .ok()
         .map(PathBuf::from)
         .or_else(|| dirs_next::cache_dir())
-        .expect("failed to resolve cache dir");
+        .expect("failed to resolve cache dir")
+}
 
-    cache_dir.join("govee2mqtt-cache.sqlite")
+/// Tests use a scratch location, so that they neither pollute
+/// nor get answered from the real cache, whichever test happens
+/// to be the first to open it
+#[cfg(test)]
+pub fn cache_dir() -> PathBuf {
+    let dir = std::env::temp_dir().join(format!("govee2mqtt-test-{}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("failed to create test cache dir");
+    dir
+}
+
+fn cache_file_name() -> PathBuf {
+    cache_dir().join("govee2mqtt-cache.sqlite")
 }
 
 fn open_cache() -> anyhow::Result<Arc<Cache>> {
@@ -96,6 +114,25 @@ pub fn invalidate_key(topic: &str, key: &str) -> anyhow::Result<()> {
     Ok(topic.delete(key)?)
 }
 
+/// Removes all of the entries for the specified topic, leaving
+/// the other topics intact.
+/// sqlite-cache doesn't provide a way to enumerate or clear a topic,
+/// so we reach into its table directly; this mirrors its own
+/// topic table naming scheme.
+#[allow(dead_code)]
+pub async fn cache_invalidate_topic(topic: &str) -> anyhow::Result<()> {
+    // Ensure that the table exists
+    CACHE.load().topic(topic)?;
+
+    let table_name = format!("topic_{}", BASE32_NOPAD.encode(topic.as_bytes()));
+    let cache_file = cache_file_name();
+    let conn = sqlite_cache::rusqlite::Connection::open(&cache_file)
+        .with_context(|| format!("opening {cache_file:?}"))?;
+    conn.execute(&format!("delete from {table_name}"), [])
+        .with_context(|| format!("invalidating cache topic {topic}"))?;
+    Ok(())
+}
+
 /// Cache an item with a soft TTL; we'll retry the operation
 /// if the TTL has expired, but allow stale reads
 pub async fn cache_get<T, Fut>(options: CacheGetOptions<'_>, future: Fut) -> anyhow::Result<T>
@@ -166,15 +203,123 @@ where
                 entry.result.into_result()
             }
             _ => {
-                let entry = CacheEntry {
+                let entry = CacheEntry::<T> {
                     expires: Utc::now() + options.negative_ttl,
                     result: CacheResult::Err(format!("{err:#}")),
                 };
 
                 let data = serde_json::to_string_pretty(&entry)?;
                 updater.write(data.as_bytes(), options.hard_ttl)?;
-                entry.result.into_result()
+                // Return the original error rather than its cached text,
+                // so that callers can inspect its type
+                Err(err)
             }
         },
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    fn options(key: &str) -> CacheGetOptions<'_> {
+        CacheGetOptions {
+            topic: "cache-test",
+            key,
+            soft_ttl: Duration::from_secs(60),
+            hard_ttl: Duration::from_secs(60),
+            negative_ttl: Duration::from_secs(60),
+            allow_stale: true,
+        }
+    }
+
+    #[tokio::test]
+    async fn concurrent_readers() {
+        invalidate_key("cache-test", "concurrent").unwrap();
+
+        let computed = Arc::new(AtomicUsize::new(0));
+        let mut readers = vec![];
+        for _ in 0..10 {
+            let computed = computed.clone();
+            readers.push(tokio::spawn(async move {
+                cache_get(options("concurrent"), async {
+                    computed.fetch_add(1, Ordering::SeqCst);
+                    Ok(CacheComputeResult::Value(42u32))
+                })
+                .await
+                .unwrap()
+            }));
+        }
+        for reader in readers {
+            k9::assert_equal!(reader.await.unwrap(), 42);
+        }
+        k9::assert_equal!(computed.load(Ordering::SeqCst), 1);
+    }
+
+    #[tokio::test]
+    async fn invalidate_whole_topic() {
+        let topic = "cache-test-invalidate";
+        let opts = |key| CacheGetOptions {
+            topic,
+            ..options(key)
+        };
+        for key in ["a", "b"] {
+            invalidate_key(topic, key).unwrap();
+            cache_get(opts(key), async { Ok(CacheComputeResult::Value(1u32)) })
+                .await
+                .unwrap();
+        }
+        invalidate_key("cache-test", "other-topic").unwrap();
+        cache_get(options("other-topic"), async {
+            Ok(CacheComputeResult::Value(1u32))
+        })
+        .await
+        .unwrap();
+
+        cache_invalidate_topic(topic).await.unwrap();
+
+        // Every entry for the topic is computed again
+        for key in ["a", "b"] {
+            let value = cache_get(opts(key), async { Ok(CacheComputeResult::Value(2u32)) })
+                .await
+                .unwrap();
+            k9::assert_equal!(value, 2);
+        }
+
+        // but other topics are untouched
+        let value = cache_get(options("other-topic"), async {
+            Ok(CacheComputeResult::Value(2u32))
+        })
+        .await
+        .unwrap();
+        k9::assert_equal!(value, 1);
+    }
+
+    #[tokio::test]
+    async fn stale_value_used_on_error() {
+        invalidate_key("cache-test", "stale").unwrap();
+
+        let opts = CacheGetOptions {
+            soft_ttl: Duration::ZERO,
+            ..options("stale")
+        };
+        let value = cache_get(opts, async { Ok(CacheComputeResult::Value(1u32)) })
+            .await
+            .unwrap();
+        k9::assert_equal!(value, 1);
+
+        // The soft ttl has expired, so we recompute, but the error
+        // causes the prior value to be returned
+        let value: u32 = cache_get(opts, async { anyhow::bail!("boom") })
+            .await
+            .unwrap();
+        k9::assert_equal!(value, 1);
+
+        // Within the negative ttl we don't try again
+        let value = cache_get(opts, async { Ok(CacheComputeResult::Value(2u32)) })
+            .await
+            .unwrap();
+        k9::assert_equal!(value, 1);
+    }
+}
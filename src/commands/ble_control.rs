@@ -0,0 +1,60 @@
+use crate::bluetooth::{is_pairing_error, BleClient};
+use btleplug::api::BDAddr;
+
+#[derive(clap::Parser, Debug)]
+#[command(group(clap::ArgGroup::new("control").required(true).multiple(true)))]
+pub struct BleControlCommand {
+    /// The bluetooth address of the device, eg: A4:C1:38:12:34:56
+    #[arg(long)]
+    mac: BDAddr,
+
+    /// Turn the device on or off
+    #[arg(long, value_enum, group = "control")]
+    power: Option<Power>,
+
+    /// Set the brightness, as a percentage
+    #[arg(long, value_parser = clap::value_parser!(u8).range(0..=100), group = "control")]
+    brightness: Option<u8>,
+
+    /// Set the color, eg: ff8000 or a CSS color name
+    #[arg(long, group = "control")]
+    color: Option<csscolorparser::Color>,
+}
+
+#[derive(clap::ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum Power {
+    On,
+    Off,
+}
+
+impl BleControlCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let client = BleClient::new(args.ble_args.scan_duration()?).await?;
+        let result = self.execute(&client).await;
+        client.disconnect().await;
+        if let Err(err) = &result {
+            if is_pairing_error(err) {
+                log::warn!(
+                    "{} refused the command; it may need to be paired with \
+                     this host first, for example by using bluetoothctl",
+                    self.mac
+                );
+            }
+        }
+        result
+    }
+
+    async fn execute(&self, client: &BleClient) -> anyhow::Result<()> {
+        if let Some(power) = self.power {
+            client.set_power(self.mac, power == Power::On).await?;
+        }
+        if let Some(percent) = self.brightness {
+            client.set_brightness(self.mac, percent).await?;
+        }
+        if let Some(color) = &self.color {
+            let [r, g, b, _a] = color.to_rgba8();
+            client.set_color_rgb(self.mac, r, g, b).await?;
+        }
+        Ok(())
+    }
+}
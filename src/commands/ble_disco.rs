@@ -0,0 +1,37 @@
+use crate::bluetooth::discover_devices;
+use crate::commands::OutputFormat;
+
+#[derive(clap::Parser, Debug)]
+pub struct BleDiscoCommand {
+    /// How to report the discovered devices. The json format
+    /// prints one JSON object per line for each device.
+    #[arg(long, value_enum, default_value_t)]
+    output: OutputFormat,
+}
+
+impl BleDiscoCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        for device in discover_devices(args.ble_args.scan_duration()?).await? {
+            if self.output == OutputFormat::Json {
+                println!("{}", serde_json::to_string(&device)?);
+                continue;
+            }
+
+            let reading = match &device.reading {
+                Some(reading) => format!("{}C {}%", reading.temperature_c, reading.humidity),
+                None => String::new(),
+            };
+            let rssi = match device.rssi {
+                Some(rssi) => format!("{rssi}dBm"),
+                None => String::new(),
+            };
+            println!(
+                "{address} {sku:<6} {name:<20} {rssi:<7} {reading}",
+                address = device.address,
+                sku = device.sku,
+                name = device.name,
+            );
+        }
+        Ok(())
+    }
+}
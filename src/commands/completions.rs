@@ -0,0 +1,20 @@
+use clap::CommandFactory;
+use clap_complete::Shell;
+
+/// Generates a shell completion script and prints it to stdout.
+/// For example, for bash:
+/// `govee completions bash > /etc/bash_completion.d/govee`
+#[derive(clap::Parser, Debug)]
+pub struct CompletionsCommand {
+    /// The shell for which to generate completions
+    shell: Shell,
+}
+
+impl CompletionsCommand {
+    pub async fn run(&self, _args: &crate::Args) -> anyhow::Result<()> {
+        let mut cmd = crate::Args::command();
+        let name = cmd.get_name().to_string();
+        clap_complete::generate(self.shell, &mut cmd, name, &mut std::io::stdout());
+        Ok(())
+    }
+}
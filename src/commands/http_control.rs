@@ -1,13 +1,21 @@
-use crate::platform_api::{DeviceParameters, EnumOption};
+use crate::platform_api::{
+    ControlDeviceResponseCapability, DeviceParameters, EnumOption, GoveeApiClient,
+    GoveeApiClientTrait, HttpDeviceInfo,
+};
+use anyhow::Context;
 use uncased::Uncased;
 
 #[derive(clap::Parser, Debug)]
 pub struct HttpControlCommand {
-    #[arg(long)]
+    #[arg(long, visible_alias = "device")]
     pub id: String,
 
+    /// Activate the named scene; a shortcut for the scene subcommand
+    #[arg(long)]
+    scene: Option<String>,
+
     #[command(subcommand)]
-    cmd: SubCommand,
+    cmd: Option<SubCommand>,
 }
 
 #[derive(clap::Parser, Debug, PartialEq)]
@@ -23,6 +31,12 @@ enum SubCommand {
     Color {
         color: csscolorparser::Color,
     },
+    /// Set the colors of several segments in a single request
+    SegmentColor {
+        /// SEGMENT=COLOR pairs, eg: 0=red 1=blue 2=ff8000
+        #[arg(required = true, value_parser = parse_segment_color)]
+        colors: Vec<(u32, csscolorparser::Color)>,
+    },
     Scene {
         /// List available scenes
         #[arg(long)]
@@ -51,7 +65,11 @@ enum SubCommand {
         mode: Option<String>,
     },
     /// Get current status
-    Status {},
+    Status {
+        /// Show only the state of this capability, eg: powerSwitch
+        #[arg(long)]
+        instance: Option<String>,
+    },
     /// Shows info about the device
     Info {},
 }
@@ -61,10 +79,23 @@ impl HttpControlCommand {
         let client = args.api_args.api_client()?;
         let device = client.get_device_by_id(&self.id).await?;
 
-        match &self.cmd {
+        let cmd = match (&self.cmd, &self.scene) {
+            (Some(_), Some(_)) => {
+                anyhow::bail!("--scene cannot be used together with a subcommand")
+            }
+            (Some(cmd), None) => cmd,
+            (None, Some(scene)) => {
+                let result = set_scene(&client, &device, scene).await?;
+                println!("{result:#?}");
+                return Ok(());
+            }
+            (None, None) => anyhow::bail!("Please specify either a subcommand or --scene"),
+        };
+
+        match cmd {
             SubCommand::On | SubCommand::Off => {
                 let result = client
-                    .set_power_state(&device, self.cmd == SubCommand::On)
+                    .set_power_state(&device, *cmd == SubCommand::On)
                     .await?;
                 println!("{result:#?}");
             }
@@ -73,11 +104,20 @@ impl HttpControlCommand {
                 println!("{device:#?}");
             }
 
-            SubCommand::Status {} => {
+            SubCommand::Status { instance: None } => {
                 let state = client.get_device_state(&device).await?;
                 println!("{state:#?}");
             }
 
+            SubCommand::Status {
+                instance: Some(instance),
+            } => {
+                let state = client
+                    .get_device_capability_state(&device, instance)
+                    .await?;
+                println!("{state:#}");
+            }
+
             SubCommand::Brightness { percent } => {
                 let result = client.set_brightness(&device, *percent).await?;
                 println!("{result:#?}");
@@ -98,6 +138,18 @@ impl HttpControlCommand {
                 println!("{result:#?}");
             }
 
+            SubCommand::SegmentColor { colors } => {
+                let segments: Vec<_> = colors
+                    .iter()
+                    .map(|(segment, color)| {
+                        let [r, g, b, _a] = color.to_rgba8();
+                        (*segment, r, g, b)
+                    })
+                    .collect();
+                let result = client.set_segment_multiple_rgb(&device, &segments).await?;
+                println!("{result:#?}");
+            }
+
             SubCommand::Scene { list, scene } => {
                 if *list {
                     let mut scenes: Vec<_> = client
@@ -111,7 +163,8 @@ impl HttpControlCommand {
                         println!("{name}");
                     }
                 } else if let Some(scene) = scene {
-                    client.set_scene_by_name(&device, scene).await?;
+                    let result = set_scene(&client, &device, scene).await?;
+                    println!("{result:#?}");
                 }
             }
             SubCommand::Music {
@@ -197,3 +250,45 @@ impl HttpControlCommand {
         Ok(())
     }
 }
+
+fn parse_segment_color(s: &str) -> anyhow::Result<(u32, csscolorparser::Color)> {
+    let (segment, color) = s
+        .split_once('=')
+        .ok_or_else(|| anyhow::anyhow!("expected SEGMENT=COLOR, got {s}"))?;
+    let segment = segment
+        .trim()
+        .parse()
+        .with_context(|| format!("parsing segment number in {s}"))?;
+    let color = color
+        .trim()
+        .parse()
+        .with_context(|| format!("parsing color in {s}"))?;
+    Ok((segment, color))
+}
+
+async fn set_scene(
+    client: &GoveeApiClient,
+    device: &HttpDeviceInfo,
+    scene: &str,
+) -> anyhow::Result<ControlDeviceResponseCapability> {
+    client
+        .set_scene_by_name(device, scene)
+        .await
+        .context("Use the `scene --list` subcommand to show the available scenes")
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn segment_color() {
+        let (segment, color) = parse_segment_color("2=ff8000").unwrap();
+        k9::assert_equal!(segment, 2);
+        k9::assert_equal!(color.to_rgba8(), [0xff, 0x80, 0x00, 0xff]);
+        k9::assert_equal!(parse_segment_color("0 = red").unwrap().0, 0);
+        assert!(parse_segment_color("red").is_err());
+        assert!(parse_segment_color("x=red").is_err());
+        assert!(parse_segment_color("1=notacolor").is_err());
+    }
+}
@@ -1,5 +1,6 @@
 use crate::ble::{Base64HexBytes, SetSceneCode};
-use crate::lan_api::{Client, DiscoOptions};
+use crate::commands::OutputFormat;
+use crate::lan_api::{Client, DiscoOptions, LanDevice};
 use crate::undoc_api::GoveeUndocumentedApi;
 use clap_num::maybe_hex;
 use std::collections::BTreeMap;
@@ -11,6 +12,10 @@ pub struct LanControlCommand {
     #[arg(long)]
     pub ip: IpAddr,
 
+    /// How to report the result of the command
+    #[arg(long, value_enum, default_value_t)]
+    output: OutputFormat,
+
     #[command(subcommand)]
     cmd: SubCommand,
 }
@@ -53,23 +58,54 @@ impl LanControlCommand {
 
         let device = client.scan_ip(self.ip).await?;
 
+        let result = self.execute(&client, &device).await;
+        if self.output == OutputFormat::Json {
+            let report = match &result {
+                Ok(response) => serde_json::json!({
+                    "device": device.device,
+                    "success": true,
+                    "response": response,
+                }),
+                Err(err) => serde_json::json!({
+                    "device": device.device,
+                    "success": false,
+                    "error": format!("{err:#}"),
+                }),
+            };
+            println!("{}", serde_json::to_string_pretty(&report)?);
+        }
+        result.map(|_| ())
+    }
+
+    /// Runs the command, returning any command-specific data
+    /// that should be included in the JSON output
+    async fn execute(
+        &self,
+        client: &Client,
+        device: &LanDevice,
+    ) -> anyhow::Result<serde_json::Value> {
+        let text = self.output == OutputFormat::Text;
+        let mut response = serde_json::Value::Null;
+
         match &self.cmd {
             SubCommand::On => {
-                device.send_turn(true).await?;
+                client.send_turn(device, true).await?;
             }
             SubCommand::Off => {
-                device.send_turn(false).await?;
+                client.send_turn(device, false).await?;
             }
             SubCommand::Brightness { percent } => {
-                device.send_brightness(*percent).await?;
+                client.send_brightness(device, *percent).await?;
             }
             SubCommand::Temperature { kelvin } => {
-                device.send_color_temperature_kelvin(*kelvin).await?;
+                client
+                    .send_color_temperature_kelvin(device, *kelvin)
+                    .await?;
             }
             SubCommand::Color { color } => {
                 let [r, g, b, _a] = color.to_rgba8();
-                device
-                    .send_color_rgb(crate::lan_api::DeviceColor { r, g, b })
+                client
+                    .send_color_rgb(device, crate::lan_api::DeviceColor { r, g, b })
                     .await?;
             }
             SubCommand::Scene { list, scene } => {
@@ -88,16 +124,30 @@ impl LanControlCommand {
                     }
                 }
                 if *list {
-                    for name in scene_code_by_name.keys() {
-                        println!("{name}");
+                    if text {
+                        for name in scene_code_by_name.keys() {
+                            println!("{name}");
+                        }
                     }
+                    response = serde_json::json!({
+                        "scenes": scene_code_by_name
+                            .keys()
+                            .map(|name| name.as_str())
+                            .collect::<Vec<_>>(),
+                    });
                 } else {
                     let scene = Uncased::new(scene.clone().expect("scene if not list"));
                     if let Some(code) = scene_code_by_name.get(&scene) {
                         let encoded =
                             Base64HexBytes::encode_for_sku("Generic:Light", code)?.base64();
-                        println!("Computed {encoded:?}");
-                        device.send_real(encoded).await?;
+                        if text {
+                            println!("Computed {encoded:?}");
+                        }
+                        response = serde_json::json!({
+                            "scene": scene.as_str(),
+                            "encoded": encoded,
+                        });
+                        client.send_real(device, encoded).await?;
                     } else {
                         anyhow::bail!("scene {scene} not found");
                     }
@@ -105,11 +155,14 @@ impl LanControlCommand {
             }
             SubCommand::Command { data } => {
                 let encoded = Base64HexBytes::with_bytes(data.to_vec()).base64();
-                println!("encoded: {encoded:?}");
-                device.send_real(encoded).await?;
+                if text {
+                    println!("encoded: {encoded:?}");
+                }
+                response = serde_json::json!({"encoded": encoded});
+                client.send_real(device, encoded).await?;
             }
         }
 
-        Ok(())
+        Ok(response)
     }
 }
@@ -1,8 +1,14 @@
+use crate::commands::OutputFormat;
 use crate::lan_api::Client;
 use tokio::time::{Duration, Instant};
 
 #[derive(clap::Parser, Debug)]
-pub struct LanDiscoCommand {}
+pub struct LanDiscoCommand {
+    /// How to report the discovered devices. The json format
+    /// prints one JSON object per line for each device.
+    #[arg(long, value_enum, default_value_t)]
+    output: OutputFormat,
+}
 
 impl LanDiscoCommand {
     pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
@@ -17,13 +23,39 @@ impl LanDiscoCommand {
 
         let state = crate::service::state::State::new();
 
-        while let Ok(Some(lan_device)) = tokio::time::timeout_at(deadline, scan.recv()).await {
+        while let Ok(Some(disco)) = tokio::time::timeout_at(deadline, scan.recv()).await {
+            if !disco.is_new {
+                continue;
+            }
+            let lan_device = disco.device;
             if !state.device_by_id(&lan_device.device).await.is_some() {
                 let mut device = state.device_mut(&lan_device.sku, &lan_device.device).await;
 
                 device.set_lan_device(lan_device.clone());
 
-                let status = match client.query_status(&lan_device).await {
+                let status = client.query_status(&lan_device).await;
+
+                if self.output == OutputFormat::Json {
+                    let mut report = serde_json::json!({
+                        "ip": lan_device.ip,
+                        "sku": lan_device.sku,
+                        "device": lan_device.device,
+                        "name": device.computed_name(),
+                    });
+                    match &status {
+                        Ok(status) => {
+                            device.set_lan_device_status(status.clone());
+                            report["status"] = serde_json::to_value(status)?;
+                        }
+                        Err(err) => {
+                            report["error"] = format!("{err:#}").into();
+                        }
+                    }
+                    println!("{}", serde_json::to_string(&report)?);
+                    continue;
+                }
+
+                let status = match status {
                     Ok(status) => {
                         device.set_lan_device_status(status.clone());
                         if status.on {
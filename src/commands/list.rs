@@ -1,4 +1,7 @@
 use crate::lan_api::Client as LanClient;
+use crate::platform_api::DeviceType;
+use crate::service::device::Device;
+use crate::service::state::StateHandle;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
@@ -8,74 +11,38 @@ use tokio::time::Instant;
 pub struct ListCommand {
     #[arg(long)]
     skip_lan: bool,
+
+    /// Only list devices of this type, eg: light, sensor, heater
+    #[arg(long = "type")]
+    device_type: Option<String>,
+
+    /// Print the devices as a JSON array
+    #[arg(long)]
+    json: bool,
 }
 
 impl ListCommand {
     pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
-        let state = Arc::new(crate::service::state::State::new());
+        let state = discover_devices(args, self.skip_lan).await?;
 
-        let options = args.lan_disco_args.to_disco_options()?;
-        if options.is_empty() {
-            anyhow::bail!("Discovery options are empty");
-        }
+        let device_type = self
+            .device_type
+            .as_deref()
+            .map(DeviceType::from_short_name)
+            .transpose()?;
 
-        let disco = if self.skip_lan {
-            None
-        } else {
-            eprintln!(
-                "Waiting {} seconds for LAN discovery, use --skip-lan to skip...",
-                args.lan_disco_args.disco_timeout()?
-            );
-            let deadline =
-                Instant::now() + Duration::from_secs(args.lan_disco_args.disco_timeout()?);
-            let state = state.clone();
-            let (client, mut scan) = LanClient::new(options).await?;
-            Some(tokio::spawn(async move {
-                while let Ok(Some(lan_device)) =
-                    tokio::time::timeout_at(deadline, scan.recv()).await
-                {
-                    state
-                        .device_mut(&lan_device.sku, &lan_device.device)
-                        .await
-                        .set_lan_device(lan_device.clone());
-
-                    if let Ok(status) = client.query_status(&lan_device).await {
-                        state
-                            .device_mut(&lan_device.sku, &lan_device.device)
-                            .await
-                            .set_lan_device_status(status);
-                    }
-                }
-            }))
-        };
-
-        if let Ok(client) = args.api_args.api_client() {
-            for info in client.get_devices().await? {
-                let mut device = state.device_mut(&info.sku, &info.device).await;
-                device.set_http_device_info(info);
-            }
-        }
-        if let Ok(client) = args.undoc_args.api_client() {
-            let acct = client.login_account_cached().await?;
-            let info = client.get_device_list(&acct.token).await?;
-            let mut group_by_id = HashMap::new();
-            for group in info.groups {
-                group_by_id.insert(group.group_id, group.group_name);
-            }
-            for entry in info.devices {
-                let mut device = state.device_mut(&entry.sku, &entry.device).await;
-                let room_name = group_by_id.get(&entry.group_id).map(|name| name.as_str());
-                device.set_undoc_device_info(entry, room_name);
-            }
+        let mut devices = state.devices().await;
+        if let Some(device_type) = &device_type {
+            devices.retain(|d| d.device_type() == *device_type);
         }
+        devices.sort_by_key(|d| (d.room_name().map(|name| name.to_string()), d.name()));
 
-        if let Some(disco) = disco {
-            disco.await?;
+        if self.json {
+            let devices: Vec<_> = devices.iter().map(device_summary).collect();
+            println!("{}", serde_json::to_string_pretty(&devices)?);
+            return Ok(());
         }
 
-        let mut devices = state.devices().await;
-        devices.sort_by_key(|d| (d.room_name().map(|name| name.to_string()), d.name()));
-
         for d in devices {
             println!(
                 "{sku:<7} {id} {ip:<15} {name} {room}",
@@ -96,3 +63,96 @@ impl ListCommand {
         Ok(())
     }
 }
+
+fn device_summary(device: &Device) -> serde_json::Value {
+    serde_json::json!({
+        "sku": device.sku,
+        "device": device.id,
+        "name": device.name(),
+        "type": device.device_type().short_name(),
+        "capabilities": {
+            "rgb": device.supports_rgb(),
+            "brightness": device.supports_brightness(),
+            "color_temperature": device.get_color_temperature_range(),
+            "lan": device.lan_device.is_some(),
+            "platform": device
+                .http_device_info
+                .as_ref()
+                .map(|info| {
+                    info.capabilities
+                        .iter()
+                        .map(|cap| cap.instance.as_str())
+                        .collect::<Vec<_>>()
+                })
+                .unwrap_or_default(),
+        },
+    })
+}
+
+/// Discovers devices via the LAN, and the Platform and undocumented
+/// APIs when credentials are available, returning the populated state
+pub async fn discover_devices(args: &crate::Args, skip_lan: bool) -> anyhow::Result<StateHandle> {
+    let state = Arc::new(crate::service::state::State::new());
+
+    let options = args.lan_disco_args.to_disco_options()?;
+    if options.is_empty() {
+        anyhow::bail!("Discovery options are empty");
+    }
+
+    let disco = if skip_lan {
+        None
+    } else {
+        eprintln!(
+            "Waiting {} seconds for LAN discovery, use --skip-lan to skip...",
+            args.lan_disco_args.disco_timeout()?
+        );
+        let deadline = Instant::now() + Duration::from_secs(args.lan_disco_args.disco_timeout()?);
+        let state = state.clone();
+        let (client, mut scan) = LanClient::new(options).await?;
+        Some(tokio::spawn(async move {
+            while let Ok(Some(disco)) = tokio::time::timeout_at(deadline, scan.recv()).await {
+                if !disco.is_new {
+                    continue;
+                }
+                let lan_device = disco.device;
+                state
+                    .device_mut(&lan_device.sku, &lan_device.device)
+                    .await
+                    .set_lan_device(lan_device.clone());
+
+                if let Ok(status) = client.query_status(&lan_device).await {
+                    state
+                        .device_mut(&lan_device.sku, &lan_device.device)
+                        .await
+                        .set_lan_device_status(status);
+                }
+            }
+        }))
+    };
+
+    if let Ok(client) = args.api_args.api_client() {
+        for info in client.get_devices().await? {
+            let mut device = state.device_mut(&info.sku, &info.device).await;
+            device.set_http_device_info(info);
+        }
+    }
+    if let Ok(client) = args.undoc_args.api_client() {
+        let acct = client.login_account_cached().await?;
+        let info = client.get_device_list(&acct.token).await?;
+        let mut group_by_id = HashMap::new();
+        for group in info.groups {
+            group_by_id.insert(group.group_id, group.group_name);
+        }
+        for entry in info.devices {
+            let mut device = state.device_mut(&entry.sku, &entry.device).await;
+            let room_name = group_by_id.get(&entry.group_id).map(|name| name.as_str());
+            device.set_undoc_device_info(entry, room_name);
+        }
+    }
+
+    if let Some(disco) = disco {
+        disco.await?;
+    }
+
+    Ok(state)
+}
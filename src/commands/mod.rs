@@ -1,7 +1,25 @@
+#[cfg(feature = "ble")]
+pub mod ble_control;
+#[cfg(feature = "ble")]
+pub mod ble_disco;
+pub mod completions;
 pub mod http_control;
 pub mod lan_control;
 pub mod lan_disco;
 pub mod list;
 pub mod list_http;
+pub mod mqtt_test;
+pub mod purge_discovery;
+pub mod scene_check;
 pub mod serve;
 pub mod undoc;
+
+/// Selects how a command reports its results
+#[derive(clap::ValueEnum, Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum OutputFormat {
+    /// Human readable text
+    #[default]
+    Text,
+    /// JSON, for consumption by scripts
+    Json,
+}
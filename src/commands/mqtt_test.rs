@@ -0,0 +1,134 @@
+use anyhow::Context;
+use mosquitto_rs::{Client, Event, QoS};
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Checks that the configured mqtt broker is reachable and that
+/// we can authenticate, publish and subscribe.
+/// Useful for verifying the mqtt configuration prior to running serve.
+#[derive(clap::Parser, Debug)]
+pub struct MqttTestCommand {
+    /// How many seconds to wait for each step of the test
+    #[arg(long, default_value_t = 10)]
+    timeout: u64,
+}
+
+impl MqttTestCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let hass_args = &args.hass_args;
+        let mqtt_host = hass_args.mqtt_host()?;
+        let mqtt_port = hass_args.mqtt_port()?;
+        let mqtt_username = hass_args.mqtt_username()?;
+        let mqtt_password = hass_args.mqtt_password()?;
+        let step_timeout = Duration::from_secs(self.timeout);
+
+        if mqtt_username.is_some() != mqtt_password.is_some() {
+            anyhow::bail!(
+                "MQTT username and password either both need to be set, or both need to be unset"
+            );
+        }
+
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let client = Client::with_id(&format!("govee2mqtt/mqtt-test/{id}"), true)?;
+        client.set_username_and_password(mqtt_username.as_deref(), mqtt_password.as_deref())?;
+
+        println!(
+            "Connecting to {mqtt_host}:{mqtt_port} {}",
+            match &mqtt_username {
+                Some(user) => format!("as {user}"),
+                None => "without authentication".to_string(),
+            }
+        );
+
+        let status = match timeout(
+            step_timeout,
+            client.connect(
+                &mqtt_host,
+                mqtt_port.into(),
+                Duration::from_secs(120),
+                hass_args.mqtt_bind_address(),
+            ),
+        )
+        .await
+        {
+            Err(_) => anyhow::bail!(
+                "Timed out after {step_timeout:?} connecting to {mqtt_host}:{mqtt_port}. \
+                 Is the broker running, and is the host and port correct?"
+            ),
+            Ok(Err(mosquitto_rs::Error::RejectedConnection(status))) => {
+                if is_auth_failure(&status) {
+                    anyhow::bail!(
+                        "Authentication failed: the broker at {mqtt_host}:{mqtt_port} \
+                         rejected the connection with {status}. \
+                         Check the mqtt username and password."
+                    );
+                }
+                anyhow::bail!(
+                    "The broker at {mqtt_host}:{mqtt_port} rejected the connection with {status}"
+                );
+            }
+            Ok(Err(mosquitto_rs::Error::Resolution(err))) => anyhow::bail!(
+                "Unable to resolve mqtt host {mqtt_host}: {err}. Is the hostname correct?"
+            ),
+            Ok(Err(err)) => anyhow::bail!(
+                "Unable to reach the broker at {mqtt_host}:{mqtt_port}: {err:#}. \
+                 Is the broker running, and is the host and port correct?"
+            ),
+            Ok(Ok(status)) => status,
+        };
+        println!("Connected: {status}");
+
+        let subscriber = client.subscriber().expect("to own the subscriber");
+        let topic = format!("gv2mqtt/mqtt-test/{id}");
+        let payload = format!("govee2mqtt test message {id}");
+
+        timeout(step_timeout, client.subscribe(&topic, QoS::AtLeastOnce))
+            .await
+            .with_context(|| format!("Timed out subscribing to {topic}"))?
+            .with_context(|| format!("Failed to subscribe to {topic}. Check the broker ACLs"))?;
+        println!("Subscribed to {topic}");
+
+        timeout(
+            step_timeout,
+            client.publish(&topic, &payload, QoS::AtLeastOnce, false),
+        )
+        .await
+        .with_context(|| format!("Timed out publishing to {topic}"))?
+        .with_context(|| format!("Failed to publish to {topic}. Check the broker ACLs"))?;
+        println!("Published test message to {topic}");
+
+        let received = timeout(step_timeout, async {
+            while let Ok(event) = subscriber.recv().await {
+                match event {
+                    Event::Message(msg) if msg.topic == topic => {
+                        return Some(msg.payload);
+                    }
+                    Event::Disconnected(reason) => {
+                        println!("Disconnected from broker with reason {reason}");
+                    }
+                    _ => {}
+                }
+            }
+            None
+        })
+        .await
+        .with_context(|| format!("Timed out waiting to receive the test message on {topic}"))?
+        .ok_or_else(|| anyhow::anyhow!("Connection closed before the test message arrived"))?;
+
+        if received != payload.as_bytes() {
+            anyhow::bail!(
+                "Received unexpected payload on {topic}: {}",
+                String::from_utf8_lossy(&received)
+            );
+        }
+
+        println!("Received test message. MQTT is working correctly!");
+        Ok(())
+    }
+}
+
+/// The CONNACK codes for "bad username or password" and
+/// "not authorized" from the MQTT 3.1.1 spec
+fn is_auth_failure(status: &mosquitto_rs::ConnectionStatus) -> bool {
+    matches!(status.0, 4 | 5)
+}
@@ -0,0 +1,177 @@
+use crate::commands::list::discover_devices;
+use crate::service::hass::topic_safe_id;
+use anyhow::Context;
+use mosquitto_rs::{Client, Event, QoS};
+use serde::Deserialize;
+use std::collections::{BTreeMap, HashSet};
+use std::time::Duration;
+use tokio::time::timeout;
+
+/// Removes home assistant discovery configs that were retained on
+/// the mqtt broker for devices that are no longer present in your
+/// account or on your LAN, such as after removing or re-pairing
+/// a device.
+#[derive(clap::Parser, Debug)]
+pub struct PurgeDiscoveryCommand {
+    /// How many seconds to wait for further retained config
+    /// messages to arrive from the broker before deciding that
+    /// we have seen all of them
+    #[arg(long, default_value_t = 5)]
+    timeout: u64,
+
+    /// Skip LAN discovery when determining the current set of
+    /// devices. Only do this if all of your devices are known
+    /// to the Govee cloud APIs, otherwise the configs for your
+    /// LAN-only devices will be removed!
+    #[arg(long)]
+    skip_lan: bool,
+}
+
+#[derive(Deserialize, Debug)]
+struct DiscoveryConfig {
+    device: Option<DiscoveryDevice>,
+}
+
+#[derive(Deserialize, Debug)]
+struct DiscoveryDevice {
+    #[serde(default)]
+    identifiers: Vec<String>,
+}
+
+/// If the discovery config payload was published by us for a device
+/// that is not in `current_ids`, returns the id of that device.
+/// Configs that belong to other integrations, or to the govee2mqtt
+/// service itself, are never considered to be orphaned.
+fn orphaned_device_id(payload: &[u8], current_ids: &HashSet<String>) -> Option<String> {
+    let config: DiscoveryConfig = serde_json::from_slice(payload).ok()?;
+    config
+        .device?
+        .identifiers
+        .into_iter()
+        .filter_map(|ident| ident.strip_prefix("gv2mqtt-").map(|id| id.to_string()))
+        .find(|id| !current_ids.contains(id))
+}
+
+impl PurgeDiscoveryCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let state = discover_devices(args, self.skip_lan).await?;
+        let current_ids: HashSet<String> =
+            state.devices().await.iter().map(topic_safe_id).collect();
+        if current_ids.is_empty() {
+            anyhow::bail!(
+                "No devices were discovered, so every govee2mqtt config would be \
+                 considered stale. Check your LAN and API configuration and try again."
+            );
+        }
+        println!("Found {} current devices", current_ids.len());
+
+        let hass_args = &args.hass_args;
+        let mqtt_host = hass_args.mqtt_host()?;
+        let mqtt_port = hass_args.mqtt_port()?;
+        let mqtt_username = hass_args.mqtt_username()?;
+        let mqtt_password = hass_args.mqtt_password()?;
+        if mqtt_username.is_some() != mqtt_password.is_some() {
+            anyhow::bail!(
+                "MQTT username and password either both need to be set, or both need to be unset"
+            );
+        }
+
+        let id = uuid::Uuid::new_v4().simple().to_string();
+        let client = Client::with_id(&format!("govee2mqtt/purge-discovery/{id}"), true)?;
+        client.set_username_and_password(mqtt_username.as_deref(), mqtt_password.as_deref())?;
+        client
+            .connect(
+                &mqtt_host,
+                mqtt_port.into(),
+                Duration::from_secs(120),
+                hass_args.mqtt_bind_address(),
+            )
+            .await
+            .with_context(|| format!("connecting to mqtt broker {mqtt_host}:{mqtt_port}"))?;
+        let subscriber = client.subscriber().expect("to own the subscriber");
+
+        let pattern = format!("{}/+/+/config", hass_args.hass_discovery_prefix());
+        client
+            .subscribe(&pattern, QoS::AtMostOnce)
+            .await
+            .with_context(|| format!("subscribing to {pattern}"))?;
+
+        // The broker sends the retained messages immediately after
+        // we subscribe; keep collecting until they stop arriving
+        let quiet_period = Duration::from_secs(self.timeout);
+        let mut orphans = BTreeMap::new();
+        while let Ok(Ok(event)) = timeout(quiet_period, subscriber.recv()).await {
+            match event {
+                Event::Message(msg) if msg.retain && !msg.payload.is_empty() => {
+                    if let Some(device_id) = orphaned_device_id(&msg.payload, &current_ids) {
+                        orphans.insert(msg.topic, device_id);
+                    }
+                }
+                Event::Disconnected(reason) => {
+                    anyhow::bail!("Disconnected from broker with reason {reason}");
+                }
+                _ => {}
+            }
+        }
+
+        if orphans.is_empty() {
+            println!("No stale discovery configs were found");
+            return Ok(());
+        }
+
+        for (topic, device_id) in &orphans {
+            // The global --dry-run flag; our client doesn't go through
+            // HassClient::publish, so we need to check for it here
+            if crate::is_dry_run() {
+                println!("Would remove {topic} for device {device_id}");
+                continue;
+            }
+            // An empty retained payload causes both the broker and
+            // hass to forget the config
+            client
+                .publish(topic, b"", QoS::AtLeastOnce, true)
+                .await
+                .with_context(|| format!("clearing {topic}"))?;
+            println!("Removed {topic} for device {device_id}");
+        }
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn orphan_detection() {
+        let current_ids: HashSet<String> = ["AABBCCDDEEFF0011".to_string()].into_iter().collect();
+
+        let config = |ident: &str| {
+            serde_json::json!({
+                "name": "Light",
+                "device": {"identifiers": [ident]},
+            })
+            .to_string()
+        };
+
+        assert_eq!(
+            orphaned_device_id(config("gv2mqtt-AABBCCDDEEFF0011").as_bytes(), &current_ids),
+            None
+        );
+        assert_eq!(
+            orphaned_device_id(config("gv2mqtt-1122334455667788").as_bytes(), &current_ids),
+            Some("1122334455667788".to_string())
+        );
+        // The service device and other integrations are left alone
+        assert_eq!(
+            orphaned_device_id(config("gv2mqtt").as_bytes(), &current_ids),
+            None
+        );
+        assert_eq!(
+            orphaned_device_id(config("zigbee2mqtt_0x1234").as_bytes(), &current_ids),
+            None
+        );
+        assert_eq!(orphaned_device_id(b"not json", &current_ids), None);
+    }
+}
@@ -0,0 +1,101 @@
+use crate::platform_api::HttpDeviceInfo;
+
+/// How similar a scene name must be to the requested name
+/// for it to be suggested as a close match
+const CLOSE_MATCH_THRESHOLD: f64 = 0.8;
+const MAX_CLOSE_MATCHES: usize = 5;
+
+/// Checks whether a scene is available for a device, ignoring case.
+/// Exits with a non-zero status if the scene is not available, and
+/// suggests scenes with similar names.
+/// Useful for verifying the scene names used in automations.
+#[derive(clap::Parser, Debug)]
+pub struct SceneCheckCommand {
+    /// The id or name of the device
+    #[arg(long, visible_alias = "id")]
+    device: String,
+
+    /// The name of the scene to check
+    scene: String,
+}
+
+impl SceneCheckCommand {
+    pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
+        let client = args.api_args.api_client()?;
+        let device = resolve_device(client.get_devices().await?, &self.device)?;
+        let scenes = client.list_scene_names(&device).await?;
+
+        if let Some(name) = scenes
+            .iter()
+            .find(|name| name.eq_ignore_ascii_case(&self.scene))
+        {
+            println!("Scene '{name}' is available for {}", device.device_name);
+            return Ok(());
+        }
+
+        let matches = close_matches(&scenes, &self.scene);
+        if matches.is_empty() {
+            anyhow::bail!(
+                "Scene '{}' is not available for {}. \
+                 Use `govee http-control --device {} scene --list` to list the available scenes",
+                self.scene,
+                device.device_name,
+                device.device
+            );
+        }
+        anyhow::bail!(
+            "Scene '{}' is not available for {}. Did you mean: {}?",
+            self.scene,
+            device.device_name,
+            matches.join(", ")
+        );
+    }
+}
+
+fn resolve_device(devices: Vec<HttpDeviceInfo>, label: &str) -> anyhow::Result<HttpDeviceInfo> {
+    devices
+        .into_iter()
+        .find(|d| d.device == label || d.device_name.eq_ignore_ascii_case(label))
+        .ok_or_else(|| anyhow::anyhow!("device {label} not found"))
+}
+
+/// Returns the scenes whose names are similar to `wanted`,
+/// most similar first
+fn close_matches<'a>(scenes: &'a [String], wanted: &str) -> Vec<&'a str> {
+    let wanted = wanted.to_lowercase();
+    let mut scored: Vec<(f64, &str)> = scenes
+        .iter()
+        .filter_map(|name| {
+            let lower = name.to_lowercase();
+            let score = if lower.contains(&wanted) || wanted.contains(&lower) {
+                1.0
+            } else {
+                strsim::jaro_winkler(&lower, &wanted)
+            };
+            (score >= CLOSE_MATCH_THRESHOLD).then_some((score, name.as_str()))
+        })
+        .collect();
+    scored.sort_by(|a, b| b.0.total_cmp(&a.0).then_with(|| a.1.cmp(b.1)));
+    scored
+        .into_iter()
+        .take(MAX_CLOSE_MATCHES)
+        .map(|(_, name)| name)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn suggestions() {
+        let scenes: Vec<String> = ["Sunrise", "Sunset", "Rainbow", "Rustling Leaves", "Aurora"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+
+        k9::assert_equal!(close_matches(&scenes, "sunsett"), vec!["Sunset", "Sunrise"]);
+        k9::assert_equal!(close_matches(&scenes, "leaves"), vec!["Rustling Leaves"]);
+        k9::assert_equal!(close_matches(&scenes, "xyzzy"), Vec::<&str>::new());
+    }
+}
@@ -1,35 +1,322 @@
-use crate::lan_api::Client as LanClient;
+use crate::brightness::BrightnessCurve;
+use crate::hass_mqtt::light::LightScenes;
+use crate::hass_mqtt::switch::PowerEntity;
+use crate::lan_api::{
+    truthy, Client as LanClient, ColorStateEncoding, LanDiscovery, QUERY_STATUS_TIMEOUT,
+};
+use crate::opt_env_var;
+use crate::platform_api::{ApiError, DeviceType};
 use crate::service::device::Device;
 use crate::service::hass::spawn_hass_integration;
-use crate::service::http::run_http_server;
+use crate::service::http::{run_health_server, run_http_server, run_webhook_server};
 use crate::service::iot::start_iot_client;
-use crate::service::state::StateHandle;
+use crate::service::state::{StateHandle, DEFAULT_BOIL_THRESHOLD_C, DEFAULT_POLL_INTERVAL_SECS};
+use crate::temperature::TemperatureScale;
 use crate::version_info::govee_version;
 use anyhow::Context;
 use chrono::Utc;
-use once_cell::sync::Lazy;
 use std::collections::HashMap;
 use std::sync::Arc;
 use tokio::time::{sleep, Duration};
 
-pub const POLL_INTERVAL: Lazy<chrono::Duration> = Lazy::new(|| chrono::Duration::seconds(900));
+/// Polling more frequently than this risks exhausting the API quota,
+/// so we never poll a device more often than this, regardless of
+/// the configuration
+pub const MIN_POLL_INTERVAL_SECS: u64 = 10;
+const DEFAULT_PUSH_STALENESS_SECS: u64 = 3600;
+const DEFAULT_WHITE_COLOR_TEMPERATURE: u32 = 5000;
 
 #[derive(clap::Parser, Debug)]
 pub struct ServeCommand {
     /// The port on which the HTTP API will listen
     #[arg(long, default_value_t = 8056)]
     http_port: u16,
+
+    /// If set, serve a health check at GET /health on this port,
+    /// for use with container liveness probes. It returns 503 while
+    /// the mqtt broker is disconnected.
+    /// You may also set this via the GOVEE_HEALTH_PORT environment
+    /// variable. If unspecified, the health check is disabled.
+    #[arg(long)]
+    health_port: Option<u16>,
+
+    /// The id or name of a device whose state is pushed to us via the IoT API.
+    /// Such devices are not polled unless no push has been received
+    /// within the --push-staleness-secs window.
+    /// May be specified multiple times.
+    /// You may also set this via the GOVEE_PUSH_UPDATED_DEVICES environment
+    /// variable, as a comma separated list of device ids.
+    #[arg(long = "push-updated-device")]
+    push_updated_devices: Vec<String>,
+
+    /// The id or name of a device to manage. If any are specified,
+    /// all other devices are ignored.
+    /// May be specified multiple times.
+    /// You may also set this via the GOVEE_INCLUDE_DEVICES environment
+    /// variable, as a comma separated list of device ids.
+    #[arg(long = "include-device")]
+    include_devices: Vec<String>,
+
+    /// The id or name of a device to ignore: it is neither polled
+    /// nor published to Home Assistant.
+    /// May be specified multiple times.
+    /// You may also set this via the GOVEE_EXCLUDE_DEVICES environment
+    /// variable, as a comma separated list of device ids.
+    #[arg(long = "exclude-device")]
+    exclude_devices: Vec<String>,
+
+    /// How many seconds may elapse without receiving a push update
+    /// for a push-updated device before we resume polling it.
+    /// You may also set this via the GOVEE_PUSH_STALENESS_SECS environment
+    /// variable. If unspecified, uses 3600.
+    #[arg(long)]
+    push_staleness_secs: Option<u64>,
+
+    /// How many seconds to wait between polling each device for its
+    /// state. Values below 10 are clamped to 10, to avoid exhausting
+    /// the API quota.
+    /// You may also set this via the GOVEE_POLL_INTERVAL_SECS environment
+    /// variable. If unspecified, uses 900.
+    #[arg(long)]
+    poll_interval_secs: Option<u64>,
+
+    /// How many seconds may elapse without hearing from a device
+    /// before its entities are marked unavailable in home assistant.
+    /// You may also set this via the GOVEE_AVAILABILITY_TIMEOUT_SECS
+    /// environment variable. If unspecified, devices are not marked
+    /// unavailable however long ago we last heard from them.
+    #[arg(long)]
+    availability_timeout_secs: Option<u64>,
+
+    /// Overrides the availability timeout, in the form KEY=SECS, where
+    /// KEY is either a device type, such as "sensor", "thermometer" or
+    /// "light", to apply to all devices of that type, or the id or name
+    /// of a device. Device entries take precedence over type entries.
+    /// May be specified multiple times.
+    /// You may also set this via the GOVEE_DEVICE_AVAILABILITY_TIMEOUTS
+    /// environment variable, as a comma separated list.
+    #[arg(long = "device-availability-timeout")]
+    device_availability_timeouts: Vec<String>,
+
+    /// The brightness response curve to apply when controlling lights.
+    /// Can be "linear", "gamma" or "gamma:VALUE", where VALUE is the
+    /// gamma exponent; "gamma" alone uses 2.2.
+    /// You may also set this via the GOVEE_BRIGHTNESS_CURVE environment
+    /// variable. If unspecified, uses linear.
+    #[arg(long)]
+    brightness_curve: Option<String>,
+
+    /// Overrides the brightness curve for a specific device, in the
+    /// form ID=CURVE, where ID is the id or name of the device.
+    /// May be specified multiple times.
+    /// You may also set this via the GOVEE_DEVICE_BRIGHTNESS_CURVES
+    /// environment variable, as a comma separated list.
+    #[arg(long = "device-brightness-curve")]
+    device_brightness_curves: Vec<String>,
+
+    /// Overrides how the color reported by the platform API is
+    /// decoded, in the form KEY=ENCODING, where KEY is either a SKU,
+    /// to apply to all devices of that model, or the id or name of
+    /// a device, and ENCODING is either "rgb" (the default) or "hsv".
+    /// May be specified multiple times.
+    /// You may also set this via the GOVEE_COLOR_STATE_ENCODINGS
+    /// environment variable, as a comma separated list.
+    #[arg(long = "color-state-encoding")]
+    color_state_encodings: Vec<String>,
+
+    /// Overrides the temperature scale for a specific device, in the
+    /// form ID=SCALE, where ID is the id or name of the device and
+    /// SCALE is either "C" or "F".
+    /// May be specified multiple times.
+    /// You may also set this via the GOVEE_DEVICE_TEMPERATURE_SCALES
+    /// environment variable, as a comma separated list.
+    #[arg(long = "device-temperature-scale")]
+    device_temperature_scales: Vec<String>,
+
+    /// When white is selected for a light that supports color temperature,
+    /// set this color temperature (in kelvin) rather than rgb white,
+    /// as that usually renders white more accurately.
+    /// Set to 0 to always use rgb.
+    /// You may also set this via the GOVEE_WHITE_COLOR_TEMPERATURE
+    /// environment variable. If unspecified, uses 5000.
+    #[arg(long)]
+    white_color_temperature: Option<u32>,
+
+    /// Present the same color temperature range to home assistant
+    /// for every light, mapping the slider position as a percentage
+    /// onto the range of each device. This keeps the sliders aligned
+    /// when lights with different ranges are grouped together.
+    /// You may also set this via the GOVEE_NORMALIZE_COLOR_TEMPERATURE
+    /// environment variable.
+    #[arg(long)]
+    normalize_color_temperature: bool,
+
+    /// Add xy to the supported color modes of lights, and report
+    /// their color as xy coordinates as well as rgb, for frontends
+    /// that prefer xy color.
+    /// You may also set this via the GOVEE_XY_COLOR environment
+    /// variable.
+    #[arg(long)]
+    xy_color: bool,
+
+    /// Honor the transition time of light commands from hass by
+    /// stepping the brightness and color towards their targets,
+    /// as Govee devices cannot do this themselves. Each step is a
+    /// separate request to the device.
+    /// You may also set this via the GOVEE_EMULATE_TRANSITIONS
+    /// environment variable.
+    #[arg(long)]
+    emulate_transitions: bool,
+
+    /// How many degrees Celsius below its target temperature a kettle
+    /// may be while its Water Boiling sensor is on.
+    /// You may also set this via the GOVEE_BOIL_THRESHOLD_C environment
+    /// variable. If unspecified, uses 2.
+    #[arg(long)]
+    boil_threshold_c: Option<f64>,
+
+    /// How to present the scenes of lights to home assistant.
+    /// Can be "effect", to use the effect list of the light entity,
+    /// "select", to use a separate Mode/Scene select entity, or
+    /// "both".
+    /// You may also set this via the GOVEE_LIGHT_SCENES environment
+    /// variable. If unspecified, uses effect.
+    #[arg(long)]
+    light_scenes: Option<String>,
+
+    /// How to present the power of lights and plugs to home assistant.
+    /// Can be "auto", to show lights as a light entity and plugs as a
+    /// switch entity, or "light" or "switch" to show both kinds of
+    /// device in the same way.
+    /// You may also set this via the GOVEE_POWER_ENTITY environment
+    /// variable. If unspecified, uses auto.
+    #[arg(long)]
+    power_entity: Option<String>,
+
+    /// Only publish the discovery configs, availability and initial
+    /// state to home assistant, then idle without polling devices or
+    /// accepting control commands via mqtt or the HTTP API.
+    /// Useful for reviewing the entities in home assistant before
+    /// going live.
+    /// You may also set this via the GOVEE_DISCOVERY_ONLY environment
+    /// variable.
+    #[arg(long)]
+    discovery_only: bool,
+
+    /// Probe each segment of segmented lights at startup, by setting
+    /// it to its current color, and only publish the segments that
+    /// accepted it. Some devices advertise more segments than they
+    /// physically have. This costs one Platform API call per segment.
+    /// You may also set this via the GOVEE_SCAN_ALL_SEGMENTS environment
+    /// variable.
+    #[arg(long)]
+    scan_all_segments: bool,
+
+    /// The externally reachable URL at which the Govee platform API
+    /// can deliver device state updates to us, for example
+    /// `https://example.com/govee/webhook`. When set, we register
+    /// it with Govee and accept updates at POST /govee/webhook.
+    /// Devices are still polled, but a webhook update resets the
+    /// poll interval for that device.
+    /// You may also set this via the GOVEE_WEBHOOK_URL environment
+    /// variable.
+    #[arg(long)]
+    webhook_url: Option<String>,
+
+    /// The port on which to accept webhook updates. If unspecified,
+    /// or the same as --http-port, the webhook is served by the
+    /// main HTTP server; otherwise a separate server is started
+    /// that serves only the webhook.
+    /// You may also set this via the GOVEE_WEBHOOK_PORT environment
+    /// variable.
+    #[arg(long)]
+    webhook_port: Option<u16>,
+}
+
+/// Parses a list of KEY=VALUE entries, either from the command
+/// line or, if none were specified, from a comma separated list
+/// in the named environment variable
+fn parse_key_value_list<T>(
+    args: &[String],
+    env_name: &str,
+    label: &str,
+    form: &str,
+) -> anyhow::Result<Vec<(String, T)>>
+where
+    T: std::str::FromStr,
+    anyhow::Error: From<T::Err>,
+{
+    let entries = if !args.is_empty() {
+        args.to_vec()
+    } else {
+        opt_env_var::<String>(env_name)?
+            .map(|entries| {
+                entries
+                    .split(',')
+                    .map(|entry| entry.trim().to_string())
+                    .filter(|entry| !entry.is_empty())
+                    .collect()
+            })
+            .unwrap_or_default()
+    };
+
+    entries
+        .iter()
+        .map(|entry| {
+            let (key, value) = entry
+                .rsplit_once('=')
+                .ok_or_else(|| anyhow::anyhow!("{label} {entry} must be in the form {form}"))?;
+            Ok((key.trim().to_string(), value.trim().parse()?))
+        })
+        .collect()
 }
 
-async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Result<()> {
+fn min_poll_interval() -> chrono::Duration {
+    chrono::Duration::seconds(MIN_POLL_INTERVAL_SECS as i64)
+}
+
+/// Enforces the minimum poll interval, warning if the
+/// configured value had to be adjusted
+fn clamp_poll_interval_secs(secs: u64) -> u64 {
+    if secs < MIN_POLL_INTERVAL_SECS {
+        log::warn!(
+            "Poll interval of {secs}s is below the minimum of \
+             {MIN_POLL_INTERVAL_SECS}s; using {MIN_POLL_INTERVAL_SECS}s instead"
+        );
+        MIN_POLL_INTERVAL_SECS
+    } else {
+        secs
+    }
+}
+
+/// Adds the token that authenticates webhook requests to the
+/// url that we register with Govee
+fn webhook_callback_url(url: &str, token: &str) -> anyhow::Result<String> {
+    let mut url = reqwest::Url::parse(url).with_context(|| format!("parsing webhook url {url}"))?;
+    url.query_pairs_mut().append_pair("token", token);
+    Ok(url.into())
+}
+
+async fn poll_single_device(
+    state: &StateHandle,
+    device: &Device,
+    push_staleness: chrono::Duration,
+) -> anyhow::Result<()> {
     let now = Utc::now();
 
-    if device.is_ble_only_device() == Some(true) {
-        // We can't poll this device, we have no ble support
+    if device.ble_address.is_some() {
+        // Its state arrives via its bluetooth advertisements
         return Ok(());
     }
 
-    let poll_interval = device.preferred_poll_interval();
+    if device.has_fresh_push_update(push_staleness) {
+        log::trace!("{device} is push-updated and its state is fresh; skip polling");
+        return Ok(());
+    }
+
+    let poll_interval = device
+        .preferred_poll_interval(state.get_poll_interval().await)
+        .max(min_poll_interval());
 
     let can_update = match &device.last_polled {
         None => true,
@@ -50,57 +337,315 @@ async fn poll_single_device(state: &StateHandle, device: &Device) -> anyhow::Res
         return Ok(());
     }
 
-    let needs_platform = device.needs_platform_poll();
-
-    // Don't interrogate via HTTP if we can use the LAN.
-    // If we have LAN and the device is stale, it is likely
-    // offline and there is little sense in burning up request
-    // quota to the platform API for it
-    if device.lan_device.is_some() && !needs_platform {
-        log::trace!("LAN-available device {device} needs a status update; it's likely offline.");
-        return Ok(());
-    }
-
-    if !needs_platform {
-        if state.poll_iot_api(&device).await? {
-            return Ok(());
-        }
-    }
-
-    state.poll_platform_api(&device).await?;
-
-    Ok(())
+    state.poll_device_now(device).await
 }
 
-async fn periodic_state_poll(state: StateHandle) -> anyhow::Result<()> {
+async fn periodic_state_poll(
+    state: StateHandle,
+    push_staleness: chrono::Duration,
+) -> anyhow::Result<()> {
     sleep(Duration::from_secs(20)).await;
     loop {
         for d in state.devices().await {
-            if let Err(err) = poll_single_device(&state, &d).await {
+            if state.is_device_excluded(&d).await {
+                continue;
+            }
+            if let Err(err) = poll_single_device(&state, &d, push_staleness).await {
                 log::error!("while polling {d}: {err:#}");
             }
         }
 
-        sleep(Duration::from_secs(60)).await;
+        // Devices that failed to respond won't have reported a state
+        // change, so re-evaluate their availability here
+        if let Some(hass) = state.get_hass_client().await {
+            for d in state.devices().await {
+                if state.is_device_excluded(&d).await {
+                    continue;
+                }
+                if let Err(err) = hass.publish_device_availability(&d, &state).await {
+                    log::error!("while publishing availability of {d}: {err:#}");
+                }
+            }
+        }
+
+        // Check at least once a minute, but more often if the
+        // configured poll interval is shorter than that
+        let poll_interval = state
+            .get_poll_interval()
+            .await
+            .max(min_poll_interval())
+            .to_std()?;
+        sleep(poll_interval.min(Duration::from_secs(60))).await;
+    }
+}
+
+/// Returns the device ids from the command line, or else from
+/// the comma separated list in the named environment variable
+fn device_list(args: &[String], env_name: &str) -> anyhow::Result<Vec<String>> {
+    if !args.is_empty() {
+        return Ok(args.to_vec());
     }
+    Ok(opt_env_var::<String>(env_name)?
+        .map(|ids| {
+            ids.split(',')
+                .map(|id| id.trim().to_string())
+                .filter(|id| !id.is_empty())
+                .collect()
+        })
+        .unwrap_or_default())
 }
 
 impl ServeCommand {
+    fn push_updated_devices(&self) -> anyhow::Result<Vec<String>> {
+        device_list(&self.push_updated_devices, "GOVEE_PUSH_UPDATED_DEVICES")
+    }
+
+    fn include_devices(&self) -> anyhow::Result<Vec<String>> {
+        device_list(&self.include_devices, "GOVEE_INCLUDE_DEVICES")
+    }
+
+    fn exclude_devices(&self) -> anyhow::Result<Vec<String>> {
+        device_list(&self.exclude_devices, "GOVEE_EXCLUDE_DEVICES")
+    }
+
+    fn push_staleness(&self) -> anyhow::Result<chrono::Duration> {
+        let secs = match self.push_staleness_secs {
+            Some(secs) => secs,
+            None => {
+                opt_env_var("GOVEE_PUSH_STALENESS_SECS")?.unwrap_or(DEFAULT_PUSH_STALENESS_SECS)
+            }
+        };
+        Ok(chrono::Duration::seconds(secs as i64))
+    }
+
+    fn health_port(&self) -> anyhow::Result<Option<u16>> {
+        match self.health_port {
+            Some(port) => Ok(Some(port)),
+            None => opt_env_var("GOVEE_HEALTH_PORT"),
+        }
+    }
+
+    fn webhook_url(&self) -> anyhow::Result<Option<String>> {
+        match &self.webhook_url {
+            Some(url) => Ok(Some(url.to_string())),
+            None => opt_env_var("GOVEE_WEBHOOK_URL"),
+        }
+    }
+
+    fn webhook_port(&self) -> anyhow::Result<Option<u16>> {
+        match self.webhook_port {
+            Some(port) => Ok(Some(port)),
+            None => opt_env_var("GOVEE_WEBHOOK_PORT"),
+        }
+    }
+
+    fn poll_interval(&self) -> anyhow::Result<chrono::Duration> {
+        let secs = match self.poll_interval_secs {
+            Some(secs) => secs,
+            None => opt_env_var("GOVEE_POLL_INTERVAL_SECS")?.unwrap_or(DEFAULT_POLL_INTERVAL_SECS),
+        };
+        Ok(chrono::Duration::seconds(
+            clamp_poll_interval_secs(secs) as i64
+        ))
+    }
+
+    fn availability_timeout(&self) -> anyhow::Result<Option<chrono::Duration>> {
+        let secs = match self.availability_timeout_secs {
+            Some(secs) => Some(secs),
+            None => opt_env_var("GOVEE_AVAILABILITY_TIMEOUT_SECS")?,
+        };
+        Ok(secs.map(|secs| chrono::Duration::seconds(secs as i64)))
+    }
+
+    fn device_availability_timeouts(&self) -> anyhow::Result<Vec<(String, u64)>> {
+        parse_key_value_list(
+            &self.device_availability_timeouts,
+            "GOVEE_DEVICE_AVAILABILITY_TIMEOUTS",
+            "device availability timeout",
+            "KEY=SECS",
+        )
+    }
+
+    fn light_scenes(&self) -> anyhow::Result<LightScenes> {
+        match &self.light_scenes {
+            Some(scenes) => scenes.parse(),
+            None => Ok(opt_env_var("GOVEE_LIGHT_SCENES")?.unwrap_or_default()),
+        }
+    }
+
+    fn power_entity(&self) -> anyhow::Result<PowerEntity> {
+        match &self.power_entity {
+            Some(entity) => entity.parse(),
+            None => Ok(opt_env_var("GOVEE_POWER_ENTITY")?.unwrap_or_default()),
+        }
+    }
+
+    fn discovery_only(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_DISCOVERY_ONLY")? {
+            return Ok(self.discovery_only || truthy(&v)?);
+        }
+        Ok(self.discovery_only)
+    }
+
+    fn scan_all_segments(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_SCAN_ALL_SEGMENTS")? {
+            return Ok(self.scan_all_segments || truthy(&v)?);
+        }
+        Ok(self.scan_all_segments)
+    }
+
+    fn brightness_curve(&self) -> anyhow::Result<BrightnessCurve> {
+        match &self.brightness_curve {
+            Some(curve) => curve.parse(),
+            None => Ok(opt_env_var("GOVEE_BRIGHTNESS_CURVE")?.unwrap_or_default()),
+        }
+    }
+
+    fn white_color_temperature(&self) -> anyhow::Result<Option<u32>> {
+        let kelvin = match self.white_color_temperature {
+            Some(kelvin) => kelvin,
+            None => opt_env_var("GOVEE_WHITE_COLOR_TEMPERATURE")?
+                .unwrap_or(DEFAULT_WHITE_COLOR_TEMPERATURE),
+        };
+        Ok(if kelvin == 0 { None } else { Some(kelvin) })
+    }
+
+    fn normalize_color_temperature(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_NORMALIZE_COLOR_TEMPERATURE")? {
+            return Ok(self.normalize_color_temperature || truthy(&v)?);
+        }
+        Ok(self.normalize_color_temperature)
+    }
+
+    fn xy_color(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_XY_COLOR")? {
+            return Ok(self.xy_color || truthy(&v)?);
+        }
+        Ok(self.xy_color)
+    }
+
+    fn emulate_transitions(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_EMULATE_TRANSITIONS")? {
+            return Ok(self.emulate_transitions || truthy(&v)?);
+        }
+        Ok(self.emulate_transitions)
+    }
+
+    fn boil_threshold_c(&self) -> anyhow::Result<f64> {
+        match self.boil_threshold_c {
+            Some(celsius) => Ok(celsius),
+            None => Ok(opt_env_var("GOVEE_BOIL_THRESHOLD_C")?.unwrap_or(DEFAULT_BOIL_THRESHOLD_C)),
+        }
+    }
+
+    fn device_brightness_curves(&self) -> anyhow::Result<Vec<(String, BrightnessCurve)>> {
+        parse_key_value_list(
+            &self.device_brightness_curves,
+            "GOVEE_DEVICE_BRIGHTNESS_CURVES",
+            "device brightness curve",
+            "ID=CURVE",
+        )
+    }
+
+    fn device_temperature_scales(&self) -> anyhow::Result<Vec<(String, TemperatureScale)>> {
+        parse_key_value_list(
+            &self.device_temperature_scales,
+            "GOVEE_DEVICE_TEMPERATURE_SCALES",
+            "device temperature scale",
+            "ID=SCALE",
+        )
+    }
+
+    fn color_state_encodings(&self) -> anyhow::Result<Vec<(String, ColorStateEncoding)>> {
+        parse_key_value_list(
+            &self.color_state_encodings,
+            "GOVEE_COLOR_STATE_ENCODINGS",
+            "color state encoding",
+            "KEY=ENCODING",
+        )
+    }
+
     pub async fn run(&self, args: &crate::Args) -> anyhow::Result<()> {
         log::info!("Starting service. version {}", govee_version());
         let state = Arc::new(crate::service::state::State::new());
+        state.set_brightness_curve(self.brightness_curve()?).await;
+        state.set_poll_interval(self.poll_interval()?).await;
+        state
+            .set_availability_timeout(self.availability_timeout()?)
+            .await;
+        state.set_light_scenes(self.light_scenes()?).await;
+        state.set_power_entity(self.power_entity()?).await;
+        state.set_boil_threshold(self.boil_threshold_c()?).await;
+        let discovery_only = self.discovery_only()?;
+        state.set_discovery_only(discovery_only).await;
+        state
+            .set_device_filter(self.include_devices()?, self.exclude_devices()?)
+            .await;
+
+        // Start this first, so that probes get an answer while
+        // we are discovering devices and connecting to mqtt
+        if let Some(port) = self.health_port()? {
+            let state = state.clone();
+            tokio::spawn(async move {
+                if let Err(err) = run_health_server(state, port).await {
+                    log::error!("health server: {err:#}");
+                }
+            });
+        }
+        state
+            .set_white_color_temperature(self.white_color_temperature()?)
+            .await;
+        state
+            .set_normalize_color_temperature(self.normalize_color_temperature()?)
+            .await;
+        state.set_xy_color(self.xy_color()?).await;
+        state
+            .set_emulate_transitions(self.emulate_transitions()?)
+            .await;
 
         // First, use the HTTP APIs to determine the list of devices and
         // their names.
 
+        let webhook_url = self.webhook_url()?;
+        let mut webhook_registered = false;
         if let Ok(client) = args.api_args.api_client() {
+            if let Some(url) = &webhook_url {
+                // Govee can't authenticate itself to us, so we include
+                // a secret in the url and reject requests that lack it
+                let token = uuid::Uuid::new_v4().simple().to_string();
+                let callback_url = webhook_callback_url(url, &token)?;
+                match client.register_webhook(&callback_url).await {
+                    Ok(()) => {
+                        log::info!("Registered webhook {url}");
+                        state.set_webhook_token(token).await;
+                        webhook_registered = true;
+                    }
+                    Err(err) => {
+                        log::warn!("Unable to register webhook, will rely on polling: {err:#}");
+                    }
+                }
+            }
+            state.set_platform_client(client).await;
+        } else if webhook_url.is_some() {
+            log::warn!("Ignoring the webhook url because no Govee API key is configured");
+        }
+        if let Some(client) = state.get_platform_client().await {
             log::info!("Querying platform API for device list");
-            for info in client.get_devices().await? {
+            let devices =
+                client
+                    .get_devices()
+                    .await
+                    .map_err(|err| match ApiError::from_err(&err) {
+                        Some(ApiError::Unauthorized(_)) => err.context(
+                            "The Govee API key was rejected. Check that GOVEE_API_KEY \
+                         is set to the key that Govee emailed to you",
+                        ),
+                        _ => err,
+                    })?;
+            for info in devices {
                 let mut device = state.device_mut(&info.sku, &info.device).await;
                 device.set_http_device_info(info);
             }
-
-            state.set_platform_client(client).await;
         }
         if let Ok(client) = args.undoc_args.api_client() {
             log::info!("Querying undocumented API for device + room list");
@@ -132,24 +677,29 @@ impl ServeCommand {
             state.set_lan_client(client.clone()).await;
 
             tokio::spawn(async move {
-                while let Some(lan_device) = scan.recv().await {
+                while let Some(LanDiscovery {
+                    device: lan_device,
+                    is_new,
+                }) = scan.recv().await
+                {
                     log::trace!("LAN disco: {lan_device:?}");
-                    state
-                        .device_mut(&lan_device.sku, &lan_device.device)
-                        .await
-                        .set_lan_device(lan_device.clone());
+                    if is_new {
+                        state
+                            .device_mut(&lan_device.sku, &lan_device.device)
+                            .await
+                            .set_lan_device(lan_device.clone());
+                    }
 
+                    // Devices answer every periodic scan, which is our
+                    // cue to refresh their status; this only tells hass
+                    // about the status when it has changed
                     let state = state.clone();
-                    let client = client.clone();
                     tokio::spawn(async move {
-                        if let Ok(status) = client.query_status(&lan_device).await {
-                            state
-                                .device_mut(&lan_device.sku, &lan_device.device)
-                                .await
-                                .set_lan_device_status(status);
-
-                            log::trace!("LAN disco: update and notify {}", lan_device.device);
-                            state.notify_of_state_change(&lan_device.device).await.ok();
+                        if let Err(err) = state
+                            .poll_lan_status(&lan_device, QUERY_STATUS_TIMEOUT)
+                            .await
+                        {
+                            log::error!("LAN disco: {}: {err:#}", lan_device.device);
                         }
                     });
                 }
@@ -164,9 +714,15 @@ impl ServeCommand {
             sleep(Duration::from_secs(10)).await;
         }
 
+        #[cfg(feature = "ble")]
+        crate::bluetooth::start_thermometer_scan(&state, args.ble_args.scan_duration()?).await;
+
         log::info!("Devices returned from Govee's APIs");
         for device in state.devices().await {
             log::info!("{device}");
+            if state.is_device_excluded(&device).await {
+                log::info!("  Excluded: not polled or published to Home Assistant");
+            }
             if let Some(lan) = &device.lan_device {
                 log::info!("  LAN API: ip={:?}", lan.ip);
             }
@@ -224,11 +780,150 @@ impl ServeCommand {
             log::info!("");
         }
 
+        for id in self.push_updated_devices()? {
+            match state.resolve_device(&id).await {
+                Some(device) => {
+                    log::info!("{device} is push-updated");
+                    state
+                        .device_mut(&device.sku, &device.id)
+                        .await
+                        .set_push_updated(true);
+                }
+                None => log::warn!("push-updated device {id} is not a known device"),
+            }
+        }
+
+        for (id, curve) in self.device_brightness_curves()? {
+            match state.resolve_device(&id).await {
+                Some(device) => {
+                    log::info!("{device} uses brightness curve {curve}");
+                    state
+                        .device_mut(&device.sku, &device.id)
+                        .await
+                        .set_brightness_curve(curve);
+                }
+                None => log::warn!("brightness curve device {id} is not a known device"),
+            }
+        }
+
+        for (id, scale) in self.device_temperature_scales()? {
+            match state.resolve_device(&id).await {
+                Some(device) => {
+                    log::info!("{device} uses temperature scale {scale}");
+                    state
+                        .device_mut(&device.sku, &device.id)
+                        .await
+                        .set_temperature_scale(scale);
+                }
+                None => log::warn!("temperature scale device {id} is not a known device"),
+            }
+        }
+
+        for (key, encoding) in self.color_state_encodings()? {
+            let mut matched = false;
+            for device in state.devices().await {
+                if device.sku.eq_ignore_ascii_case(&key) {
+                    log::info!("{device} uses {encoding} color state encoding");
+                    state
+                        .device_mut(&device.sku, &device.id)
+                        .await
+                        .set_color_state_encoding(encoding);
+                    matched = true;
+                }
+            }
+            if matched {
+                continue;
+            }
+            match state.resolve_device(&key).await {
+                Some(device) => {
+                    log::info!("{device} uses {encoding} color state encoding");
+                    state
+                        .device_mut(&device.sku, &device.id)
+                        .await
+                        .set_color_state_encoding(encoding);
+                }
+                None => log::warn!("color state encoding {key} is not a known sku or device"),
+            }
+        }
+
+        // The per-type timeouts are kept in the state, so that they
+        // also apply to devices that are discovered later on, while
+        // those for specific devices are set on the device and take
+        // precedence over them
+        for (key, secs) in self.device_availability_timeouts()? {
+            let timeout = chrono::Duration::seconds(secs as i64);
+            if let Ok(device_type) = DeviceType::from_short_name(&key) {
+                log::info!("{key} devices have an availability timeout of {secs}s");
+                state
+                    .set_device_type_availability_timeout(device_type, timeout)
+                    .await;
+                continue;
+            }
+            match state.resolve_device(&key).await {
+                Some(device) => {
+                    log::info!(
+                        "{device} has an availability timeout of {}s",
+                        timeout.num_seconds()
+                    );
+                    state
+                        .device_mut(&device.sku, &device.id)
+                        .await
+                        .set_availability_timeout(timeout);
+                }
+                None => {
+                    log::warn!("availability timeout {key} is not a known device type or device")
+                }
+            }
+        }
+
+        if discovery_only {
+            log::info!("Discovery-only mode: devices will not be polled or controlled");
+            spawn_hass_integration(state.clone(), &args.hass_args).await?;
+            return wait_for_shutdown(&state).await;
+        }
+
+        // Segment state is only available via the platform API,
+        // so fetch it now, otherwise segments would remain unknown
+        // until they are next controlled
+        let scan_all_segments = self.scan_all_segments()?;
+        for device in state.devices().await {
+            if state.is_device_excluded(&device).await {
+                continue;
+            }
+            let segmented = device
+                .http_device_info
+                .as_ref()
+                .and_then(|info| info.supports_segmented_rgb())
+                .is_some();
+            if segmented && !device.avoid_platform_api() {
+                if let Err(err) = state.poll_platform_api(&device).await {
+                    log::warn!("Unable to fetch segment state for {device}: {err:#}");
+                }
+
+                if scan_all_segments {
+                    // Re-read the device so that we probe with the
+                    // segment colors that we just fetched
+                    let device = state.device_by_id(&device.id).await.unwrap_or(device);
+                    match state.probe_segments(&device).await {
+                        Ok(segments) => {
+                            log::info!("{device} has {} responsive segments", segments.len());
+                            state
+                                .device_mut(&device.sku, &device.id)
+                                .await
+                                .set_responsive_segments(segments);
+                        }
+                        Err(err) => log::warn!("Unable to probe segments of {device}: {err:#}"),
+                    }
+                }
+            }
+        }
+
         // Start periodic status polling
         {
             let state = state.clone();
+            let push_staleness = self.push_staleness()?;
             tokio::spawn(async move {
-                if let Err(err) = periodic_state_poll(state).await {
+                if let Err(err) = periodic_state_poll(state, push_staleness).await {
                     log::error!("periodic_state_poll: {err:#}");
                 }
             });
@@ -237,8 +932,104 @@ impl ServeCommand {
         // start advertising on local mqtt
         spawn_hass_integration(state.clone(), &args.hass_args).await?;
 
-        run_http_server(state.clone(), self.http_port)
-            .await
-            .with_context(|| format!("Starting HTTP service on port {}", self.http_port))
+        let mut webhook_on_http_server = false;
+        if webhook_registered {
+            match self.webhook_port()? {
+                Some(port) if port != self.http_port => {
+                    let state = state.clone();
+                    tokio::spawn(async move {
+                        if let Err(err) = run_webhook_server(state, port).await {
+                            log::error!("webhook server: {err:#}");
+                        }
+                    });
+                }
+                _ => webhook_on_http_server = true,
+            }
+        }
+
+        tokio::select! {
+            result = run_http_server(state.clone(), self.http_port, webhook_on_http_server) => {
+                result.with_context(|| format!("Starting HTTP service on port {}", self.http_port))
+            }
+            result = wait_for_shutdown(&state) => result,
+        }
+    }
+}
+
+/// How long to allow for a clean shutdown before exiting anyway
+const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Resolves when we receive SIGINT or SIGTERM
+async fn shutdown_signal() -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        let mut terminate = signal(SignalKind::terminate()).context("SIGTERM handler")?;
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => result.context("SIGINT handler")?,
+            _ = terminate.recv() => {}
+        }
+    }
+    #[cfg(not(unix))]
+    tokio::signal::ctrl_c().await.context("ctrl-c handler")?;
+    Ok(())
+}
+
+/// Waits for a shutdown signal, then shuts down cleanly
+async fn wait_for_shutdown(state: &StateHandle) -> anyhow::Result<()> {
+    shutdown_signal().await?;
+    log::info!("Shutting down cleanly");
+    if tokio::time::timeout(SHUTDOWN_TIMEOUT, shutdown(state))
+        .await
+        .is_err()
+    {
+        log::warn!("Timed out after {SHUTDOWN_TIMEOUT:?} while shutting down");
+    }
+    Ok(())
+}
+
+/// Tell hass, and any other MQTT clients, that our entities and
+/// devices are no longer available, so that they don't keep showing
+/// stale state while we're not running.
+/// Our mqtt client has a last-will that does the same for the global
+/// availability topic, but the broker may not send that until its
+/// keepalive timeout expires.
+/// The mosquitto-rs Client doesn't provide a way to disconnect, so
+/// the connection is closed when the process exits; the offline
+/// messages were published with QoS 1, so the broker already has them.
+/// There is nothing to flush to the disk cache, as each cache
+/// update is written through to sqlite as it happens.
+async fn shutdown(state: &StateHandle) {
+    if let Some(client) = state.get_hass_client().await {
+        if let Err(err) = client.publish_offline(state).await {
+            log::error!("Failed to publish offline status: {err:#}");
+        }
+        log::info!("The MQTT client has no clean disconnect; the connection will close on exit");
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn poll_interval_floor() {
+        k9::assert_equal!(clamp_poll_interval_secs(0), MIN_POLL_INTERVAL_SECS);
+        k9::assert_equal!(clamp_poll_interval_secs(5), MIN_POLL_INTERVAL_SECS);
+        k9::assert_equal!(clamp_poll_interval_secs(10), 10);
+        k9::assert_equal!(clamp_poll_interval_secs(900), 900);
+    }
+
+    #[test]
+    fn webhook_url_has_token() {
+        k9::assert_equal!(
+            webhook_callback_url("https://example.com/govee/webhook", "abc").unwrap(),
+            "https://example.com/govee/webhook?token=abc"
+        );
+        k9::assert_equal!(
+            webhook_callback_url("https://example.com/govee/webhook?x=1", "abc").unwrap(),
+            "https://example.com/govee/webhook?x=1&token=abc"
+        );
+        assert!(webhook_callback_url("not a url", "abc").is_err());
     }
 }
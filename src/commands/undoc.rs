@@ -1,4 +1,6 @@
+use crate::platform_api::DeviceParameters;
 use crate::service::iot::start_iot_client;
+use crate::undoc_api::GoveeUndocumentedApi;
 use std::sync::Arc;
 
 #[derive(clap::Parser, Debug)]
@@ -11,7 +13,17 @@ pub struct UndocCommand {
 enum SubCommand {
     DumpOneClick {},
     ShowOneClick {},
-    OneClick { name: String },
+    OneClick {
+        name: String,
+    },
+    /// List the scenes that the undocumented API reports for a SKU
+    ListScenes {
+        sku: String,
+
+        /// Print the scenes as a JSON array
+        #[arg(long)]
+        json: bool,
+    },
 }
 
 impl UndocCommand {
@@ -43,6 +55,33 @@ impl UndocCommand {
 
                 iot.activate_one_click(&item).await?;
             }
+            SubCommand::ListScenes { sku, json } => {
+                let caps = GoveeUndocumentedApi::synthesize_platform_api_scene_list(sku).await?;
+                let mut scenes = vec![];
+                for cap in caps {
+                    if let Some(DeviceParameters::Enum { options }) = cap.parameters {
+                        for opt in options {
+                            scenes.push(serde_json::json!({
+                                "name": opt.name,
+                                "id": opt.value.get("id"),
+                                "param_id": opt.value.get("paramId"),
+                            }));
+                        }
+                    }
+                }
+
+                if *json {
+                    println!("{}", serde_json::to_string_pretty(&scenes)?);
+                } else {
+                    for scene in scenes {
+                        println!(
+                            "{id:>8} {name}",
+                            id = scene["id"].to_string(),
+                            name = scene["name"].as_str().unwrap_or_default()
+                        );
+                    }
+                }
+            }
         }
         Ok(())
     }
@@ -0,0 +1,249 @@
+use anyhow::Context;
+use serde_json::Value as JsonValue;
+use std::path::Path;
+
+/// The environment variables that may be set from the config file.
+/// GOVEE_CONFIG itself is not included, as it is meaningless there.
+const KNOWN_VARIABLES: &[&str] = &[
+    "GOVEE_API_BASE_URL",
+    "GOVEE_API_CONNECT_TIMEOUT_SECS",
+    "GOVEE_API_KEY",
+    "GOVEE_API_TIMEOUT_SECS",
+    "GOVEE_AVAILABILITY_TIMEOUT_SECS",
+    "GOVEE_BLE_SCAN_DURATION_SECS",
+    "GOVEE_BOIL_THRESHOLD_C",
+    "GOVEE_BRIGHTNESS_CURVE",
+    "GOVEE_CACHE_DIR",
+    "GOVEE_COLOR_STATE_ENCODINGS",
+    "GOVEE_DEVICE_AVAILABILITY_TIMEOUTS",
+    "GOVEE_DEVICE_BRIGHTNESS_CURVES",
+    "GOVEE_DEVICE_TEMPERATURE_SCALES",
+    "GOVEE_DISCOVERY_ONLY",
+    "GOVEE_DRY_RUN",
+    "GOVEE_EMAIL",
+    "GOVEE_EMULATE_TRANSITIONS",
+    "GOVEE_EXCLUDE_DEVICES",
+    "GOVEE_HASS_DEVICE_DISCOVERY",
+    "GOVEE_HASS_DISCOVERY_BATCH_SIZE",
+    "GOVEE_HASS_DISCOVERY_DELAY_MS",
+    "GOVEE_HEALTH_PORT",
+    "GOVEE_INCLUDE_DEVICES",
+    "GOVEE_LAN_BROADCAST_ALL",
+    "GOVEE_LAN_BROADCAST_GLOBAL",
+    "GOVEE_LAN_COMMAND_DELAY_MS",
+    "GOVEE_LAN_DISCO_TIMEOUT",
+    "GOVEE_LAN_INTERFACE",
+    "GOVEE_LAN_NO_MULTICAST",
+    "GOVEE_LAN_SCAN",
+    "GOVEE_LIGHT_SCENES",
+    "GOVEE_LOG_LEVEL",
+    "GOVEE_LOG_SENSITIVE_DATA",
+    "GOVEE_MQTT_CONNECT_TIMEOUT_SECS",
+    "GOVEE_MQTT_HOST",
+    "GOVEE_MQTT_PASSWORD",
+    "GOVEE_MQTT_PORT",
+    "GOVEE_MQTT_USER",
+    "GOVEE_NORMALIZE_COLOR_TEMPERATURE",
+    "GOVEE_PASSWORD",
+    "GOVEE_POLL_INTERVAL_SECS",
+    "GOVEE_POWER_ENTITY",
+    "GOVEE_PUSH_STALENESS_SECS",
+    "GOVEE_PUSH_UPDATED_DEVICES",
+    "GOVEE_SCAN_ALL_SEGMENTS",
+    "GOVEE_TEMPERATURE_SCALE",
+    "GOVEE_VERBOSE_API",
+    "GOVEE_WEBHOOK_PORT",
+    "GOVEE_WEBHOOK_URL",
+    "GOVEE_WHITE_COLOR_TEMPERATURE",
+    "GOVEE_XY_COLOR",
+];
+
+/// Loads settings from a config file into the environment.
+/// The file is TOML, unless its name ends in .json, in which
+/// case it is JSON.
+/// Each key is the name of one of the GOVEE_XXX environment
+/// variables, with or without the GOVEE_ prefix and in any case,
+/// so that the file can use every setting that the environment can.
+/// Unknown keys are an error, so that typos don't go unnoticed.
+/// Variables that are already set in the environment take precedence
+/// over the file, and the command line takes precedence over both.
+/// Returns the number of variables that were set from the file.
+pub fn load_config_file(path: &Path) -> anyhow::Result<usize> {
+    let data =
+        std::fs::read_to_string(path).with_context(|| format!("reading config file {path:?}"))?;
+    let config =
+        parse_config(path, &data).with_context(|| format!("parsing config file {path:?}"))?;
+
+    let mut count = 0;
+    for (name, value) in config_to_env(&config).with_context(|| format!("in {path:?}"))? {
+        if std::env::var_os(&name).is_none() {
+            std::env::set_var(name, value);
+            count += 1;
+        }
+    }
+    Ok(count)
+}
+
+/// Parses the config file into a JSON object, which is the
+/// form that config_to_env operates upon
+fn parse_config(path: &Path, data: &str) -> anyhow::Result<JsonValue> {
+    if path
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("json"))
+    {
+        return Ok(serde_json::from_str(data)?);
+    }
+    let table: toml::Table = toml::from_str(data)?;
+    Ok(serde_json::to_value(table)?)
+}
+
+/// Maps the config object to a list of environment variable
+/// names and values. Arrays become comma separated lists, and
+/// objects become comma separated lists of KEY=VALUE entries,
+/// such as are used for the per-device overrides.
+fn config_to_env(config: &JsonValue) -> anyhow::Result<Vec<(String, String)>> {
+    let JsonValue::Object(map) = config else {
+        anyhow::bail!("the config file must contain a JSON object");
+    };
+
+    let mut result = vec![];
+    for (key, value) in map {
+        let mut name = key.to_ascii_uppercase().replace('-', "_");
+        if !name.starts_with("GOVEE_") {
+            name = format!("GOVEE_{name}");
+        }
+        if !KNOWN_VARIABLES.contains(&name.as_str()) {
+            anyhow::bail!("unknown setting {key:?} (maps to {name}, which is not a known setting)");
+        }
+
+        let value = match value {
+            JsonValue::Null => continue,
+            JsonValue::Array(items) => items
+                .iter()
+                .map(scalar_to_string)
+                .collect::<anyhow::Result<Vec<_>>>()
+                .with_context(|| format!("setting {key}"))?
+                .join(","),
+            JsonValue::Object(entries) => entries
+                .iter()
+                .map(|(k, v)| Ok(format!("{k}={}", scalar_to_string(v)?)))
+                .collect::<anyhow::Result<Vec<_>>>()
+                .with_context(|| format!("setting {key}"))?
+                .join(","),
+            value => scalar_to_string(value).with_context(|| format!("setting {key}"))?,
+        };
+        result.push((name, value));
+    }
+    Ok(result)
+}
+
+fn scalar_to_string(value: &JsonValue) -> anyhow::Result<String> {
+    match value {
+        JsonValue::String(s) => Ok(s.to_string()),
+        JsonValue::Number(n) => Ok(n.to_string()),
+        JsonValue::Bool(b) => Ok(b.to_string()),
+        _ => anyhow::bail!("expected a string, number or boolean, but found {value}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn config_mapping() {
+        let config = json!({
+            "api_key": "my-key",
+            "GOVEE_MQTT_HOST": "mqtt.local",
+            "mqtt-port": 1883,
+            "xy_color": true,
+            "push_updated_devices": ["Kitchen", "AA:BB:CC:DD:EE:FF:00:11"],
+            "device_brightness_curves": {"Kitchen": "gamma", "Hall": "linear"},
+            "temperature_scale": null,
+        });
+
+        k9::assert_equal!(
+            config_to_env(&config).unwrap(),
+            vec![
+                ("GOVEE_MQTT_HOST".to_string(), "mqtt.local".to_string()),
+                ("GOVEE_API_KEY".to_string(), "my-key".to_string()),
+                (
+                    "GOVEE_DEVICE_BRIGHTNESS_CURVES".to_string(),
+                    "Hall=linear,Kitchen=gamma".to_string()
+                ),
+                ("GOVEE_MQTT_PORT".to_string(), "1883".to_string()),
+                (
+                    "GOVEE_PUSH_UPDATED_DEVICES".to_string(),
+                    "Kitchen,AA:BB:CC:DD:EE:FF:00:11".to_string()
+                ),
+                ("GOVEE_XY_COLOR".to_string(), "true".to_string()),
+            ]
+        );
+
+        assert!(config_to_env(&json!(["not", "an", "object"])).is_err());
+        assert!(config_to_env(&json!({"push_updated_devices": [{"nested": 1}]})).is_err());
+    }
+
+    #[test]
+    fn toml_config() {
+        let config = parse_config(
+            Path::new("govee.toml"),
+            r#"
+# Comments are the reason to prefer TOML
+api_key = "my-key"
+mqtt_port = 1883
+push_updated_devices = ["Kitchen"]
+
+[device_brightness_curves]
+"AA:BB:CC:DD:EE:FF:00:11" = "gamma"
+"#,
+        )
+        .unwrap();
+
+        k9::assert_equal!(
+            config_to_env(&config).unwrap(),
+            vec![
+                ("GOVEE_API_KEY".to_string(), "my-key".to_string()),
+                (
+                    "GOVEE_DEVICE_BRIGHTNESS_CURVES".to_string(),
+                    "AA:BB:CC:DD:EE:FF:00:11=gamma".to_string()
+                ),
+                ("GOVEE_MQTT_PORT".to_string(), "1883".to_string()),
+                (
+                    "GOVEE_PUSH_UPDATED_DEVICES".to_string(),
+                    "Kitchen".to_string()
+                ),
+            ]
+        );
+
+        // JSON is still accepted for .json files
+        k9::assert_equal!(
+            parse_config(Path::new("govee.json"), r#"{"api_key": "my-key"}"#).unwrap(),
+            json!({"api_key": "my-key"})
+        );
+        assert!(parse_config(Path::new("govee.toml"), r#"{"api_key": "my-key"}"#).is_err());
+    }
+
+    #[test]
+    fn unknown_keys_are_rejected() {
+        k9::assert_equal!(
+            config_to_env(&json!({"mqtt_hots": "mqtt.local"}))
+                .unwrap_err()
+                .to_string(),
+            "unknown setting \"mqtt_hots\" (maps to GOVEE_MQTT_HOTS, which is not a known setting)"
+        );
+        assert!(config_to_env(&json!({"config": "other.json"})).is_err());
+        assert!(config_to_env(&json!({"ble_scan_duration_secs": 10})).is_ok());
+    }
+
+    #[test]
+    fn documented_variables_are_known() {
+        let docs = include_str!("../docs/CONFIG.md");
+        for word in docs.split(|c: char| !(c.is_ascii_alphanumeric() || c == '_')) {
+            if word.starts_with("GOVEE_") && !matches!(word, "GOVEE_" | "GOVEE_CONFIG") {
+                assert!(KNOWN_VARIABLES.contains(&word), "{word} is not known");
+            }
+        }
+    }
+}
@@ -1,5 +1,5 @@
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::topic_safe_id;
+use crate::service::hass::{device_availability_topic, topic_safe_id};
 use crate::version_info::govee_version;
 use serde::Serialize;
 
@@ -53,6 +53,10 @@ pub struct Device {
     pub identifiers: Vec<String>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub connections: Vec<(String, String)>,
+    /// The per-device availability topic, which is combined with
+    /// the availability topic of the entity when it is published
+    #[serde(skip)]
+    pub availability_topic: Option<String>,
 }
 
 impl Device {
@@ -72,6 +76,7 @@ impl Device {
                 */
             ],
             connections: vec![],
+            availability_topic: Some(device_availability_topic(device)),
         }
     }
 
@@ -85,6 +90,7 @@ impl Device {
             via_device: None,
             identifiers: vec!["gv2mqtt".to_string()],
             connections: vec![],
+            availability_topic: None,
         }
     }
 }
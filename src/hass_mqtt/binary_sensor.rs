@@ -0,0 +1,365 @@
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::climate::reported_target_temperature;
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::platform_api::{DeviceCapability, DeviceParameters, EventState};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::state::StateHandle;
+use crate::temperature::{TemperatureUnits, TemperatureValue};
+use async_trait::async_trait;
+use serde::Serialize;
+
+#[derive(Serialize, Clone, Debug)]
+pub struct BinarySensorConfig {
+    #[serde(flatten)]
+    pub base: EntityConfig,
+
+    pub state_topic: String,
+    pub payload_on: String,
+    pub payload_off: String,
+}
+
+impl BinarySensorConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("binary_sensor", state, client, &self.base, self).await
+    }
+
+    pub async fn notify_state(&self, client: &HassClient, on: bool) -> anyhow::Result<()> {
+        let value = if on {
+            &self.payload_on
+        } else {
+            &self.payload_off
+        };
+        client.publish(&self.state_topic, value).await
+    }
+}
+
+/// Returns true if the instance reports that the water tank of
+/// a humidifier (or similar) needs refilling
+pub fn is_water_shortage(instance: &str) -> bool {
+    let inst = instance.to_ascii_lowercase();
+    inst.contains("lackwater") || inst.contains("watershortage")
+}
+
+/// Represents an event capability, such as a water shortage,
+/// leak or smoke alarm, as a binary sensor.
+/// Some devices report water shortage as a property rather
+/// than an event; those are represented in the same way.
+#[derive(Clone)]
+pub struct EventBinarySensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+    event_state: EventState,
+}
+
+impl EventBinarySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, instance: &DeviceCapability) -> Self {
+        let unique_id = format!(
+            "binary-sensor-{id}-{inst}",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        let event_state = instance.parsed_event_state().unwrap_or_default();
+
+        let name = event_state
+            .options
+            .first()
+            .and_then(|opt| opt.message.clone())
+            .unwrap_or_else(|| {
+                if is_water_shortage(&instance.instance) {
+                    "Water Shortage".to_string()
+                } else {
+                    instance.instance.to_string()
+                }
+            });
+
+        let inst = instance.instance.to_ascii_lowercase();
+        let device_class = if inst.contains("smoke") {
+            "smoke"
+        } else if inst.contains("leak") {
+            "moisture"
+        } else {
+            "problem"
+        };
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(name),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some(device_class),
+                    icon: None,
+                },
+                state_topic: format!("gv2mqtt/binary_sensor/{unique_id}/state"),
+                payload_on: "ON".to_string(),
+                payload_off: "OFF".to_string(),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+            event_state,
+        }
+    }
+}
+
+/// Returns true if the capability is a range that only reports
+/// whether the tank is empty (0) or full (1), rather than a level
+pub fn is_binary_range(instance: &DeviceCapability) -> bool {
+    matches!(
+        &instance.parameters,
+        Some(DeviceParameters::Integer { range, .. }) if range.min == 0 && range.max == 1
+    )
+}
+
+/// Represents a water tank level that is reported only as
+/// empty or full as a binary sensor that is on while the
+/// tank is empty
+#[derive(Clone)]
+pub struct WaterTankEmptySensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+}
+
+impl WaterTankEmptySensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, instance: &DeviceCapability) -> Self {
+        let unique_id = format!(
+            "binary-sensor-{id}-{inst}",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Water Tank Empty".to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("problem"),
+                    icon: Some("mdi:water-off".to_string()),
+                },
+                state_topic: format!("gv2mqtt/binary_sensor/{unique_id}/state"),
+                payload_on: "ON".to_string(),
+                payload_off: "OFF".to_string(),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for WaterTankEmptySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        match device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|cap| cap.state.pointer("/value").and_then(|v| v.as_i64()))
+        {
+            Some(level) => self.sensor.notify_state(client, level == 0).await,
+            None => {
+                log::trace!(
+                    "WaterTankEmptySensor::notify_state: no state for {device} {instance}",
+                    instance = self.instance_name
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+/// Returns true if current is within threshold_c degrees Celsius
+/// of target, or has exceeded it
+pub fn is_boiling(current: TemperatureValue, target: TemperatureValue, threshold_c: f64) -> bool {
+    target.as_celsius() - current.as_celsius() <= threshold_c
+}
+
+/// Reports whether a kettle has heated its water to the target
+/// temperature, so that automations can take the next step
+#[derive(Clone)]
+pub struct KettleBoilingSensor {
+    sensor: BinarySensorConfig,
+    device_id: String,
+    state: StateHandle,
+    target_instance: String,
+}
+
+impl KettleBoilingSensor {
+    pub fn new(device: &ServiceDevice, state: &StateHandle, target: &DeviceCapability) -> Self {
+        let unique_id = format!("binary-sensor-{id}-boiling", id = topic_safe_id(device));
+
+        Self {
+            sensor: BinarySensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Water Boiling".to_string()),
+                    entity_category: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: Some("heat"),
+                    icon: Some("mdi:kettle-steam".to_string()),
+                },
+                state_topic: format!("gv2mqtt/binary_sensor/{unique_id}/state"),
+                payload_on: "ON".to_string(),
+                payload_off: "OFF".to_string(),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            target_instance: target.instance.to_string(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for KettleBoilingSensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let quirk_units = device
+            .resolve_quirk()
+            .and_then(|q| q.platform_temperature_sensor_units);
+
+        let current = device
+            .get_state_capability_by_instance("sensorTemperature")
+            .and_then(|cap| cap.state.pointer("/value").and_then(|v| v.as_f64()))
+            .map(|v| TemperatureValue::new(v, quirk_units.unwrap_or(TemperatureUnits::Fahrenheit)));
+        let target = device
+            .get_state_capability_by_instance(&self.target_instance)
+            .and_then(|cap| reported_target_temperature(&cap.state, quirk_units));
+
+        match (current, target) {
+            (Some(current), Some(target)) => {
+                let threshold = self.state.get_boil_threshold().await;
+                self.sensor
+                    .notify_state(client, is_boiling(current, target, threshold))
+                    .await
+            }
+            _ => {
+                log::trace!("KettleBoilingSensor::notify_state: {device} temperatures are unknown");
+                Ok(())
+            }
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for EventBinarySensor {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let active = device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|cap| cap.state.pointer("/value"))
+            .and_then(|value| self.event_state.is_active_value(value));
+
+        match active {
+            Some(on) => self.sensor.notify_state(client, on).await,
+            None => {
+                log::trace!(
+                    "EventBinarySensor::notify_state: no event state for {device} {instance}",
+                    instance = self.instance_name
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::service::state::State;
+    use serde_json::json;
+    use std::sync::Arc;
+
+    #[test]
+    fn water_shortage_property() {
+        let cap: DeviceCapability = serde_json::from_value(json!({
+            "type": "devices.capabilities.property",
+            "instance": "waterShortage",
+        }))
+        .unwrap();
+        assert!(is_water_shortage(&cap.instance));
+        assert!(is_water_shortage("lackWaterEvent"));
+        assert!(!is_water_shortage("sensorHumidity"));
+
+        let device = ServiceDevice::new("H7143", "AA:BB:CC:DD:EE:FF:00:33");
+        let sensor = EventBinarySensor::new(&device, &Arc::new(State::new()), &cap);
+        k9::assert_equal!(sensor.sensor.base.name.as_deref(), Some("Water Shortage"));
+        k9::assert_equal!(sensor.sensor.base.device_class, Some("problem"));
+
+        // Without event options, any non-zero value is a shortage
+        k9::assert_equal!(sensor.event_state.is_active_value(&json!(1)), Some(true));
+        k9::assert_equal!(sensor.event_state.is_active_value(&json!(0)), Some(false));
+    }
+
+    #[test]
+    fn binary_tank_level() {
+        let cap = |max| -> DeviceCapability {
+            serde_json::from_value(json!({
+                "type": "devices.capabilities.range",
+                "instance": "waterTankLevel",
+                "parameters": {
+                    "unit": "unit.percent",
+                    "dataType": "INTEGER",
+                    "range": {"min": 0, "max": max, "precision": 1}
+                }
+            }))
+            .unwrap()
+        };
+        assert!(is_binary_range(&cap(1)));
+        assert!(!is_binary_range(&cap(100)));
+    }
+
+    #[test]
+    fn kettle_boiling() {
+        let c = |v| TemperatureValue::new(v, TemperatureUnits::Celsius);
+        let f = |v| TemperatureValue::new(v, TemperatureUnits::Fahrenheit);
+
+        assert!(!is_boiling(c(90.), c(100.), 2.));
+        assert!(is_boiling(c(98.), c(100.), 2.));
+        assert!(is_boiling(c(100.), c(100.), 2.));
+        assert!(is_boiling(c(101.), c(100.), 2.));
+        // The readings may be in different units
+        assert!(is_boiling(f(210.), c(100.), 2.));
+        assert!(!is_boiling(f(190.), c(100.), 2.));
+    }
+}
@@ -12,6 +12,7 @@ use anyhow::anyhow;
 use axum::async_trait;
 use mosquitto_rs::router::{Params, Payload, State};
 use serde::Deserialize;
+use serde_json::Value as JsonValue;
 use std::str::FromStr;
 
 // TODO: register an actual climate entity.
@@ -75,13 +76,35 @@ pub fn parse_temperature_constraints(
     }
 }
 
+/// Parses the target temperature from the reported state of a
+/// TemperatureSetting capability. The units are taken from the
+/// state if present, falling back to quirk_units, then Celsius.
+pub fn reported_target_temperature(
+    state: &JsonValue,
+    quirk_units: Option<TemperatureUnits>,
+) -> Option<TemperatureValue> {
+    let units = state
+        .pointer("/value/unit")
+        .and_then(|unit| {
+            unit.as_str()
+                .and_then(|s| TemperatureScale::from_str(s).map(Into::into).ok())
+        })
+        .or(quirk_units)
+        .unwrap_or(TemperatureUnits::Celsius);
+
+    state
+        .pointer("/value/targetTemperature")
+        .and_then(|v| v.as_f64())
+        .map(|v| TemperatureValue::new(v, units))
+}
+
 impl TargetTemperatureEntity {
     pub async fn new(
         device: &ServiceDevice,
         state: &StateHandle,
         instance: &DeviceCapability,
     ) -> anyhow::Result<Self> {
-        let units = state.get_temperature_scale().await;
+        let units = state.temperature_scale_for_device(device).await;
 
         let constraints = parse_temperature_constraints(instance)?.as_unit(units.into());
         let unique_id = format!(
@@ -147,26 +170,10 @@ impl EntityInstance for TargetTemperatureEntity {
         if let Some(cap) = device.get_state_capability_by_instance(&self.instance_name) {
             log::debug!("have: {cap:?}");
 
-            let units = cap
-                .state
-                .pointer("/value/unit")
-                .and_then(|unit| {
-                    unit.as_str()
-                        .and_then(|s| TemperatureScale::from_str(s).map(Into::into).ok())
-                })
-                .or_else(|| quirk.and_then(|q| q.platform_temperature_sensor_units))
-                .unwrap_or(TemperatureUnits::Celsius);
-
-            log::debug!("units are reported as {units:?}");
-
-            let value = match cap
-                .state
-                .pointer("/value/targetTemperature")
-                .and_then(|v| v.as_f64())
-                .map(|v| TemperatureValue::new(v, units))
-            {
+            let quirk_units = quirk.and_then(|q| q.platform_temperature_sensor_units);
+            let value = match reported_target_temperature(&cap.state, quirk_units) {
                 Some(v) => {
-                    let pref_units = self.state.get_temperature_scale().await;
+                    let pref_units = self.state.temperature_scale_for_device(&device).await;
                     log::debug!("reported temp is {v}, pref_units: {pref_units}");
                     let value = v.as_unit(pref_units.into()).value();
                     format!("{value:.2}")
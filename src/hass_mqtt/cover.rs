@@ -1,13 +1,291 @@
-use crate::hass_mqtt::base::EntityConfig;
+use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::number::range_state_value;
+use crate::platform_api::{DeviceCapability, DeviceParameters, DeviceType, IntegerRange};
+use crate::service::device::Device as ServiceDevice;
+use crate::service::hass::{
+    availability_topic, topic_safe_id, topic_safe_string, HassClient, IdAndInst,
+};
+use crate::service::state::StateHandle;
+use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
 use serde::Serialize;
 
+/// The instance used by curtains that can also angle their slats
+pub const TILT_INSTANCE: &str = "tiltAngle";
+
 #[derive(Serialize, Clone, Debug)]
 pub struct CoverConfig {
     #[serde(flatten)]
     pub base: EntityConfig,
 
-    pub state_topic: String,
+    pub command_topic: String,
+    /// We have no way to stop a cover part way, so this is always
+    /// null, which causes hass to hide its stop button
+    pub payload_stop: Option<String>,
     pub position_topic: String,
     pub set_position_topic: String,
-    pub command_topic: String,
+    pub position_open: u32,
+    pub position_closed: u32,
+
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tilt_command_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tilt_status_topic: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tilt_min: Option<u32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub tilt_max: Option<u32>,
+}
+
+impl CoverConfig {
+    pub async fn publish(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        publish_entity_config("cover", state, client, &self.base, self).await
+    }
+}
+
+/// The Range instances through which curtains report their position
+const COVER_POSITION_INSTANCES: &[&str] = &["position", "curtainPosition"];
+
+/// Returns true if the instance reports the position of a curtain.
+/// Other kinds of device may have a similarly named Range that
+/// means something else, so only curtains are considered.
+pub fn is_cover_position(device_type: &DeviceType, instance: &str) -> bool {
+    *device_type == DeviceType::Curtain
+        && COVER_POSITION_INSTANCES
+            .iter()
+            .any(|name| name.eq_ignore_ascii_case(instance))
+}
+
+fn integer_range(instance: &DeviceCapability) -> Option<&IntegerRange> {
+    match &instance.parameters {
+        Some(DeviceParameters::Integer { range, .. }) => Some(range),
+        _ => None,
+    }
+}
+
+/// The topic through which hass sets a Range capability; the same
+/// one that is used by RangeNumber, so that the same handler applies
+fn set_range_topic(device: &ServiceDevice, instance: &str) -> String {
+    format!(
+        "gv2mqtt/number/{id}/set-range/{inst}",
+        id = topic_safe_id(device),
+        inst = topic_safe_string(instance)
+    )
+}
+
+/// Represents a curtain whose position, and optionally the angle
+/// of its slats, is controlled via Range capabilities
+pub struct PositionCover {
+    cover: CoverConfig,
+    device_id: String,
+    state: StateHandle,
+    position_instance: String,
+    position_range: IntegerRange,
+    tilt: Option<(String, IntegerRange)>,
+}
+
+impl PositionCover {
+    /// Returns None if the position capability doesn't describe
+    /// an integer range
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        position: &DeviceCapability,
+    ) -> Option<Self> {
+        let position_range = integer_range(position)?.clone();
+        let tilt = device
+            .get_capability_by_instance(TILT_INSTANCE)
+            .and_then(|cap| Some((cap.instance.to_string(), integer_range(cap)?.clone())));
+
+        let id = topic_safe_id(device);
+        let inst = topic_safe_string(&position.instance);
+        let unique_id = format!("gv2mqtt-{id}-{inst}-cover");
+
+        Some(Self {
+            cover: CoverConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: None,
+                    device_class: Some("curtain"),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: None,
+                },
+                command_topic: format!("gv2mqtt/cover/{id}/command/{inst}"),
+                payload_stop: None,
+                position_topic: format!("gv2mqtt/cover/{id}/position"),
+                set_position_topic: set_range_topic(device, &position.instance),
+                position_open: position_range.max,
+                position_closed: position_range.min,
+                tilt_command_topic: tilt
+                    .as_ref()
+                    .map(|(instance, _)| set_range_topic(device, instance)),
+                tilt_status_topic: tilt.as_ref().map(|_| format!("gv2mqtt/cover/{id}/tilt")),
+                tilt_min: tilt.as_ref().map(|(_, range)| range.min),
+                tilt_max: tilt.as_ref().map(|(_, range)| range.max),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            position_instance: position.instance.to_string(),
+            position_range,
+            tilt,
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for PositionCover {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.cover.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let value_of = |instance: &str, range: &IntegerRange| {
+            device
+                .get_state_capability_by_instance(instance)
+                .and_then(|cap| cap.state.pointer("/value"))
+                .and_then(|value| range_state_value(range, value))
+        };
+
+        match value_of(&self.position_instance, &self.position_range) {
+            Some(position) => {
+                client
+                    .publish(&self.cover.position_topic, position.to_string())
+                    .await?
+            }
+            None => {
+                log::trace!("PositionCover::notify_state: no position for {device}");
+            }
+        }
+
+        if let (Some((instance, range)), Some(topic)) = (&self.tilt, &self.cover.tilt_status_topic)
+        {
+            if let Some(tilt) = value_of(instance, range) {
+                client.publish(topic, tilt.to_string()).await?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+/// Handles the OPEN and CLOSE commands by moving the cover to
+/// the corresponding end of its range
+pub async fn mqtt_cover_command(
+    Payload(command): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("{instance} for {id}: {command}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let range = device
+        .get_capability_by_instance(&instance)
+        .and_then(integer_range)
+        .ok_or_else(|| anyhow::anyhow!("{device} has no {instance} range"))?;
+
+    let value = match command.as_str() {
+        "OPEN" => range.max,
+        "CLOSE" => range.min,
+        _ => anyhow::bail!("unsupported cover command {command} for {device}"),
+    };
+
+    state
+        .device_set_range_value(&device, &instance, value as f64)
+        .await
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::{DeviceCapabilityKind, HttpDeviceInfo};
+    use serde_json::json;
+    use serde_json::Value as JsonValue;
+
+    #[test]
+    fn cover_position_instances() {
+        assert!(is_cover_position(&DeviceType::Curtain, "position"));
+        assert!(is_cover_position(&DeviceType::Curtain, "curtainPosition"));
+        assert!(!is_cover_position(&DeviceType::Curtain, "tiltAngle"));
+        assert!(!is_cover_position(&DeviceType::Curtain, "sensorPosition"));
+        assert!(!is_cover_position(&DeviceType::Light, "position"));
+        assert!(!is_cover_position(&DeviceType::Fan, "curtainPosition"));
+    }
+
+    #[test]
+    fn curtain_position_and_tilt() {
+        let range_cap = |instance: &str, max| DeviceCapability {
+            kind: DeviceCapabilityKind::Range,
+            instance: instance.to_string(),
+            parameters: Some(DeviceParameters::Integer {
+                unit: Some("unit.percent".to_string()),
+                range: IntegerRange {
+                    min: 0,
+                    max,
+                    precision: 1,
+                },
+            }),
+            alarm_type: None,
+            event_state: None,
+        };
+
+        let mut device = ServiceDevice::new("H8100", "AA:BB:CC:DD:EE:FF:00:66");
+        device.set_http_device_info(HttpDeviceInfo {
+            sku: "H8100".to_string(),
+            device: "AA:BB:CC:DD:EE:FF:00:66".to_string(),
+            device_name: "Curtain".to_string(),
+            device_type: DeviceType::Curtain,
+            capabilities: vec![
+                range_cap("curtainPosition", 100),
+                range_cap("tiltAngle", 90),
+            ],
+        });
+        let state = std::sync::Arc::new(crate::service::state::State::new());
+
+        let position = device
+            .get_capability_by_instance("curtainPosition")
+            .unwrap()
+            .clone();
+        assert!(is_cover_position(&device.device_type(), &position.instance));
+        let cover = PositionCover::new(&device, &state, &position).unwrap();
+        let config = serde_json::to_value(&cover.cover).unwrap();
+
+        k9::assert_equal!(
+            config["set_position_topic"],
+            json!("gv2mqtt/number/AABBCCDDEEFF0066/set-range/curtainposition")
+        );
+        k9::assert_equal!(
+            config["position_topic"],
+            json!("gv2mqtt/cover/AABBCCDDEEFF0066/position")
+        );
+        k9::assert_equal!(config["position_open"], json!(100));
+        k9::assert_equal!(config["position_closed"], json!(0));
+        k9::assert_equal!(config["payload_stop"], JsonValue::Null);
+        k9::assert_equal!(
+            config["tilt_command_topic"],
+            json!("gv2mqtt/number/AABBCCDDEEFF0066/set-range/tiltangle")
+        );
+        k9::assert_equal!(
+            config["tilt_status_topic"],
+            json!("gv2mqtt/cover/AABBCCDDEEFF0066/tilt")
+        );
+        k9::assert_equal!(config["tilt_min"], json!(0));
+        k9::assert_equal!(config["tilt_max"], json!(90));
+
+        // Without the tilt capability, the tilt fields are omitted
+        device.http_device_info.as_mut().unwrap().capabilities.pop();
+        let cover = PositionCover::new(&device, &state, &position).unwrap();
+        let config = serde_json::to_value(&cover.cover).unwrap();
+        assert!(config.get("tilt_command_topic").is_none());
+        assert!(config.get("tilt_max").is_none());
+    }
 }
@@ -1,16 +1,27 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
+use crate::hass_mqtt::binary_sensor::{
+    is_binary_range, is_water_shortage, EventBinarySensor, KettleBoilingSensor,
+    WaterTankEmptySensor,
+};
 use crate::hass_mqtt::button::ButtonConfig;
 use crate::hass_mqtt::climate::TargetTemperatureEntity;
+use crate::hass_mqtt::cover::{is_cover_position, PositionCover, TILT_INSTANCE};
 use crate::hass_mqtt::humidifier::Humidifier;
-use crate::hass_mqtt::instance::EntityList;
+use crate::hass_mqtt::instance::{DeviceAvailability, EntityList};
 use crate::hass_mqtt::light::DeviceLight;
-use crate::hass_mqtt::number::WorkModeNumber;
+use crate::hass_mqtt::number::{RangeNumber, WorkModeNumber};
 use crate::hass_mqtt::scene::SceneConfig;
-use crate::hass_mqtt::select::{SceneModeSelect, WorkModeSelect};
-use crate::hass_mqtt::sensor::{CapabilitySensor, DeviceStatusDiagnostic, GlobalFixedDiagnostic};
-use crate::hass_mqtt::switch::CapabilitySwitch;
+use crate::hass_mqtt::select::{
+    CapabilityModeSelect, CapabilitySceneSelect, SceneModeSelect, WorkModeSelect,
+    DEDICATED_SCENE_INSTANCES,
+};
+use crate::hass_mqtt::sensor::{
+    is_power_or_energy, is_uv_index, is_water_tank_level, CapabilitiesDiagnostic, CapabilitySensor,
+    ControlResultDiagnostic, DeviceStatusDiagnostic, GlobalFixedDiagnostic,
+};
+use crate::hass_mqtt::switch::{CapabilitySwitch, KeepWarmSwitch, PowerEntity};
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
-use crate::platform_api::{DeviceCapability, DeviceCapabilityKind, DeviceType};
+use crate::platform_api::{is_scene_mode, DeviceCapability, DeviceCapabilityKind, DeviceType};
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{availability_topic, oneclick_topic, purge_cache_topic};
 use crate::service::state::StateHandle;
@@ -28,6 +39,10 @@ pub async fn enumerate_all_entites(state: &StateHandle) -> anyhow::Result<Entity
     let devices = state.devices().await;
 
     for d in &devices {
+        if state.is_device_excluded(d).await {
+            log::debug!("{d} is excluded; not publishing it to hass");
+            continue;
+        }
         enumerate_entities_for_device(d, state, &mut entities)
             .await
             .with_context(|| format!("Config::for_device({d})"))?;
@@ -144,6 +159,44 @@ async fn entities_for_work_mode<'a>(
     Ok(())
 }
 
+/// Returns true if the Range capability is a reading that the
+/// device reports, rather than a setting, and so should be a sensor
+/// rather than a number. Only the exact instance names of such
+/// readings are matched, so that settings that merely mention
+/// power or energy remain numbers.
+fn is_range_reading(cap: &DeviceCapability) -> bool {
+    is_uv_index(&cap.instance) || is_power_or_energy(&cap.instance)
+}
+
+/// Decides whether to show a light entity for the device, and whether
+/// to show its powerSwitch capability as a switch entity.
+/// Lights and plugs get exactly one of the two, so that there is a
+/// single entity that controls their power.
+fn power_entities(d: &ServiceDevice, mode: PowerEntity) -> (bool, bool) {
+    let looks_like_light =
+        d.supports_rgb() || d.supports_color_temperature() || d.supports_brightness();
+    if !d.power_is_light() {
+        return (looks_like_light, true);
+    }
+
+    let has_power_switch = d.get_capability_by_instance("powerSwitch").is_some();
+    let use_switch = match mode {
+        PowerEntity::Light => false,
+        PowerEntity::Switch => true,
+        PowerEntity::Auto => d.device_type() == DeviceType::Socket,
+    };
+
+    if use_switch && has_power_switch {
+        (false, true)
+    } else if use_switch {
+        // We can't show a switch without the capability, so fall
+        // back to the light, if it looks like one
+        (looks_like_light, false)
+    } else {
+        (true, false)
+    }
+}
+
 pub async fn enumerate_entities_for_device<'a>(
     d: &'a ServiceDevice,
     state: &StateHandle,
@@ -153,10 +206,16 @@ pub async fn enumerate_entities_for_device<'a>(
         return Ok(());
     }
 
+    entities.add(DeviceAvailability::new(d, state));
     entities.add(DeviceStatusDiagnostic::new(d, state));
+    entities.add(ControlResultDiagnostic::new(d, state));
+    if d.http_device_info.is_some() {
+        entities.add(CapabilitiesDiagnostic::new(d, state));
+    }
     entities.add(ButtonConfig::request_platform_data_for_device(d));
 
-    if d.supports_rgb() || d.get_color_temperature_range().is_some() || d.supports_brightness() {
+    let (show_light, show_power_switch) = power_entities(d, state.get_power_entity().await);
+    if show_light {
         entities.add(DeviceLight::for_device(&d, state, None).await?);
     }
 
@@ -167,29 +226,94 @@ pub async fn enumerate_entities_for_device<'a>(
         entities.add(Humidifier::new(&d, state).await?);
     }
 
-    if d.device_type() != DeviceType::Light {
+    if d.device_type() != DeviceType::Light || state.get_light_scenes().await.use_select() {
         if let Some(scenes) = SceneModeSelect::new(d, state).await? {
             entities.add(scenes);
         }
     }
 
+    for (instance, name) in DEDICATED_SCENE_INSTANCES {
+        if let Some(scenes) = CapabilitySceneSelect::new(d, state, instance, name).await? {
+            entities.add(scenes);
+        }
+    }
+
     if let Some(info) = &d.http_device_info {
         for cap in &info.capabilities {
             match &cap.kind {
+                DeviceCapabilityKind::OnOff
+                    if cap.instance == "powerSwitch" && !show_power_switch => {}
                 DeviceCapabilityKind::Toggle | DeviceCapabilityKind::OnOff => {
                     entities.add(CapabilitySwitch::new(&d, state, cap).await?);
                 }
                 DeviceCapabilityKind::ColorSetting
                 | DeviceCapabilityKind::SegmentColorSetting
                 | DeviceCapabilityKind::MusicSetting
-                | DeviceCapabilityKind::Event
-                | DeviceCapabilityKind::Mode
                 | DeviceCapabilityKind::DynamicScene => {}
 
+                // Scene-like modes are part of the scene list
+                DeviceCapabilityKind::Mode if is_scene_mode(&cap.instance) => {}
+                DeviceCapabilityKind::Mode => match CapabilityModeSelect::new(d, state, cap) {
+                    Some(select) => entities.add(select),
+                    None => {
+                        log::warn!("Unexpected parameters for {} {d} {cap:?}", cap.instance);
+                    }
+                },
+
                 DeviceCapabilityKind::Range if cap.instance == "brightness" => {}
                 DeviceCapabilityKind::Range if cap.instance == "humidity" => {}
+                // The tank level is read-only, so it is a sensor rather
+                // than a number
+                DeviceCapabilityKind::Range if is_water_tank_level(&cap.instance) => {
+                    if is_binary_range(cap) {
+                        entities.add(WaterTankEmptySensor::new(d, state, cap));
+                    } else {
+                        entities.add(CapabilitySensor::new(d, state, cap).await?);
+                    }
+                }
+                // Likewise, the UV index and the power usage reported
+                // by outlets are readings rather than settings
+                DeviceCapabilityKind::Range if is_range_reading(cap) => {
+                    entities.add(CapabilitySensor::new(d, state, cap).await?);
+                }
+                DeviceCapabilityKind::Range
+                    if is_cover_position(&info.device_type, &cap.instance) =>
+                {
+                    match PositionCover::new(d, state, cap) {
+                        Some(cover) => entities.add(cover),
+                        None => {
+                            log::warn!("Unexpected parameters for {} {d} {cap:?}", cap.instance);
+                        }
+                    }
+                }
+                // The tilt is controlled via the cover, if there is one
+                DeviceCapabilityKind::Range
+                    if cap.instance == TILT_INSTANCE
+                        && info
+                            .capabilities
+                            .iter()
+                            .any(|c| is_cover_position(&info.device_type, &c.instance)) => {}
+                DeviceCapabilityKind::Range => match RangeNumber::new(d, state, cap) {
+                    Some(number) => entities.add(number),
+                    None => {
+                        log::warn!("Unexpected parameters for {} {d} {cap:?}", cap.instance);
+                    }
+                },
                 DeviceCapabilityKind::WorkMode => {
                     entities_for_work_mode(d, state, cap, entities).await?;
+                    if d.device_type() == DeviceType::Kettle {
+                        if let Some(switch) = KeepWarmSwitch::new(d, state) {
+                            entities.add(switch);
+                        }
+                    }
+                }
+
+                DeviceCapabilityKind::Event => {
+                    entities.add(EventBinarySensor::new(d, state, cap));
+                }
+
+                DeviceCapabilityKind::Property if is_water_shortage(&cap.instance) => {
+                    entities.add(EventBinarySensor::new(d, state, cap));
                 }
 
                 DeviceCapabilityKind::Property => {
@@ -198,6 +322,11 @@ pub async fn enumerate_entities_for_device<'a>(
 
                 DeviceCapabilityKind::TemperatureSetting => {
                     entities.add(TargetTemperatureEntity::new(&d, state, cap).await?);
+                    if d.device_type() == DeviceType::Kettle
+                        && info.capability_by_instance("sensorTemperature").is_some()
+                    {
+                        entities.add(KettleBoilingSensor::new(d, state, cap));
+                    }
                 }
 
                 kind => {
@@ -210,6 +339,10 @@ pub async fn enumerate_entities_for_device<'a>(
         }
 
         if let Some(segments) = info.supports_segmented_rgb() {
+            let segments = match &d.responsive_segments {
+                Some(responsive) => responsive.clone(),
+                None => segments.collect(),
+            };
             for n in segments {
                 entities.add(DeviceLight::for_device(&d, state, Some(n)).await?);
             }
@@ -217,3 +350,102 @@ pub async fn enumerate_entities_for_device<'a>(
     }
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::HttpDeviceInfo;
+    use serde_json::json;
+
+    fn device(device_type: &str, instances: &[&str]) -> ServiceDevice {
+        let capabilities: Vec<_> = instances
+            .iter()
+            .map(|instance| match *instance {
+                "powerSwitch" => json!({
+                    "type": "devices.capabilities.on_off",
+                    "instance": "powerSwitch",
+                    "parameters": {
+                        "dataType": "ENUM",
+                        "options": [{"name": "on", "value": 1}, {"name": "off", "value": 0}]
+                    }
+                }),
+                "brightness" => json!({
+                    "type": "devices.capabilities.range",
+                    "instance": "brightness",
+                    "parameters": {
+                        "unit": "unit.percent",
+                        "dataType": "INTEGER",
+                        "range": {"min": 1, "max": 100, "precision": 1}
+                    }
+                }),
+                _ => unreachable!(),
+            })
+            .collect();
+        let info: HttpDeviceInfo = serde_json::from_value(json!({
+            "sku": "H0000",
+            "device": "AA:BB:CC:DD:EE:FF:00:11",
+            "type": device_type,
+            "capabilities": capabilities,
+        }))
+        .unwrap();
+
+        let mut device = ServiceDevice::new(&info.sku, &info.device);
+        device.set_http_device_info(info);
+        device
+    }
+
+    #[test]
+    fn power_entity_selection() {
+        let light = device("devices.types.light", &["powerSwitch", "brightness"]);
+        let socket = device("devices.types.socket", &["powerSwitch"]);
+        let heater = device("devices.types.heater", &["powerSwitch"]);
+
+        // (show light, show power switch)
+        k9::assert_equal!(power_entities(&light, PowerEntity::Auto), (true, false));
+        k9::assert_equal!(power_entities(&socket, PowerEntity::Auto), (false, true));
+
+        k9::assert_equal!(power_entities(&light, PowerEntity::Switch), (false, true));
+        k9::assert_equal!(power_entities(&socket, PowerEntity::Light), (true, false));
+
+        // Other kinds of device keep their power switch
+        for mode in [PowerEntity::Auto, PowerEntity::Light, PowerEntity::Switch] {
+            k9::assert_equal!(power_entities(&heater, mode), (false, true));
+        }
+
+        // Without the capability, we can't use a switch
+        let lan_light = device("devices.types.light", &["brightness"]);
+        k9::assert_equal!(
+            power_entities(&lan_light, PowerEntity::Switch),
+            (true, false)
+        );
+    }
+
+    #[test]
+    fn range_readings() {
+        let range = |instance: &str| -> DeviceCapability {
+            serde_json::from_value(json!({
+                "type": "devices.capabilities.range",
+                "instance": instance,
+                "parameters": {
+                    "dataType": "INTEGER",
+                    "range": {"min": 0, "max": 100, "precision": 1}
+                }
+            }))
+            .unwrap()
+        };
+
+        for instance in [
+            "powerConsumption",
+            "energyConsumption",
+            "wattage",
+            "uvIndex",
+        ] {
+            assert!(is_range_reading(&range(instance)), "{instance}");
+        }
+
+        // Settings that mention power or energy remain numbers
+        for instance in ["power", "powerLevel", "energySaving"] {
+            assert!(!is_range_reading(&range(instance)), "{instance}");
+        }
+    }
+}
@@ -282,15 +282,17 @@ pub async fn mqtt_humidifier_set_target(
     if !use_iot {
         if let Some(info) = &device.http_device_info {
             if let Some(cap) = info.capability_by_instance("humidity") {
-                state.device_control(&device, cap, percent).await?;
+                let result = state.device_control(&device, cap, percent).await?;
 
                 // We're running in optimistic mode; stash
                 // the last set value so that we can report it
-                // to hass
+                // to hass. Prefer the value that the response
+                // says was applied over the one we requested.
+                let applied = result.value.as_i64().unwrap_or(percent);
                 state
                     .device_mut(&device.sku, &device.id)
                     .await
-                    .set_target_humidity(percent as u8);
+                    .set_target_humidity(applied as u8);
 
                 // For the H7160 at least, setting the humidity
                 // will put the device into auto mode and turn
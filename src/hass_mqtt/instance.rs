@@ -1,9 +1,11 @@
 use crate::hass_mqtt::base::EntityConfig;
+use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::HassClient;
 use crate::service::state::StateHandle;
 use anyhow::Context;
 use async_trait::async_trait;
 use serde::Serialize;
+use serde_json::{json, Value as JsonValue};
 use std::sync::Arc;
 
 #[async_trait]
@@ -27,7 +29,72 @@ pub async fn publish_entity_config<T: Serialize>(
         unique_id = base.unique_id
     );
 
-    client.publish_obj(topic, config).await
+    match &base.device.availability_topic {
+        Some(device_topic) => {
+            let mut config = serde_json::to_value(config)?;
+            add_device_availability(&mut config, base, device_topic);
+            client.publish_config(topic, &config).await
+        }
+        None => client.publish_config(topic, config).await,
+    }
+}
+
+/// hass doesn't allow both availability_topic and availability to
+/// be set, so replace the former with a list that also includes the
+/// availability of the device; the entity is available only while
+/// both are online
+fn add_device_availability(config: &mut JsonValue, base: &EntityConfig, device_topic: &str) {
+    if let Some(obj) = config.as_object_mut() {
+        obj.remove("availability_topic");
+        obj.insert(
+            "availability".to_string(),
+            json!([
+                {"topic": base.availability_topic},
+                {"topic": device_topic},
+            ]),
+        );
+        obj.insert("availability_mode".to_string(), json!("all"));
+    }
+}
+
+/// Reports the per-device availability alongside the state of
+/// the other entities of the device
+pub struct DeviceAvailability {
+    device_id: String,
+    state: StateHandle,
+}
+
+impl DeviceAvailability {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        Self {
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for DeviceAvailability {
+    async fn publish_config(
+        &self,
+        _state: &StateHandle,
+        _client: &HassClient,
+    ) -> anyhow::Result<()> {
+        // There is no entity as such; the topic is referenced
+        // by the configs of the other entities of the device
+        Ok(())
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+        client
+            .publish_device_availability(&device, &self.state)
+            .await
+    }
 }
 
 #[derive(Default, Clone)]
@@ -53,15 +120,17 @@ impl EntityList {
         state: &StateHandle,
         client: &HassClient,
     ) -> anyhow::Result<()> {
-        // Allow HASS time to process each entity before registering the next
-        let delay = tokio::time::Duration::from_millis(100);
+        // Queue up all of the configs, then let the client publish
+        // them in batches so that we don't flood HASS with them
         for e in &self.entities {
             e.publish_config(state, client)
                 .await
                 .context("EntityList::publish_config")?;
-            tokio::time::sleep(delay).await;
         }
-        Ok(())
+        client
+            .flush_discovery_configs(state)
+            .await
+            .context("EntityList::publish_config")
     }
 
     pub async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
@@ -73,3 +142,35 @@ impl EntityList {
         Ok(())
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hass_mqtt::base::Device;
+
+    #[test]
+    fn device_availability() {
+        let device = ServiceDevice::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        let base = EntityConfig {
+            availability_topic: "gv2mqtt/availability".to_string(),
+            device: Device::for_device(&device),
+            ..EntityConfig::default()
+        };
+        let mut config = serde_json::to_value(&base).unwrap();
+        add_device_availability(
+            &mut config,
+            &base,
+            base.device.availability_topic.as_deref().unwrap(),
+        );
+
+        k9::assert_equal!(config.get("availability_topic"), None);
+        k9::assert_equal!(
+            config["availability"],
+            json!([
+                {"topic": "gv2mqtt/availability"},
+                {"topic": "gv2mqtt/AABBCCDDEEFF422A/availability"},
+            ])
+        );
+        k9::assert_equal!(config["availability_mode"], "all");
+    }
+}
@@ -1,15 +1,64 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::lan_api::DeviceColor;
 use crate::platform_api::DeviceType;
-use crate::service::device::Device as ServiceDevice;
+use crate::service::device::{Device as ServiceDevice, DeviceState};
 use crate::service::hass::{
-    availability_topic, kelvin_to_mired, light_segment_state_topic, light_state_topic,
-    topic_safe_id, HassClient,
+    availability_topic, kelvin_to_mired, light_attributes_topic, light_segment_state_topic,
+    light_state_topic, topic_safe_id, HassClient, NORMALIZED_MAX_MIREDS, NORMALIZED_MIN_MIREDS,
 };
 use crate::service::state::StateHandle;
 use async_trait::async_trait;
 use serde::Serialize;
-use serde_json::json;
+use serde_json::{json, Value as JsonValue};
+
+/// Controls how the scenes of a light are presented to hass
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum LightScenes {
+    /// As the effect_list of the light entity
+    #[default]
+    Effect,
+    /// As a standalone Mode/Scene select entity
+    Select,
+    /// As both an effect_list and a select entity
+    Both,
+}
+
+impl std::fmt::Display for LightScenes {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Effect => fmt.write_str("effect"),
+            Self::Select => fmt.write_str("select"),
+            Self::Both => fmt.write_str("both"),
+        }
+    }
+}
+
+impl std::str::FromStr for LightScenes {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("effect") {
+            Ok(Self::Effect)
+        } else if s.eq_ignore_ascii_case("select") {
+            Ok(Self::Select)
+        } else if s.eq_ignore_ascii_case("both") {
+            Ok(Self::Both)
+        } else {
+            anyhow::bail!("Unknown light scene style {s}. Use effect, select or both")
+        }
+    }
+}
+
+impl LightScenes {
+    pub fn use_effects(self) -> bool {
+        matches!(self, Self::Effect | Self::Both)
+    }
+
+    pub fn use_select(self) -> bool {
+        matches!(self, Self::Select | Self::Both)
+    }
+}
 
 /// <https://www.home-assistant.io/integrations/light.mqtt/#json-schema>
 #[derive(Serialize, Clone, Debug)]
@@ -22,11 +71,17 @@ pub struct LightConfig {
     /// The docs say that this is optional, but hass errors out if
     /// it is not passed
     pub state_topic: String,
+    /// Additional context about the state, such as the active scene
+    /// and where the state came from, is published here
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub json_attributes_topic: Option<String>,
     pub optimistic: bool,
     pub supported_color_modes: Vec<String>,
     /// Flag that defines if the light supports brightness.
     pub brightness: bool,
     /// Defines the maximum brightness value (i.e., 100%) of the MQTT device.
+    /// We always report and accept brightness as a percentage, so this
+    /// must be 100, rather than the hass default of 255.
     pub brightness_scale: u32,
 
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -38,6 +93,11 @@ pub struct LightConfig {
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub effect_list: Vec<String>,
 
+    /// Flag that defines if the light supports transitions.
+    /// We can only honor them by emulation, so this is only
+    /// set when that is enabled.
+    pub transition: bool,
+
     #[serde(skip_serializing_if = "Option::is_none")]
     pub min_mireds: Option<u32>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -56,6 +116,7 @@ impl LightConfig {
 pub struct DeviceLight {
     light: LightConfig,
     device_id: String,
+    segment: Option<u32>,
     state: StateHandle,
 }
 
@@ -66,51 +127,81 @@ impl EntityInstance for DeviceLight {
     }
 
     async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
-        if self.light.optimistic {
-            return Ok(());
-        }
-
         let device = self
             .state
             .device_by_id(&self.device_id)
             .await
             .expect("device to exist");
 
+        if let Some(segment) = self.segment {
+            return self.notify_segment_state(&device, segment, client).await;
+        }
+
+        if self.light.optimistic {
+            return Ok(());
+        }
+
         match device.device_state() {
             Some(device_state) => {
                 log::trace!("LightConfig::notify_state: state is {device_state:?}");
 
                 let is_on = device_state.light_on.unwrap_or(false);
+                let brightness_curve = self.state.brightness_curve_for_device(&device).await;
+                let brightness = device
+                    .reported_brightness()
+                    .map(|brightness| brightness_curve.invert(brightness));
+
+                let color_temp = self
+                    .state
+                    .kelvin_to_hass_mired(&device, device_state.kelvin)
+                    .await;
+
+                let effect = device_state
+                    .scene
+                    .clone()
+                    .filter(|scene| self.light.effect && self.light.effect_list.contains(scene));
 
                 let light_state = if is_on {
                     if device_state.kelvin == 0 {
+                        let (color_mode, color) = self.color_state(&device_state.color);
                         json!({
                             "state": "ON",
-                            "color_mode": "rgb",
-                            "color": {
-                                "r": device_state.color.r,
-                                "g": device_state.color.g,
-                                "b": device_state.color.b,
-                            },
-                            "brightness": device_state.brightness,
-                            "effect": device_state.scene,
+                            "color_mode": color_mode,
+                            "color": color,
+                            "brightness": brightness.unwrap_or(0),
+                            "effect": effect,
                         })
                     } else {
                         json!({
                             "state": "ON",
                             "color_mode": "color_temp",
-                            "brightness": device_state.brightness,
-                            "color_temp": kelvin_to_mired(device_state.kelvin),
-                            "effect": device_state.scene,
+                            "brightness": brightness.unwrap_or(0),
+                            "color_temp": color_temp,
+                            "effect": effect,
                         })
                     }
                 } else {
-                    json!({"state":"OFF"})
+                    // Report the last known brightness so that the
+                    // slider is restored when the light is turned on
+                    match brightness {
+                        Some(brightness) => json!({
+                            "state": "OFF",
+                            "brightness": brightness,
+                        }),
+                        None => json!({"state":"OFF"}),
+                    }
                 };
 
                 client
                     .publish_obj(&self.light.state_topic, &light_state)
-                    .await
+                    .await?;
+
+                if let Some(topic) = &self.light.json_attributes_topic {
+                    client
+                        .publish_obj(topic, &light_attributes(&device_state))
+                        .await?;
+                }
+                Ok(())
             }
             None => {
                 // TODO: mark as unavailable or something? Don't
@@ -124,7 +215,83 @@ impl EntityInstance for DeviceLight {
     }
 }
 
+/// Builds the json attributes object for a light entity
+fn light_attributes(device_state: &DeviceState) -> JsonValue {
+    json!({
+        "active_scene": device_state.scene,
+        "state_source": if device_state.source == "LAN API" {
+            "lan"
+        } else {
+            "cloud"
+        },
+        "last_updated": device_state.updated.to_rfc3339(),
+    })
+}
+
 impl DeviceLight {
+    /// Returns the color mode and color to report for color.
+    /// When xy is one of our supported color modes, the color is
+    /// reported as both rgb and xy so that either can be used
+    fn color_state(&self, color: &DeviceColor) -> (&'static str, JsonValue) {
+        let mut value = json!({
+            "r": color.r,
+            "g": color.g,
+            "b": color.b,
+        });
+        if !self.light.supported_color_modes.iter().any(|m| m == "xy") {
+            return ("rgb", value);
+        }
+        let (x, y) = color.to_xy();
+        value["x"] = ((x * 10000.).round() / 10000.).into();
+        value["y"] = ((y * 10000.).round() / 10000.).into();
+        ("xy", value)
+    }
+
+    /// Segments are controlled optimistically, but if the platform API
+    /// reported the segment state then we can publish it so that the
+    /// entity doesn't start out unknown
+    async fn notify_segment_state(
+        &self,
+        device: &ServiceDevice,
+        segment: u32,
+        client: &HassClient,
+    ) -> anyhow::Result<()> {
+        let Some(light_state) = self.segment_light_state(device, segment) else {
+            return Ok(());
+        };
+
+        client
+            .publish_obj(&self.light.state_topic, &light_state)
+            .await
+    }
+
+    /// Builds the state to report for a segment, from whichever of
+    /// its color and brightness were reported by the platform API
+    fn segment_light_state(&self, device: &ServiceDevice, segment: u32) -> Option<JsonValue> {
+        let seg_state = device.segment_state(segment)?;
+
+        let mut light_state = match seg_state.rgb {
+            Some(rgb) => {
+                let (color_mode, color) = self.color_state(&device.decode_color_state(rgb));
+                json!({
+                    "state": if rgb == 0 { "OFF" } else { "ON" },
+                    "color_mode": color_mode,
+                    "color": color,
+                })
+            }
+            None => {
+                let brightness = seg_state.brightness?;
+                json!({
+                    "state": if brightness == 0 { "OFF" } else { "ON" },
+                })
+            }
+        };
+        if let Some(brightness) = seg_state.brightness {
+            light_state["brightness"] = brightness.into();
+        }
+        Some(light_state)
+    }
+
     pub async fn for_device(
         device: &ServiceDevice,
         state: &StateHandle,
@@ -151,6 +318,10 @@ impl DeviceLight {
             Some(seg) => light_segment_state_topic(device, seg),
             None => light_state_topic(device),
         };
+        let json_attributes_topic = match segment {
+            Some(_) => None,
+            None => Some(light_attributes_topic(device)),
+        };
         let availability_topic = availability_topic();
         let unique_id = format!(
             "gv2mqtt-{id}{seg}",
@@ -158,10 +329,11 @@ impl DeviceLight {
             seg = segment.map(|n| format!("-{n}")).unwrap_or(String::new())
         );
 
-        let effect_list = if segment.is_some() {
+        let use_effects = state.get_light_scenes().await.use_effects();
+        let effect_list = if segment.is_some() || !use_effects {
             vec![]
         } else {
-            match state.device_list_scenes(device).await {
+            match state.device_list_main_scenes(device).await {
                 Ok(scenes) => scenes,
                 Err(err) => {
                     log::error!("Unable to list scenes for {device}: {err:#}");
@@ -174,29 +346,56 @@ impl DeviceLight {
 
         if segment.is_some() || device.supports_rgb() {
             supported_color_modes.push("rgb".to_string());
+            if state.get_xy_color().await {
+                supported_color_modes.push("xy".to_string());
+            }
         }
 
-        let (min_mireds, max_mireds) = if segment.is_some() {
-            (None, None)
-        } else if let Some((min, max)) = device.get_color_temperature_range() {
+        let color_temp = segment.is_none() && device.supports_color_temperature();
+        if color_temp {
             supported_color_modes.push("color_temp".to_string());
+        }
+
+        let (min_mireds, max_mireds) = match device.get_color_temperature_range() {
+            Some(_) if color_temp && state.get_normalize_color_temperature().await => {
+                (Some(NORMALIZED_MIN_MIREDS), Some(NORMALIZED_MAX_MIREDS))
+            }
             // Note that min and max are swapped by the translation
             // from kelvin to mired
-            (Some(kelvin_to_mired(max)), Some(kelvin_to_mired(min)))
-        } else {
-            (None, None)
+            Some((min, max)) if color_temp => {
+                (Some(kelvin_to_mired(max)), Some(kelvin_to_mired(min)))
+            }
+            _ => (None, None),
         };
 
-        let brightness = segment.is_some()
-            || quirk
-                .as_ref()
-                .map(|q| q.supports_brightness)
-                .unwrap_or(false)
-            || device
+        let brightness = match segment {
+            Some(_) => device
                 .http_device_info
                 .as_ref()
-                .map(|info| info.supports_brightness())
-                .unwrap_or(false);
+                .map(|info| info.supports_segmented_color())
+                .unwrap_or(false),
+            None => {
+                quirk
+                    .as_ref()
+                    .map(|q| q.supports_brightness)
+                    .unwrap_or(false)
+                    || device
+                        .http_device_info
+                        .as_ref()
+                        .map(|info| info.supports_brightness())
+                        .unwrap_or(false)
+            }
+        };
+
+        // hass requires at least one color mode; plugs that are
+        // shown as a light can only be turned on and off
+        if supported_color_modes.is_empty() && !brightness {
+            supported_color_modes.push("onoff".to_string());
+        }
+
+        let transition = segment.is_none()
+            && (brightness || device.supports_rgb())
+            && state.get_emulate_transitions().await;
 
         let name = match segment {
             Some(n) => Some(format!("Segment {:03}", n + 1)),
@@ -219,11 +418,13 @@ impl DeviceLight {
                 schema: "json".to_string(),
                 command_topic,
                 state_topic,
+                json_attributes_topic,
                 supported_color_modes,
                 brightness,
                 brightness_scale: 100,
-                effect: true,
+                effect: use_effects,
                 effect_list,
+                transition,
                 payload_available: "online".to_string(),
                 max_mireds,
                 min_mireds,
@@ -231,7 +432,164 @@ impl DeviceLight {
                 icon,
             },
             device_id: device.id.to_string(),
+            segment,
             state: state.clone(),
         })
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::mock::{
+        device_state_from_fixture, devices_from_fixture, MockGoveeApiClient,
+    };
+    use crate::service::state::State;
+    use chrono::{TimeZone, Utc};
+    use std::sync::Arc;
+
+    /// Returns a State whose only device is the first light from
+    /// list_devices.json, backed by a mock platform client
+    async fn state_with_light() -> (StateHandle, ServiceDevice) {
+        let info =
+            devices_from_fixture(include_str!("../../test-data/list_devices.json"))[0].clone();
+        let state = MockGoveeApiClient::with_devices(vec![info.clone()])
+            .service_state()
+            .await;
+        let device = state.device_by_id(&info.device).await.unwrap();
+        (state, device)
+    }
+
+    #[tokio::test]
+    async fn discovery_brightness_is_percent() {
+        let (state, device) = state_with_light().await;
+
+        let light = DeviceLight::for_device(&device, &state, None)
+            .await
+            .unwrap();
+        let config = serde_json::to_value(&light.light).unwrap();
+        k9::assert_equal!(config["brightness"], json!(true));
+        k9::assert_equal!(config["brightness_scale"], json!(100));
+        k9::assert_equal!(config["transition"], json!(false));
+
+        // Transitions are only advertised when we emulate them
+        state.set_emulate_transitions(true).await;
+        let light = DeviceLight::for_device(&device, &state, None)
+            .await
+            .unwrap();
+        let config = serde_json::to_value(&light.light).unwrap();
+        k9::assert_equal!(config["transition"], json!(true));
+    }
+
+    #[tokio::test]
+    async fn xy_color_mode() {
+        let (state, device) = state_with_light().await;
+        let red = DeviceColor { r: 255, g: 0, b: 0 };
+
+        // rgb remains the default
+        let light = DeviceLight::for_device(&device, &state, None)
+            .await
+            .unwrap();
+        assert!(!light
+            .light
+            .supported_color_modes
+            .contains(&"xy".to_string()));
+        k9::assert_equal!(
+            light.color_state(&red),
+            ("rgb", json!({"r": 255, "g": 0, "b": 0}))
+        );
+
+        state.set_xy_color(true).await;
+        let light = DeviceLight::for_device(&device, &state, None)
+            .await
+            .unwrap();
+        assert!(light
+            .light
+            .supported_color_modes
+            .contains(&"rgb".to_string()));
+        assert!(light
+            .light
+            .supported_color_modes
+            .contains(&"xy".to_string()));
+        k9::assert_equal!(
+            light.color_state(&red),
+            (
+                "xy",
+                json!({"r": 255, "g": 0, "b": 0, "x": 0.6401, "y": 0.33})
+            )
+        );
+    }
+
+    #[tokio::test]
+    async fn segment_brightness_is_reported() {
+        let http_state = device_state_from_fixture(include_str!(
+            "../../test-data/get_device_state_segmented_brightness.json"
+        ));
+
+        let state = Arc::new(State::new());
+        state
+            .device_mut(&http_state.sku, &http_state.device)
+            .await
+            .set_http_device_state(http_state.clone());
+        let device = state.device_by_id(&http_state.device).await.unwrap();
+
+        let light = DeviceLight::for_device(&device, &state, Some(0))
+            .await
+            .unwrap();
+        let segment_state = |segment| light.segment_light_state(&device, segment);
+
+        // Segments that only reported their brightness
+        k9::assert_equal!(
+            segment_state(0),
+            Some(json!({"state": "ON", "brightness": 20}))
+        );
+        k9::assert_equal!(
+            segment_state(2),
+            Some(json!({"state": "OFF", "brightness": 0}))
+        );
+        k9::assert_equal!(
+            segment_state(3),
+            Some(json!({
+                "state": "ON",
+                "color_mode": "rgb",
+                "color": {"r": 0, "g": 0, "b": 255},
+                "brightness": 100,
+            }))
+        );
+        k9::assert_equal!(segment_state(4), None);
+    }
+
+    #[test]
+    fn attributes() {
+        let mut device_state = DeviceState {
+            on: true,
+            light_on: Some(true),
+            online: Some(true),
+            kelvin: 0,
+            color: Default::default(),
+            brightness: 100,
+            scene: Some("Sunrise".to_string()),
+            source: "LAN API",
+            updated: Utc.with_ymd_and_hms(2024, 1, 2, 3, 4, 5).unwrap(),
+        };
+        k9::assert_equal!(
+            light_attributes(&device_state),
+            json!({
+                "active_scene": "Sunrise",
+                "state_source": "lan",
+                "last_updated": "2024-01-02T03:04:05+00:00",
+            })
+        );
+
+        device_state.scene = None;
+        device_state.source = "PLATFORM API";
+        k9::assert_equal!(
+            light_attributes(&device_state),
+            json!({
+                "active_scene": null,
+                "state_source": "cloud",
+                "last_updated": "2024-01-02T03:04:05+00:00",
+            })
+        );
+    }
+}
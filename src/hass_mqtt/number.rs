@@ -1,7 +1,10 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::platform_api::{DeviceCapability, DeviceParameters, IntegerRange};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
+use crate::service::hass::{
+    availability_topic, topic_safe_id, topic_safe_string, HassClient, IdAndInst,
+};
 use crate::service::state::StateHandle;
 use anyhow::anyhow;
 use async_trait::async_trait;
@@ -42,6 +45,16 @@ impl NumberConfig {
             )
             .await
     }
+
+    /// Set the min, max and step to match a platform API integer range.
+    /// The step is 1/precision rather than the precision itself,
+    /// because the API values are scaled by the precision; see
+    /// IntegerRange::to_api_value
+    pub fn apply_integer_range(&mut self, range: &IntegerRange) {
+        self.min.replace(range.min as f32);
+        self.max.replace(range.max as f32);
+        self.step = range.step() as f32;
+    }
 }
 
 pub struct WorkModeNumber {
@@ -167,6 +180,144 @@ impl EntityInstance for WorkModeNumber {
     }
 }
 
+/// Represents a Range capability, other than those handled by
+/// a more specific entity such as the light brightness,
+/// as a number entity
+pub struct RangeNumber {
+    number: NumberConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+    range: IntegerRange,
+}
+
+impl RangeNumber {
+    /// Returns None if the capability doesn't describe an integer range
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> Option<Self> {
+        let Some(DeviceParameters::Integer { unit, range }) = &instance.parameters else {
+            return None;
+        };
+
+        let command_topic = format!(
+            "gv2mqtt/number/{id}/set-range/{inst}",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+        let state_topic = format!(
+            "gv2mqtt/number/{id}/range/{inst}/state",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-{inst}-range",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(&instance.instance)
+        );
+
+        let mut number = NumberConfig {
+            base: EntityConfig {
+                availability_topic: availability_topic(),
+                name: Some(instance.instance.to_string()),
+                device_class: None,
+                origin: Origin::default(),
+                device: Device::for_device(device),
+                unique_id,
+                entity_category: None,
+                icon: None,
+            },
+            command_topic,
+            state_topic: Some(state_topic),
+            min: None,
+            max: None,
+            step: 1.,
+            unit_of_measurement: unit.as_deref().and_then(unit_of_measurement),
+        };
+        number.apply_integer_range(range);
+
+        Some(Self {
+            number,
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+            range: range.clone(),
+        })
+    }
+}
+
+/// Maps the unit of a platform API integer parameter to the
+/// equivalent hass unit of measurement
+fn unit_of_measurement(unit: &str) -> Option<&'static str> {
+    match unit {
+        "unit.percent" => Some("%"),
+        "unit.celsius" => Some("°C"),
+        "unit.fahrenheit" => Some("°F"),
+        "unit.kelvin" => Some("K"),
+        _ => None,
+    }
+}
+
+/// Converts the reported value of a Range capability into the
+/// units of the range. The API reports an empty string when
+/// the value is not known.
+pub(crate) fn range_state_value(range: &IntegerRange, value: &JsonValue) -> Option<f64> {
+    let value = value.as_u64()?;
+    Some(range.scale_api_value(value as u32))
+}
+
+#[async_trait]
+impl EntityInstance for RangeNumber {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.number.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let value = device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|cap| cap.state.pointer("/value"))
+            .and_then(|value| range_state_value(&self.range, value));
+
+        match value {
+            Some(value) => self.number.notify_state(client, &value.to_string()).await,
+            None => {
+                log::trace!(
+                    "RangeNumber::notify_state: no value for {device} {instance}",
+                    instance = self.instance_name
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+pub async fn mqtt_range_number_command(
+    Payload(value): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("{instance} for {id}: {value}");
+    let value: f64 = value
+        .trim()
+        .parse()
+        .map_err(|err| anyhow!("invalid value {value} for {instance}: {err}"))?;
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_set_range_value(&device, &instance, value)
+        .await?;
+
+    Ok(())
+}
+
 #[derive(Deserialize)]
 pub struct IdAndModeName {
     id: String,
@@ -193,3 +344,104 @@ pub async fn mqtt_number_command(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn integer_range_step() {
+        let mut number = NumberConfig {
+            base: EntityConfig {
+                availability_topic: availability_topic(),
+                name: None,
+                device_class: None,
+                origin: Origin::default(),
+                device: Device::this_service(),
+                unique_id: "test-number".to_string(),
+                entity_category: None,
+                icon: None,
+            },
+            command_topic: "command".to_string(),
+            state_topic: None,
+            min: None,
+            max: None,
+            step: 1.0,
+            unit_of_measurement: None,
+        };
+        number.apply_integer_range(&IntegerRange {
+            min: 0,
+            max: 100,
+            precision: 10,
+        });
+        k9::assert_equal!(number.min, Some(0.0));
+        k9::assert_equal!(number.max, Some(100.0));
+        k9::assert_equal!(number.step, 0.1);
+    }
+
+    #[test]
+    fn range_number_discovery() {
+        let device = ServiceDevice::new("H7141", "AA:BB:CC:DD:EE:FF:00:11");
+        let state = std::sync::Arc::new(crate::service::state::State::new());
+        let cap = DeviceCapability {
+            kind: crate::platform_api::DeviceCapabilityKind::Range,
+            instance: "mistLevel".to_string(),
+            parameters: Some(DeviceParameters::Integer {
+                unit: Some("unit.percent".to_string()),
+                range: IntegerRange {
+                    min: 0,
+                    max: 100,
+                    precision: 5,
+                },
+            }),
+            alarm_type: None,
+            event_state: None,
+        };
+
+        let number = RangeNumber::new(&device, &state, &cap).unwrap();
+        let config = serde_json::to_value(&number.number).unwrap();
+        k9::assert_equal!(config["min"], serde_json::json!(0.0));
+        k9::assert_equal!(config["max"], serde_json::json!(100.0));
+        // A precision of 5 means that the range is in increments of 1/5
+        k9::assert_equal!(config["step"], serde_json::json!(0.2f32));
+        k9::assert_equal!(config["unit_of_measurement"], serde_json::json!("%"));
+
+        let cap = DeviceCapability {
+            parameters: Some(DeviceParameters::Integer {
+                unit: None,
+                range: IntegerRange {
+                    min: 0,
+                    max: 100,
+                    precision: 1,
+                },
+            }),
+            ..cap
+        };
+        let number = RangeNumber::new(&device, &state, &cap).unwrap();
+        let config = serde_json::to_value(&number.number).unwrap();
+        k9::assert_equal!(config["step"], serde_json::json!(1.0));
+        assert!(config.get("unit_of_measurement").is_none());
+    }
+
+    #[test]
+    fn range_state() {
+        let range = IntegerRange {
+            min: 0,
+            max: 100,
+            precision: 1,
+        };
+        k9::assert_equal!(
+            range_state_value(&range, &serde_json::json!(42)),
+            Some(42.0)
+        );
+        k9::assert_equal!(range_state_value(&range, &serde_json::json!("")), None);
+        k9::assert_equal!(range_state_value(&range, &JsonValue::Null), None);
+
+        let range = IntegerRange {
+            min: 0,
+            max: 10,
+            precision: 10,
+        };
+        k9::assert_equal!(range_state_value(&range, &serde_json::json!(55)), Some(5.5));
+    }
+}
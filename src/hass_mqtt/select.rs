@@ -1,8 +1,12 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
 use crate::hass_mqtt::work_mode::ParsedWorkMode;
+use crate::platform_api::{DeviceCapability, DeviceParameters, EnumOption};
 use crate::service::device::Device as ServiceDevice;
-use crate::service::hass::{availability_topic, topic_safe_id, HassClient, IdParameter};
+use crate::service::hass::{
+    availability_topic, camel_case_to_space_separated, topic_safe_id, topic_safe_string,
+    HassClient, IdAndInst, IdParameter,
+};
 use crate::service::state::StateHandle;
 use anyhow::Context;
 use axum::async_trait;
@@ -108,7 +112,7 @@ pub struct SceneModeSelect {
 
 impl SceneModeSelect {
     pub async fn new(device: &ServiceDevice, state: &StateHandle) -> anyhow::Result<Option<Self>> {
-        let scenes = state.device_list_scenes(device).await?;
+        let scenes = state.device_list_main_scenes(device).await?;
         if scenes.is_empty() {
             return Ok(None);
         }
@@ -154,18 +158,215 @@ impl EntityInstance for SceneModeSelect {
             .expect("device to exist");
 
         if let Some(device_state) = device.device_state() {
-            client
-                .publish(
-                    &self.select.state_topic,
-                    device_state.scene.as_deref().unwrap_or(""),
-                )
-                .await?;
+            // DIY scenes and snapshots are reported by their own select
+            let scene = device_state
+                .scene
+                .as_deref()
+                .filter(|scene| self.select.options.iter().any(|opt| opt == scene))
+                .unwrap_or("");
+            client.publish(&self.select.state_topic, scene).await?;
         }
 
         Ok(())
     }
 }
 
+/// The scene capabilities that are presented by their own
+/// CapabilitySceneSelect, along with the name of that entity
+pub const DEDICATED_SCENE_INSTANCES: &[(&str, &str)] =
+    &[("diyScene", "DIY Scenes"), ("snapshot", "Snapshots")];
+
+/// Presents the scenes of a single scene capability, such as the
+/// user-created DIY scenes or the saved snapshots, separately from
+/// the built-in scenes
+pub struct CapabilitySceneSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl CapabilitySceneSelect {
+    pub async fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &str,
+        name: &str,
+    ) -> anyhow::Result<Option<Self>> {
+        let scenes = state
+            .device_list_scenes_for_instance(device, instance)
+            .await?;
+        if scenes.is_empty() {
+            return Ok(None);
+        }
+
+        // Scenes are activated by name, so we can share the
+        // command topic with the Mode/Scene select
+        let command_topic = format!("gv2mqtt/{id}/set-mode-scene", id = topic_safe_id(device));
+        let state_topic = format!(
+            "gv2mqtt/{id}/notify-scene/{inst}",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(instance)
+        );
+        let unique_id = format!(
+            "gv2mqtt-{id}-{inst}",
+            id = topic_safe_id(device),
+            inst = topic_safe_string(instance)
+        );
+
+        Ok(Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(name.to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: None,
+                },
+                command_topic,
+                state_topic,
+                options: scenes,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }))
+    }
+}
+
+#[async_trait]
+impl EntityInstance for CapabilitySceneSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        if let Some(device_state) = device.device_state() {
+            // Only report the active scene if it is one of ours
+            let scene = device_state
+                .scene
+                .as_deref()
+                .filter(|scene| self.select.options.iter().any(|opt| opt == scene))
+                .unwrap_or("");
+            client.publish(&self.select.state_topic, scene).await?;
+        }
+
+        Ok(())
+    }
+}
+
+/// Presents a Mode capability that adjusts a setting, such as
+/// the fan mode of a purifier, rather than selecting a scene
+pub struct CapabilityModeSelect {
+    select: SelectConfig,
+    device_id: String,
+    state: StateHandle,
+    instance_name: String,
+    options: Vec<EnumOption>,
+}
+
+impl CapabilityModeSelect {
+    /// Returns None if the capability doesn't have enum options
+    pub fn new(
+        device: &ServiceDevice,
+        state: &StateHandle,
+        instance: &DeviceCapability,
+    ) -> Option<Self> {
+        let Some(DeviceParameters::Enum { options }) = &instance.parameters else {
+            return None;
+        };
+
+        let id = topic_safe_id(device);
+        let inst = topic_safe_string(&instance.instance);
+
+        Some(Self {
+            select: SelectConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some(camel_case_to_space_separated(&instance.instance)),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: format!("gv2mqtt-{id}-{inst}-mode"),
+                    entity_category: None,
+                    icon: None,
+                },
+                command_topic: format!("gv2mqtt/{id}/set-mode/{inst}"),
+                state_topic: format!("gv2mqtt/{id}/notify-mode/{inst}"),
+                options: options.iter().map(|opt| opt.name.to_string()).collect(),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+            instance_name: instance.instance.to_string(),
+            options: options.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for CapabilityModeSelect {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.select.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let option = device
+            .get_state_capability_by_instance(&self.instance_name)
+            .and_then(|cap| cap.state.pointer("/value").cloned())
+            .and_then(|value| self.options.iter().find(|opt| opt.value == value));
+
+        match option {
+            Some(opt) => client.publish(&self.select.state_topic, &opt.name).await,
+            None => {
+                log::trace!(
+                    "CapabilityModeSelect::notify_state: no state for {device} {instance}",
+                    instance = self.instance_name
+                );
+                Ok(())
+            }
+        }
+    }
+}
+
+pub async fn mqtt_set_mode(
+    Payload(name): Payload<String>,
+    Params(IdAndInst { id, instance }): Params<IdAndInst>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("{instance} for {id}: {name}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let value = match device
+        .get_capability_by_instance(&instance)
+        .and_then(|cap| cap.parameters.as_ref())
+    {
+        Some(DeviceParameters::Enum { options }) => options
+            .iter()
+            .find(|opt| opt.name == name)
+            .and_then(|opt| opt.value.as_u64()),
+        _ => None,
+    }
+    .ok_or_else(|| anyhow::anyhow!("{name} is not a valid {instance} for {device}"))?;
+
+    state
+        .device_set_mode(&device, &instance, value as u32)
+        .await
+        .context("mqtt_set_mode: state.device_set_mode")
+}
+
 pub async fn mqtt_set_mode_scene(
     Payload(scene): Payload<String>,
     Params(IdParameter { id }): Params<IdParameter>,
@@ -180,3 +381,36 @@ pub async fn mqtt_set_mode_scene(
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::DeviceCapabilityKind;
+
+    #[test]
+    fn capability_mode_select() {
+        let device = ServiceDevice::new("H7126", "AA:BB:CC:DD:EE:FF:00:77");
+        let state = std::sync::Arc::new(crate::service::state::State::new());
+        let cap: DeviceCapability = serde_json::from_value(json!({
+            "type": "devices.capabilities.mode",
+            "instance": "fanMode",
+            "parameters": {
+                "dataType": "ENUM",
+                "options": [
+                    {"name": "Sleep", "value": 1},
+                    {"name": "Auto", "value": 2},
+                ]
+            }
+        }))
+        .unwrap();
+        k9::assert_equal!(cap.kind, DeviceCapabilityKind::Mode);
+
+        let select = CapabilityModeSelect::new(&device, &state, &cap).unwrap();
+        k9::assert_equal!(select.select.options, vec!["Sleep", "Auto"]);
+        k9::assert_equal!(select.select.base.name.as_deref(), Some("Fan Mode"));
+        k9::assert_equal!(
+            select.select.command_topic,
+            "gv2mqtt/AABBCCDDEEFF0077/set-mode/fanmode"
+        );
+    }
+}
@@ -1,8 +1,7 @@
-use crate::commands::serve::POLL_INTERVAL;
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::humidifier::DEVICE_CLASS_HUMIDITY;
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
-use crate::platform_api::DeviceCapability;
+use crate::platform_api::{DeviceCapability, DeviceParameters, HttpDeviceInfo};
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{availability_topic, topic_safe_id, topic_safe_string, HassClient};
 use crate::service::quirks::HumidityUnits;
@@ -92,12 +91,105 @@ impl GlobalFixedDiagnostic {
     }
 }
 
+/// How to present a numeric property, such as the power usage
+/// reported by a plug, or the tank level of a humidifier
+#[derive(Debug, PartialEq)]
+struct MeasuredProperty {
+    name: &'static str,
+    device_class: Option<&'static str>,
+    unit: &'static str,
+    state_class: StateClass,
+    icon: Option<&'static str>,
+}
+
+/// Returns true if the instance reports the water tank level of
+/// a humidifier (or similar), such as waterLevel or waterTankLevel
+pub fn is_water_tank_level(instance: &str) -> bool {
+    let inst = instance.to_ascii_lowercase();
+    inst.contains("waterlevel") || inst.contains("tanklevel")
+}
+
+/// Returns true if the instance reports the UV index, as is
+/// the case for some weather stations and outdoor sensors
+pub fn is_uv_index(instance: &str) -> bool {
+    instance.eq_ignore_ascii_case("uvIndex")
+}
+
+/// Many devices report the UV index multiplied by 10 in order to
+/// express a fractional index as an integer. The index itself
+/// rarely exceeds 11, so if the advertised range goes well beyond
+/// that, we assume that the value is scaled and divide it back down.
+fn uv_index_divisor(instance: &DeviceCapability) -> f64 {
+    match &instance.parameters {
+        Some(DeviceParameters::Integer { range, .. }) if range.max > 20 => 10.,
+        _ => 1.,
+    }
+}
+
+/// Returns true if the instance is one of the power draw or
+/// cumulative energy readings reported by smart outlets,
+/// such as powerConsumption or energyConsumption
+pub fn is_power_or_energy(instance: &str) -> bool {
+    matches!(
+        measured_property(instance).and_then(|m| m.device_class),
+        Some("power" | "energy")
+    )
+}
+
+fn measured_property(instance: &str) -> Option<MeasuredProperty> {
+    let inst = instance.to_ascii_lowercase();
+    if is_uv_index(instance) {
+        // hass has no UV index device class, and illuminance
+        // requires lx, so this is a plain measurement
+        return Some(MeasuredProperty {
+            name: "UV Index",
+            device_class: None,
+            unit: "UV index",
+            state_class: StateClass::Measurement,
+            icon: Some("mdi:sun-wireless"),
+        });
+    }
+    if is_water_tank_level(instance) {
+        return Some(MeasuredProperty {
+            name: "Water Level",
+            device_class: Some("moisture"),
+            unit: "%",
+            state_class: StateClass::Measurement,
+            icon: Some("mdi:water-percent"),
+        });
+    }
+
+    // Only the instance names that outlets are known to report are
+    // matched, so that an unrelated instance that happens to mention
+    // power or current isn't reported in the wrong units.
+    // Note that "powerConsumption" is an instantaneous power reading.
+    let (name, device_class, unit, state_class) = match inst.as_str() {
+        "powerconsumption" | "watts" | "wattage" => {
+            ("Power", "power", "W", StateClass::Measurement)
+        }
+        // Must be total_increasing for the energy dashboard
+        "energyconsumption" | "kwh" => ("Energy", "energy", "kWh", StateClass::TotalIncreasing),
+        "voltage" => ("Voltage", "voltage", "V", StateClass::Measurement),
+        "electriccurrent" => ("Current", "current", "A", StateClass::Measurement),
+        _ => return None,
+    };
+    Some(MeasuredProperty {
+        name,
+        device_class: Some(device_class),
+        unit,
+        state_class,
+        icon: None,
+    })
+}
+
 #[derive(Clone)]
 pub struct CapabilitySensor {
     sensor: SensorConfig,
     device_id: String,
     state: StateHandle,
     instance_name: String,
+    /// Measured values are divided by this before being reported
+    value_divisor: f64,
 }
 
 impl CapabilitySensor {
@@ -112,29 +204,53 @@ impl CapabilitySensor {
             inst = topic_safe_string(&instance.instance)
         );
 
+        let measured = measured_property(&instance.instance);
+
         let unit_of_measurement = match instance.instance.as_str() {
-            "sensorTemperature" => Some(state.get_temperature_scale().await.unit_of_measurement()),
+            "sensorTemperature" => Some(
+                state
+                    .temperature_scale_for_device(device)
+                    .await
+                    .unit_of_measurement(),
+            ),
             "sensorHumidity" => Some("%"),
-            _ => None,
+            _ => measured.as_ref().map(|m| m.unit),
         };
 
         let device_class = match instance.instance.as_str() {
             "sensorTemperature" => Some(DEVICE_CLASS_TEMPERATURE),
             "sensorHumidity" => Some(DEVICE_CLASS_HUMIDITY),
-            _ => None,
+            _ => measured.as_ref().and_then(|m| m.device_class),
         };
 
         let state_class = match instance.instance.as_str() {
             "sensorTemperature" => Some(StateClass::Measurement),
             "sensorHumidity" => Some(StateClass::Measurement),
-            _ => None,
+            _ => measured.as_ref().map(|m| m.state_class),
         };
 
         let name = match instance.instance.as_str() {
             "sensorTemperature" => "Temperature".to_string(),
             "sensorHumidity" => "Humidity".to_string(),
             "online" => "Connected to Govee Cloud".to_string(),
-            _ => instance.instance.to_string(),
+            _ => match &measured {
+                Some(m) => m.name.to_string(),
+                None => instance.instance.to_string(),
+            },
+        };
+
+        // Measurements are primary readings of the device, rather
+        // than diagnostics, so that they are shown on its card
+        let entity_category = match measured {
+            Some(_) => None,
+            None => Some("diagnostic".to_string()),
+        };
+        let icon = measured.and_then(|m| m.icon).map(|icon| icon.to_string());
+
+        let value_divisor = if is_uv_index(&instance.instance) {
+            uv_index_divisor(instance)
+        } else {
+            1.
         };
 
         Ok(Self {
@@ -142,12 +258,12 @@ impl CapabilitySensor {
                 base: EntityConfig {
                     availability_topic: availability_topic(),
                     name: Some(name),
-                    entity_category: Some("diagnostic".to_string()),
+                    entity_category,
                     origin: Origin::default(),
                     device: Device::for_device(device),
                     unique_id: unique_id.clone(),
                     device_class,
-                    icon: None,
+                    icon,
                 },
                 state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
                 state_class: state_class,
@@ -157,6 +273,7 @@ impl CapabilitySensor {
             device_id: device.id.to_string(),
             state: state.clone(),
             instance_name: instance.instance.to_string(),
+            value_divisor,
         })
     }
 }
@@ -191,7 +308,12 @@ impl EntityInstance for CapabilitySensor {
                     {
                         Some(v) => {
                             let value = v
-                                .as_unit(self.state.get_temperature_scale().await.into())
+                                .as_unit(
+                                    self.state
+                                        .temperature_scale_for_device(&device)
+                                        .await
+                                        .into(),
+                                )
                                 .value();
                             format!("{value:.2}")
                         }
@@ -212,6 +334,12 @@ impl EntityInstance for CapabilitySensor {
                         None => "".to_string(),
                     }
                 }
+                inst if measured_property(inst).is_some() => {
+                    match cap.state.pointer("/value").and_then(|v| v.as_f64()) {
+                        Some(v) => format!("{:.2}", v / self.value_divisor),
+                        None => "".to_string(),
+                    }
+                }
                 _ => cap.state.to_string(),
             };
 
@@ -225,6 +353,75 @@ impl EntityInstance for CapabilitySensor {
     }
 }
 
+/// Reports whether the most recent control request for a device
+/// succeeded, so that users can be alerted to a device that has
+/// stopped responding to commands
+pub struct ControlResultDiagnostic {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl ControlResultDiagnostic {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-gv2mqtt-last-control",
+            id = topic_safe_id(device)
+        );
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Last Control".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:remote".to_string()),
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: None,
+                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+                unit_of_measurement: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+#[async_trait]
+impl EntityInstance for ControlResultDiagnostic {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(result) = &device.last_control_result else {
+            return Ok(());
+        };
+
+        let summary = if result.error.is_some() {
+            "error"
+        } else {
+            "ok"
+        };
+        self.sensor.notify_state(client, summary).await?;
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client.publish_obj(topic, result).await?;
+        }
+        Ok(())
+    }
+}
+
 pub struct DeviceStatusDiagnostic {
     sensor: SensorConfig,
     device_id: String,
@@ -280,7 +477,7 @@ impl EntityInstance for DeviceStatusDiagnostic {
 
         let now = Utc::now();
 
-        let threshold = *POLL_INTERVAL + chrono::Duration::seconds(30);
+        let threshold = self.state.get_poll_interval().await + chrono::Duration::seconds(30);
 
         let summary = match &device_state {
             Some(state) => {
@@ -309,3 +506,264 @@ impl EntityInstance for DeviceStatusDiagnostic {
         Ok(())
     }
 }
+
+/// Lists the capabilities that the platform API reported for
+/// the device, so that they can be seen from within hass, which
+/// is helpful when reporting issues with unsupported devices
+#[derive(Clone)]
+pub struct CapabilitiesDiagnostic {
+    sensor: SensorConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl CapabilitiesDiagnostic {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Self {
+        let unique_id = format!(
+            "sensor-{id}-gv2mqtt-capabilities",
+            id = topic_safe_id(device)
+        );
+
+        Self {
+            sensor: SensorConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Capabilities".to_string()),
+                    entity_category: Some("diagnostic".to_string()),
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id: unique_id.clone(),
+                    device_class: None,
+                    icon: Some("mdi:format-list-bulleted".to_string()),
+                },
+                state_topic: format!("gv2mqtt/sensor/{unique_id}/state"),
+                state_class: None,
+                json_attributes_topic: Some(format!("gv2mqtt/sensor/{unique_id}/attributes")),
+                unit_of_measurement: None,
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        }
+    }
+}
+
+/// Summarizes the instance and kind of each capability
+fn capability_summary(info: &HttpDeviceInfo) -> serde_json::Value {
+    let capabilities: Vec<_> = info
+        .capabilities
+        .iter()
+        .map(|cap| json!({"instance": cap.instance, "kind": cap.kind}))
+        .collect();
+    json!({
+        "sku": info.sku,
+        "device_type": info.device_type,
+        "capabilities": capabilities,
+    })
+}
+
+#[async_trait]
+impl EntityInstance for CapabilitiesDiagnostic {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.sensor.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some(info) = &device.http_device_info else {
+            return Ok(());
+        };
+
+        self.sensor
+            .notify_state(client, &info.capabilities.len().to_string())
+            .await?;
+        if let Some(topic) = &self.sensor.json_attributes_topic {
+            client.publish_obj(topic, capability_summary(info)).await?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::{from_json, DeviceCapabilityKind, DeviceType};
+    use crate::service::state::State;
+    use std::sync::Arc;
+
+    #[tokio::test]
+    async fn plug_energy_sensors() {
+        let resp: serde_json::Value =
+            from_json(include_str!("../../test-data/list_devices_plug.json")).unwrap();
+        let info: HttpDeviceInfo = serde_json::from_value(resp["data"][0].clone()).unwrap();
+        k9::assert_equal!(info.device_type, DeviceType::Socket);
+
+        let state = Arc::new(State::new());
+        let device = ServiceDevice::new(&info.sku, &info.device);
+
+        let mut configs = vec![];
+        for cap in &info.capabilities {
+            if cap.kind != DeviceCapabilityKind::Property {
+                continue;
+            }
+            let sensor = CapabilitySensor::new(&device, &state, cap).await.unwrap();
+            let config = serde_json::to_value(&sensor.sensor).unwrap();
+            configs.push(json!({
+                "name": config["name"],
+                "device_class": config["device_class"],
+                "unit_of_measurement": config["unit_of_measurement"],
+                "state_class": config["state_class"],
+                "entity_category": config["entity_category"],
+            }));
+        }
+
+        k9::assert_equal!(
+            configs,
+            vec![
+                json!({
+                    "name": "Power",
+                    "device_class": "power",
+                    "unit_of_measurement": "W",
+                    "state_class": "measurement",
+                    "entity_category": null,
+                }),
+                json!({
+                    "name": "Energy",
+                    "device_class": "energy",
+                    "unit_of_measurement": "kWh",
+                    "state_class": "total_increasing",
+                    "entity_category": null,
+                }),
+                json!({
+                    "name": "Voltage",
+                    "device_class": "voltage",
+                    "unit_of_measurement": "V",
+                    "state_class": "measurement",
+                    "entity_category": null,
+                }),
+            ]
+        );
+    }
+
+    #[test]
+    fn measured_property_names() {
+        let m = measured_property("electricCurrent").unwrap();
+        k9::assert_equal!((m.device_class, m.unit), (Some("current"), "A"));
+
+        // Unknown instances are plain sensors, even when their
+        // names mention a measured property
+        for inst in ["currentTemperature", "powerSwitch", "energySaving"] {
+            assert!(measured_property(inst).is_none(), "{inst}");
+        }
+    }
+
+    #[tokio::test]
+    async fn humidifier_water_level() {
+        let cap: DeviceCapability = serde_json::from_value(json!({
+            "type": "devices.capabilities.property",
+            "instance": "waterLevel",
+        }))
+        .unwrap();
+        let state = Arc::new(State::new());
+        let device = ServiceDevice::new("H7143", "AA:BB:CC:DD:EE:FF:00:33");
+        let sensor = CapabilitySensor::new(&device, &state, &cap).await.unwrap();
+
+        k9::assert_equal!(sensor.sensor.base.name.as_deref(), Some("Water Level"));
+        k9::assert_equal!(sensor.sensor.base.device_class, Some("moisture"));
+        k9::assert_equal!(sensor.sensor.unit_of_measurement, Some("%"));
+        k9::assert_equal!(sensor.sensor.state_class, Some(StateClass::Measurement));
+        k9::assert_equal!(sensor.sensor.base.entity_category, None);
+    }
+
+    #[tokio::test]
+    async fn outlet_power_ranges() {
+        for inst in ["powerConsumption", "watts", "wattage"] {
+            let m = measured_property(inst).unwrap();
+            k9::assert_equal!((m.device_class, m.unit), (Some("power"), "W"), "{inst}");
+        }
+        for inst in ["energyConsumption", "kWh"] {
+            let m = measured_property(inst).unwrap();
+            k9::assert_equal!(
+                (m.device_class, m.state_class),
+                (Some("energy"), StateClass::TotalIncreasing),
+                "{inst}"
+            );
+        }
+        assert!(!is_power_or_energy("brightness"));
+        assert!(!is_power_or_energy("voltage"));
+
+        let cap: DeviceCapability = serde_json::from_value(json!({
+            "type": "devices.capabilities.range",
+            "instance": "wattage",
+            "parameters": {
+                "dataType": "INTEGER",
+                "range": {"min": 0, "max": 3600, "precision": 1}
+            }
+        }))
+        .unwrap();
+        assert!(is_power_or_energy(&cap.instance));
+        let state = Arc::new(State::new());
+        let device = ServiceDevice::new("H5086", "AA:BB:CC:DD:EE:FF:00:22");
+        let sensor = CapabilitySensor::new(&device, &state, &cap).await.unwrap();
+        k9::assert_equal!(sensor.sensor.base.name.as_deref(), Some("Power"));
+        k9::assert_equal!(sensor.sensor.unit_of_measurement, Some("W"));
+        k9::assert_equal!(sensor.sensor.state_class, Some(StateClass::Measurement));
+    }
+
+    #[test]
+    fn capabilities_diagnostic() {
+        let resp: serde_json::Value =
+            from_json(include_str!("../../test-data/list_devices_plug.json")).unwrap();
+        let info: HttpDeviceInfo = serde_json::from_value(resp["data"][0].clone()).unwrap();
+
+        k9::assert_equal!(
+            capability_summary(&info),
+            json!({
+                "sku": "H5086",
+                "device_type": "devices.types.socket",
+                "capabilities": [
+                    {"instance": "powerSwitch", "kind": "devices.capabilities.on_off"},
+                    {"instance": "powerConsumption", "kind": "devices.capabilities.property"},
+                    {"instance": "energyConsumption", "kind": "devices.capabilities.property"},
+                    {"instance": "voltage", "kind": "devices.capabilities.property"},
+                    {"instance": "online", "kind": "devices.capabilities.online"},
+                ],
+            })
+        );
+    }
+
+    #[tokio::test]
+    async fn uv_index() {
+        let cap = |max| -> DeviceCapability {
+            serde_json::from_value(json!({
+                "type": "devices.capabilities.range",
+                "instance": "uvIndex",
+                "parameters": {
+                    "dataType": "INTEGER",
+                    "range": {"min": 0, "max": max, "precision": 1}
+                }
+            }))
+            .unwrap()
+        };
+        let state = Arc::new(State::new());
+        let device = ServiceDevice::new("H5109", "AA:BB:CC:DD:EE:FF:00:44");
+
+        let sensor = CapabilitySensor::new(&device, &state, &cap(160))
+            .await
+            .unwrap();
+        k9::assert_equal!(sensor.sensor.base.name.as_deref(), Some("UV Index"));
+        k9::assert_equal!(sensor.sensor.base.device_class, None);
+        k9::assert_equal!(sensor.sensor.unit_of_measurement, Some("UV index"));
+        k9::assert_equal!(sensor.sensor.state_class, Some(StateClass::Measurement));
+        k9::assert_equal!(sensor.value_divisor, 10.);
+
+        let sensor = CapabilitySensor::new(&device, &state, &cap(16))
+            .await
+            .unwrap();
+        k9::assert_equal!(sensor.value_divisor, 1.);
+    }
+}
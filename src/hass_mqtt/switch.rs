@@ -1,15 +1,18 @@
 use crate::hass_mqtt::base::{Device, EntityConfig, Origin};
 use crate::hass_mqtt::instance::{publish_entity_config, EntityInstance};
+use crate::hass_mqtt::work_mode::ParsedWorkMode;
 use crate::platform_api::DeviceCapability;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::hass::{
     availability_topic, camel_case_to_space_separated, switch_instance_state_topic, topic_safe_id,
-    HassClient,
+    HassClient, IdParameter,
 };
 use crate::service::state::StateHandle;
+use anyhow::anyhow;
 use async_trait::async_trait;
+use mosquitto_rs::router::{Params, Payload, State};
 use serde::Serialize;
-use serde_json::json;
+use serde_json::{json, Value as JsonValue};
 
 #[derive(Serialize, Clone, Debug)]
 pub struct SwitchConfig {
@@ -58,6 +61,44 @@ impl SwitchConfig {
     }
 }
 
+/// Controls how the power of lights and plugs is presented to hass
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum PowerEntity {
+    /// Lights are shown as a light entity, and plugs as a switch
+    #[default]
+    Auto,
+    /// Lights and plugs are both shown as a light entity
+    Light,
+    /// Lights and plugs are both shown as a switch entity
+    Switch,
+}
+
+impl std::fmt::Display for PowerEntity {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Auto => fmt.write_str("auto"),
+            Self::Light => fmt.write_str("light"),
+            Self::Switch => fmt.write_str("switch"),
+        }
+    }
+}
+
+impl std::str::FromStr for PowerEntity {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("auto") {
+            Ok(Self::Auto)
+        } else if s.eq_ignore_ascii_case("light") {
+            Ok(Self::Light)
+        } else if s.eq_ignore_ascii_case("switch") {
+            Ok(Self::Switch)
+        } else {
+            anyhow::bail!("Unknown power entity {s}. Use auto, light or switch")
+        }
+    }
+}
+
 pub struct CapabilitySwitch {
     switch: SwitchConfig,
     device_id: String,
@@ -142,3 +183,110 @@ impl EntityInstance for CapabilitySwitch {
         Ok(())
     }
 }
+
+/// Returns the value of the keep-warm work mode of the device,
+/// if it has one, along with the work mode it is currently in
+fn keep_warm_mode(device: &ServiceDevice) -> Option<(JsonValue, Option<JsonValue>)> {
+    let work_modes = ParsedWorkMode::with_device(device).ok()?;
+    let keep_warm = work_modes.keep_warm_mode()?.value.clone();
+    let current = device
+        .get_state_capability_by_instance("workMode")
+        .and_then(|cap| cap.state.pointer("/value/workMode"))
+        .cloned();
+    Some((keep_warm, current))
+}
+
+/// Controls the keep-warm mode of a kettle separately from its
+/// power, so that automations can boil and then keep warm
+/// as distinct steps
+pub struct KeepWarmSwitch {
+    switch: SwitchConfig,
+    device_id: String,
+    state: StateHandle,
+}
+
+impl KeepWarmSwitch {
+    pub fn new(device: &ServiceDevice, state: &StateHandle) -> Option<Self> {
+        keep_warm_mode(device)?;
+
+        let unique_id = format!("gv2mqtt-{id}-keepWarm", id = topic_safe_id(device));
+        Some(Self {
+            switch: SwitchConfig {
+                base: EntityConfig {
+                    availability_topic: availability_topic(),
+                    name: Some("Keep Warm".to_string()),
+                    device_class: None,
+                    origin: Origin::default(),
+                    device: Device::for_device(device),
+                    unique_id,
+                    entity_category: None,
+                    icon: Some("mdi:kettle-steam".to_string()),
+                },
+                command_topic: format!("gv2mqtt/switch/{id}/keep-warm", id = topic_safe_id(device)),
+                state_topic: switch_instance_state_topic(device, "keepWarm"),
+            },
+            device_id: device.id.to_string(),
+            state: state.clone(),
+        })
+    }
+}
+
+#[async_trait]
+impl EntityInstance for KeepWarmSwitch {
+    async fn publish_config(&self, state: &StateHandle, client: &HassClient) -> anyhow::Result<()> {
+        self.switch.publish(state, client).await
+    }
+
+    async fn notify_state(&self, client: &HassClient) -> anyhow::Result<()> {
+        let device = self
+            .state
+            .device_by_id(&self.device_id)
+            .await
+            .expect("device to exist");
+
+        let Some((keep_warm, Some(current))) = keep_warm_mode(&device) else {
+            return Ok(());
+        };
+        let on = device.device_state().map(|s| s.on).unwrap_or(false) && current == keep_warm;
+        client
+            .publish(&self.switch.state_topic, if on { "ON" } else { "OFF" })
+            .await
+    }
+}
+
+pub async fn mqtt_keep_warm_command(
+    Payload(command): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("keep warm for {id}: {command}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    let on = match command.as_str() {
+        "ON" | "on" => true,
+        "OFF" | "off" => false,
+        _ => anyhow::bail!("invalid {command} for {id}"),
+    };
+
+    let work_modes = ParsedWorkMode::with_device(&device)?;
+    let keep_warm = work_modes
+        .keep_warm_mode()
+        .ok_or_else(|| anyhow!("{device} has no keep warm mode"))?;
+
+    if on {
+        let mode_num = keep_warm
+            .value
+            .as_i64()
+            .ok_or_else(|| anyhow!("expected workMode to be a number"))?;
+        state
+            .humidifier_set_parameter(&device, mode_num, keep_warm.default_value())
+            .await?;
+    } else if keep_warm_mode(&device).is_some_and(|(mode, current)| current == Some(mode)) {
+        // There is no mode that means "not keeping warm", so we stop
+        // keeping warm by turning the kettle off. If it is in some
+        // other mode, such as boiling, we leave it alone.
+        state.device_power_on(&device, false).await?;
+    }
+
+    Ok(())
+}
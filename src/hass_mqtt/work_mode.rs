@@ -110,6 +110,21 @@ impl ParsedWorkMode {
         self.modes.get(name)
     }
 
+    /// Returns the keep-warm mode of a kettle, if it has one.
+    /// The exact name varies, so we match it loosely; eg:
+    /// "Keep Warm", "keepWarm" or "Warm"
+    pub fn keep_warm_mode(&self) -> Option<&WorkMode> {
+        self.modes.values().find(|mode| {
+            let name: String = mode
+                .name
+                .chars()
+                .filter(|c| c.is_ascii_alphanumeric())
+                .collect::<String>()
+                .to_ascii_lowercase();
+            name == "warm" || name.contains("keepwarm")
+        })
+    }
+
     #[allow(unused)]
     pub fn mode_by_label(&self, name: &str) -> Option<&WorkMode> {
         for mode in self.modes.values() {
@@ -172,7 +187,7 @@ pub struct WorkModeValue {
 
 impl WorkMode {
     pub fn add_values(&mut self, opt: &EnumOption) {
-        self.default_value = opt.extras.get("defaultValue").cloned();
+        self.default_value = opt.extra_field("defaultValue").cloned();
 
         #[derive(Deserialize)]
         struct ModeRange {
@@ -180,7 +195,7 @@ impl WorkMode {
             max: i64,
         }
 
-        if let Some(range) = opt.extras.get("range") {
+        if let Some(range) = opt.extra_field("range") {
             if let Ok(range) = serde_json::from_value::<ModeRange>(range.clone()) {
                 self.value_range = Some(range.min..range.max + 1);
                 return;
@@ -193,7 +208,7 @@ impl WorkMode {
             value: JsonValue,
         }
 
-        let Some(options) = opt.extras.get("options") else {
+        let Some(options) = opt.extra_field("options") else {
             return;
         };
 
@@ -368,6 +383,21 @@ ParsedWorkMode {
         );
     }
 
+    #[test]
+    fn keep_warm_mode() {
+        let mut wm = ParsedWorkMode::default();
+        wm.add("Boiling".to_string(), 1.into());
+        wm.add("Tea".to_string(), 2.into());
+        assert!(wm.keep_warm_mode().is_none());
+
+        wm.add("Keep Warm".to_string(), 3.into());
+        k9::assert_equal!(wm.keep_warm_mode().unwrap().value, json!(3));
+
+        let mut wm = ParsedWorkMode::default();
+        wm.add("keepWarm".to_string(), 4.into());
+        k9::assert_equal!(wm.keep_warm_mode().unwrap().value, json!(4));
+    }
+
     #[test]
     fn test_work_mode_parser2() {
         let cap: DeviceCapability =
@@ -6,12 +6,13 @@ use anyhow::Context;
 use if_addrs::IfAddr;
 use serde::{Deserialize, Serialize};
 use serde_json::Value as JsonValue;
+use std::collections::{BTreeSet, HashMap, VecDeque};
 use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::net::UdpSocket;
 use tokio::sync::mpsc::{channel, Receiver, Sender};
-use tokio::sync::Mutex;
+use tokio::sync::{oneshot, Mutex};
 use tokio::time::Instant;
 
 // <https://app-h5.govee.com/user-manual/wlan-guide>
@@ -25,6 +26,14 @@ const LISTEN_PORT: u16 = 4002;
 const CMD_PORT: u16 = 4003;
 /// The multicast group of which govee LAN-API enabled devices are members
 const MULTICAST: IpAddr = IpAddr::V4(Ipv4Addr::new(239, 255, 255, 250));
+const DEFAULT_COMMAND_DELAY_MS: u64 = 50;
+/// How many commands may be pending for a single device
+const COMMAND_QUEUE_CAPACITY: usize = 8;
+/// How long the command queue worker for a device waits for
+/// another command before it shuts down
+const COMMAND_QUEUE_IDLE: Duration = Duration::from_secs(60);
+/// How long query_status waits for a device to respond
+pub const QUERY_STATUS_TIMEOUT: Duration = Duration::from_secs(10);
 
 #[derive(clap::Parser, Debug)]
 pub struct LanDiscoArguments {
@@ -50,10 +59,24 @@ pub struct LanDiscoArguments {
     #[arg(long, global = true)]
     pub scan: Vec<IpAddr>,
 
+    /// The name (eg: eth1) or IPv4 address of the network interface
+    /// from which to send discovery packets. Useful on hosts with
+    /// multiple interfaces, such as those with a dedicated IoT VLAN.
+    /// You may also set GOVEE_LAN_INTERFACE via the environment.
+    #[arg(long, global = true)]
+    pub lan_interface: Option<String>,
+
     /// How long to wait for discovery to complete, in seconds
     /// You may also set GOVEE_LAN_DISCO_TIMEOUT via the environment.
     #[arg(long, default_value_t = 3, global = true)]
     disco_timeout: u64,
+
+    /// How many milliseconds to wait between consecutive commands
+    /// sent to the same device.
+    /// You may also set GOVEE_LAN_COMMAND_DELAY_MS via the environment.
+    /// If unspecified, uses 50.
+    #[arg(long, global = true)]
+    lan_command_delay_ms: Option<u64>,
 }
 
 pub fn truthy(s: &str) -> anyhow::Result<bool> {
@@ -81,6 +104,8 @@ impl LanDiscoArguments {
             additional_addresses: self.scan.clone(),
             broadcast_all_interfaces: self.broadcast_all,
             global_broadcast: self.global_broadcast,
+            interface: None,
+            command_delay: Duration::from_millis(DEFAULT_COMMAND_DELAY_MS),
         };
 
         if let Some(v) = opt_env_var::<String>("GOVEE_LAN_NO_MULTICAST")? {
@@ -105,6 +130,24 @@ impl LanDiscoArguments {
             }
         }
 
+        let interface = match &self.lan_interface {
+            Some(iface) => Some(iface.to_string()),
+            None => opt_env_var("GOVEE_LAN_INTERFACE")?,
+        };
+        if let Some(iface) = interface {
+            let addr = resolve_interface(&iface)?;
+            log::info!("Using interface {iface} ({addr}) for LAN discovery");
+            options.interface.replace(addr);
+        }
+
+        let command_delay = match self.lan_command_delay_ms {
+            Some(ms) => Some(ms),
+            None => opt_env_var("GOVEE_LAN_COMMAND_DELAY_MS")?,
+        };
+        if let Some(ms) = command_delay {
+            options.command_delay = Duration::from_millis(ms);
+        }
+
         Ok(options)
     }
 
@@ -117,6 +160,37 @@ impl LanDiscoArguments {
     }
 }
 
+/// Resolve an interface name or address to the IPv4 address
+/// of that interface
+fn resolve_interface(iface: &str) -> anyhow::Result<Ipv4Addr> {
+    let iface = iface.trim();
+    if let Ok(addr) = iface.parse::<IpAddr>() {
+        return match addr {
+            IpAddr::V4(v4) => Ok(v4),
+            IpAddr::V6(_) => {
+                anyhow::bail!("LAN interface {iface} must be an IPv4 address")
+            }
+        };
+    }
+
+    let ifaces = if_addrs::get_if_addrs().context("get_if_addrs")?;
+    ifaces
+        .iter()
+        .filter(|i| i.name == iface)
+        .find_map(|i| match &i.addr {
+            IfAddr::V4(v4) => Some(v4.ip),
+            IfAddr::V6(_) => None,
+        })
+        .ok_or_else(|| {
+            let names: BTreeSet<&str> = ifaces.iter().map(|i| i.name.as_str()).collect();
+            anyhow::anyhow!(
+                "LAN interface {iface} was not found, or has no IPv4 address. \
+                 Available interfaces are: {}",
+                names.into_iter().collect::<Vec<_>>().join(", ")
+            )
+        })
+}
+
 pub struct DiscoOptions {
     /// Use the MULTICAST address defined in the LAN protocol
     pub enable_multicast: bool,
@@ -128,6 +202,12 @@ pub struct DiscoOptions {
     pub broadcast_all_interfaces: bool,
     /// Broadcast to the global broadcast address
     pub global_broadcast: bool,
+    /// Send discovery packets from the interface with this address,
+    /// rather than letting the routing table decide
+    pub interface: Option<Ipv4Addr>,
+    /// How long to wait between consecutive commands sent
+    /// to the same device
+    pub command_delay: Duration,
 }
 
 impl DiscoOptions {
@@ -146,6 +226,8 @@ impl Default for DiscoOptions {
             additional_addresses: vec![],
             broadcast_all_interfaces: false,
             global_broadcast: false,
+            interface: None,
+            command_delay: Duration::from_millis(DEFAULT_COMMAND_DELAY_MS),
         }
     }
 }
@@ -176,89 +258,211 @@ struct RequestMessage {
     msg: Request,
 }
 
-#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
-pub struct LanDevice {
-    pub ip: IpAddr,
-    pub device: String,
-    pub sku: String,
-    #[serde(rename = "bleVersionHard")]
-    pub ble_version_hard: String,
-    #[serde(rename = "bleVersionSoft")]
-    pub ble_version_soft: String,
-    #[serde(rename = "wifiVersionHard")]
-    pub wifi_version_hard: String,
-    #[serde(rename = "wifiVersionSoft")]
-    pub wifi_version_soft: String,
+/// A request that is waiting to be sent by the command queue
+/// worker for its device
+struct QueuedCommand {
+    ip: IpAddr,
+    request: Request,
+    reply: oneshot::Sender<anyhow::Result<()>>,
 }
 
-impl LanDevice {
-    pub async fn send_request(&self, msg: Request) -> anyhow::Result<()> {
-        log::trace!("LanDevice::send_request to {:?} {msg:?}", self.ip);
-        let client = udp_socket_for_target(self.ip).await?;
-        let data = serde_json::to_string(&RequestMessage { msg })?;
-        client.send_to(data.as_bytes(), (self.ip, CMD_PORT)).await?;
-
-        Ok(())
-    }
+/// The pending commands for a device. The commands are held here
+/// rather than in the channel so that a superseded command can be
+/// replaced while it waits; the channel just wakes up the worker.
+struct CommandQueue {
+    pending: VecDeque<QueuedCommand>,
+    wake: Sender<()>,
+}
 
-    pub async fn send_turn(&self, on: bool) -> anyhow::Result<()> {
-        self.send_request(Request::Turn {
-            value: if on { 1 } else { 0 },
-        })
-        .await
-    }
+/// Serializes the commands sent to each device, so that rapid
+/// commands don't interleave and get rejected by the firmware.
+/// Each device with pending commands has a worker task that sends
+/// them in order; the worker shuts down once the device has been
+/// idle for a while.
+#[derive(Clone)]
+struct CommandQueues {
+    queues: Arc<parking_lot::Mutex<HashMap<String, CommandQueue>>>,
+    /// How long to wait after sending a command to a device
+    /// before sending it the next one
+    delay: Duration,
+    idle: Duration,
+}
 
-    pub async fn send_brightness(&self, percent: u8) -> anyhow::Result<()> {
-        self.send_request(Request::Brightness { value: percent })
-            .await
+impl CommandQueues {
+    fn new(delay: Duration, idle: Duration) -> Self {
+        Self {
+            queues: Default::default(),
+            delay,
+            idle,
+        }
     }
 
-    pub async fn send_color_rgb(&self, color: DeviceColor) -> anyhow::Result<()> {
-        self.send_request(Request::Color {
-            color,
-            color_temperature_kelvin: 0,
-        })
-        .await
+    async fn send(&self, device: &str, ip: IpAddr, request: Request) -> anyhow::Result<()> {
+        self.enqueue(device, ip, request).await?
     }
 
-    pub async fn send_real(&self, commands: Vec<String>) -> anyhow::Result<()> {
-        self.send_request(Request::PtReal { command: commands })
-            .await
+    /// Adds a command to the queue for device, starting its worker
+    /// if necessary, and returns the receiver for its result.
+    /// This happens with the map locked, so that the worker can't
+    /// shut down between us finding the queue and adding to it.
+    fn enqueue(
+        &self,
+        device: &str,
+        ip: IpAddr,
+        request: Request,
+    ) -> oneshot::Receiver<anyhow::Result<()>> {
+        let (reply, rx) = oneshot::channel();
+        let mut queues = self.queues.lock();
+        let queue = queues.entry(device.to_string()).or_insert_with(|| {
+            let (wake, wake_rx) = channel(1);
+            tokio::spawn(self.clone().run(device.to_string(), wake_rx));
+            CommandQueue {
+                pending: Default::default(),
+                wake,
+            }
+        });
+        match enqueue_command(
+            &mut queue.pending,
+            QueuedCommand { ip, request, reply },
+            COMMAND_QUEUE_CAPACITY,
+        ) {
+            Enqueued::Added => {}
+            Enqueued::Replaced(displaced) => {
+                log::warn!(
+                    "LAN command queue for {device} is full; dropping {:?}",
+                    displaced.request
+                );
+                displaced
+                    .reply
+                    .send(Err(anyhow::anyhow!(
+                        "LAN command queue for {device} is full; \
+                         this command was replaced by a newer one"
+                    )))
+                    .ok();
+            }
+            Enqueued::Rejected(rejected) => {
+                log::warn!(
+                    "LAN command queue for {device} is full; rejecting {:?}",
+                    rejected.request
+                );
+                rejected
+                    .reply
+                    .send(Err(anyhow::anyhow!(
+                        "LAN command queue for {device} is full"
+                    )))
+                    .ok();
+                return rx;
+            }
+        }
+        // If the worker is already awake it will pick up our
+        // command, so a full channel is fine
+        queue.wake.try_send(()).ok();
+        rx
     }
 
-    pub async fn send_color_temperature_kelvin(
-        &self,
-        color_temperature_kelvin: u32,
-    ) -> anyhow::Result<()> {
-        self.send_request(Request::Color {
-            color: DeviceColor { r: 0, g: 0, b: 0 },
-            color_temperature_kelvin,
-        })
-        .await
+    fn pop(&self, device: &str) -> Option<QueuedCommand> {
+        self.queues.lock().get_mut(device)?.pending.pop_front()
     }
 
-    pub async fn set_scene_by_name(&self, scene_name: &str) -> anyhow::Result<()> {
-        for category in GoveeUndocumentedApi::get_scenes_for_device(&self.sku).await? {
-            for scene in category.scenes {
-                for effect in scene.light_effects {
-                    if scene.scene_name == scene_name && effect.scene_code != 0 {
-                        let encoded = Base64HexBytes::encode_for_sku(
-                            "Generic:Light",
-                            &SetSceneCode::new(effect.scene_code, effect.scence_param),
-                        )?
-                        .base64();
-                        log::info!(
-                            "sending scene packet {encoded:x?} for {scene_name}, code {}",
-                            effect.scene_code
-                        );
-                        return self.send_real(encoded).await;
+    async fn run(self, device: String, mut wake: Receiver<()>) {
+        loop {
+            match tokio::time::timeout(self.idle, wake.recv()).await {
+                Ok(Some(())) => {}
+                Ok(None) => break,
+                Err(_) => {
+                    let mut queues = self.queues.lock();
+                    if queues
+                        .get(&device)
+                        .map(|q| q.pending.is_empty())
+                        .unwrap_or(true)
+                    {
+                        log::trace!("LAN command queue for {device} is idle; stopping");
+                        queues.remove(&device);
+                        break;
                     }
                 }
             }
+
+            while let Some(cmd) = self.pop(&device) {
+                let result = send_request_now(cmd.ip, cmd.request).await;
+                if let Err(err) = &result {
+                    log::trace!("LAN command for {device}: {err:#}");
+                }
+                cmd.reply.send(result).ok();
+                tokio::time::sleep(self.delay).await;
+            }
         }
+    }
+}
+
+/// The outcome of enqueue_command
+enum Enqueued {
+    Added,
+    /// The queue was full, so this older command of the same kind
+    /// was removed to make room
+    Replaced(QueuedCommand),
+    /// The queue was full of commands of other kinds, so the new
+    /// command was not added; commands of a different kind are not
+    /// made to give way to it
+    Rejected(QueuedCommand),
+}
 
-        anyhow::bail!("unable to set scene {scene_name} for {}", self.device);
+/// Adds cmd to the queue. If the queue is full, the oldest pending
+/// command of the same kind as cmd is replaced, and if there is
+/// none, cmd is rejected
+fn enqueue_command(
+    queue: &mut VecDeque<QueuedCommand>,
+    cmd: QueuedCommand,
+    capacity: usize,
+) -> Enqueued {
+    if queue.len() < capacity {
+        queue.push_back(cmd);
+        return Enqueued::Added;
     }
+
+    let kind = std::mem::discriminant(&cmd.request);
+    match queue
+        .iter()
+        .position(|q| std::mem::discriminant(&q.request) == kind)
+        .and_then(|idx| queue.remove(idx))
+    {
+        Some(displaced) => {
+            queue.push_back(cmd);
+            Enqueued::Replaced(displaced)
+        }
+        None => Enqueued::Rejected(cmd),
+    }
+}
+
+async fn send_request_now(ip: IpAddr, msg: Request) -> anyhow::Result<()> {
+    let client = udp_socket_for_target(ip).await?;
+    let data = serde_json::to_string(&RequestMessage { msg })?;
+    client.send_to(data.as_bytes(), (ip, CMD_PORT)).await?;
+    Ok(())
+}
+
+#[derive(Serialize, Deserialize, Debug, Clone, Hash, Eq, PartialEq)]
+pub struct LanDevice {
+    pub ip: IpAddr,
+    pub device: String,
+    pub sku: String,
+    #[serde(rename = "bleVersionHard")]
+    pub ble_version_hard: String,
+    #[serde(rename = "bleVersionSoft")]
+    pub ble_version_soft: String,
+    #[serde(rename = "wifiVersionHard")]
+    pub wifi_version_hard: String,
+    #[serde(rename = "wifiVersionSoft")]
+    pub wifi_version_soft: String,
+}
+
+/// A response to a discovery scan
+#[derive(Debug, Clone)]
+pub struct LanDiscovery {
+    pub device: LanDevice,
+    /// True if the device is newly discovered or has changed its
+    /// address, false if the response repeats one that we already had
+    pub is_new: bool,
 }
 
 pub fn boolean_int<'de, D: serde::de::Deserializer<'de>>(
@@ -286,6 +490,115 @@ pub struct DeviceStatus {
     pub color_temperature_kelvin: u32,
 }
 
+/// Most LAN firmware uses kelvin for the colorTemInKelvin field of
+/// colorwc and devStatus, but some use a 1-100 warmth percentage,
+/// where 100 is the warmest, lowest kelvin value that the device supports.
+/// In either encoding, 0 means that the device is in rgb mode.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorTemperatureEncoding {
+    #[default]
+    Kelvin,
+    Warmth,
+}
+
+impl ColorTemperatureEncoding {
+    /// Infer the encoding from a colorTemInKelvin value reported by
+    /// the device. Kelvin values are always well above 100.
+    /// Returns None for 0, which means that the device is in rgb mode
+    /// and tells us nothing about the encoding.
+    pub fn detect(reported: u32) -> Option<Self> {
+        match reported {
+            0 => None,
+            1..=100 => Some(Self::Warmth),
+            _ => Some(Self::Kelvin),
+        }
+    }
+
+    /// Convert kelvin to the value to send to the device.
+    /// `range` is the (min, max) kelvin range of the device.
+    pub fn encode(self, kelvin: u32, (min, max): (u32, u32)) -> u32 {
+        match self {
+            Self::Kelvin => kelvin,
+            Self::Warmth => {
+                if max <= min {
+                    return 100;
+                }
+                let kelvin = kelvin.clamp(min, max);
+                let warmth = 1. + (max - kelvin) as f64 * 99. / (max - min) as f64;
+                warmth.round() as u32
+            }
+        }
+    }
+
+    /// Convert a value reported by the device to kelvin.
+    /// `range` is the (min, max) kelvin range of the device.
+    pub fn decode(self, value: u32, (min, max): (u32, u32)) -> u32 {
+        match self {
+            Self::Kelvin => value,
+            Self::Warmth if value == 0 => 0,
+            Self::Warmth => {
+                let warmth = value.min(100) as f64;
+                let kelvin = max as f64 - ((warmth - 1.) * max.saturating_sub(min) as f64 / 99.);
+                kelvin.round() as u32
+            }
+        }
+    }
+}
+
+/// How the integer colorRgb state value reported by the platform
+/// API is packed. Most devices report 0xRRGGBB, but some report
+/// HSV, with the hue in degrees in the upper 16 bits followed by the
+/// saturation and value percentages in the lower two bytes.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum ColorStateEncoding {
+    #[default]
+    Rgb,
+    Hsv,
+}
+
+impl std::fmt::Display for ColorStateEncoding {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Rgb => fmt.write_str("rgb"),
+            Self::Hsv => fmt.write_str("hsv"),
+        }
+    }
+}
+
+impl std::str::FromStr for ColorStateEncoding {
+    type Err = anyhow::Error;
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let s = s.trim();
+        if s.eq_ignore_ascii_case("rgb") {
+            Ok(Self::Rgb)
+        } else if s.eq_ignore_ascii_case("hsv") {
+            Ok(Self::Hsv)
+        } else {
+            anyhow::bail!("Unknown color encoding {s}. Use rgb or hsv")
+        }
+    }
+}
+
+impl ColorStateEncoding {
+    pub fn decode(self, value: u32) -> DeviceColor {
+        match self {
+            Self::Rgb => DeviceColor {
+                r: ((value >> 16) & 0xff) as u8,
+                g: ((value >> 8) & 0xff) as u8,
+                b: (value & 0xff) as u8,
+            },
+            Self::Hsv => {
+                let hue = (value >> 16) as f32;
+                let saturation = ((value >> 8) & 0xff).min(100) as f32 / 100.;
+                let hsv_value = (value & 0xff).min(100) as f32 / 100.;
+                let [r, g, b, _a] =
+                    csscolorparser::Color::from_hsva(hue, saturation, hsv_value, 1.).to_rgba8();
+                DeviceColor { r, g, b }
+            }
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone, Copy, Default, PartialEq, Eq)]
 pub struct DeviceColor {
     pub r: u8,
@@ -293,6 +606,72 @@ pub struct DeviceColor {
     pub b: u8,
 }
 
+impl DeviceColor {
+    pub fn is_white(&self) -> bool {
+        self.r == 255 && self.g == 255 && self.b == 255
+    }
+
+    /// Converts this sRGB color to CIE 1931 xy chromaticity
+    /// coordinates, using the D65 white point
+    pub fn to_xy(self) -> (f64, f64) {
+        fn linearize(c: u8) -> f64 {
+            let c = c as f64 / 255.;
+            if c <= 0.04045 {
+                c / 12.92
+            } else {
+                ((c + 0.055) / 1.055).powf(2.4)
+            }
+        }
+        let (r, g, b) = (linearize(self.r), linearize(self.g), linearize(self.b));
+
+        let x = r * 0.4124 + g * 0.3576 + b * 0.1805;
+        let y = r * 0.2126 + g * 0.7152 + b * 0.0722;
+        let z = r * 0.0193 + g * 0.1192 + b * 0.9505;
+        let sum = x + y + z;
+        if sum == 0. {
+            // Black has no chromaticity; report the white point
+            return (0.3127, 0.3290);
+        }
+        (x / sum, y / sum)
+    }
+
+    /// Converts CIE 1931 xy chromaticity coordinates to the
+    /// brightest sRGB color with that chromaticity. Colors outside
+    /// of the sRGB gamut are clamped to it.
+    pub fn from_xy(x: f64, y: f64) -> Self {
+        if y <= 0. {
+            return Self { r: 0, g: 0, b: 0 };
+        }
+        let big_y = 1.;
+        let big_x = big_y * x / y;
+        let big_z = big_y * (1. - x - y) / y;
+
+        let r = big_x * 3.2406 - big_y * 1.5372 - big_z * 0.4986;
+        let g = -big_x * 0.9689 + big_y * 1.8758 + big_z * 0.0415;
+        let b = big_x * 0.0557 - big_y * 0.2040 + big_z * 1.0570;
+
+        let (r, g, b) = (r.max(0.), g.max(0.), b.max(0.));
+        let max = r.max(g).max(b);
+        if max == 0. {
+            return Self { r: 0, g: 0, b: 0 };
+        }
+
+        fn encode(c: f64) -> u8 {
+            let c = if c <= 0.0031308 {
+                12.92 * c
+            } else {
+                1.055 * c.powf(1. / 2.4) - 0.055
+            };
+            (c * 255.).round().clamp(0., 255.) as u8
+        }
+        Self {
+            r: encode(r / max),
+            g: encode(g / max),
+            b: encode(b / max),
+        }
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(tag = "cmd", content = "data")]
 pub enum Response {
@@ -318,9 +697,41 @@ struct ClientListener {
     tx: Sender<Response>,
 }
 
-#[derive(Default)]
 struct ClientInner {
     mux: Mutex<Vec<ClientListener>>,
+    command_queues: CommandQueues,
+    /// The address at which each device was last discovered
+    discovered: parking_lot::Mutex<HashMap<String, IpAddr>>,
+}
+
+impl ClientInner {
+    fn new(command_delay: Duration) -> Self {
+        Self {
+            mux: Default::default(),
+            command_queues: CommandQueues::new(command_delay, COMMAND_QUEUE_IDLE),
+            discovered: Default::default(),
+        }
+    }
+
+    /// Devices answer every scan, and retries or multicast echoes
+    /// can deliver the same answer several times over, so most scan
+    /// responses repeat what we already know. Returns true if the
+    /// device is newly discovered or has changed its address.
+    fn is_new_discovery(&self, info: &LanDevice) -> bool {
+        match self.discovered.lock().insert(info.device.clone(), info.ip) {
+            None => true,
+            Some(prior) if prior == info.ip => false,
+            Some(prior) => {
+                log::warn!(
+                    "{} {}: LAN address changed from {prior} to {}",
+                    info.sku,
+                    info.device,
+                    info.ip
+                );
+                true
+            }
+        }
+    }
 }
 
 #[derive(Clone)]
@@ -342,13 +753,23 @@ async fn udp_socket_for_target(addr: IpAddr) -> std::io::Result<UdpSocket> {
 }
 
 impl Broadcaster {
-    pub async fn new(addr: IpAddr) -> std::io::Result<Self> {
-        let socket = udp_socket_for_target(addr).await?;
+    /// Create a broadcaster for `addr`. If `interface` is specified,
+    /// and `addr` is an IPv4 address, the packets are sent from the
+    /// interface with that address.
+    pub async fn new(addr: IpAddr, interface: Option<Ipv4Addr>) -> std::io::Result<Self> {
+        let interface = match addr {
+            IpAddr::V4(_) => interface,
+            IpAddr::V6(_) => None,
+        };
+        let socket = match interface {
+            Some(iface) => UdpSocket::bind((iface, 0)).await?,
+            None => udp_socket_for_target(addr).await?,
+        };
 
         if addr.is_multicast() {
             match addr {
                 IpAddr::V4(v4) => {
-                    socket.join_multicast_v4(v4, Ipv4Addr::UNSPECIFIED)?;
+                    socket.join_multicast_v4(v4, interface.unwrap_or(Ipv4Addr::UNSPECIFIED))?;
                     socket.set_multicast_loop_v4(false)?;
                 }
                 IpAddr::V6(v6) => {
@@ -386,6 +807,11 @@ async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
                     if iface.is_loopback() {
                         continue;
                     }
+                    if let Some(wanted) = options.interface {
+                        if iface.ip() != IpAddr::V4(wanted) {
+                            continue;
+                        }
+                    }
                     let bcast = match iface.addr {
                         IfAddr::V4(v4) => v4.broadcast.map(IpAddr::V4),
                         IfAddr::V6(v6) => v6.broadcast.map(IpAddr::V6),
@@ -404,7 +830,7 @@ async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
 
     let mut broadcasters = vec![];
     for addr in addresses {
-        match Broadcaster::new(addr).await {
+        match Broadcaster::new(addr, options.interface).await {
             Ok(b) => broadcasters.push(b),
             Err(err) => {
                 log::error!("{addr}: {err:#}");
@@ -427,10 +853,47 @@ async fn send_scan(options: &DiscoOptions) -> anyhow::Result<()> {
     Ok(())
 }
 
+async fn process_packet(
+    addr: SocketAddr,
+    data: &[u8],
+    inner: &Arc<ClientInner>,
+    tx: &Sender<LanDiscovery>,
+) -> anyhow::Result<()> {
+    log::trace!(
+        "process_packet: addr={addr:?} data={}",
+        String::from_utf8_lossy(data)
+    );
+
+    let response: ResponseWrapper =
+        from_json(data).with_context(|| format!("Parsing: {}", String::from_utf8_lossy(data)))?;
+
+    let mut mux = inner.mux.lock().await;
+    mux.retain(|l| !l.tx.is_closed());
+    for l in mux.iter() {
+        if l.addr == addr.ip() {
+            l.tx.send(response.msg.clone()).await.ok();
+        }
+    }
+
+    if let Response::Scan(device) = response.msg {
+        let is_new = inner.is_new_discovery(&device);
+        if !is_new {
+            log::trace!(
+                "{} {}: repeated discovery response",
+                device.sku,
+                device.device
+            );
+        }
+        tx.send(LanDiscovery { device, is_new }).await?;
+    }
+
+    Ok(())
+}
+
 async fn lan_disco(
     options: DiscoOptions,
     inner: Arc<ClientInner>,
-) -> anyhow::Result<Receiver<LanDevice>> {
+) -> anyhow::Result<Receiver<LanDiscovery>> {
     let listen = UdpSocket::bind(("0.0.0.0", LISTEN_PORT)).await.context(
         "Cannot bind to UDP Port 4002, which is required \
         for the Govee LAN API to function. Most likely cause is that you \
@@ -442,39 +905,10 @@ async fn lan_disco(
     )?;
     let (tx, rx) = channel(8);
 
-    async fn process_packet(
-        addr: SocketAddr,
-        data: &[u8],
-        inner: &Arc<ClientInner>,
-        tx: &Sender<LanDevice>,
-    ) -> anyhow::Result<()> {
-        log::trace!(
-            "process_packet: addr={addr:?} data={}",
-            String::from_utf8_lossy(data)
-        );
-
-        let response: ResponseWrapper = from_json(data)
-            .with_context(|| format!("Parsing: {}", String::from_utf8_lossy(data)))?;
-
-        let mut mux = inner.mux.lock().await;
-        mux.retain(|l| !l.tx.is_closed());
-        for l in mux.iter() {
-            if l.addr == addr.ip() {
-                l.tx.send(response.msg.clone()).await.ok();
-            }
-        }
-
-        if let Response::Scan(info) = response.msg {
-            tx.send(info).await?;
-        }
-
-        Ok(())
-    }
-
     async fn run_disco(
         options: &DiscoOptions,
         listen: UdpSocket,
-        tx: Sender<LanDevice>,
+        tx: Sender<LanDiscovery>,
         inner: Arc<ClientInner>,
     ) -> anyhow::Result<()> {
         send_scan(options).await?;
@@ -514,8 +948,8 @@ async fn lan_disco(
 }
 
 impl Client {
-    pub async fn new(options: DiscoOptions) -> anyhow::Result<(Self, Receiver<LanDevice>)> {
-        let inner = Arc::new(ClientInner::default());
+    pub async fn new(options: DiscoOptions) -> anyhow::Result<(Self, Receiver<LanDiscovery>)> {
+        let inner = Arc::new(ClientInner::new(options.command_delay));
         let rx = lan_disco(options, Arc::clone(&inner)).await?;
 
         Ok((Self { inner }, rx))
@@ -536,7 +970,7 @@ impl Client {
     pub async fn scan_ip(&self, addr: IpAddr) -> anyhow::Result<LanDevice> {
         let mut rx = self.add_listener(addr).await?;
 
-        let bcast = Broadcaster::new(addr).await?;
+        let bcast = Broadcaster::new(addr, None).await?;
         let scan = serde_json::to_string(&RequestMessage {
             msg: Request::Scan {
                 account_topic: AccountTopic::Reserve,
@@ -558,11 +992,22 @@ impl Client {
     }
 
     pub async fn query_status(&self, device: &LanDevice) -> anyhow::Result<DeviceStatus> {
+        self.query_status_with_timeout(device, QUERY_STATUS_TIMEOUT)
+            .await
+    }
+
+    /// Queries the status of the device, giving up if it hasn't
+    /// responded within timeout
+    pub async fn query_status_with_timeout(
+        &self,
+        device: &LanDevice,
+        timeout: Duration,
+    ) -> anyhow::Result<DeviceStatus> {
         let mut rx = self.add_listener(device.ip).await?;
-        let deadline = Instant::now() + Duration::from_secs(10);
+        let deadline = Instant::now() + timeout;
         while Instant::now() <= deadline {
             log::trace!("query status of {}", device.ip);
-            device.send_request(Request::DevStatus {}).await?;
+            self.send_request(device, Request::DevStatus {}).await?;
             match tokio::time::timeout(Duration::from_millis(350), rx.recv()).await {
                 Ok(Some(Response::DevStatus(status))) => {
                     return Ok(status);
@@ -575,4 +1020,419 @@ impl Client {
 
         anyhow::bail!("timed out waiting for status");
     }
+
+    pub async fn send_request(&self, device: &LanDevice, msg: Request) -> anyhow::Result<()> {
+        log::trace!("Client::send_request to {:?} {msg:?}", device.ip);
+        // Status queries don't change anything, so are allowed
+        if crate::is_dry_run() && !matches!(msg, Request::DevStatus {}) {
+            log::info!("dry-run: LAN send_request to {:?} {msg:?}", device.ip);
+            return Ok(());
+        }
+        self.inner
+            .command_queues
+            .send(&device.device, device.ip, msg)
+            .await
+    }
+
+    pub async fn send_turn(&self, device: &LanDevice, on: bool) -> anyhow::Result<()> {
+        self.send_request(
+            device,
+            Request::Turn {
+                value: if on { 1 } else { 0 },
+            },
+        )
+        .await
+    }
+
+    pub async fn send_brightness(&self, device: &LanDevice, percent: u8) -> anyhow::Result<()> {
+        self.send_request(device, Request::Brightness { value: percent })
+            .await
+    }
+
+    pub async fn send_color_rgb(
+        &self,
+        device: &LanDevice,
+        color: DeviceColor,
+    ) -> anyhow::Result<()> {
+        self.send_request(
+            device,
+            Request::Color {
+                color,
+                color_temperature_kelvin: 0,
+            },
+        )
+        .await
+    }
+
+    pub async fn send_real(&self, device: &LanDevice, commands: Vec<String>) -> anyhow::Result<()> {
+        self.send_request(device, Request::PtReal { command: commands })
+            .await
+    }
+
+    /// Sends a colorwc request; the color temperature value must
+    /// already be in the encoding expected by the device.
+    /// See ColorTemperatureEncoding.
+    pub async fn send_color_temperature_kelvin(
+        &self,
+        device: &LanDevice,
+        color_temperature_kelvin: u32,
+    ) -> anyhow::Result<()> {
+        self.send_request(
+            device,
+            Request::Color {
+                color: DeviceColor { r: 0, g: 0, b: 0 },
+                color_temperature_kelvin,
+            },
+        )
+        .await
+    }
+
+    pub async fn set_scene_by_name(
+        &self,
+        device: &LanDevice,
+        scene_name: &str,
+    ) -> anyhow::Result<()> {
+        for category in GoveeUndocumentedApi::get_scenes_for_device(&device.sku).await? {
+            for scene in category.scenes {
+                for effect in scene.light_effects {
+                    if scene.scene_name == scene_name && effect.scene_code != 0 {
+                        let encoded = Base64HexBytes::encode_for_sku(
+                            "Generic:Light",
+                            &SetSceneCode::new(effect.scene_code, effect.scence_param),
+                        )?
+                        .base64();
+                        log::info!(
+                            "sending scene packet {encoded:x?} for {scene_name}, code {}",
+                            effect.scene_code
+                        );
+                        return self.send_real(device, encoded).await;
+                    }
+                }
+            }
+        }
+
+        anyhow::bail!("unable to set scene {scene_name} for {}", device.device);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    const RANGE: (u32, u32) = (2000, 9000);
+
+    #[test]
+    fn color_temperature_detect() {
+        k9::assert_equal!(ColorTemperatureEncoding::detect(0), None);
+        k9::assert_equal!(
+            ColorTemperatureEncoding::detect(50),
+            Some(ColorTemperatureEncoding::Warmth)
+        );
+        k9::assert_equal!(
+            ColorTemperatureEncoding::detect(100),
+            Some(ColorTemperatureEncoding::Warmth)
+        );
+        k9::assert_equal!(
+            ColorTemperatureEncoding::detect(4000),
+            Some(ColorTemperatureEncoding::Kelvin)
+        );
+    }
+
+    #[test]
+    fn color_temperature_kelvin_encoding() {
+        let enc = ColorTemperatureEncoding::Kelvin;
+        k9::assert_equal!(enc.encode(4000, RANGE), 4000);
+        k9::assert_equal!(enc.decode(4000, RANGE), 4000);
+    }
+
+    #[test]
+    fn color_temperature_warmth_encoding() {
+        let enc = ColorTemperatureEncoding::Warmth;
+        k9::assert_equal!(enc.encode(2000, RANGE), 100);
+        k9::assert_equal!(enc.encode(9000, RANGE), 1);
+        k9::assert_equal!(enc.encode(5500, RANGE), 51);
+        // Out of range values are clamped
+        k9::assert_equal!(enc.encode(1000, RANGE), 100);
+
+        k9::assert_equal!(enc.decode(100, RANGE), 2000);
+        k9::assert_equal!(enc.decode(51, RANGE), 5465);
+        k9::assert_equal!(enc.decode(1, RANGE), 9000);
+        // 0 means rgb mode rather than the coolest temperature
+        k9::assert_equal!(enc.decode(0, RANGE), 0);
+    }
+
+    #[test]
+    fn color_state_rgb_encoding() {
+        let enc = ColorStateEncoding::Rgb;
+        k9::assert_equal!(
+            enc.decode(0xff8000),
+            DeviceColor {
+                r: 0xff,
+                g: 0x80,
+                b: 0
+            }
+        );
+        k9::assert_equal!(enc.decode(0), DeviceColor::default());
+    }
+
+    #[test]
+    fn color_state_hsv_encoding() {
+        let enc = ColorStateEncoding::Hsv;
+        let hsv = |h: u32, s: u32, v: u32| enc.decode((h << 16) | (s << 8) | v);
+        k9::assert_equal!(hsv(0, 100, 100), DeviceColor { r: 255, g: 0, b: 0 });
+        k9::assert_equal!(hsv(120, 100, 100), DeviceColor { r: 0, g: 255, b: 0 });
+        k9::assert_equal!(hsv(240, 100, 50), DeviceColor { r: 0, g: 0, b: 128 });
+        k9::assert_equal!(
+            hsv(0, 0, 100),
+            DeviceColor {
+                r: 255,
+                g: 255,
+                b: 255
+            }
+        );
+        k9::assert_equal!(hsv(0, 0, 0), DeviceColor::default());
+    }
+
+    #[test]
+    fn xy_color_conversion() {
+        let round = |(x, y): (f64, f64)| ((x * 1000.).round(), (y * 1000.).round());
+        // The sRGB primaries and white point
+        k9::assert_equal!(
+            round(DeviceColor { r: 255, g: 0, b: 0 }.to_xy()),
+            (640., 330.)
+        );
+        k9::assert_equal!(
+            round(DeviceColor { r: 0, g: 255, b: 0 }.to_xy()),
+            (300., 600.)
+        );
+        k9::assert_equal!(
+            round(DeviceColor { r: 0, g: 0, b: 255 }.to_xy()),
+            (150., 60.)
+        );
+        k9::assert_equal!(
+            round(
+                DeviceColor {
+                    r: 255,
+                    g: 255,
+                    b: 255
+                }
+                .to_xy()
+            ),
+            (313., 329.)
+        );
+
+        k9::assert_equal!(
+            DeviceColor::from_xy(0.64, 0.33),
+            DeviceColor { r: 255, g: 0, b: 0 }
+        );
+        k9::assert_equal!(
+            DeviceColor::from_xy(0.3127, 0.3290),
+            DeviceColor {
+                r: 255,
+                g: 255,
+                b: 255
+            }
+        );
+        // Out of gamut colors are clamped rather than wrapping around
+        k9::assert_equal!(DeviceColor::from_xy(0.7, 0.3).r, 255);
+        k9::assert_equal!(DeviceColor::from_xy(0.3, 0.), DeviceColor::default());
+
+        // Fully saturated colors survive a round trip
+        for color in [
+            DeviceColor {
+                r: 255,
+                g: 128,
+                b: 0,
+            },
+            DeviceColor {
+                r: 0,
+                g: 64,
+                b: 255,
+            },
+            DeviceColor {
+                r: 255,
+                g: 0,
+                b: 255,
+            },
+        ] {
+            let (x, y) = color.to_xy();
+            let back = DeviceColor::from_xy(x, y);
+            for (a, b) in [(color.r, back.r), (color.g, back.g), (color.b, back.b)] {
+                assert!(a.abs_diff(b) <= 1, "{color:?} -> {back:?}");
+            }
+        }
+    }
+
+    #[test]
+    fn command_queue_replaces_same_kind() {
+        let ip: IpAddr = Ipv4Addr::new(10, 0, 0, 5).into();
+        let cmd = |request| QueuedCommand {
+            ip,
+            request,
+            reply: oneshot::channel().0,
+        };
+        let describe = |queue: &VecDeque<QueuedCommand>| {
+            queue
+                .iter()
+                .map(|q| serde_json::to_string(&q.request).unwrap())
+                .collect::<Vec<_>>()
+        };
+
+        let mut queue = VecDeque::new();
+        assert!(matches!(
+            enqueue_command(&mut queue, cmd(Request::Turn { value: 1 }), 2),
+            Enqueued::Added
+        ));
+        assert!(matches!(
+            enqueue_command(&mut queue, cmd(Request::Brightness { value: 10 }), 2),
+            Enqueued::Added
+        ));
+
+        // The queue is full, so the pending brightness is replaced
+        let Enqueued::Replaced(displaced) =
+            enqueue_command(&mut queue, cmd(Request::Brightness { value: 20 }), 2)
+        else {
+            panic!("brightness was not replaced");
+        };
+        assert!(matches!(
+            displaced.request,
+            Request::Brightness { value: 10 }
+        ));
+        k9::assert_equal!(
+            describe(&queue),
+            vec![
+                r#"{"cmd":"turn","data":{"value":1}}"#,
+                r#"{"cmd":"brightness","data":{"value":20}}"#
+            ]
+        );
+    }
+
+    #[test]
+    fn command_queue_rejects_when_full_of_other_kinds() {
+        let ip: IpAddr = Ipv4Addr::new(10, 0, 0, 5).into();
+        let cmd = |request| QueuedCommand {
+            ip,
+            request,
+            reply: oneshot::channel().0,
+        };
+
+        let mut queue = VecDeque::new();
+        enqueue_command(&mut queue, cmd(Request::Brightness { value: 10 }), 2);
+        enqueue_command(&mut queue, cmd(Request::DevStatus {}), 2);
+
+        // Nothing of the same kind is pending, so the new command is
+        // rejected, rather than cancelling a command of another kind
+        let Enqueued::Rejected(rejected) =
+            enqueue_command(&mut queue, cmd(Request::Turn { value: 1 }), 2)
+        else {
+            panic!("turn was not rejected");
+        };
+        assert!(matches!(rejected.request, Request::Turn { value: 1 }));
+        k9::assert_equal!(queue.len(), 2);
+        assert!(matches!(
+            queue[0].request,
+            Request::Brightness { value: 10 }
+        ));
+        assert!(matches!(queue[1].request, Request::DevStatus {}));
+    }
+
+    #[tokio::test]
+    async fn command_queue_reports_replaced_commands() {
+        let ip: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let queues = CommandQueues::new(Duration::ZERO, COMMAND_QUEUE_IDLE);
+        // The worker doesn't get to run until we yield, so this
+        // overflows the queue and replaces the first command
+        let mut replies: Vec<_> = (0..=COMMAND_QUEUE_CAPACITY)
+            .map(|value| queues.enqueue("dev", ip, Request::Brightness { value: value as u8 }))
+            .collect();
+        let first = replies.remove(0).await.unwrap();
+        k9::assert_equal!(
+            first.unwrap_err().to_string(),
+            "LAN command queue for dev is full; this command was replaced by a newer one"
+        );
+        for reply in replies {
+            reply.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn command_queue_reports_rejected_commands() {
+        let ip: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let queues = CommandQueues::new(Duration::ZERO, COMMAND_QUEUE_IDLE);
+        // The worker doesn't get to run until we yield, so the queue
+        // fills up with brightness commands and the power command
+        // has nothing that it can replace
+        let replies: Vec<_> = (0..COMMAND_QUEUE_CAPACITY)
+            .map(|value| queues.enqueue("dev", ip, Request::Brightness { value: value as u8 }))
+            .collect();
+        let turn = queues.enqueue("dev", ip, Request::Turn { value: 1 });
+        k9::assert_equal!(
+            turn.await.unwrap().unwrap_err().to_string(),
+            "LAN command queue for dev is full"
+        );
+        for reply in replies {
+            reply.await.unwrap().unwrap();
+        }
+    }
+
+    #[tokio::test]
+    async fn command_queue_stops_when_idle() {
+        let ip: IpAddr = Ipv4Addr::LOCALHOST.into();
+        let queues = CommandQueues::new(Duration::ZERO, Duration::from_millis(50));
+        queues.send("dev", ip, Request::DevStatus {}).await.unwrap();
+        assert!(queues.queues.lock().contains_key("dev"));
+
+        tokio::time::sleep(Duration::from_millis(200)).await;
+        assert!(queues.queues.lock().is_empty());
+
+        // A new command starts a new worker
+        queues.send("dev", ip, Request::DevStatus {}).await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn discovery_dedup() {
+        let scan = |ip: &str| {
+            format!(
+                r#"{{"msg":{{"cmd":"scan","data":{{"ip":"{ip}",
+                "device":"AA:BB:CC:DD:EE:FF:42:2A","sku":"H6000",
+                "bleVersionHard":"1","bleVersionSoft":"1",
+                "wifiVersionHard":"1","wifiVersionSoft":"1"}}}}}}"#
+            )
+        };
+        let inner = Arc::new(ClientInner::new(Duration::ZERO));
+        let (tx, mut rx) = channel(8);
+        let from: SocketAddr = (Ipv4Addr::new(10, 0, 0, 5), CMD_PORT).into();
+
+        let recv = |rx: &mut Receiver<LanDiscovery>| {
+            let disco = rx.try_recv().unwrap();
+            (disco.device.ip.to_string(), disco.is_new)
+        };
+
+        // The same response twice, as from a multicast echo
+        for _ in 0..2 {
+            process_packet(from, scan("10.0.0.5").as_bytes(), &inner, &tx)
+                .await
+                .unwrap();
+        }
+        k9::assert_equal!(recv(&mut rx), ("10.0.0.5".to_string(), true));
+        k9::assert_equal!(recv(&mut rx), ("10.0.0.5".to_string(), false));
+
+        // The device moved to a new address, so is new again
+        let from: SocketAddr = (Ipv4Addr::new(10, 0, 0, 6), CMD_PORT).into();
+        process_packet(from, scan("10.0.0.6").as_bytes(), &inner, &tx)
+            .await
+            .unwrap();
+        k9::assert_equal!(recv(&mut rx), ("10.0.0.6".to_string(), true));
+        assert!(rx.try_recv().is_err());
+    }
+
+    #[test]
+    fn interface_resolution() {
+        k9::assert_equal!(
+            resolve_interface("10.0.0.5").unwrap(),
+            Ipv4Addr::new(10, 0, 0, 5)
+        );
+        assert!(resolve_interface("::1").is_err());
+        assert!(resolve_interface("no-such-interface0").is_err());
+    }
 }
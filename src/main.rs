@@ -3,11 +3,17 @@ use crate::platform_api::GoveeApiArguments;
 use crate::service::hass::HassArguments;
 use crate::undoc_api::UndocApiArguments;
 use clap::Parser;
+use std::path::PathBuf;
 use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 
 mod ble;
+#[cfg(feature = "ble")]
+mod bluetooth;
+mod brightness;
 mod cache;
 mod commands;
+mod config_file;
 mod hass_mqtt;
 mod lan_api;
 #[macro_use]
@@ -27,38 +33,108 @@ pub struct Args {
     lan_disco_args: LanDiscoArguments,
     #[command(flatten)]
     undoc_args: UndocApiArguments,
+    #[cfg(feature = "ble")]
+    #[command(flatten)]
+    ble_args: bluetooth::BleArguments,
     #[command(flatten)]
     hass_args: HassArguments,
 
+    /// The level of logging to produce for govee2mqtt itself, one of
+    /// error, warn, info, debug or trace. Overrides RUST_LOG for
+    /// govee2mqtt, while logging from the libraries that it uses is
+    /// limited to warn.
+    /// You may also set this via the GOVEE_LOG_LEVEL environment variable.
+    #[arg(long, global = true)]
+    log_level: Option<log::LevelFilter>,
+
+    /// Log the device control requests and mqtt messages that would
+    /// be sent, rather than sending them.
+    /// You may also set GOVEE_DRY_RUN=true via the environment.
+    #[arg(long, global = true)]
+    dry_run: bool,
+
+    /// Load settings from a TOML config file, or a JSON config file
+    /// if its name ends in .json. Its keys are the names
+    /// of the GOVEE_XXX environment variables, which take precedence
+    /// over the file; command line options take precedence over both.
+    /// You may also set this via the GOVEE_CONFIG environment variable.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+
     #[command(subcommand)]
     cmd: SubCommand,
 }
 
 #[derive(clap::Parser, Debug)]
 pub enum SubCommand {
+    #[cfg(feature = "ble")]
+    BleControl(commands::ble_control::BleControlCommand),
+    #[cfg(feature = "ble")]
+    BleDisco(commands::ble_disco::BleDiscoCommand),
+    Completions(commands::completions::CompletionsCommand),
     LanControl(commands::lan_control::LanControlCommand),
     LanDisco(commands::lan_disco::LanDiscoCommand),
     ListHttp(commands::list_http::ListHttpCommand),
     List(commands::list::ListCommand),
     HttpControl(commands::http_control::HttpControlCommand),
-    Serve(commands::serve::ServeCommand),
+    MqttTest(commands::mqtt_test::MqttTestCommand),
+    PurgeDiscovery(commands::purge_discovery::PurgeDiscoveryCommand),
+    SceneCheck(commands::scene_check::SceneCheckCommand),
+    Serve(Box<commands::serve::ServeCommand>),
     Undoc(commands::undoc::UndocCommand),
 }
 
 impl Args {
+    fn log_level(&self) -> anyhow::Result<Option<log::LevelFilter>> {
+        match self.log_level {
+            Some(level) => Ok(Some(level)),
+            None => opt_env_var("GOVEE_LOG_LEVEL"),
+        }
+    }
+
+    fn config(&self) -> anyhow::Result<Option<PathBuf>> {
+        match &self.config {
+            Some(path) => Ok(Some(path.clone())),
+            None => opt_env_var("GOVEE_CONFIG"),
+        }
+    }
+
+    fn dry_run(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_DRY_RUN")? {
+            return Ok(self.dry_run || crate::lan_api::truthy(&v)?);
+        }
+        Ok(self.dry_run)
+    }
+
     pub async fn run(&self) -> anyhow::Result<()> {
         match &self.cmd {
+            #[cfg(feature = "ble")]
+            SubCommand::BleControl(cmd) => cmd.run(self).await,
+            #[cfg(feature = "ble")]
+            SubCommand::BleDisco(cmd) => cmd.run(self).await,
+            SubCommand::Completions(cmd) => cmd.run(self).await,
             SubCommand::LanControl(cmd) => cmd.run(self).await,
             SubCommand::LanDisco(cmd) => cmd.run(self).await,
             SubCommand::ListHttp(cmd) => cmd.run(self).await,
             SubCommand::HttpControl(cmd) => cmd.run(self).await,
             SubCommand::List(cmd) => cmd.run(self).await,
+            SubCommand::MqttTest(cmd) => cmd.run(self).await,
+            SubCommand::PurgeDiscovery(cmd) => cmd.run(self).await,
+            SubCommand::SceneCheck(cmd) => cmd.run(self).await,
             SubCommand::Serve(cmd) => cmd.run(self).await,
             SubCommand::Undoc(cmd) => cmd.run(self).await,
         }
     }
 }
 
+static DRY_RUN: AtomicBool = AtomicBool::new(false);
+
+/// Returns true if state-changing operations should be logged
+/// rather than performed
+pub fn is_dry_run() -> bool {
+    DRY_RUN.load(Ordering::Relaxed)
+}
+
 pub fn opt_env_var<T: FromStr>(name: &str) -> anyhow::Result<Option<T>>
 where
     <T as FromStr>::Err: std::fmt::Display,
@@ -74,7 +150,7 @@ where
     }
 }
 
-fn setup_logger() {
+fn setup_logger(level: Option<log::LevelFilter>) {
     fn resolve_timezone() -> chrono_tz::Tz {
         std::env::var("TZ")
             .or_else(|_| iana_time_zone::get_timezone())
@@ -86,7 +162,8 @@ fn setup_logger() {
     let tz = resolve_timezone();
     let utc_suffix = if tz == chrono_tz::UTC { "Z" } else { "" };
 
-    env_logger::builder()
+    let mut builder = env_logger::builder();
+    builder
         // A bit of boilerplate here to get timestamps printed in local time.
         // <https://github.com/rust-cli/env_logger/issues/158>
         .format(move |buf, record| {
@@ -106,19 +183,49 @@ fn setup_logger() {
             writeln!(buf, "] {}", record.args())
         })
         .filter_level(log::LevelFilter::Info)
-        .parse_env("RUST_LOG")
-        .init();
+        .parse_env("RUST_LOG");
+
+    if let Some(level) = level {
+        builder
+            .filter_level(level.min(log::LevelFilter::Warn))
+            .filter_module(env!("CARGO_CRATE_NAME"), level);
+    }
+
+    builder.init();
 }
 
-#[tokio::main(worker_threads = 2)]
-async fn main() -> anyhow::Result<()> {
+fn main() -> anyhow::Result<()> {
     color_backtrace::install();
     if let Ok(path) = dotenvy::dotenv() {
         eprintln!("Loading environment overrides from {path:?}");
     }
 
-    setup_logger();
-
     let args = Args::parse();
-    args.run().await
+    // Load this before anything else consults the environment.
+    // It modifies the environment, which is only safe to do before
+    // the runtime has started its threads, so the runtime is built
+    // by hand below rather than via #[tokio::main]
+    if let Some(path) = args.config()? {
+        let count = config_file::load_config_file(&path)?;
+        eprintln!("Loaded {count} settings from {path:?}");
+    }
+    setup_logger(args.log_level()?);
+
+    if args.dry_run()? {
+        log::info!("Dry run: devices will not be controlled and nothing will be published");
+        DRY_RUN.store(true, Ordering::Relaxed);
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .worker_threads(2)
+        .enable_all()
+        .build()?
+        .block_on(args.run())
+}
+
+#[cfg(test)]
+#[test]
+fn verify_cli() {
+    use clap::CommandFactory;
+    Args::command().debug_assert();
 }
@@ -1,14 +1,16 @@
-use crate::cache::{cache_get, CacheComputeResult, CacheGetOptions};
+use crate::cache::{cache_get, invalidate_key, CacheComputeResult, CacheGetOptions};
 use crate::hass_mqtt::climate::parse_temperature_constraints;
+use crate::lan_api::truthy;
 use crate::opt_env_var;
 use crate::service::state::sort_and_dedup_scenes;
-use crate::temperature::{TemperatureUnits, TemperatureValue};
-use crate::undoc_api::GoveeUndocumentedApi;
+use crate::temperature::{TemperatureScale, TemperatureUnits, TemperatureValue};
+use crate::undoc_api::{GoveeUndocumentedApi, Redacted};
 use anyhow::Context;
+use async_trait::async_trait;
 use reqwest::Method;
 use serde::{Deserialize, Deserializer, Serialize, Serializer};
 use serde_json::{json, Value as JsonValue};
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
 use std::time::Duration;
 use thiserror::Error;
 
@@ -22,10 +24,18 @@ use thiserror::Error;
 const SERVER: &str = "https://openapi.api.govee.com";
 pub const ONE_WEEK: Duration = Duration::from_secs(86400 * 7);
 pub const FIVE_MINUTES: Duration = Duration::from_secs(5 * 60);
-
-fn endpoint(url: &str) -> String {
-    format!("{SERVER}{url}")
-}
+/// A safety limit on the number of pages of devices that we fetch
+const MAX_DEVICE_LIST_PAGES: u32 = 20;
+/// How many times a rate limited, or otherwise transiently
+/// failing, request will be retried
+const MAX_RATE_LIMIT_RETRIES: u32 = 3;
+/// Upper bound on how long we'll wait before retrying
+/// a rate limited request
+const MAX_RATE_LIMIT_DELAY: Duration = Duration::from_secs(60);
+/// How many times to send a control request whose response
+/// indicates that it didn't take effect
+const MAX_CONTROL_ATTEMPTS: u32 = 2;
+const DEFAULT_API_TIMEOUT_SECS: u64 = 60;
 
 #[derive(clap::Parser, Debug)]
 pub struct GoveeApiArguments {
@@ -33,6 +43,35 @@ pub struct GoveeApiArguments {
     /// the GOVEE_API_KEY environment variable.
     #[arg(long, global = true)]
     pub api_key: Option<String>,
+
+    /// Log the method, url, request and response bodies of calls
+    /// made to the Govee Platform API. The API key is redacted.
+    /// You may also set GOVEE_VERBOSE_API=true via the environment.
+    #[arg(long, global = true)]
+    pub verbose_api: bool,
+
+    /// How many seconds to allow for each request made to the Govee
+    /// Platform API, including the time to connect and to read the
+    /// response.
+    /// You may also set this via the GOVEE_API_TIMEOUT_SECS environment
+    /// variable. If unspecified, uses 60.
+    #[arg(long, global = true)]
+    pub api_timeout_secs: Option<u64>,
+
+    /// How many seconds to allow for establishing the connection to
+    /// the Govee Platform API.
+    /// You may also set this via the GOVEE_API_CONNECT_TIMEOUT_SECS
+    /// environment variable. If unspecified, only --api-timeout-secs
+    /// applies.
+    #[arg(long, global = true)]
+    pub api_connect_timeout_secs: Option<u64>,
+
+    /// The base URL of the Govee Platform API. Useful for testing
+    /// against a local fake of the API.
+    /// You may also set this via the GOVEE_API_BASE_URL environment
+    /// variable. If unspecified, uses https://openapi.api.govee.com
+    #[arg(long, global = true)]
+    pub api_base_url: Option<String>,
 }
 
 impl GoveeApiArguments {
@@ -52,20 +91,106 @@ impl GoveeApiArguments {
         })
     }
 
+    pub fn verbose_api(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_VERBOSE_API")? {
+            return Ok(self.verbose_api || truthy(&v)?);
+        }
+        Ok(self.verbose_api)
+    }
+
+    pub fn api_timeout(&self) -> anyhow::Result<Duration> {
+        let secs = match self.api_timeout_secs {
+            Some(secs) => secs,
+            None => opt_env_var("GOVEE_API_TIMEOUT_SECS")?.unwrap_or(DEFAULT_API_TIMEOUT_SECS),
+        };
+        Ok(Duration::from_secs(secs))
+    }
+
+    pub fn api_connect_timeout(&self) -> anyhow::Result<Option<Duration>> {
+        let secs = match self.api_connect_timeout_secs {
+            Some(secs) => Some(secs),
+            None => opt_env_var("GOVEE_API_CONNECT_TIMEOUT_SECS")?,
+        };
+        Ok(secs.map(Duration::from_secs))
+    }
+
+    pub fn api_base_url(&self) -> anyhow::Result<String> {
+        let url = match &self.api_base_url {
+            Some(url) => url.to_string(),
+            None => opt_env_var("GOVEE_API_BASE_URL")?.unwrap_or_else(|| SERVER.to_string()),
+        };
+        reqwest::Url::parse(&url).with_context(|| format!("Invalid API base URL {url}"))?;
+        Ok(url)
+    }
+
     pub fn api_client(&self) -> anyhow::Result<GoveeApiClient> {
         let key = self.api_key()?;
-        Ok(GoveeApiClient::new(key))
+        Ok(GoveeApiClient::new(key)
+            .with_base_url(self.api_base_url()?)
+            .with_verbose_logging(self.verbose_api()?)
+            .with_dry_run(crate::is_dry_run())
+            .with_timeouts(self.api_timeout()?, self.api_connect_timeout()?))
     }
 }
 
 #[derive(Clone)]
 pub struct GoveeApiClient {
     key: String,
+    server: String,
+    verbose: bool,
+    dry_run: bool,
+    timeout: Duration,
+    connect_timeout: Option<Duration>,
 }
 
 impl GoveeApiClient {
     pub fn new<K: Into<String>>(key: K) -> Self {
-        Self { key: key.into() }
+        Self {
+            key: key.into(),
+            server: SERVER.to_string(),
+            verbose: false,
+            dry_run: false,
+            timeout: Duration::from_secs(DEFAULT_API_TIMEOUT_SECS),
+            connect_timeout: None,
+        }
+    }
+
+    /// Enables logging of request and response bodies
+    pub fn with_verbose_logging(mut self, verbose: bool) -> Self {
+        self.verbose = verbose;
+        self
+    }
+
+    /// Log control requests rather than sending them
+    pub fn with_dry_run(mut self, dry_run: bool) -> Self {
+        self.dry_run = dry_run;
+        self
+    }
+
+    /// Sets the overall timeout for each request, and optionally
+    /// a separate timeout for establishing the connection
+    pub fn with_timeouts(mut self, timeout: Duration, connect_timeout: Option<Duration>) -> Self {
+        self.timeout = timeout;
+        self.connect_timeout = connect_timeout;
+        self
+    }
+
+    /// Talk to an alternative server rather than the Govee Platform API
+    pub fn with_base_url<S: Into<String>>(mut self, server: S) -> Self {
+        let server: String = server.into();
+        self.server = server.trim_end_matches('/').to_string();
+        self
+    }
+
+    /// Create a client that talks to an alternative server,
+    /// such as a mock server used for testing
+    #[cfg(test)]
+    pub fn with_server<K: Into<String>, S: Into<String>>(key: K, server: S) -> Self {
+        Self::new(key).with_base_url(server)
+    }
+
+    fn endpoint(&self, url: &str) -> String {
+        format!("{}{url}", self.server)
     }
 
     pub async fn get_devices(&self) -> anyhow::Result<Vec<HttpDeviceInfo>> {
@@ -78,15 +203,36 @@ impl GoveeApiClient {
                 negative_ttl: Duration::from_secs(60),
                 allow_stale: true,
             },
-            async {
-                let url = endpoint("/router/api/v1/user/devices");
-                let resp: GetDevicesResponse = self.get_request_with_json_response(url).await?;
-                Ok(CacheComputeResult::Value(resp.data))
-            },
+            async { Ok(CacheComputeResult::Value(self.get_device_pages().await?)) },
         )
         .await
     }
 
+    /// Fetches the device list, following the pagination if the
+    /// response indicates that there is more than one page
+    async fn get_device_pages(&self) -> anyhow::Result<Vec<HttpDeviceInfo>> {
+        let url = self.endpoint("/router/api/v1/user/devices");
+        let resp: GetDevicesResponse = self.get_request_with_json_response(&url).await?;
+        let mut devices = resp.data;
+
+        let total_pages = resp.total_page.unwrap_or(1);
+        if total_pages > MAX_DEVICE_LIST_PAGES {
+            log::warn!(
+                "Device list has {total_pages} pages, only fetching the first \
+                 {MAX_DEVICE_LIST_PAGES}"
+            );
+        }
+        for page in 2..=total_pages.min(MAX_DEVICE_LIST_PAGES) {
+            let resp: GetDevicesResponse = self
+                .get_request_with_json_response(format!("{url}?page={page}"))
+                .await
+                .with_context(|| format!("fetching page {page} of {total_pages}"))?;
+            devices.extend(resp.data);
+        }
+
+        Ok(devices)
+    }
+
     pub async fn get_device_by_id<I: AsRef<str>>(&self, id: I) -> anyhow::Result<HttpDeviceInfo> {
         let id = id.as_ref();
         let devices = self.get_devices().await?;
@@ -104,7 +250,21 @@ impl GoveeApiClient {
         capability: &DeviceCapability,
         value: V,
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
-        let url = endpoint("/router/api/v1/device/control");
+        let value = value.into();
+        if self.dry_run {
+            log::info!(
+                "dry-run: control_device {device} instance={instance} value={value}",
+                instance = capability.instance
+            );
+            return Ok(ControlDeviceResponseCapability {
+                kind: capability.kind.clone(),
+                instance: capability.instance.to_string(),
+                value,
+                state: json!({"status": "success"}),
+            });
+        }
+
+        let url = self.endpoint("/router/api/v1/device/control");
         let request = ControlDeviceRequest {
             request_id: "uuid".to_string(),
             payload: ControlDevicePayload {
@@ -113,25 +273,57 @@ impl GoveeApiClient {
                 capability: ControlDeviceCapability {
                     kind: capability.kind.clone(),
                     instance: capability.instance.to_string(),
-                    value: value.into(),
+                    value,
                 },
             },
         };
 
-        let resp: ControlDeviceResponse = self
-            .request_with_json_response(Method::POST, url, &request)
-            .await?;
+        let mut attempt = 1;
+        loop {
+            let (resp, retries): (ControlDeviceResponse, u32) = self
+                .request_with_json_response_and_retries(Method::POST, &url, &request)
+                .await
+                .with_context(|| {
+                    format!(
+                        "control_device {device} instance={instance}",
+                        instance = capability.instance
+                    )
+                })?;
+
+            log::info!("control_device result: {resp:?}");
+
+            // Govee may accept the request but report that it didn't
+            // take effect; callers use the returned capability to
+            // report the state, so it is returned either way
+            let value = &request.payload.capability.value;
+            if !resp.capability.diverges_from(value) {
+                return Ok(resp.capability);
+            }
 
-        log::info!("control_device result: {resp:?}");
+            log::warn!(
+                "control_device {device} instance={instance}: \
+                 requested {value} but the response reported value={resp_value} \
+                 state={resp_state} (attempt {attempt} of {MAX_CONTROL_ATTEMPTS})",
+                instance = capability.instance,
+                resp_value = resp.capability.value,
+                resp_state = resp.capability.state,
+            );
 
-        Ok(resp.capability)
+            // If the HTTP layer had to retry, the service is already
+            // struggling; don't add to its load by sending it again
+            if attempt >= MAX_CONTROL_ATTEMPTS || retries > 0 {
+                return Ok(resp.capability);
+            }
+            attempt += 1;
+            tokio::time::sleep(control_resend_delay()).await;
+        }
     }
 
     pub async fn get_device_state(
         &self,
         device: &HttpDeviceInfo,
     ) -> anyhow::Result<HttpDeviceState> {
-        let url = endpoint("/router/api/v1/device/state");
+        let url = self.endpoint("/router/api/v1/device/state");
         let request = GetDeviceStateRequest {
             request_id: "uuid".to_string(),
             payload: GetDeviceStateRequestPayload {
@@ -142,11 +334,29 @@ impl GoveeApiClient {
 
         let resp: GetDeviceStateResponse = self
             .request_with_json_response(Method::POST, url, &request)
-            .await?;
+            .await
+            .with_context(|| format!("get_device_state {device}"))?;
 
         Ok(resp.payload)
     }
 
+    /// Asks Govee to POST device state changes to callback_url
+    pub async fn register_webhook(&self, callback_url: &str) -> anyhow::Result<()> {
+        let url = self.endpoint("/router/api/v1/user/webhooks");
+        let request = RegisterWebhookRequest {
+            request_id: "uuid".to_string(),
+            payload: RegisterWebhookPayload {
+                url: callback_url.to_string(),
+            },
+        };
+
+        let _: JsonValue = self
+            .request_with_json_response(Method::POST, url, &request)
+            .await
+            .with_context(|| format!("register_webhook url={callback_url}"))?;
+        Ok(())
+    }
+
     pub async fn get_device_diy_scenes(
         &self,
         device: &HttpDeviceInfo,
@@ -155,7 +365,7 @@ impl GoveeApiClient {
             return Ok(vec![]);
         }
 
-        let key = format!("scene-list-diy-{}-{}", device.sku, device.device);
+        let key = diy_scene_list_cache_key(device);
         cache_get(
             CacheGetOptions {
                 topic: "http-api",
@@ -166,7 +376,7 @@ impl GoveeApiClient {
                 allow_stale: true,
             },
             async {
-                let url = endpoint("/router/api/v1/device/diy-scenes");
+                let url = self.endpoint("/router/api/v1/device/diy-scenes");
                 let request = GetDeviceScenesRequest {
                     request_id: "uuid".to_string(),
                     payload: GetDeviceScenesPayload {
@@ -177,7 +387,8 @@ impl GoveeApiClient {
 
                 let resp: GetDeviceScenesResponse = self
                     .request_with_json_response(Method::POST, url, &request)
-                    .await?;
+                    .await
+                    .with_context(|| format!("get_device_diy_scenes {device}"))?;
 
                 Ok(CacheComputeResult::Value(resp.payload.capabilities))
             },
@@ -204,7 +415,7 @@ impl GoveeApiClient {
                 allow_stale: true,
             },
             async {
-                let url = endpoint("/router/api/v1/device/scenes");
+                let url = self.endpoint("/router/api/v1/device/scenes");
                 let request = GetDeviceScenesRequest {
                     request_id: "uuid".to_string(),
                     payload: GetDeviceScenesPayload {
@@ -215,7 +426,8 @@ impl GoveeApiClient {
 
                 let resp: GetDeviceScenesResponse = self
                     .request_with_json_response(Method::POST, url, &request)
-                    .await?;
+                    .await
+                    .with_context(|| format!("get_device_scenes {device}"))?;
 
                 Ok(CacheComputeResult::Value(resp.payload.capabilities))
             },
@@ -223,6 +435,14 @@ impl GoveeApiClient {
         .await
     }
 
+    /// Discards the cached DIY scene list for the device, so that the
+    /// next request fetches it from the API again. Use this when the
+    /// list is known to have changed, such as when a scene that was
+    /// created in the Govee app cannot be found.
+    pub fn invalidate_diy_scenes(&self, device: &HttpDeviceInfo) -> anyhow::Result<()> {
+        invalidate_key("http-api", &diy_scene_list_cache_key(device))
+    }
+
     pub async fn get_scene_caps(
         &self,
         device: &HttpDeviceInfo,
@@ -247,12 +467,14 @@ impl GoveeApiClient {
             ("undoc_caps", &undoc_caps),
         ] {
             for cap in caps {
-                let is_scene = matches!(
-                    cap.kind,
-                    DeviceCapabilityKind::DynamicScene
-                        | DeviceCapabilityKind::DynamicSetting
-                        | DeviceCapabilityKind::Mode
-                );
+                let is_scene = match cap.kind {
+                    DeviceCapabilityKind::DynamicScene | DeviceCapabilityKind::DynamicSetting => {
+                        true
+                    }
+                    // Setting-like modes are presented as their own select
+                    DeviceCapabilityKind::Mode => is_scene_mode(&cap.instance),
+                    _ => false,
+                };
                 if !is_scene {
                     continue;
                 }
@@ -266,11 +488,9 @@ impl GoveeApiClient {
                     }
                     _ => {
                         log::warn!(
-                            "get_scene_caps(sku={sku} device={id}): \
+                            "get_scene_caps({device}): \
                             Unexpected cap.parameters in {origin}: {cap:#?}. \
-                            Ignoring this entry.",
-                            sku = device.sku,
-                            id = device.device
+                            Ignoring this entry."
                         );
                     }
                 }
@@ -280,13 +500,52 @@ impl GoveeApiClient {
         Ok(result)
     }
 
+    /// If the device state reports an active scene, returns its name,
+    /// by matching the reported value against the scene capabilities
+    /// of the same instance
+    pub async fn active_scene_name(
+        &self,
+        device: &HttpDeviceInfo,
+        state: &HttpDeviceState,
+    ) -> anyhow::Result<Option<String>> {
+        let reported: Vec<(&str, &JsonValue)> = SCENE_STATE_INSTANCES
+            .iter()
+            .filter_map(|&instance| {
+                let value = state.capability_by_instance(instance)?.state.get("value")?;
+                (!is_empty_scene_value(value)).then_some((instance, value))
+            })
+            .collect();
+        if reported.is_empty() {
+            return Ok(None);
+        }
+
+        let caps = self.get_scene_caps(device).await?;
+        Ok(reported
+            .into_iter()
+            .find_map(|(instance, value)| find_scene_name(&caps, instance, value)))
+    }
+
+    /// Returns the names of the scenes provided by a single scene
+    /// capability instance, such as diyScene or snapshot
+    pub async fn list_scene_names_for_instance(
+        &self,
+        device: &HttpDeviceInfo,
+        instance: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        let caps = self
+            .get_scene_caps(device)
+            .await
+            .with_context(|| format!("list_scene_names_for_instance: get_scene_caps {device}"))?;
+        Ok(scene_names_for_instance(&caps, instance))
+    }
+
     pub async fn list_scene_names(&self, device: &HttpDeviceInfo) -> anyhow::Result<Vec<String>> {
         let mut result = vec![];
 
         let caps = self
             .get_scene_caps(device)
             .await
-            .context("list_scene_names: get_scene_caps")?;
+            .with_context(|| format!("list_scene_names: get_scene_caps {device}"))?;
         for cap in caps {
             match &cap.parameters {
                 Some(DeviceParameters::Enum { options }) => {
@@ -294,7 +553,7 @@ impl GoveeApiClient {
                         result.push(opt.name.to_string());
                     }
                 }
-                _ => anyhow::bail!("list_scene_names: unexpected type {cap:#?}"),
+                _ => anyhow::bail!("list_scene_names {device}: unexpected type {cap:#?}"),
             }
         }
 
@@ -330,41 +589,120 @@ impl GoveeApiClient {
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         if scene == "" {
             // Can't set no scene
-            anyhow::bail!("Cannot set scene to no-scene");
+            anyhow::bail!("set_scene_by_name {device}: Cannot set scene to no-scene");
         }
 
         if let Some(music_mode) = scene.strip_prefix("Music: ") {
             if let Some(cap) = device.capability_by_instance("musicMode") {
                 if let Some(field) = cap.struct_field_by_name("musicMode") {
                     if let Some(value) = field.field_type.enum_parameter_by_name(music_mode) {
-                        let value = serde_json::json!({
-                            "musicMode": value,
-                            "sensitivity": 100,
-                            "autoColor": 1,
-                        });
-                        return self.control_device(&device, &cap, value).await;
+                        let sensitivity = cap
+                            .default_value_for("sensitivity")
+                            .and_then(|v| v.as_u64())
+                            .map(|v| v as u32)
+                            .unwrap_or(100);
+                        let value = MusicModeValue {
+                            music_mode: value,
+                            sensitivity,
+                            auto_color: 1,
+                        };
+                        return self
+                            .control_device(device, cap, serde_json::to_value(&value)?)
+                            .await;
                     }
                 }
             }
         }
 
-        let caps = self.get_scene_caps(device).await?;
+        let caps = self
+            .get_scene_caps(device)
+            .await
+            .with_context(|| format!("set_scene_by_name: get_scene_caps {device}"))?;
         for cap in caps {
             match &cap.parameters {
                 Some(DeviceParameters::Enum { options }) => {
                     for opt in options {
                         if scene.eq_ignore_ascii_case(&opt.name) {
-                            return self.control_device(&device, &cap, opt.value.clone()).await;
+                            let device_mode = cap.kind == DeviceCapabilityKind::Mode
+                                && device.capability_by_instance(&cap.instance).is_some();
+                            if let (true, Some(value)) = (device_mode, opt.value.as_u64()) {
+                                return self.set_mode(device, &cap.instance, value as u32).await;
+                            }
+                            return self.control_device(device, &cap, opt.value.clone()).await;
                         }
                     }
                 }
-                _ => anyhow::bail!("set_scene_by_name: unexpected type {cap:#?}"),
+                _ => anyhow::bail!("set_scene_by_name {device}: unexpected type {cap:#?}"),
             }
         }
-        anyhow::bail!("Scene '{scene}' is not available for this device");
+        Err(
+            ApiError::InvalidParameter(format!("Scene '{scene}' is not available for {device}"))
+                .into(),
+        )
+    }
+}
+
+/// The Platform API operations that are used by the service.
+/// This is implemented by GoveeApiClient, and allows the service
+/// logic to be tested with a mock client that doesn't make any
+/// HTTP requests.
+/// The higher level control methods are provided in terms of
+/// control_device.
+#[async_trait]
+pub trait GoveeApiClientTrait: Send + Sync {
+    async fn get_devices(&self) -> anyhow::Result<Vec<HttpDeviceInfo>>;
+
+    async fn get_device_state(&self, device: &HttpDeviceInfo) -> anyhow::Result<HttpDeviceState>;
+
+    async fn control_device(
+        &self,
+        device: &HttpDeviceInfo,
+        capability: &DeviceCapability,
+        value: JsonValue,
+    ) -> anyhow::Result<ControlDeviceResponseCapability>;
+
+    async fn list_scene_names(&self, device: &HttpDeviceInfo) -> anyhow::Result<Vec<String>>;
+
+    async fn list_scene_names_for_instance(
+        &self,
+        device: &HttpDeviceInfo,
+        instance: &str,
+    ) -> anyhow::Result<Vec<String>>;
+
+    async fn set_scene_by_name(
+        &self,
+        device: &HttpDeviceInfo,
+        scene: &str,
+    ) -> anyhow::Result<ControlDeviceResponseCapability>;
+
+    async fn active_scene_name(
+        &self,
+        device: &HttpDeviceInfo,
+        state: &HttpDeviceState,
+    ) -> anyhow::Result<Option<String>>;
+
+    fn invalidate_diy_scenes(&self, device: &HttpDeviceInfo) -> anyhow::Result<()>;
+
+    /// Returns the reported state of a single capability, which is
+    /// usually an object with a `value` field
+    async fn get_device_capability_state(
+        &self,
+        device: &HttpDeviceInfo,
+        instance: &str,
+    ) -> anyhow::Result<JsonValue> {
+        let state = self.get_device_state(device).await?;
+        state
+            .capability_by_instance(instance)
+            .map(|cap| cap.state.clone())
+            .ok_or_else(|| anyhow::anyhow!("{device} didn't report the state of {instance}"))
     }
 
-    pub async fn set_target_temperature(
+    /// Sets the target temperature, clamping it to the constraints
+    /// of the capability. The current value is queried first: a
+    /// device that reports a temperature outside of its advertised
+    /// range evidently accepts it, so the range is widened to
+    /// include it.
+    async fn set_target_temperature(
         &self,
         device: &HttpDeviceInfo,
         instance_name: &str,
@@ -372,14 +710,33 @@ impl GoveeApiClient {
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let cap = device
             .capability_by_instance(instance_name)
-            .ok_or_else(|| anyhow::anyhow!("device has no {instance_name}"))?;
+            .ok_or_else(|| anyhow::anyhow!("{device} has no {instance_name}"))?;
 
-        let constraints = parse_temperature_constraints(cap)?.as_unit(TemperatureUnits::Celsius);
+        let constraints = parse_temperature_constraints(cap)
+            .with_context(|| format!("set_target_temperature {device}"))?
+            .as_unit(TemperatureUnits::Celsius);
 
-        let min = constraints.min.as_celsius();
-        let max = constraints.max.as_celsius();
-        let celsius = target.as_celsius().max(min).min(max);
+        let mut min = constraints.min.as_celsius();
+        let mut max = constraints.max.as_celsius();
+        match self
+            .get_device_capability_state(device, instance_name)
+            .await
+        {
+            Ok(current) => {
+                if let Some(current) = reported_temperature(&current) {
+                    let current = current.as_celsius();
+                    log::debug!("set_target_temperature: {instance_name} is currently {current}C");
+                    min = min.min(current);
+                    max = max.max(current);
+                }
+            }
+            Err(err) => {
+                log::debug!("set_target_temperature: current state is unknown: {err:#}");
+            }
+        }
+        let celsius = target.as_celsius();
         let clamped = celsius.max(min).min(max);
+
         if clamped != celsius {
             log::info!(
                 "set_target_temperature: constraining requested {celsius} to \
@@ -388,14 +745,14 @@ impl GoveeApiClient {
         }
 
         let value = json!({
-            "temperature": celsius,
+            "temperature": clamped,
             "unit": "Celsius",
         });
 
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value).await
     }
 
-    pub async fn set_work_mode(
+    async fn set_work_mode(
         &self,
         device: &HttpDeviceInfo,
         work_mode: i64,
@@ -403,17 +760,18 @@ impl GoveeApiClient {
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let cap = device
             .capability_by_instance("workMode")
-            .ok_or_else(|| anyhow::anyhow!("device has no workMode"))?;
+            .ok_or_else(|| anyhow::anyhow!("{device} has no workMode"))?;
 
-        let value = json!({
-            "workMode": work_mode,
-            "modeValue": value
-        });
+        let value = WorkModeValue {
+            work_mode,
+            mode_value: value,
+        };
 
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, serde_json::to_value(&value)?)
+            .await
     }
 
-    pub async fn set_toggle_state(
+    async fn set_toggle_state(
         &self,
         device: &HttpDeviceInfo,
         instance: &str,
@@ -421,16 +779,16 @@ impl GoveeApiClient {
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let cap = device
             .capability_by_instance(instance)
-            .ok_or_else(|| anyhow::anyhow!("device has no {instance}"))?;
+            .ok_or_else(|| anyhow::anyhow!("{device} has no {instance}"))?;
 
         let value = cap
             .enum_parameter_by_name(if on { "on" } else { "off" })
-            .ok_or_else(|| anyhow::anyhow!("{instance} has no on/off!?"))?;
+            .ok_or_else(|| anyhow::anyhow!("{device}: {instance} has no on/off!?"))?;
 
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value.into()).await
     }
 
-    pub async fn set_power_state(
+    async fn set_power_state(
         &self,
         device: &HttpDeviceInfo,
         on: bool,
@@ -438,43 +796,81 @@ impl GoveeApiClient {
         self.set_toggle_state(device, "powerSwitch", on).await
     }
 
-    pub async fn set_brightness(
+    /// Sets a Mode capability, such as nightlightScene, to the
+    /// numeric value of one of its options
+    async fn set_mode(
+        &self,
+        device: &HttpDeviceInfo,
+        instance: &str,
+        value: u32,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let cap = device
+            .capability_by_instance(instance)
+            .filter(|cap| cap.kind == DeviceCapabilityKind::Mode)
+            .ok_or_else(|| anyhow::anyhow!("{device} has no {instance} mode"))?;
+        if let Some(DeviceParameters::Enum { options }) = &cap.parameters {
+            if !options
+                .iter()
+                .any(|opt| opt.value.as_u64() == Some(value as u64))
+            {
+                return Err(ApiError::InvalidParameter(format!(
+                    "{value} is not a valid {instance} for {device}"
+                ))
+                .into());
+            }
+        }
+        self.control_device(device, cap, value.into()).await
+    }
+
+    /// Sets the value of a Range capability, expressed in the units
+    /// of the range; it is clamped and scaled by its precision
+    async fn set_range_value(
+        &self,
+        device: &HttpDeviceInfo,
+        instance_name: &str,
+        value: f64,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let cap = device
+            .capability_by_instance(instance_name)
+            .ok_or_else(|| anyhow::anyhow!("{device} has no {instance_name}"))?;
+        let value = match &cap.parameters {
+            Some(DeviceParameters::Integer { range, .. }) => range.to_api_value(value),
+            _ => anyhow::bail!("{device}: {instance_name} is not an integer range"),
+        };
+        self.control_device(device, cap, value.into()).await
+    }
+
+    async fn set_brightness(
         &self,
         device: &HttpDeviceInfo,
         percent: u8,
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let cap = device
             .capability_by_instance("brightness")
-            .ok_or_else(|| anyhow::anyhow!("device has no brightness"))?;
+            .ok_or_else(|| anyhow::anyhow!("{device} has no brightness"))?;
         let value = match &cap.parameters {
-            Some(DeviceParameters::Integer {
-                range: IntegerRange { min, max, .. },
-                ..
-            }) => (percent as u32).max(*min).min(*max),
-            _ => anyhow::bail!("unexpected parameter type for brightness"),
+            Some(DeviceParameters::Integer { range, .. }) => range.to_api_value(percent as f64),
+            _ => anyhow::bail!("{device}: unexpected parameter type for brightness"),
         };
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value.into()).await
     }
 
-    pub async fn set_color_temperature(
+    async fn set_color_temperature(
         &self,
         device: &HttpDeviceInfo,
         kelvin: u32,
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let cap = device
             .capability_by_instance("colorTemperatureK")
-            .ok_or_else(|| anyhow::anyhow!("device has no colorTemperatureK"))?;
+            .ok_or_else(|| anyhow::anyhow!("{device} has no colorTemperatureK"))?;
         let value = match &cap.parameters {
-            Some(DeviceParameters::Integer {
-                range: IntegerRange { min, max, .. },
-                ..
-            }) => (kelvin).max(*min).min(*max),
-            _ => anyhow::bail!("unexpected parameter type for colorTemperatureK"),
+            Some(DeviceParameters::Integer { range, .. }) => range.to_api_value(kelvin as f64),
+            _ => anyhow::bail!("{device}: unexpected parameter type for colorTemperatureK"),
         };
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value.into()).await
     }
 
-    pub async fn set_color_rgb(
+    async fn set_color_rgb(
         &self,
         device: &HttpDeviceInfo,
         r: u8,
@@ -483,12 +879,12 @@ impl GoveeApiClient {
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let cap = device
             .capability_by_instance("colorRgb")
-            .ok_or_else(|| anyhow::anyhow!("device has no colorRgb"))?;
+            .ok_or_else(|| anyhow::anyhow!("{device} has no colorRgb"))?;
         let value = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
-        self.control_device(&device, &cap, value).await
+        self.control_device(device, cap, value.into()).await
     }
 
-    pub async fn set_segment_rgb(
+    async fn set_segment_rgb(
         &self,
         device: &HttpDeviceInfo,
         segment: u32,
@@ -498,11 +894,20 @@ impl GoveeApiClient {
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let cap = device
             .capability_by_instance("segmentedColorRgb")
-            .ok_or_else(|| anyhow::anyhow!("device has no segmentedColorRgb"))?;
+            .ok_or_else(|| anyhow::anyhow!("{device} has no segmentedColorRgb"))?;
+        if let Some(segments) = device.supports_segmented_rgb() {
+            if !segments.contains(&segment) {
+                return Err(ApiError::InvalidParameter(format!(
+                    "segment {segment} is outside the range {segments:?} of \
+                     {device}",
+                ))
+                .into());
+            }
+        }
         let value = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
         self.control_device(
-            &device,
-            &cap,
+            device,
+            cap,
             json!({
                 "segment": vec![segment],
                 "rgb": value,
@@ -511,24 +916,70 @@ impl GoveeApiClient {
         .await
     }
 
-    pub async fn set_segment_brightness(
+    /// Sets the color of several segments in a single request.
+    /// Segments that share a color are grouped into the same entry
+    /// of the array that is sent as the segmentedColorRgb value.
+    async fn set_segment_multiple_rgb(
         &self,
         device: &HttpDeviceInfo,
-        segment: u32,
-        percent: u8,
+        segments: &[(u32, u8, u8, u8)],
     ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let cap = device
-            .capability_by_instance("segmentedBrightness")
-            .ok_or_else(|| anyhow::anyhow!("device has no segmentedBrightness"))?;
+            .capability_by_instance("segmentedColorRgb")
+            .ok_or_else(|| anyhow::anyhow!("{device} has no segmentedColorRgb"))?;
+        let valid_segments = device.supports_segmented_rgb();
+
+        let mut by_color: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+        for &(segment, r, g, b) in segments {
+            if let Some(valid) = &valid_segments {
+                if !valid.contains(&segment) {
+                    return Err(ApiError::InvalidParameter(format!(
+                        "segment {segment} is outside the range {valid:?} of \
+                         {device}",
+                    ))
+                    .into());
+                }
+            }
+            let value = ((r as u32) << 16) | ((g as u32) << 8) | (b as u32);
+            by_color.entry(value).or_default().push(segment);
+        }
+
+        let value: Vec<JsonValue> = by_color
+            .into_iter()
+            .map(|(rgb, segment)| json!({"segment": segment, "rgb": rgb}))
+            .collect();
+        self.control_device(device, cap, value.into()).await
+    }
 
-        let (min, max) = device
+    async fn set_segment_brightness(
+        &self,
+        device: &HttpDeviceInfo,
+        segment: u32,
+        percent: u8,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        let range = device
             .supports_segmented_brightness()
-            .ok_or_else(|| anyhow::anyhow!("device doesnt support segmented brightness"))?;
+            .ok_or_else(|| anyhow::anyhow!("{device} doesnt support segmented brightness"))?;
+
+        let cap = match device.capability_by_instance("segmentedBrightness") {
+            Some(cap) => cap.clone(),
+            // Devices that list segmentedColorRgb without also listing
+            // segmentedBrightness get the instance that they would have
+            // had if they did; supports_segmented_brightness has already
+            // derived its range from the overall brightness
+            None => DeviceCapability {
+                kind: DeviceCapabilityKind::SegmentColorSetting,
+                instance: "segmentedBrightness".to_string(),
+                parameters: None,
+                alarm_type: None,
+                event_state: None,
+            },
+        };
 
-        let value = (percent as u32).max(min).min(max);
+        let value = range.to_api_value(percent as f64);
 
         self.control_device(
-            &device,
+            device,
             &cap,
             json!({
                 "segment": vec![segment],
@@ -539,6 +990,58 @@ impl GoveeApiClient {
     }
 }
 
+#[async_trait]
+impl GoveeApiClientTrait for GoveeApiClient {
+    async fn get_devices(&self) -> anyhow::Result<Vec<HttpDeviceInfo>> {
+        GoveeApiClient::get_devices(self).await
+    }
+
+    async fn get_device_state(&self, device: &HttpDeviceInfo) -> anyhow::Result<HttpDeviceState> {
+        GoveeApiClient::get_device_state(self, device).await
+    }
+
+    async fn control_device(
+        &self,
+        device: &HttpDeviceInfo,
+        capability: &DeviceCapability,
+        value: JsonValue,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        GoveeApiClient::control_device(self, device, capability, value).await
+    }
+
+    async fn list_scene_names(&self, device: &HttpDeviceInfo) -> anyhow::Result<Vec<String>> {
+        GoveeApiClient::list_scene_names(self, device).await
+    }
+
+    async fn list_scene_names_for_instance(
+        &self,
+        device: &HttpDeviceInfo,
+        instance: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        GoveeApiClient::list_scene_names_for_instance(self, device, instance).await
+    }
+
+    async fn set_scene_by_name(
+        &self,
+        device: &HttpDeviceInfo,
+        scene: &str,
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
+        GoveeApiClient::set_scene_by_name(self, device, scene).await
+    }
+
+    async fn active_scene_name(
+        &self,
+        device: &HttpDeviceInfo,
+        state: &HttpDeviceState,
+    ) -> anyhow::Result<Option<String>> {
+        GoveeApiClient::active_scene_name(self, device, state).await
+    }
+
+    fn invalidate_diy_scenes(&self, device: &HttpDeviceInfo) -> anyhow::Result<()> {
+        GoveeApiClient::invalidate_diy_scenes(self, device)
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 struct GetDeviceScenesResponse {
@@ -593,6 +1096,103 @@ struct ControlDeviceCapability {
     pub value: JsonValue,
 }
 
+/// The scene instances whose reported state may identify the
+/// active scene
+const SCENE_STATE_INSTANCES: &[&str] = &["lightScene", "diyScene", "snapshot"];
+
+/// Devices report an empty string, or sometimes an empty object,
+/// for lightScene when no scene is active
+fn is_empty_scene_value(value: &JsonValue) -> bool {
+    match value {
+        JsonValue::Null => true,
+        JsonValue::String(s) => s.is_empty(),
+        JsonValue::Object(map) => map.is_empty(),
+        _ => false,
+    }
+}
+
+/// Returns true if the value of a scene option matches the value
+/// reported in the device state. Scene values are usually objects
+/// of the form `{"id": N, "paramId": N}`; the reported value may
+/// omit or reorder fields, so those are compared by id, and by
+/// paramId if both sides have it.
+fn scene_value_matches(option: &JsonValue, reported: &JsonValue) -> bool {
+    match (option, reported) {
+        (JsonValue::Object(option), JsonValue::Object(reported)) => {
+            let Some(id) = option.get("id") else {
+                return option == reported;
+            };
+            if reported.get("id") != Some(id) {
+                return false;
+            }
+            match (option.get("paramId"), reported.get("paramId")) {
+                (Some(a), Some(b)) => a == b,
+                _ => true,
+            }
+        }
+        // A bare id may be reported for an object-valued option
+        (JsonValue::Object(option), reported) => option.get("id") == Some(reported),
+        // and an object may be reported for a bare id option
+        (option, JsonValue::Object(reported)) => reported.get("id") == Some(option),
+        (option, reported) => option == reported,
+    }
+}
+
+/// Searches the scene capabilities of the specified instance for
+/// the option whose value matches `reported`, returning its name.
+/// Values are only unique within an instance; a snapshot and a
+/// lightScene may both have a value of 1.
+fn find_scene_name(
+    caps: &[DeviceCapability],
+    instance: &str,
+    reported: &JsonValue,
+) -> Option<String> {
+    caps.iter()
+        .filter(|cap| cap.instance.eq_ignore_ascii_case(instance))
+        .filter_map(|cap| match &cap.parameters {
+            Some(DeviceParameters::Enum { options }) => Some(options),
+            _ => None,
+        })
+        .flatten()
+        .find(|opt| scene_value_matches(&opt.value, reported))
+        .map(|opt| opt.name.to_string())
+}
+
+/// Returns the names of the options of the scene capabilities
+/// that have the specified instance name
+fn scene_names_for_instance(caps: &[DeviceCapability], instance: &str) -> Vec<String> {
+    caps.iter()
+        .filter(|cap| cap.instance.eq_ignore_ascii_case(instance))
+        .filter_map(|cap| match &cap.parameters {
+            Some(DeviceParameters::Enum { options }) => Some(options),
+            _ => None,
+        })
+        .flatten()
+        .map(|opt| opt.name.to_string())
+        .collect()
+}
+
+fn diy_scene_list_cache_key(device: &HttpDeviceInfo) -> String {
+    format!("scene-list-diy-{}-{}", device.sku, device.device)
+}
+
+#[derive(Serialize, Debug)]
+struct WorkModeValue {
+    #[serde(rename = "workMode")]
+    pub work_mode: i64,
+    #[serde(rename = "modeValue")]
+    pub mode_value: i64,
+}
+
+#[derive(Serialize, Debug)]
+struct MusicModeValue {
+    #[serde(rename = "musicMode")]
+    pub music_mode: u32,
+    pub sensitivity: u32,
+    #[serde(rename = "autoColor")]
+    pub auto_color: u32,
+}
+
 #[derive(Deserialize, Debug)]
 #[allow(dead_code)]
 struct ControlDeviceResponse {
@@ -615,6 +1215,34 @@ pub struct ControlDeviceResponseCapability {
     pub state: JsonValue,
 }
 
+impl ControlDeviceResponseCapability {
+    /// Returns false if the state reports something other than success
+    pub fn succeeded(&self) -> bool {
+        match self.state.get("status").and_then(|s| s.as_str()) {
+            Some(status) => status.eq_ignore_ascii_case("success"),
+            None => true,
+        }
+    }
+
+    /// Returns true if the response indicates that the requested
+    /// value didn't take effect, either because the state reports
+    /// something other than success, or because the value that
+    /// was applied differs from the one that was requested
+    pub fn diverges_from(&self, requested: &JsonValue) -> bool {
+        if !self.succeeded() {
+            return true;
+        }
+
+        // The value isn't always echoed back, and numbers may
+        // come back as floats, so only compare what we can
+        match (&self.value, requested) {
+            (JsonValue::Null, _) => false,
+            (JsonValue::Number(a), JsonValue::Number(b)) => a.as_f64() != b.as_f64(),
+            (a, b) => a != b,
+        }
+    }
+}
+
 #[derive(Serialize, Debug)]
 struct GetDeviceStateRequest {
     #[serde(rename = "requestId")]
@@ -628,6 +1256,18 @@ struct GetDeviceStateRequestPayload {
     pub device: String,
 }
 
+#[derive(Serialize, Debug)]
+struct RegisterWebhookRequest {
+    #[serde(rename = "requestId")]
+    pub request_id: String,
+    pub payload: RegisterWebhookPayload,
+}
+
+#[derive(Serialize, Debug)]
+struct RegisterWebhookPayload {
+    pub url: String,
+}
+
 #[derive(Deserialize, Serialize, Debug)]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 struct GetDeviceStateResponse {
@@ -653,24 +1293,108 @@ impl HttpDeviceState {
             .iter()
             .find(|c| c.instance.eq_ignore_ascii_case(instance))
     }
-}
-
-#[derive(Deserialize, Serialize, Debug, Clone)]
-#[serde(tag = "type")]
-#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
-pub struct DeviceCapabilityState {
-    #[serde(rename = "type")]
-    pub kind: DeviceCapabilityKind,
-    pub instance: String,
-    pub state: JsonValue,
-}
 
-#[derive(Deserialize, Serialize, Debug)]
+    /// Returns the per-segment color and brightness, keyed by segment
+    /// number, for segmented lights that report it.
+    /// Many devices report an empty string in place of the segment list;
+    /// those produce an empty map.
+    /// Some devices report segmentedBrightness as a plain array of
+    /// brightness values, indexed by segment number.
+    pub fn segment_states(&self) -> BTreeMap<u32, SegmentState> {
+        #[derive(Deserialize)]
+        struct SegmentedValueState {
+            value: Vec<SegmentedValue>,
+        }
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum SegmentedValue {
+            Segments {
+                segment: SegmentList,
+                rgb: Option<u32>,
+                brightness: Option<u8>,
+            },
+            Brightness(u8),
+        }
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum SegmentList {
+            Many(Vec<u32>),
+            One(u32),
+        }
+
+        let mut result: BTreeMap<u32, SegmentState> = BTreeMap::new();
+
+        for instance in ["segmentedColorRgb", "segmentedBrightness"] {
+            let Some(cap) = self.capability_by_instance(instance) else {
+                continue;
+            };
+            let Ok(state) = serde_json::from_value::<SegmentedValueState>(cap.state.clone()) else {
+                continue;
+            };
+            for (idx, value) in state.value.into_iter().enumerate() {
+                let (segments, rgb, brightness) = match value {
+                    SegmentedValue::Segments {
+                        segment: SegmentList::Many(segments),
+                        rgb,
+                        brightness,
+                    } => (segments, rgb, brightness),
+                    SegmentedValue::Segments {
+                        segment: SegmentList::One(segment),
+                        rgb,
+                        brightness,
+                    } => (vec![segment], rgb, brightness),
+                    SegmentedValue::Brightness(brightness) if instance == "segmentedBrightness" => {
+                        (vec![idx as u32], None, Some(brightness))
+                    }
+                    SegmentedValue::Brightness(_) => continue,
+                };
+                for segment in segments {
+                    let entry = result.entry(segment).or_default();
+                    if let Some(rgb) = rgb {
+                        entry.rgb.replace(rgb);
+                    }
+                    if let Some(brightness) = brightness {
+                        entry.brightness.replace(brightness);
+                    }
+                }
+            }
+        }
+
+        result
+    }
+}
+
+/// The state of an individual segment of a segmented light
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct SegmentState {
+    /// The color, encoded as 0xRRGGBB
+    pub rgb: Option<u32>,
+    /// The brightness in percent
+    pub brightness: Option<u8>,
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+#[serde(tag = "type")]
+#[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
+pub struct DeviceCapabilityState {
+    #[serde(rename = "type")]
+    pub kind: DeviceCapabilityKind,
+    pub instance: String,
+    pub state: JsonValue,
+}
+
+#[derive(Deserialize, Serialize, Debug)]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 struct GetDevicesResponse {
     pub code: u32,
     pub message: String,
     pub data: Vec<HttpDeviceInfo>,
+    /// The API doesn't currently paginate the device list,
+    /// but these are present if it starts to do so
+    #[serde(default)]
+    pub page: Option<u32>,
+    #[serde(rename = "totalPage", default)]
+    pub total_page: Option<u32>,
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -685,6 +1409,13 @@ pub struct HttpDeviceInfo {
     pub capabilities: Vec<DeviceCapability>,
 }
 
+/// Identifies the device in log messages and error context
+impl std::fmt::Display for HttpDeviceInfo {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        write!(fmt, "sku={} device={}", self.sku, self.device)
+    }
+}
+
 impl HttpDeviceInfo {
     pub fn capability_by_instance(&self, instance: &str) -> Option<&DeviceCapability> {
         self.capabilities
@@ -738,18 +1469,42 @@ impl HttpDeviceInfo {
         }
     }
 
-    pub fn supports_segmented_brightness(&self) -> Option<(u32, u32)> {
-        let cap = self.capability_by_instance("segmentedBrightness")?;
-        let field = cap.struct_field_by_name("brightness")?;
-        match &field.field_type {
-            DeviceParameters::Integer {
-                range: IntegerRange { min, max, .. },
-                ..
-            } => Some((*min, *max)),
-            _ => None,
+    /// Returns the range of the brightness of an individual segment.
+    /// Some devices have segmentedColorRgb but don't list
+    /// segmentedBrightness; for those, the range of the overall
+    /// brightness is used
+    pub fn supports_segmented_brightness(&self) -> Option<&IntegerRange> {
+        match self.capability_by_instance("segmentedBrightness") {
+            Some(cap) => {
+                let field = cap.struct_field_by_name("brightness")?;
+                match &field.field_type {
+                    DeviceParameters::Integer { range, .. } => Some(range),
+                    _ => None,
+                }
+            }
+            None => {
+                self.capability_by_instance("segmentedColorRgb")?;
+                match &self.capability_by_instance("brightness")?.parameters {
+                    Some(DeviceParameters::Integer { range, .. }) => Some(range),
+                    _ => None,
+                }
+            }
         }
     }
 
+    /// Returns true if both the color and the brightness of the
+    /// individual segments can be controlled
+    pub fn supports_segmented_color(&self) -> bool {
+        self.supports_segmented_rgb().is_some() && self.supports_segmented_brightness().is_some()
+    }
+
+    /// Returns true if the device advertises a usable color
+    /// temperature range. A colorTemperatureK capability without
+    /// an integer range is not considered to be usable.
+    pub fn supports_color_temperature(&self) -> bool {
+        self.get_color_temperature_range().is_some()
+    }
+
     pub fn get_color_temperature_range(&self) -> Option<(u32, u32)> {
         let cap = self.capability_by_instance("colorTemperatureK")?;
 
@@ -829,9 +1584,61 @@ pub enum DeviceType {
     AromaDiffuser = "devices.types.aroma_diffuser",
     Fan = "devices.types.fan",
     Kettle = "devices.types.kettle",
+    Curtain = "devices.types.curtain",
 }
 }
 
+const DEVICE_TYPE_PREFIX: &str = "devices.types.";
+
+/// Mode instances that is_scene_mode would otherwise classify
+/// incorrectly; true means that the instance selects a scene
+const MODE_INSTANCE_OVERRIDES: &[(&str, bool)] = &[
+    // Selects one of the music visualization effects, which
+    // is more like choosing a scene than adjusting a setting
+    ("musicMode", true),
+];
+
+/// Returns true if a Mode capability selects a scene, such as
+/// nightlightScene, rather than adjusting a setting, such as the
+/// fan mode of a purifier. Scenes are merged into the scene list
+/// of the device, whereas settings are presented separately.
+pub fn is_scene_mode(instance: &str) -> bool {
+    if let Some((_, is_scene)) = MODE_INSTANCE_OVERRIDES
+        .iter()
+        .find(|(name, _)| name.eq_ignore_ascii_case(instance))
+    {
+        return *is_scene;
+    }
+    let inst = instance.to_ascii_lowercase();
+    !["mode", "gear", "speed", "level"]
+        .iter()
+        .any(|word| inst.contains(word))
+}
+
+impl DeviceType {
+    /// Returns the type name without the "devices.types." prefix,
+    /// eg: "light"
+    pub fn short_name(&self) -> String {
+        let name = match self {
+            Self::Other(name) => name.to_string(),
+            _ => self.to_string(),
+        };
+        match name.strip_prefix(DEVICE_TYPE_PREFIX) {
+            Some(short) => short.to_string(),
+            None => name,
+        }
+    }
+
+    /// Parses a short type name, such as "light", into a known DeviceType
+    pub fn from_short_name(name: &str) -> anyhow::Result<Self> {
+        let full_name = format!("{DEVICE_TYPE_PREFIX}{}", name.trim().to_ascii_lowercase());
+        match full_name.parse::<Self>() {
+            Ok(Self::Other(_)) | Err(_) => anyhow::bail!("Unknown device type {name}"),
+            Ok(device_type) => Ok(device_type),
+        }
+    }
+}
+
 enum_string! {
 pub enum DeviceCapabilityKind {
     OnOff = "devices.capabilities.on_off",
@@ -865,12 +1672,26 @@ pub struct DeviceCapability {
 }
 
 impl DeviceCapability {
+    /// Parses the eventState of an event capability
+    pub fn parsed_event_state(&self) -> Option<EventState> {
+        if self.kind != DeviceCapabilityKind::Event {
+            return None;
+        }
+        serde_json::from_value(self.event_state.clone()?).ok()
+    }
+
     pub fn enum_parameter_by_name(&self, name: &str) -> Option<u32> {
         self.parameters
             .as_ref()
             .and_then(|p| p.enum_parameter_by_name(name))
     }
 
+    pub fn default_value_for(&self, field_name: &str) -> Option<&JsonValue> {
+        self.parameters
+            .as_ref()
+            .and_then(|p| p.default_value_for(field_name))
+    }
+
     pub fn struct_field_by_name(&self, name: &str) -> Option<&StructField> {
         match &self.parameters {
             Some(DeviceParameters::Struct { fields }) => {
@@ -916,6 +1737,18 @@ impl DeviceParameters {
             _ => None,
         }
     }
+
+    /// Returns the API-reported default value for the named field
+    /// of a struct parameter
+    pub fn default_value_for(&self, field_name: &str) -> Option<&JsonValue> {
+        match self {
+            DeviceParameters::Struct { fields } => fields
+                .iter()
+                .find(|f| f.field_name == field_name)
+                .and_then(|f| f.default_value.as_ref()),
+            _ => None,
+        }
+    }
 }
 
 #[derive(Deserialize, Serialize, Debug, Clone)]
@@ -956,6 +1789,83 @@ pub struct IntegerRange {
     pub precision: u32,
 }
 
+impl IntegerRange {
+    /// The increment between adjacent values in the range.
+    /// When precision is greater than 1, the range represents
+    /// values in increments of 1/precision.
+    pub fn step(&self) -> f64 {
+        1.0 / self.scale()
+    }
+
+    /// Clamps value to the range and scales it by the precision,
+    /// producing the raw integer value that the API expects
+    pub fn to_api_value(&self, value: f64) -> u32 {
+        let clamped = value.max(self.min as f64).min(self.max as f64);
+        (clamped * self.scale()).round() as u32
+    }
+
+    /// Converts a raw integer value reported by the API back
+    /// into the units of the range
+    pub fn scale_api_value(&self, value: u32) -> f64 {
+        value as f64 / self.scale()
+    }
+
+    fn scale(&self) -> f64 {
+        self.precision.max(1) as f64
+    }
+}
+
+/// The eventState of an event capability.
+///
+/// The device list describes the events that the capability can raise.
+/// For example, the `lackWaterEvent` of a humidifier:
+///
+/// ```json
+/// {"options": [{"name": "lack", "value": 1, "message": "Lack of Water"}]}
+/// ```
+///
+/// Water leak detectors (`leakEvent`) and smoke detectors (`smokeEvent`)
+/// use the same shape, with options such as `leak` or `smoke` naming
+/// the alarm condition, typically with a value of `1`.
+///
+/// The polled state of the capability holds either the value or the
+/// name of the option that was raised. A value of `0`, `false`, `null`,
+/// or one that doesn't match any option means that no alarm is active.
+#[derive(Deserialize, Serialize, Debug, Clone, Default)]
+pub struct EventState {
+    #[serde(default)]
+    pub options: Vec<EventOption>,
+}
+
+impl EventState {
+    /// Interprets a reported event value against our options.
+    /// Returns None if the value is null, meaning unknown.
+    pub fn is_active_value(&self, value: &JsonValue) -> Option<bool> {
+        match value {
+            JsonValue::Null => None,
+            JsonValue::Bool(b) => Some(*b),
+            JsonValue::Number(n) if n.as_i64() == Some(0) => Some(false),
+            JsonValue::String(name) => Some(
+                self.options
+                    .iter()
+                    .any(|opt| opt.name.eq_ignore_ascii_case(name)),
+            ),
+            value => {
+                Some(self.options.is_empty() || self.options.iter().any(|opt| opt.value == *value))
+            }
+        }
+    }
+}
+
+#[derive(Deserialize, Serialize, Debug, Clone)]
+pub struct EventOption {
+    pub name: String,
+    #[serde(default)]
+    pub value: JsonValue,
+    #[serde(default)]
+    pub message: Option<String>,
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 pub struct EnumOption {
     pub name: String,
@@ -965,6 +1875,14 @@ pub struct EnumOption {
     pub extras: HashMap<String, JsonValue>,
 }
 
+impl EnumOption {
+    /// Returns a field that is present in the option in addition
+    /// to the usual name and value fields
+    pub fn extra_field(&self, key: &str) -> Option<&JsonValue> {
+        self.extras.get(key)
+    }
+}
+
 #[derive(Deserialize, Serialize, Debug, Clone)]
 #[cfg_attr(debug_assertions, serde(deny_unknown_fields))]
 pub struct ArrayOption {
@@ -1000,68 +1918,126 @@ pub struct HttpRequestFailed {
 impl HttpRequestFailed {
     #[allow(unused)]
     pub fn from_err(err: &anyhow::Error) -> Option<&Self> {
-        err.root_cause().downcast_ref::<Self>()
+        match ApiError::from_err(err)? {
+            ApiError::RateLimited(failed)
+            | ApiError::Unauthorized(failed)
+            | ApiError::DeviceNotFound(failed)
+            | ApiError::Http(failed) => Some(failed),
+            ApiError::Transport(_) | ApiError::InvalidParameter(_) => None,
+        }
+    }
+
+    pub fn status(&self) -> reqwest::StatusCode {
+        self.status
     }
 }
 
-pub async fn json_body<T: serde::de::DeserializeOwned>(
-    response: reqwest::Response,
-) -> anyhow::Result<T> {
-    let url = response.url().clone();
-    let data = response
-        .bytes()
-        .await
-        .with_context(|| format!("read {url} response body"))?;
+/// Classifies the ways in which a platform API request can fail,
+/// so that callers can make retry decisions or produce a helpful
+/// message without having to inspect the error text.
+/// The API methods still return anyhow::Error; use ApiError::from_err
+/// to recover this from the error chain.
+#[derive(Error, Debug)]
+pub enum ApiError {
+    #[error("Rate limited by the Govee API. {0}")]
+    RateLimited(HttpRequestFailed),
+    #[error("The Govee API rejected the API key. {0}")]
+    Unauthorized(HttpRequestFailed),
+    #[error("The Govee API doesn't know this device. {0}")]
+    DeviceNotFound(HttpRequestFailed),
+    #[error(transparent)]
+    Http(HttpRequestFailed),
+    #[error("Unable to communicate with the Govee API: {0}")]
+    Transport(#[from] reqwest::Error),
+    #[error("Invalid parameter: {0}")]
+    InvalidParameter(String),
+}
 
-    if let Ok(status) = from_json::<EmbeddedRequestStatus, _>(&data) {
+impl From<HttpRequestFailed> for ApiError {
+    fn from(failed: HttpRequestFailed) -> Self {
+        match failed.status() {
+            reqwest::StatusCode::TOO_MANY_REQUESTS => Self::RateLimited(failed),
+            reqwest::StatusCode::UNAUTHORIZED | reqwest::StatusCode::FORBIDDEN => {
+                Self::Unauthorized(failed)
+            }
+            reqwest::StatusCode::NOT_FOUND => Self::DeviceNotFound(failed),
+            _ => Self::Http(failed),
+        }
+    }
+}
+
+impl ApiError {
+    pub fn from_err(err: &anyhow::Error) -> Option<&Self> {
+        err.chain().find_map(|e| e.downcast_ref::<Self>())
+    }
+
+    /// Returns true if the same request might succeed if it
+    /// is tried again later
+    pub fn is_transient(&self) -> bool {
+        match self {
+            Self::RateLimited(_) | Self::Transport(_) => true,
+            Self::Unauthorized(_) | Self::DeviceNotFound(_) | Self::InvalidParameter(_) => false,
+            Self::Http(failed) => failed.status().is_server_error(),
+        }
+    }
+}
+
+fn json_body<T: serde::de::DeserializeOwned>(url: &reqwest::Url, data: &[u8]) -> anyhow::Result<T> {
+    if let Ok(status) = from_json::<EmbeddedRequestStatus, _>(data) {
         if status.status != reqwest::StatusCode::OK.as_u16() {
             if let Ok(code) = reqwest::StatusCode::from_u16(status.status) {
-                return Err(HttpRequestFailed {
+                return Err(ApiError::from(HttpRequestFailed {
                     status: code,
                     content: format!(
                         "Request to {url} failed with code {code} {message}. Full response: {}",
-                        String::from_utf8_lossy(&data),
+                        String::from_utf8_lossy(data),
                         message = status.message
                     ),
-                })
+                }))
                 .with_context(|| format!("parsing {url} response"));
             }
 
             anyhow::bail!(
                 "Request to {url} failed with status={status} {message}. Full response was: {}",
-                String::from_utf8_lossy(&data),
+                String::from_utf8_lossy(data),
                 status = status.status,
                 message = status.message,
             );
         }
     }
 
-    from_json(&data).with_context(|| format!("parsing {url} response"))
+    from_json(data).with_context(|| format!("parsing {url} response"))
 }
 
-pub async fn http_response_body<R: serde::de::DeserializeOwned>(
-    response: reqwest::Response,
-) -> anyhow::Result<R> {
+async fn read_response_bytes(response: reqwest::Response) -> anyhow::Result<Vec<u8>> {
     let url = response.url().clone();
-
     let status = response.status();
-    if !status.is_success() {
-        let body_bytes = response.bytes().await.with_context(|| {
-            format!(
-                "request {url} status {}: {}, and failed to read response body",
-                status.as_u16(),
-                status.canonical_reason().unwrap_or("")
-            )
-        })?;
-
-        anyhow::bail!(
-            "request {url} status {}: {}. Response body: {}",
+    let data = response.bytes().await.with_context(|| {
+        format!(
+            "request {url} status {}: {}, and failed to read response body",
             status.as_u16(),
-            status.canonical_reason().unwrap_or(""),
-            String::from_utf8_lossy(&body_bytes)
-        );
+            status.canonical_reason().unwrap_or("")
+        )
+    })?;
+    Ok(data.to_vec())
+}
+
+fn decode_http_response_body<R: serde::de::DeserializeOwned>(
+    url: &reqwest::Url,
+    status: reqwest::StatusCode,
+    data: &[u8],
+) -> anyhow::Result<R> {
+    if !status.is_success() {
+        return Err(ApiError::from(HttpRequestFailed {
+            status,
+            content: format!(
+                "Request to {url} failed. Response body: {}",
+                String::from_utf8_lossy(data)
+            ),
+        })
+        .into());
     }
-    json_body(response).await.with_context(|| {
+    json_body(url, data).with_context(|| {
         format!(
             "request {url} status {}: {}",
             status.as_u16(),
@@ -1070,20 +2046,28 @@ pub async fn http_response_body<R: serde::de::DeserializeOwned>(
     })
 }
 
+pub async fn http_response_body<R: serde::de::DeserializeOwned>(
+    response: reqwest::Response,
+) -> anyhow::Result<R> {
+    let url = response.url().clone();
+    let status = response.status();
+    let data = read_response_bytes(response).await?;
+    decode_http_response_body(&url, status, &data)
+}
+
 impl GoveeApiClient {
     async fn get_request_with_json_response<T: reqwest::IntoUrl, R: serde::de::DeserializeOwned>(
         &self,
         url: T,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .build()?
+        let request = self
+            .http_client()?
             .request(Method::GET, url)
-            .header("Govee-API-Key", &self.key)
-            .send()
-            .await?;
+            .header("Govee-API-Key", &self.key);
+        self.log_request(&request, None);
+        let (response, _retries) = send_with_rate_limit_retry(request).await?;
 
-        http_response_body(response).await
+        self.response_body(response).await
     }
 
     async fn request_with_json_response<
@@ -1096,16 +2080,345 @@ impl GoveeApiClient {
         url: T,
         body: &B,
     ) -> anyhow::Result<R> {
-        let response = reqwest::Client::builder()
-            .timeout(Duration::from_secs(60))
-            .build()?
+        let (resp, _retries) = self
+            .request_with_json_response_and_retries(method, url, body)
+            .await?;
+        Ok(resp)
+    }
+
+    /// Like request_with_json_response, but also returns the number
+    /// of times that the request had to be retried
+    async fn request_with_json_response_and_retries<
+        T: reqwest::IntoUrl,
+        B: serde::Serialize,
+        R: serde::de::DeserializeOwned,
+    >(
+        &self,
+        method: Method,
+        url: T,
+        body: &B,
+    ) -> anyhow::Result<(R, u32)> {
+        let request = self
+            .http_client()?
             .request(method, url)
             .header("Govee-API-Key", &self.key)
-            .json(body)
+            .json(body);
+        self.log_request(&request, Some(serde_json::to_value(body)?));
+        let (response, retries) = send_with_rate_limit_retry(request).await?;
+
+        Ok((self.response_body(response).await?, retries))
+    }
+
+    fn http_client(&self) -> anyhow::Result<reqwest::Client> {
+        let mut builder = reqwest::Client::builder().timeout(self.timeout);
+        if let Some(connect_timeout) = self.connect_timeout {
+            builder = builder.connect_timeout(connect_timeout);
+        }
+        Ok(builder.build()?)
+    }
+
+    fn log_request(&self, request: &reqwest::RequestBuilder, body: Option<JsonValue>) {
+        if !self.verbose {
+            return;
+        }
+        let Some(request) = request.try_clone().and_then(|r| r.build().ok()) else {
+            return;
+        };
+        log::info!(
+            "Platform API request: {method} {url} Govee-API-Key={key:?} body={body}",
+            method = request.method(),
+            url = request.url(),
+            key = Redacted::new(&self.key),
+            body = body.unwrap_or(JsonValue::Null)
+        );
+    }
+
+    async fn response_body<R: serde::de::DeserializeOwned>(
+        &self,
+        response: reqwest::Response,
+    ) -> anyhow::Result<R> {
+        if !self.verbose {
+            return http_response_body(response).await;
+        }
+
+        let url = response.url().clone();
+        let status = response.status();
+        let data = read_response_bytes(response).await?;
+        log::info!(
+            "Platform API response: {url} status {status} body={}",
+            String::from_utf8_lossy(&data)
+        );
+        decode_http_response_body(&url, status, &data)
+    }
+}
+
+/// Sends the request, retrying it if the server responds with
+/// 429 Too Many Requests, or if it fails in some other transient
+/// way, such as a transport error. The delay is taken from the
+/// Retry-After header when present, otherwise we use an exponential
+/// backoff.
+/// Returns the response along with the number of retries made.
+async fn send_with_rate_limit_retry(
+    request: reqwest::RequestBuilder,
+) -> anyhow::Result<(reqwest::Response, u32)> {
+    let mut attempt = 0;
+    loop {
+        let response = match request
+            .try_clone()
+            .ok_or_else(|| anyhow::anyhow!("request body cannot be retried"))?
             .send()
-            .await?;
+            .await
+        {
+            Ok(response) => response,
+            Err(err) if attempt < MAX_RATE_LIMIT_RETRIES => {
+                attempt += 1;
+                let delay = retry_backoff(attempt);
+                log::warn!("{err:#}, retry {attempt} of {MAX_RATE_LIMIT_RETRIES} in {delay:?}");
+                tokio::time::sleep(delay).await;
+                continue;
+            }
+            Err(err) => return Err(ApiError::Transport(err).into()),
+        };
+
+        if response.status().is_success() || attempt >= MAX_RATE_LIMIT_RETRIES {
+            return Ok((response, attempt));
+        }
+        let err = ApiError::from(HttpRequestFailed {
+            status: response.status(),
+            content: String::new(),
+        });
+        if !err.is_transient() {
+            return Ok((response, attempt));
+        }
+        attempt += 1;
+
+        let delay = response
+            .headers()
+            .get(reqwest::header::RETRY_AFTER)
+            .and_then(|v| v.to_str().ok())
+            .and_then(|v| v.trim().parse::<u64>().ok())
+            .map(Duration::from_secs)
+            .unwrap_or_else(|| retry_backoff(attempt))
+            .min(MAX_RATE_LIMIT_DELAY);
+
+        log::warn!(
+            "{} failed with status {}, retry {attempt} of {MAX_RATE_LIMIT_RETRIES} in {delay:?}",
+            response.url(),
+            response.status()
+        );
+        tokio::time::sleep(delay).await;
+    }
+}
+
+/// The delay before the specified retry of a request when the
+/// server didn't tell us how long to wait
+#[cfg(not(test))]
+fn retry_backoff(attempt: u32) -> Duration {
+    Duration::from_secs(1 << attempt)
+}
+
+/// Tests don't need to wait out the backoff
+#[cfg(test)]
+fn retry_backoff(_attempt: u32) -> Duration {
+    Duration::ZERO
+}
+
+/// How long to wait before resending a control request whose
+/// response indicates that it didn't take effect
+#[cfg(not(test))]
+fn control_resend_delay() -> Duration {
+    Duration::from_secs(2)
+}
+
+/// Tests don't need to wait for the device to settle
+#[cfg(test)]
+fn control_resend_delay() -> Duration {
+    Duration::ZERO
+}
+
+/// Parses the reported state of a temperature_setting capability
+fn reported_temperature(state: &JsonValue) -> Option<TemperatureValue> {
+    let temperature = state.pointer("/value/temperature")?.as_f64()?;
+    let units = state
+        .pointer("/value/unit")
+        .and_then(|unit| unit.as_str())
+        .and_then(|unit| unit.parse::<TemperatureScale>().ok())
+        .map(Into::into)
+        .unwrap_or(TemperatureUnits::Celsius);
+    Some(TemperatureValue::new(temperature, units))
+}
+
+/// A GoveeApiClientTrait implementation for use in tests.
+/// It returns the configured devices, states and scenes, and records
+/// the control requests that it receives rather than making them.
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use crate::service::state::{State, StateHandle};
+    use std::sync::{Arc, Mutex};
+
+    #[derive(Debug, Clone, PartialEq)]
+    pub struct MockControl {
+        pub device: String,
+        pub instance: String,
+        pub value: JsonValue,
+    }
+
+    #[derive(Clone, Default)]
+    pub struct MockGoveeApiClient {
+        pub devices: Vec<HttpDeviceInfo>,
+        pub states: HashMap<String, HttpDeviceState>,
+        pub scenes: Vec<String>,
+        controls: Arc<Mutex<Vec<MockControl>>>,
+        control_errors: Arc<Mutex<std::collections::VecDeque<ApiError>>>,
+    }
+
+    /// Parses the devices from a list_devices style fixture
+    pub fn devices_from_fixture(fixture: &str) -> Vec<HttpDeviceInfo> {
+        let resp: GetDevicesResponse = from_json(fixture).unwrap();
+        resp.data
+    }
+
+    /// Parses the state from a get_device_state style fixture
+    pub fn device_state_from_fixture(fixture: &str) -> HttpDeviceState {
+        let resp: GetDeviceStateResponse = from_json(fixture).unwrap();
+        resp.payload
+    }
+
+    impl MockGoveeApiClient {
+        pub fn with_devices(devices: Vec<HttpDeviceInfo>) -> Self {
+            Self {
+                devices,
+                ..Self::default()
+            }
+        }
+
+        /// Returns a State holding this client's devices, with a
+        /// clone of this client as its platform client
+        pub async fn service_state(&self) -> StateHandle {
+            let state = Arc::new(State::new());
+            for info in &self.devices {
+                state
+                    .device_mut(&info.sku, &info.device)
+                    .await
+                    .set_http_device_info(info.clone());
+            }
+            state.set_platform_client(self.clone()).await;
+            state
+        }
 
-        http_response_body(response).await
+        /// Returns the control requests received so far. Clones of
+        /// the client share the same list, so a clone can be handed
+        /// to the service and the original used to inspect it
+        pub fn controls(&self) -> Vec<MockControl> {
+            self.controls.lock().unwrap().clone()
+        }
+
+        /// Makes the next control request fail with err. May be
+        /// called repeatedly to fail several requests in turn
+        pub fn fail_next_control(&self, err: ApiError) {
+            self.control_errors.lock().unwrap().push_back(err);
+        }
+
+        /// Produces the error for an HTTP request that failed with status
+        pub fn http_error(status: reqwest::StatusCode) -> ApiError {
+            HttpRequestFailed {
+                status,
+                content: String::new(),
+            }
+            .into()
+        }
+
+        fn record(&self, device: &HttpDeviceInfo, instance: &str, value: JsonValue) {
+            self.controls.lock().unwrap().push(MockControl {
+                device: device.device.to_string(),
+                instance: instance.to_string(),
+                value,
+            });
+        }
+    }
+
+    #[async_trait]
+    impl GoveeApiClientTrait for MockGoveeApiClient {
+        async fn get_devices(&self) -> anyhow::Result<Vec<HttpDeviceInfo>> {
+            Ok(self.devices.clone())
+        }
+
+        async fn get_device_state(
+            &self,
+            device: &HttpDeviceInfo,
+        ) -> anyhow::Result<HttpDeviceState> {
+            self.states
+                .get(&device.device)
+                .cloned()
+                .ok_or_else(|| anyhow::anyhow!("no state for {}", device.device))
+        }
+
+        async fn control_device(
+            &self,
+            device: &HttpDeviceInfo,
+            capability: &DeviceCapability,
+            value: JsonValue,
+        ) -> anyhow::Result<ControlDeviceResponseCapability> {
+            if let Some(err) = self.control_errors.lock().unwrap().pop_front() {
+                return Err(err.into());
+            }
+            self.record(device, &capability.instance, value.clone());
+            Ok(ControlDeviceResponseCapability {
+                kind: capability.kind.clone(),
+                instance: capability.instance.to_string(),
+                value,
+                state: json!({"status": "success"}),
+            })
+        }
+
+        async fn list_scene_names(&self, _device: &HttpDeviceInfo) -> anyhow::Result<Vec<String>> {
+            Ok(self.scenes.clone())
+        }
+
+        async fn list_scene_names_for_instance(
+            &self,
+            device: &HttpDeviceInfo,
+            instance: &str,
+        ) -> anyhow::Result<Vec<String>> {
+            Ok(scene_names_for_instance(&device.capabilities, instance))
+        }
+
+        async fn set_scene_by_name(
+            &self,
+            device: &HttpDeviceInfo,
+            scene: &str,
+        ) -> anyhow::Result<ControlDeviceResponseCapability> {
+            let Some(name) = self
+                .scenes
+                .iter()
+                .find(|name| name.eq_ignore_ascii_case(scene))
+            else {
+                return Err(ApiError::InvalidParameter(format!(
+                    "Scene '{scene}' is not available"
+                ))
+                .into());
+            };
+            self.record(device, "lightScene", json!(name));
+            Ok(ControlDeviceResponseCapability {
+                kind: DeviceCapabilityKind::DynamicScene,
+                instance: "lightScene".to_string(),
+                value: json!(name),
+                state: json!({"status": "success"}),
+            })
+        }
+
+        async fn active_scene_name(
+            &self,
+            _device: &HttpDeviceInfo,
+            _state: &HttpDeviceState,
+        ) -> anyhow::Result<Option<String>> {
+            Ok(None)
+        }
+
+        fn invalidate_diy_scenes(&self, _device: &HttpDeviceInfo) -> anyhow::Result<()> {
+            Ok(())
+        }
     }
 }
 
@@ -1121,6 +2434,148 @@ mod test {
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn scene_mode_heuristic() {
+        assert!(is_scene_mode("nightlightScene"));
+        assert!(is_scene_mode("presetScene"));
+        assert!(!is_scene_mode("fanMode"));
+        assert!(!is_scene_mode("gearMode"));
+        assert!(!is_scene_mode("windSpeed"));
+        // Overridden
+        assert!(is_scene_mode("musicMode"));
+        assert!(is_scene_mode("MusicMode"));
+    }
+
+    #[test]
+    fn active_scene_matching() {
+        let caps = vec![DeviceCapability {
+            kind: DeviceCapabilityKind::DynamicScene,
+            instance: "lightScene".to_string(),
+            parameters: Some(DeviceParameters::Enum {
+                options: vec![
+                    EnumOption {
+                        name: "Sunrise".to_string(),
+                        value: json!({"id": 10, "paramId": 100}),
+                        extras: Default::default(),
+                    },
+                    EnumOption {
+                        name: "Sunset".to_string(),
+                        value: json!({"id": 11, "paramId": 101}),
+                        extras: Default::default(),
+                    },
+                    EnumOption {
+                        name: "Movie".to_string(),
+                        value: json!(5),
+                        extras: Default::default(),
+                    },
+                ],
+            }),
+            alarm_type: None,
+            event_state: None,
+        }];
+
+        k9::assert_equal!(
+            find_scene_name(&caps, "lightScene", &json!({"paramId": 101, "id": 11})),
+            Some("Sunset".to_string())
+        );
+        k9::assert_equal!(
+            find_scene_name(&caps, "lightScene", &json!({"id": 10})),
+            Some("Sunrise".to_string())
+        );
+        k9::assert_equal!(
+            find_scene_name(&caps, "lightScene", &json!(11)),
+            Some("Sunset".to_string())
+        );
+        k9::assert_equal!(
+            find_scene_name(&caps, "lightScene", &json!(5)),
+            Some("Movie".to_string())
+        );
+        k9::assert_equal!(
+            find_scene_name(&caps, "lightScene", &json!({"id": 11, "paramId": 1})),
+            None
+        );
+        k9::assert_equal!(
+            find_scene_name(&caps, "lightScene", &json!({"id": 99})),
+            None
+        );
+
+        assert!(is_empty_scene_value(&json!("")));
+        assert!(is_empty_scene_value(&json!({})));
+        assert!(!is_empty_scene_value(&json!({"id": 10})));
+    }
+
+    #[test]
+    fn active_scene_matching_h6601() {
+        let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE).unwrap();
+        let info = resp.data.into_iter().find(|d| d.sku == "H6601").unwrap();
+        let caps: Vec<DeviceCapability> = info
+            .capabilities
+            .into_iter()
+            .filter(|cap| cap.kind == DeviceCapabilityKind::DynamicScene)
+            .collect();
+
+        k9::assert_equal!(
+            find_scene_name(&caps, "lightScene", &json!(3055)),
+            Some("Party".to_string())
+        );
+        // The state may report an object for a bare id option
+        k9::assert_equal!(
+            find_scene_name(&caps, "lightScene", &json!({"id": 3054, "paramId": 4280})),
+            Some("Tudum".to_string())
+        );
+        k9::assert_equal!(
+            find_scene_name(&caps, "diyScene", &json!(8216567)),
+            Some("Fade".to_string())
+        );
+        // Values are only compared within the reported instance
+        k9::assert_equal!(
+            find_scene_name(&caps, "snapshot", &json!(1)),
+            Some("Sunset".to_string())
+        );
+        k9::assert_equal!(find_scene_name(&caps, "lightScene", &json!(1)), None);
+    }
+
+    #[test]
+    fn device_type_short_name() {
+        k9::assert_equal!(DeviceType::Light.short_name(), "light");
+        k9::assert_equal!(DeviceType::IceMaker.short_name(), "ice_maker");
+        k9::assert_equal!(
+            DeviceType::Other("devices.types.box".to_string()).short_name(),
+            "box"
+        );
+        k9::assert_equal!(
+            DeviceType::from_short_name("Heater").unwrap(),
+            DeviceType::Heater
+        );
+        assert!(DeviceType::from_short_name("box").is_err());
+    }
+
+    #[test]
+    fn enum_option_extras() {
+        // lightScene options carry their id and paramId in the value
+        // rather than as extra fields
+        let resp: GetDeviceScenesResponse = from_json(SCENE_LIST).unwrap();
+        let Some(DeviceParameters::Enum { options }) = &resp.payload.capabilities[0].parameters
+        else {
+            panic!("expected enum parameters");
+        };
+        let opt = &options[0];
+        k9::assert_equal!(opt.value, json!({"paramId": 11837, "id": 7691}));
+        k9::assert_equal!(opt.extra_field("id"), None);
+
+        // Work mode options carry additional fields such as defaultValue
+        let cap: DeviceCapability =
+            from_json(include_str!("../test-data/work-mode-issue-100.json")).unwrap();
+        let Some(DeviceParameters::Enum { options }) =
+            cap.struct_field_by_name("modeValue").map(|f| &f.field_type)
+        else {
+            panic!("expected enum modeValue");
+        };
+        k9::assert_equal!(options[0].extra_field("defaultValue"), Some(&json!(0)));
+        assert!(options[1].extra_field("options").is_some());
+        k9::assert_equal!(options[1].extra_field("bogus"), None);
+    }
+
     const GET_DEVICE_STATE_EXAMPLE: &str = include_str!("../test-data/get_device_state.json");
 
     #[test]
@@ -1129,6 +2584,22 @@ mod test {
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn get_device_state_segmented() {
+        let resp: GetDeviceStateResponse =
+            from_json(include_str!("../test-data/get_device_state_segmented.json")).unwrap();
+        k9::assert_matches_snapshot!(format!("{:#?}", resp.payload.segment_states()));
+    }
+
+    #[test]
+    fn get_device_state_segmented_brightness_array() {
+        let resp: GetDeviceStateResponse = from_json(include_str!(
+            "../test-data/get_device_state_segmented_brightness.json"
+        ))
+        .unwrap();
+        k9::assert_matches_snapshot!(format!("{:#?}", resp.payload.segment_states()));
+    }
+
     const LIST_DEVICES_EXAMPLE: &str = include_str!("../test-data/list_devices.json");
     const LIST_DEVICES_EXAMPLE2: &str = include_str!("../test-data/list_devices_2.json");
 
@@ -1139,6 +2610,37 @@ mod test {
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn color_temperature_support() {
+        let info = |parameters: JsonValue| -> HttpDeviceInfo {
+            serde_json::from_value(json!({
+                "sku": "H6000",
+                "device": "AA:BB:CC:DD:EE:FF:00:88",
+                "capabilities": [{
+                    "type": "devices.capabilities.color_setting",
+                    "instance": "colorTemperatureK",
+                    "parameters": parameters,
+                }]
+            }))
+            .unwrap()
+        };
+
+        let ranged = info(json!({
+            "dataType": "INTEGER",
+            "range": {"min": 2000, "max": 9000, "precision": 1}
+        }));
+        assert!(ranged.supports_color_temperature());
+        k9::assert_equal!(ranged.get_color_temperature_range(), Some((2000, 9000)));
+
+        // Present, but without a range that we could use
+        let unranged = info(json!({"dataType": "ENUM", "options": []}));
+        assert!(unranged
+            .capability_by_instance("colorTemperatureK")
+            .is_some());
+        assert!(!unranged.supports_color_temperature());
+        k9::assert_equal!(unranged.get_color_temperature_range(), None);
+    }
+
     #[test]
     fn list_devices_2() {
         let resp: GetDevicesResponse = from_json(&LIST_DEVICES_EXAMPLE2).unwrap();
@@ -1151,6 +2653,759 @@ mod test {
         k9::assert_matches_snapshot!(format!("{resp:#?}"));
     }
 
+    #[test]
+    fn mode_value_serialization() {
+        k9::assert_equal!(
+            serde_json::to_value(&WorkModeValue {
+                work_mode: 1,
+                mode_value: 3,
+            })
+            .unwrap(),
+            json!({"workMode": 1, "modeValue": 3})
+        );
+        k9::assert_equal!(
+            serde_json::to_value(&MusicModeValue {
+                music_mode: 5,
+                sensitivity: 100,
+                auto_color: 1,
+            })
+            .unwrap(),
+            json!({"musicMode": 5, "sensitivity": 100, "autoColor": 1})
+        );
+    }
+
+    #[test]
+    fn struct_field_default_value() {
+        let cap: DeviceCapability = serde_json::from_value(serde_json::json!({
+            "type": "devices.capabilities.music_setting",
+            "instance": "musicMode",
+            "parameters": {
+                "dataType": "STRUCT",
+                "fields": [
+                    {
+                        "fieldName": "musicMode",
+                        "dataType": "ENUM",
+                        "options": [{"name": "Energic", "value": 5}],
+                        "required": true
+                    },
+                    {
+                        "fieldName": "sensitivity",
+                        "dataType": "INTEGER",
+                        "range": {"min": 0, "max": 100, "precision": 1},
+                        "defaultValue": 60,
+                        "required": true
+                    }
+                ]
+            }
+        }))
+        .unwrap();
+        k9::assert_equal!(
+            cap.default_value_for("sensitivity"),
+            Some(&serde_json::json!(60))
+        );
+        k9::assert_equal!(cap.default_value_for("musicMode"), None);
+        k9::assert_equal!(cap.default_value_for("bogus"), None);
+    }
+
+    #[tokio::test]
+    async fn target_temperature_is_clamped() {
+        use super::mock::MockGoveeApiClient;
+        let resp: GetDevicesResponse =
+            from_json(include_str!("../test-data/list_devices_issue4.json")).unwrap();
+        let device = resp
+            .data
+            .into_iter()
+            .find(|d| d.capability_by_instance("targetTemperature").is_some())
+            .unwrap();
+
+        let mut client = MockGoveeApiClient::with_devices(vec![device.clone()]);
+        let celsius = |t| TemperatureValue::new(t, TemperatureUnits::Celsius);
+        let reported = |value: JsonValue| -> HttpDeviceState {
+            serde_json::from_value(json!({
+                "sku": device.sku,
+                "device": device.device,
+                "capabilities": [{
+                    "type": "devices.capabilities.temperature_setting",
+                    "instance": "targetTemperature",
+                    "state": {"value": value},
+                }],
+            }))
+            .unwrap()
+        };
+
+        // The request is sent even if it matches the current state
+        client.states.insert(
+            device.device.to_string(),
+            reported(json!({"temperature": 22, "unit": "Celsius"})),
+        );
+        client
+            .set_target_temperature(&device, "targetTemperature", celsius(22.))
+            .await
+            .unwrap();
+
+        // Out of range requests are clamped
+        client
+            .set_target_temperature(&device, "targetTemperature", celsius(40.))
+            .await
+            .unwrap();
+
+        // unless the device reports a temperature outside of its
+        // advertised range, which it evidently accepts
+        client.states.insert(
+            device.device.to_string(),
+            reported(json!({"temperature": 95, "unit": "Fahrenheit"})),
+        );
+        client
+            .set_target_temperature(&device, "targetTemperature", celsius(40.))
+            .await
+            .unwrap();
+
+        // If the current state can't be queried, the advertised
+        // range applies
+        client.states.clear();
+        client
+            .set_target_temperature(&device, "targetTemperature", celsius(40.))
+            .await
+            .unwrap();
+
+        let temperatures: Vec<_> = client
+            .controls()
+            .iter()
+            .map(|c| c.value["temperature"].clone())
+            .collect();
+        k9::assert_equal!(
+            temperatures,
+            vec![json!(22.0), json!(30.0), json!(35.0), json!(30.0)]
+        );
+    }
+
+    #[test]
+    fn event_state() {
+        let resp: GetDevicesResponse =
+            from_json(include_str!("../test-data/list_devices_issue4.json")).unwrap();
+        let event = resp
+            .data
+            .iter()
+            .find_map(|d| d.capability_by_instance("lackWaterEvent"))
+            .unwrap()
+            .parsed_event_state()
+            .unwrap();
+        k9::assert_equal!(event.options.len(), 1);
+        k9::assert_equal!(event.options[0].message.as_deref(), Some("Lack of Water"));
+
+        k9::assert_equal!(event.is_active_value(&json!(1)), Some(true));
+        k9::assert_equal!(event.is_active_value(&json!("lack")), Some(true));
+        k9::assert_equal!(event.is_active_value(&json!(0)), Some(false));
+        k9::assert_equal!(event.is_active_value(&json!(2)), Some(false));
+        k9::assert_equal!(event.is_active_value(&JsonValue::Null), None);
+    }
+
+    #[test]
+    fn control_response_divergence() {
+        let resp = |value: JsonValue, status: &str| ControlDeviceResponseCapability {
+            kind: DeviceCapabilityKind::OnOff,
+            instance: "powerSwitch".to_string(),
+            value,
+            state: json!({"status": status}),
+        };
+
+        assert!(!resp(json!(1), "success").diverges_from(&json!(1)));
+        assert!(!resp(json!(1.0), "success").diverges_from(&json!(1)));
+        assert!(!resp(JsonValue::Null, "success").diverges_from(&json!(1)));
+        assert!(resp(json!(0), "success").diverges_from(&json!(1)));
+        assert!(resp(json!(1), "failure").diverges_from(&json!(1)));
+    }
+
+    #[test]
+    fn capability_by_instance_ignores_case() {
+        let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE).unwrap();
+        let info = &resp.data[0];
+        let resp: GetDeviceStateResponse = from_json(GET_DEVICE_STATE_EXAMPLE).unwrap();
+        let state = &resp.payload;
+
+        for instance in ["powerSwitch", "PowerSwitch", "powerswitch"] {
+            k9::assert_equal!(
+                info.capability_by_instance(instance)
+                    .map(|c| c.instance.as_str()),
+                Some("powerSwitch")
+            );
+            k9::assert_equal!(
+                state
+                    .capability_by_instance(instance)
+                    .map(|c| c.instance.as_str()),
+                Some("powerSwitch")
+            );
+        }
+    }
+
+    #[test]
+    fn scene_names_by_instance() {
+        let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE).unwrap();
+        let caps = &resp.data[0].capabilities;
+        k9::assert_equal!(scene_names_for_instance(caps, "diyScene"), vec!["Fade"]);
+        k9::assert_equal!(
+            scene_names_for_instance(caps, "snapshot"),
+            vec!["Sunrise", "Sunset"]
+        );
+        assert!(scene_names_for_instance(caps, "bogus").is_empty());
+    }
+
+    #[test]
+    fn segmented_brightness_fallback() {
+        let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE2).unwrap();
+        let mut info = resp
+            .data
+            .into_iter()
+            .find(|info| info.capability_by_instance("segmentedBrightness").is_some())
+            .unwrap();
+        assert!(info.supports_segmented_color());
+
+        // Without segmentedBrightness, the overall brightness range is used
+        info.capabilities
+            .retain(|cap| cap.instance != "segmentedBrightness");
+        assert!(info.capability_by_instance("brightness").is_some());
+        let range = info.supports_segmented_brightness().unwrap();
+        k9::assert_equal!((range.min, range.max), (1, 100));
+        assert!(info.supports_segmented_color());
+
+        // but only if the segments have a color that can be set
+        info.capabilities
+            .retain(|cap| cap.instance != "segmentedColorRgb");
+        assert!(info.supports_segmented_brightness().is_none());
+        assert!(!info.supports_segmented_color());
+    }
+
+    #[tokio::test]
+    async fn segment_brightness_without_segmented_brightness() {
+        use super::mock::MockGoveeApiClient;
+        let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE2).unwrap();
+        let mut info = resp
+            .data
+            .into_iter()
+            .find(|info| info.capability_by_instance("segmentedBrightness").is_some())
+            .unwrap();
+        info.capabilities
+            .retain(|cap| cap.instance != "segmentedBrightness");
+        let client = MockGoveeApiClient::with_devices(vec![info.clone()]);
+
+        client.set_segment_brightness(&info, 0, 50).await.unwrap();
+        let controls = client.controls();
+        k9::assert_equal!(controls.len(), 1);
+        k9::assert_equal!(controls[0].instance, "segmentedBrightness");
+        k9::assert_equal!(controls[0].value, json!({"segment": [0], "brightness": 50}));
+    }
+
+    mod mock_server {
+        use super::*;
+        use wiremock::matchers::{body_json, header, method, path, query_param};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        const API_KEY: &str = "test-api-key";
+
+        fn json_response(body: &str) -> ResponseTemplate {
+            ResponseTemplate::new(200).set_body_raw(body, "application/json")
+        }
+
+        fn example_device() -> HttpDeviceInfo {
+            let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE).unwrap();
+            resp.data.into_iter().next().unwrap()
+        }
+
+        #[tokio::test]
+        async fn get_devices() {
+            crate::cache::invalidate_key("http-api", "device-list").unwrap();
+
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/router/api/v1/user/devices"))
+                .and(header("Govee-API-Key", API_KEY))
+                .respond_with(json_response(LIST_DEVICES_EXAMPLE))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let devices = client.get_devices().await.unwrap();
+            let skus: Vec<_> = devices.iter().map(|d| d.sku.as_str()).collect();
+            k9::assert_equal!(skus, vec!["H6601", "H605C", "H7055"]);
+        }
+
+        #[tokio::test]
+        async fn get_devices_follows_pages() {
+            let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE).unwrap();
+            let page = |page: u32, devices: &[HttpDeviceInfo]| {
+                json!({
+                    "code": 200,
+                    "message": "success",
+                    "page": page,
+                    "totalPage": 2,
+                    "data": devices,
+                })
+            };
+
+            let server = MockServer::start().await;
+            Mock::given(method("GET"))
+                .and(path("/router/api/v1/user/devices"))
+                .and(query_param("page", "2"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page(2, &resp.data[2..])))
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("GET"))
+                .and(path("/router/api/v1/user/devices"))
+                .respond_with(ResponseTemplate::new(200).set_body_json(page(1, &resp.data[..2])))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let devices = client.get_device_pages().await.unwrap();
+            let skus: Vec<_> = devices.iter().map(|d| d.sku.as_str()).collect();
+            k9::assert_equal!(skus, vec!["H6601", "H605C", "H7055"]);
+        }
+
+        #[tokio::test]
+        async fn rate_limit_is_retried() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/state"))
+                .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+                .up_to_n_times(2)
+                .expect(2)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/state"))
+                .respond_with(json_response(GET_DEVICE_STATE_EXAMPLE))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let state = client.get_device_state(&example_device()).await.unwrap();
+            k9::assert_equal!(state.sku, "H7143");
+        }
+
+        #[tokio::test]
+        async fn server_error_is_retried() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/state"))
+                .respond_with(ResponseTemplate::new(503).insert_header("Retry-After", "0"))
+                .up_to_n_times(1)
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/state"))
+                .respond_with(json_response(GET_DEVICE_STATE_EXAMPLE))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let state = client.get_device_state(&example_device()).await.unwrap();
+            k9::assert_equal!(state.sku, "H7143");
+        }
+
+        #[tokio::test]
+        async fn transport_error_is_retried() {
+            use tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+            // Drop the first connection without responding, then
+            // answer the retry
+            let listener = tokio::net::TcpListener::bind("127.0.0.1:0").await.unwrap();
+            let addr = listener.local_addr().unwrap();
+            tokio::spawn(async move {
+                let (conn, _) = listener.accept().await.unwrap();
+                drop(conn);
+
+                let (mut conn, _) = listener.accept().await.unwrap();
+                let mut buf = vec![0u8; 8192];
+                let _ = conn.read(&mut buf).await.unwrap();
+                let response = format!(
+                    "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\n\
+                     Content-Length: {}\r\nConnection: close\r\n\r\n{GET_DEVICE_STATE_EXAMPLE}",
+                    GET_DEVICE_STATE_EXAMPLE.len()
+                );
+                conn.write_all(response.as_bytes()).await.unwrap();
+            });
+
+            let client = GoveeApiClient::with_server(API_KEY, format!("http://{addr}"));
+            let state = client.get_device_state(&example_device()).await.unwrap();
+            k9::assert_equal!(state.sku, "H7143");
+        }
+
+        #[tokio::test]
+        async fn rate_limit_gives_up() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/state"))
+                .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+                .expect(1 + MAX_RATE_LIMIT_RETRIES as u64)
+                .mount(&server)
+                .await;
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let err = client
+                .get_device_state(&example_device())
+                .await
+                .unwrap_err();
+            assert!(
+                format!("{err:#}").contains("429"),
+                "expected a 429 error, got {err:#}"
+            );
+            assert!(
+                matches!(ApiError::from_err(&err), Some(ApiError::RateLimited(_))),
+                "expected ApiError::RateLimited, got {err:#}"
+            );
+        }
+
+        #[tokio::test]
+        async fn errors_are_classified() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/state"))
+                .respond_with(ResponseTemplate::new(401).set_body_string("invalid key"))
+                .mount(&server)
+                .await;
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let err = client
+                .get_device_state(&example_device())
+                .await
+                .unwrap_err();
+            let api_err = ApiError::from_err(&err).expect("an ApiError");
+            assert!(matches!(api_err, ApiError::Unauthorized(_)), "{api_err:?}");
+            assert!(!api_err.is_transient());
+
+            // The API also reports failures in the body of a 200 response
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/state"))
+                .respond_with(json_response(r#"{"code": 404, "msg": "device not found"}"#))
+                .mount(&server)
+                .await;
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let err = client
+                .get_device_state(&example_device())
+                .await
+                .unwrap_err();
+            let api_err = ApiError::from_err(&err).expect("an ApiError");
+            assert!(
+                matches!(api_err, ApiError::DeviceNotFound(_)),
+                "{api_err:?}"
+            );
+            k9::assert_equal!(
+                HttpRequestFailed::from_err(&err).map(|f| f.status()),
+                Some(reqwest::StatusCode::NOT_FOUND)
+            );
+
+            let err = GoveeApiClient::with_server(API_KEY, "http://127.0.0.1:1")
+                .get_device_state(&example_device())
+                .await
+                .unwrap_err();
+            let api_err = ApiError::from_err(&err).expect("an ApiError");
+            assert!(matches!(api_err, ApiError::Transport(_)), "{api_err:?}");
+            assert!(api_err.is_transient());
+        }
+
+        #[tokio::test]
+        async fn control_is_retried_when_not_applied() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/control"))
+                .respond_with(json_response(
+                    r#"{"requestId": "uuid", "code": 200, "msg": "success",
+                        "capability": {"type": "devices.capabilities.on_off",
+                        "instance": "powerSwitch", "value": 0,
+                        "state": {"status": "failure"}}}"#,
+                ))
+                .expect(u64::from(MAX_CONTROL_ATTEMPTS))
+                .mount(&server)
+                .await;
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let device = example_device();
+            let capability = device.capability_by_instance("powerSwitch").unwrap();
+            let result = client.control_device(&device, capability, 1).await.unwrap();
+            // The response is returned so that the caller can
+            // report the actual state
+            k9::assert_equal!(result.value, json!(0));
+            assert!(result.diverges_from(&json!(1)));
+        }
+
+        #[tokio::test]
+        async fn control_is_not_resent_after_http_retries() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/control"))
+                .respond_with(ResponseTemplate::new(429).insert_header("Retry-After", "0"))
+                .up_to_n_times(1)
+                .expect(1)
+                .mount(&server)
+                .await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/control"))
+                .respond_with(json_response(
+                    r#"{"requestId": "uuid", "code": 200, "msg": "success",
+                        "capability": {"type": "devices.capabilities.on_off",
+                        "instance": "powerSwitch", "value": 0,
+                        "state": {"status": "failure"}}}"#,
+                ))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let device = example_device();
+            let capability = device.capability_by_instance("powerSwitch").unwrap();
+            let result = client.control_device(&device, capability, 1).await.unwrap();
+            assert!(result.diverges_from(&json!(1)));
+        }
+
+        #[tokio::test]
+        async fn segment_out_of_range_is_not_sent() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/control"))
+                .respond_with(ResponseTemplate::new(500))
+                .expect(0)
+                .mount(&server)
+                .await;
+
+            let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE2).unwrap();
+            let device = resp
+                .data
+                .into_iter()
+                .find(|info| info.supports_segmented_rgb().is_some())
+                .unwrap();
+            let segments = device.supports_segmented_rgb().unwrap();
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let err = client
+                .set_segment_rgb(&device, segments.end, 255, 0, 0)
+                .await
+                .unwrap_err();
+            let api_err = ApiError::from_err(&err).expect("an ApiError");
+            assert!(
+                matches!(api_err, ApiError::InvalidParameter(_)),
+                "{api_err:?}"
+            );
+        }
+
+        #[tokio::test]
+        async fn set_segment_multiple_rgb_groups_colors() {
+            let resp: GetDevicesResponse = from_json(LIST_DEVICES_EXAMPLE2).unwrap();
+            let device = resp
+                .data
+                .into_iter()
+                .find(|info| info.supports_segmented_rgb().is_some())
+                .unwrap();
+
+            let client = mock::MockGoveeApiClient::with_devices(vec![device.clone()]);
+            client
+                .set_segment_multiple_rgb(
+                    &device,
+                    &[(0, 255, 0, 0), (1, 0, 0, 255), (2, 255, 0, 0)],
+                )
+                .await
+                .unwrap();
+            k9::assert_equal!(client.controls().len(), 1);
+            k9::assert_equal!(
+                client.controls()[0].value,
+                json!([
+                    {"segment": [1], "rgb": 0x0000ff},
+                    {"segment": [0, 2], "rgb": 0xff0000},
+                ])
+            );
+
+            let segments = device.supports_segmented_rgb().unwrap();
+            let err = client
+                .set_segment_multiple_rgb(&device, &[(segments.end, 0, 0, 0)])
+                .await
+                .unwrap_err();
+            assert!(matches!(
+                ApiError::from_err(&err),
+                Some(ApiError::InvalidParameter(_))
+            ));
+        }
+
+        #[tokio::test]
+        async fn set_mode_validates_value() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/control"))
+                .respond_with(ResponseTemplate::new(500))
+                .expect(0)
+                .mount(&server)
+                .await;
+
+            let resp: GetDevicesResponse =
+                from_json(include_str!("../test-data/list_devices_issue4.json")).unwrap();
+            let device = resp
+                .data
+                .into_iter()
+                .find(|info| info.capability_by_instance("nightlightScene").is_some())
+                .unwrap();
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let err = client
+                .set_mode(&device, "nightlightScene", 999)
+                .await
+                .unwrap_err();
+            let api_err = ApiError::from_err(&err).expect("an ApiError");
+            assert!(
+                matches!(api_err, ApiError::InvalidParameter(_)),
+                "{api_err:?}"
+            );
+
+            // Only Mode capabilities can be set this way
+            assert!(client.set_mode(&device, "powerSwitch", 1).await.is_err());
+        }
+
+        #[tokio::test]
+        async fn dry_run_does_not_control() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/control"))
+                .respond_with(ResponseTemplate::new(500))
+                .expect(0)
+                .mount(&server)
+                .await;
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri()).with_dry_run(true);
+            let device = example_device();
+            let capability = device.capability_by_instance("powerSwitch").unwrap();
+            let result = client.control_device(&device, capability, 1).await.unwrap();
+            k9::assert_equal!(result.instance, "powerSwitch");
+            k9::assert_equal!(result.value, json!(1));
+        }
+
+        #[tokio::test]
+        async fn get_device_state_offline() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/state"))
+                .and(header("Govee-API-Key", API_KEY))
+                .respond_with(json_response(GET_DEVICE_STATE_EXAMPLE))
+                .mount(&server)
+                .await;
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let state = client.get_device_state(&example_device()).await.unwrap();
+            let online = state.capability_by_instance("online").unwrap();
+            k9::assert_equal!(online.kind, DeviceCapabilityKind::Online);
+            k9::assert_equal!(online.state, json!({"value": false}));
+        }
+
+        #[tokio::test]
+        async fn verbose_api_decodes_response() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/state"))
+                .and(header("Govee-API-Key", API_KEY))
+                .respond_with(json_response(GET_DEVICE_STATE_EXAMPLE))
+                .mount(&server)
+                .await;
+
+            let client =
+                GoveeApiClient::with_server(API_KEY, server.uri()).with_verbose_logging(true);
+            let state = client.get_device_state(&example_device()).await.unwrap();
+            k9::assert_equal!(state.sku, "H7143");
+        }
+
+        #[test]
+        fn base_url() {
+            let client = GoveeApiClient::new(API_KEY);
+            k9::assert_equal!(
+                client.endpoint("/router/api/v1/user/devices"),
+                "https://openapi.api.govee.com/router/api/v1/user/devices"
+            );
+            let client = client.with_base_url("http://localhost:8080/");
+            k9::assert_equal!(
+                client.endpoint("/router/api/v1/user/devices"),
+                "http://localhost:8080/router/api/v1/user/devices"
+            );
+        }
+
+        #[tokio::test]
+        async fn register_webhook() {
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/user/webhooks"))
+                .and(header("Govee-API-Key", API_KEY))
+                .and(body_json(serde_json::json!({
+                    "requestId": "uuid",
+                    "payload": {"url": "https://example.com/govee/webhook"},
+                })))
+                .respond_with(json_response(
+                    r#"{"requestId": "uuid", "code": 200, "msg": "success"}"#,
+                ))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            client
+                .register_webhook("https://example.com/govee/webhook")
+                .await
+                .unwrap();
+        }
+
+        #[tokio::test]
+        async fn get_device_diy_scenes() {
+            let device = example_device();
+            GoveeApiClient::new(API_KEY)
+                .invalidate_diy_scenes(&device)
+                .unwrap();
+
+            let server = MockServer::start().await;
+            Mock::given(method("POST"))
+                .and(path("/router/api/v1/device/diy-scenes"))
+                .and(header("Govee-API-Key", API_KEY))
+                .respond_with(json_response(include_str!("../test-data/diy_scenes.json")))
+                .expect(1)
+                .mount(&server)
+                .await;
+
+            let client = GoveeApiClient::with_server(API_KEY, server.uri());
+            let caps = client.get_device_diy_scenes(&device).await.unwrap();
+            k9::assert_equal!(caps.len(), 1);
+            k9::assert_equal!(caps[0].instance, "diyScene");
+            let names: Vec<_> = match &caps[0].parameters {
+                Some(DeviceParameters::Enum { options }) => {
+                    options.iter().map(|o| o.name.as_str()).collect()
+                }
+                _ => panic!("unexpected parameters {:?}", caps[0].parameters),
+            };
+            k9::assert_equal!(names, vec!["Fireplace", "Aurora Custom"]);
+        }
+    }
+
+    #[test]
+    fn integer_range_precision() {
+        let range = IntegerRange {
+            min: 0,
+            max: 100,
+            precision: 10,
+        };
+        k9::assert_equal!(range.step(), 0.1);
+        k9::assert_equal!(range.to_api_value(50.0), 500);
+        k9::assert_equal!(range.to_api_value(12.3), 123);
+        // Out of range values are clamped before scaling
+        k9::assert_equal!(range.to_api_value(150.0), 1000);
+        k9::assert_equal!(range.scale_api_value(123), 12.3);
+
+        let range = IntegerRange {
+            min: 1,
+            max: 100,
+            precision: 1,
+        };
+        k9::assert_equal!(range.step(), 1.0);
+        k9::assert_equal!(range.to_api_value(0.0), 1);
+        k9::assert_equal!(range.to_api_value(42.0), 42);
+    }
+
     #[test]
     fn enum_repr() {
         k9::assert_equal!(
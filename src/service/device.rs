@@ -1,10 +1,16 @@
 use crate::ble::NotifyHumidifierNightlightParams;
-use crate::commands::serve::POLL_INTERVAL;
-use crate::lan_api::{DeviceColor, DeviceStatus as LanDeviceStatus, LanDevice};
+use crate::brightness::BrightnessCurve;
+use crate::lan_api::{
+    ColorStateEncoding, ColorTemperatureEncoding, DeviceColor, DeviceStatus as LanDeviceStatus,
+    LanDevice,
+};
 use crate::platform_api::{
-    DeviceCapability, DeviceCapabilityState, DeviceType, HttpDeviceInfo, HttpDeviceState,
+    ControlDeviceResponseCapability, DeviceCapability, DeviceCapabilityState, DeviceType,
+    HttpDeviceInfo, HttpDeviceState, SegmentState,
 };
+use crate::service::product_names::product_name;
 use crate::service::quirks::{resolve_quirk, Quirk, BULB};
+use crate::temperature::TemperatureScale;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 use std::borrow::Cow;
@@ -22,6 +28,8 @@ pub struct Device {
     pub last_lan_device_update: Option<DateTime<Utc>>,
 
     pub lan_device_status: Option<LanDeviceStatus>,
+    /// Detected from the status reported by the device
+    lan_color_temperature_encoding: Option<ColorTemperatureEncoding>,
     pub last_lan_device_status_update: Option<DateTime<Utc>>,
 
     pub http_device_info: Option<HttpDeviceInfo>,
@@ -36,6 +44,10 @@ pub struct Device {
     pub iot_device_status: Option<LanDeviceStatus>,
     pub last_iot_device_status_update: Option<DateTime<Utc>>,
 
+    /// The bluetooth address from which we heard the device
+    /// advertising its state
+    pub ble_address: Option<String>,
+
     pub nightlight_state: Option<NotifyHumidifierNightlightParams>,
     pub target_humidity_percent: Option<u8>,
     pub humidifier_work_mode: Option<u8>,
@@ -43,18 +55,110 @@ pub struct Device {
 
     pub last_polled: Option<DateTime<Utc>>,
 
+    /// If true, the device state is pushed to us via the IoT API,
+    /// so we don't need to poll it while those pushes are arriving
+    pub push_updated: bool,
+
+    /// Overrides the global brightness curve for this device
+    pub brightness_curve: Option<BrightnessCurve>,
+
+    /// Overrides how the colorRgb state reported by the
+    /// platform API is decoded for this device
+    pub color_state_encoding: Option<ColorStateEncoding>,
+
+    /// Overrides the global availability timeout for this device
+    pub availability_timeout: Option<chrono::Duration>,
+
+    /// Overrides the global temperature scale for this device
+    pub temperature_scale: Option<TemperatureScale>,
+
+    /// The most recently reported non-zero brightness.
+    /// Some devices report a brightness of 0 while they are off,
+    /// so we remember the prior level in order to report it instead.
+    pub last_nonzero_brightness: Option<u8>,
+
+    /// The outcome of the most recent control request for this device
+    pub last_control_result: Option<ControlResult>,
+
+    /// Set when the device was just powered on, as it restores its
+    /// prior brightness and color which we need to fetch in order
+    /// for hass to show them
+    refresh_after_power_on: bool,
+
+    /// The segments that accepted a color when they were probed
+    /// at startup; when set, only these segments are published
+    pub responsive_segments: Option<Vec<u32>>,
+
+    /// Values sent via the Platform API since the last poll, which
+    /// are compared with the polled state to detect conflicts
+    sent_values: Vec<SentValue>,
+
     active_scene: Option<ActiveSceneInfo>,
 }
 
+/// Records whether a control request succeeded, so that it can be
+/// reported to hass
+#[derive(Serialize, Clone, Debug)]
+pub struct ControlResult {
+    pub updated: DateTime<Utc>,
+    /// The error message, if the request failed
+    pub error: Option<String>,
+}
+
+/// A value that we asked the device to adopt
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SentValue {
+    Power(bool),
+    Brightness(u8),
+    Color(DeviceColor),
+    ColorTemperature(u32),
+}
+
+impl SentValue {
+    /// The Platform API instance that reports this value
+    fn instance(&self) -> &'static str {
+        match self {
+            Self::Power(_) => "powerSwitch",
+            Self::Brightness(_) => "brightness",
+            Self::Color(_) => "colorRgb",
+            Self::ColorTemperature(_) => "colorTemperatureK",
+        }
+    }
+
+    /// Returns the corresponding value from the device state
+    fn reported_in(&self, state: &DeviceState) -> Self {
+        match self {
+            Self::Power(_) => Self::Power(state.on),
+            Self::Brightness(_) => Self::Brightness(state.brightness),
+            Self::Color(_) => Self::Color(state.color),
+            Self::ColorTemperature(_) => Self::ColorTemperature(state.kelvin),
+        }
+    }
+}
+
+impl std::fmt::Display for SentValue {
+    fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
+        match self {
+            Self::Power(on) => write!(fmt, "power {}", if *on { "on" } else { "off" }),
+            Self::Brightness(percent) => write!(fmt, "brightness {percent}%"),
+            Self::Color(color) => {
+                write!(fmt, "color #{:02x}{:02x}{:02x}", color.r, color.g, color.b)
+            }
+            Self::ColorTemperature(kelvin) => write!(fmt, "color temperature {kelvin}K"),
+        }
+    }
+}
+
 impl std::fmt::Display for Device {
     fn fmt(&self, fmt: &mut std::fmt::Formatter) -> std::fmt::Result {
         write!(fmt, "{} ({} {})", self.name(), self.id, self.sku)
     }
 }
 
-/// Govee doesn't report the active scene or music mode,
+/// Govee doesn't reliably report the active scene or music mode,
 /// so we maintain our own idea of it, clearing it when
-/// the color of the light is changed
+/// the color of the light is changed. The platform API may
+/// report the lightScene value, which updates this when polled.
 #[derive(Clone, Debug)]
 struct ActiveSceneInfo {
     pub name: String,
@@ -124,10 +228,10 @@ impl Device {
 
     /// Returns the name defined for the device in the Govee App
     pub fn govee_name(&self) -> Option<&str> {
-        if let Some(info) = &self.http_device_info {
-            return Some(&info.device_name);
-        }
-        None
+        self.http_device_info
+            .as_ref()
+            .map(|info| info.device_name.as_str())
+            .filter(|name| !name.trim().is_empty())
     }
 
     pub fn room_name(&self) -> Option<&str> {
@@ -141,7 +245,22 @@ impl Device {
     /// device id, similar to the device name that would show up in a BLE
     /// scan, or the default name for the device if not otherwise configured
     /// in the Govee App.
+    /// If we know the product name for the SKU, that is used in place
+    /// of the SKU.
     pub fn computed_name(&self) -> String {
+        match product_name(&self.sku) {
+            Some(product) => format!("{product} {}", self.id_suffix()),
+            None => self.sku_name(),
+        }
+    }
+
+    /// compute a name from the SKU and the last couple of bytes
+    /// from the device id, eg: H6000_422A
+    pub fn sku_name(&self) -> String {
+        format!("{}_{}", self.sku, self.id_suffix())
+    }
+
+    fn id_suffix(&self) -> String {
         // The id is usually "XX:XX:XX:XX:XX:XX:XX:XX" but some devices
         // report it without colons, and in lowercase.  Normalize it.
         let mut id = String::new();
@@ -151,21 +270,22 @@ impl Device {
             }
             id.push(c.to_ascii_uppercase());
         }
-
-        format!("{}_{}", self.sku, &id[id.len().saturating_sub(4)..])
+        id[id.len().saturating_sub(4)..].to_string()
     }
 
-    pub fn preferred_poll_interval(&self) -> chrono::Duration {
+    /// Returns how often to poll this device, given the configured
+    /// `poll_interval`
+    pub fn preferred_poll_interval(&self, poll_interval: chrono::Duration) -> chrono::Duration {
         match self.device_type() {
             // If the kettle is on, read its temperature more frequently
             DeviceType::Kettle => {
                 if self.device_state().map(|s| s.on).unwrap_or(false) {
-                    chrono::Duration::seconds(60)
+                    chrono::Duration::seconds(60).min(poll_interval)
                 } else {
-                    *POLL_INTERVAL
+                    poll_interval
                 }
             }
-            _ => *POLL_INTERVAL,
+            _ => poll_interval,
         }
     }
 
@@ -177,6 +297,136 @@ impl Device {
         self.last_polled.replace(Utc::now());
     }
 
+    pub fn request_refresh_after_power_on(&mut self) {
+        self.refresh_after_power_on = true;
+    }
+
+    /// Returns true, and clears the request, if the device was
+    /// powered on since the last time this was called
+    pub fn take_refresh_after_power_on(&mut self) -> bool {
+        std::mem::take(&mut self.refresh_after_power_on)
+    }
+
+    /// Records a value sent to the device, replacing any earlier
+    /// value of the same kind
+    pub fn note_sent_value(&mut self, value: SentValue) {
+        self.sent_values
+            .retain(|v| std::mem::discriminant(v) != std::mem::discriminant(&value));
+        self.sent_values.push(value);
+    }
+
+    /// Compares the values sent since the last poll with the polled
+    /// state, returning the (sent, polled) pairs that differ, and
+    /// forgets the sent values. Values that the state doesn't
+    /// report are not considered to be in conflict.
+    pub fn take_state_conflicts(&mut self) -> Vec<(SentValue, SentValue)> {
+        let sent = std::mem::take(&mut self.sent_values);
+        let (Some(http_state), Some(polled)) =
+            (&self.http_device_state, self.compute_http_device_state())
+        else {
+            return vec![];
+        };
+
+        sent.into_iter()
+            .filter(|value| {
+                http_state
+                    .capability_by_instance(value.instance())
+                    .is_some()
+            })
+            .map(|value| (value, value.reported_in(&polled)))
+            .filter(|(sent, polled)| sent != polled)
+            .collect()
+    }
+
+    pub fn set_last_control_result(&mut self, error: Option<String>) {
+        self.last_control_result.replace(ControlResult {
+            updated: Utc::now(),
+            error,
+        });
+    }
+
+    pub fn set_brightness_curve(&mut self, curve: BrightnessCurve) {
+        self.brightness_curve.replace(curve);
+    }
+
+    pub fn set_responsive_segments(&mut self, segments: Vec<u32>) {
+        self.responsive_segments.replace(segments);
+    }
+
+    pub fn set_temperature_scale(&mut self, scale: TemperatureScale) {
+        self.temperature_scale.replace(scale);
+    }
+
+    pub fn set_color_state_encoding(&mut self, encoding: ColorStateEncoding) {
+        self.color_state_encoding.replace(encoding);
+    }
+
+    /// Decodes a colorRgb value reported by the platform API
+    pub fn decode_color_state(&self, value: u32) -> DeviceColor {
+        self.color_state_encoding.unwrap_or_default().decode(value)
+    }
+
+    pub fn set_availability_timeout(&mut self, timeout: chrono::Duration) {
+        self.availability_timeout.replace(timeout);
+    }
+
+    /// Returns false if we haven't heard from the device within its
+    /// availability timeout, falling back to default_timeout if it
+    /// doesn't have its own. Devices that we have yet to hear from
+    /// are considered to be available.
+    pub fn is_available(&self, default_timeout: Option<chrono::Duration>) -> bool {
+        let Some(timeout) = self.availability_timeout.or(default_timeout) else {
+            return true;
+        };
+        match self.device_state() {
+            Some(state) => Utc::now() - state.updated <= timeout,
+            None => true,
+        }
+    }
+
+    pub fn set_push_updated(&mut self, push_updated: bool) {
+        self.push_updated = push_updated;
+    }
+
+    /// Returns true if the device is push-updated and we have received
+    /// a push within the staleness window, which means that there is
+    /// no need to poll it.
+    pub fn has_fresh_push_update(&self, staleness: chrono::Duration) -> bool {
+        if !self.push_updated {
+            return false;
+        }
+        match &self.last_iot_device_status_update {
+            Some(last) => Utc::now() - *last < staleness,
+            None => false,
+        }
+    }
+
+    fn record_nonzero_brightness(&mut self) {
+        if let Some(state) = self.device_state() {
+            if state.brightness != 0 {
+                self.last_nonzero_brightness.replace(state.brightness);
+            }
+        }
+    }
+
+    /// Returns the brightness that should be reported for the device;
+    /// the current brightness, unless that is zero, in which case the
+    /// last known non-zero brightness
+    pub fn reported_brightness(&self) -> Option<u8> {
+        match self.device_state().map(|s| s.brightness) {
+            Some(0) | None => self.last_nonzero_brightness,
+            Some(brightness) => Some(brightness),
+        }
+    }
+
+    /// Returns the color and brightness of the specified segment,
+    /// if the platform API reported it
+    pub fn segment_state(&self, segment: u32) -> Option<SegmentState> {
+        self.http_device_state
+            .as_ref()
+            .and_then(|state| state.segment_states().remove(&segment))
+    }
+
     pub fn set_nightlight_state(&mut self, params: NotifyHumidifierNightlightParams) {
         self.nightlight_state.replace(params);
     }
@@ -203,9 +453,13 @@ impl Device {
             .as_ref()
             .map(|prior| *prior != status)
             .unwrap_or(true);
+        if let Some(encoding) = ColorTemperatureEncoding::detect(status.color_temperature_kelvin) {
+            self.lan_color_temperature_encoding.replace(encoding);
+        }
         self.lan_device_status.replace(status);
         self.last_lan_device_status_update.replace(Utc::now());
         self.clear_scene_if_color_changed();
+        self.record_nonzero_brightness();
         changed
     }
 
@@ -213,6 +467,7 @@ impl Device {
         self.iot_device_status.replace(status);
         self.last_iot_device_status_update.replace(Utc::now());
         self.clear_scene_if_color_changed();
+        self.record_nonzero_brightness();
     }
 
     pub fn set_http_device_info(&mut self, info: HttpDeviceInfo) {
@@ -224,6 +479,41 @@ impl Device {
         self.http_device_state.replace(state);
         self.last_http_device_state_update.replace(Utc::now());
         self.clear_scene_if_color_changed();
+        self.record_nonzero_brightness();
+    }
+
+    /// Updates the platform API state with the value that a control
+    /// response says was applied, so that we report that rather than
+    /// the value that we requested.
+    pub fn apply_control_response(&mut self, resp: &ControlDeviceResponseCapability) {
+        if resp.value.is_null() || !resp.succeeded() {
+            return;
+        }
+        let Some(state) = self.http_device_state.as_mut() else {
+            return;
+        };
+
+        let value = serde_json::json!({"value": resp.value});
+        match state
+            .capabilities
+            .iter_mut()
+            .find(|c| c.instance.eq_ignore_ascii_case(&resp.instance))
+        {
+            Some(cap) => cap.state = value,
+            None => state.capabilities.push(DeviceCapabilityState {
+                kind: resp.kind.clone(),
+                instance: resp.instance.clone(),
+                state: value,
+            }),
+        }
+        self.last_http_device_state_update.replace(Utc::now());
+        self.clear_scene_if_color_changed();
+        self.record_nonzero_brightness();
+    }
+
+    #[cfg(feature = "ble")]
+    pub fn set_ble_address(&mut self, address: String) {
+        self.ble_address.replace(address);
     }
 
     pub fn set_undoc_device_info(
@@ -245,7 +535,7 @@ impl Device {
 
         Some(DeviceState {
             on: status.on,
-            light_on: if self.device_type() == DeviceType::Light {
+            light_on: if self.power_is_light() {
                 Some(status.on)
             } else {
                 self.nightlight_state.as_ref().map(|s| s.on)
@@ -260,17 +550,33 @@ impl Device {
         })
     }
 
+    /// Returns the encoding used by the colorTemInKelvin LAN API field
+    pub fn lan_color_temperature_encoding(&self) -> ColorTemperatureEncoding {
+        self.lan_color_temperature_encoding.unwrap_or_default()
+    }
+
+    /// Returns the kelvin range used to map to and from the
+    /// LAN API warmth encoding
+    pub fn lan_color_temperature_range(&self) -> (u32, u32) {
+        self.get_color_temperature_range().unwrap_or((2000, 9000))
+    }
+
     pub fn compute_lan_device_state(&self) -> Option<DeviceState> {
         let updated = self.last_lan_device_status_update?;
         let status = self.lan_device_status.as_ref()?;
+        let kelvin = self.lan_color_temperature_encoding().decode(
+            status.color_temperature_kelvin,
+            self.lan_color_temperature_range(),
+        );
 
         Some(DeviceState {
             on: status.on,
             light_on: Some(status.on), // assumption: LAN API == light
-            online: None,
+            // We only have a status if the device responded to us
+            online: Some(true),
             brightness: status.brightness,
             color: status.color,
-            kelvin: status.color_temperature_kelvin,
+            kelvin,
             scene: self.active_scene.as_ref().map(|info| info.name.to_string()),
             source: "LAN API",
             updated,
@@ -314,11 +620,7 @@ impl Device {
                         on = value.value != 0;
                     }
                     "colorRgb" => {
-                        color = DeviceColor {
-                            r: ((value.value >> 16) & 0xff) as u8,
-                            g: ((value.value >> 8) & 0xff) as u8,
-                            b: (value.value & 0xff) as u8,
-                        };
+                        color = self.decode_color_state(value.value);
                     }
                     "brightness" => {
                         brightness = value.value as u8;
@@ -499,9 +801,15 @@ impl Device {
             .and_then(|info| info.capability_by_instance(instance))
     }
 
+    /// Returns true if the power of the device as a whole is what
+    /// a light entity for it turns on and off
+    pub fn power_is_light(&self) -> bool {
+        matches!(self.device_type(), DeviceType::Light | DeviceType::Socket)
+    }
+
     pub fn get_light_power_toggle_instance_name(&self) -> Option<&'static str> {
         match self.device_type() {
-            DeviceType::Light => Some("powerSwitch"),
+            DeviceType::Light | DeviceType::Socket => Some("powerSwitch"),
             _ => {
                 // If the device's primary function is not a light,
                 // then we need to avoid powering on its other function
@@ -536,6 +844,31 @@ impl Device {
             .and_then(|info| info.get_color_temperature_range())
     }
 
+    /// Follows the same precedence as get_color_temperature_range,
+    /// so that the two always agree
+    pub fn supports_color_temperature(&self) -> bool {
+        if let Some(quirk) = self.resolve_quirk() {
+            return quirk.color_temp_range.is_some();
+        }
+
+        if self.lan_device.is_some() {
+            return true;
+        }
+
+        self.http_device_info
+            .as_ref()
+            .map(|info| info.supports_color_temperature())
+            .unwrap_or(false)
+    }
+
+    /// If the device supports color temperature, returns the specified
+    /// kelvin value clamped to the range supported by the device.
+    /// Used to render white using color temperature rather than rgb.
+    pub fn white_color_temperature(&self, kelvin: u32) -> Option<u32> {
+        let (min, max) = self.get_color_temperature_range()?;
+        Some(kelvin.clamp(min, max))
+    }
+
     pub fn supports_brightness(&self) -> bool {
         if let Some(quirk) = self.resolve_quirk() {
             return quirk.supports_brightness;
@@ -607,6 +940,30 @@ impl Device {
 mod test {
     use super::*;
 
+    #[test]
+    fn color_state_override() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        // Red, packed as 0xRRGGBB and as hue 0, 100% saturation and value
+        k9::assert_equal!(
+            device.decode_color_state(0xff0000),
+            DeviceColor { r: 255, g: 0, b: 0 }
+        );
+        k9::assert_equal!(
+            device.decode_color_state(0x006464),
+            DeviceColor {
+                r: 0,
+                g: 0x64,
+                b: 0x64
+            }
+        );
+
+        device.set_color_state_encoding(ColorStateEncoding::Hsv);
+        k9::assert_equal!(
+            device.decode_color_state(0x006464),
+            DeviceColor { r: 255, g: 0, b: 0 }
+        );
+    }
+
     #[test]
     fn name_compute() {
         let device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
@@ -617,5 +974,186 @@ mod test {
 
         let device = Device::new("H6127", "ce");
         assert_eq!(device.name(), "H6127_CE");
+
+        let mut device = Device::new("H6072", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(device.name(), "RGBIC Floor Lamp 422A");
+        assert_eq!(device.sku_name(), "H6072_422A");
+
+        // A blank name in the Govee App is ignored
+        device.set_http_device_info(HttpDeviceInfo {
+            sku: "H6072".to_string(),
+            device: "AA:BB:CC:DD:EE:FF:42:2A".to_string(),
+            device_name: " ".to_string(),
+            device_type: DeviceType::Light,
+            capabilities: vec![],
+        });
+        assert_eq!(device.name(), "RGBIC Floor Lamp 422A");
+    }
+
+    #[test]
+    fn push_updated_staleness() {
+        let staleness = chrono::Duration::seconds(3600);
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.set_iot_device_status(LanDeviceStatus::default());
+        assert!(!device.has_fresh_push_update(staleness), "not marked");
+
+        device.set_push_updated(true);
+        assert!(device.has_fresh_push_update(staleness), "recent push");
+
+        device.last_iot_device_status_update = Some(Utc::now() - chrono::Duration::seconds(7200));
+        assert!(!device.has_fresh_push_update(staleness), "stale push");
+
+        device.last_iot_device_status_update = None;
+        assert!(!device.has_fresh_push_update(staleness), "no push yet");
+    }
+
+    #[test]
+    fn availability_timeout() {
+        let hour = chrono::Duration::seconds(3600);
+        let mut device = Device::new("H5179", "AA:BB:CC:DD:EE:FF:42:2B");
+        assert!(device.is_available(Some(hour)), "not heard from yet");
+
+        device.set_iot_device_status(LanDeviceStatus::default());
+        device.last_iot_device_status_update = Some(Utc::now() - chrono::Duration::seconds(7200));
+        assert!(device.is_available(None), "no timeout");
+        assert!(!device.is_available(Some(hour)), "default timeout");
+
+        device.set_availability_timeout(chrono::Duration::seconds(3 * 3600));
+        assert!(device.is_available(Some(hour)), "device timeout overrides");
+    }
+
+    #[test]
+    fn brightness_zero_while_off() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(device.reported_brightness(), None);
+
+        device.set_iot_device_status(LanDeviceStatus {
+            on: true,
+            brightness: 42,
+            ..LanDeviceStatus::default()
+        });
+        assert_eq!(device.reported_brightness(), Some(42));
+
+        device.set_iot_device_status(LanDeviceStatus {
+            on: false,
+            brightness: 0,
+            ..LanDeviceStatus::default()
+        });
+        assert_eq!(device.reported_brightness(), Some(42));
+
+        device.set_iot_device_status(LanDeviceStatus {
+            on: true,
+            brightness: 10,
+            ..LanDeviceStatus::default()
+        });
+        assert_eq!(device.reported_brightness(), Some(10));
+    }
+
+    #[test]
+    fn state_conflicts() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.note_sent_value(SentValue::Brightness(20));
+        device.note_sent_value(SentValue::Brightness(50));
+        device.note_sent_value(SentValue::Power(true));
+        device.note_sent_value(SentValue::Color(DeviceColor { r: 255, g: 0, b: 0 }));
+
+        device.set_http_device_state(
+            serde_json::from_value(serde_json::json!({
+                "sku": "H6000",
+                "device": "AA:BB:CC:DD:EE:FF:42:2A",
+                "capabilities": [{
+                    "type": "devices.capabilities.on_off",
+                    "instance": "powerSwitch",
+                    "state": {"value": 1},
+                }, {
+                    "type": "devices.capabilities.range",
+                    "instance": "brightness",
+                    "state": {"value": 80},
+                }],
+            }))
+            .unwrap(),
+        );
+
+        // Only the most recent brightness counts, and the color
+        // isn't reported so it can't conflict
+        let conflicts = device.take_state_conflicts();
+        k9::assert_equal!(
+            conflicts,
+            vec![(SentValue::Brightness(50), SentValue::Brightness(80))]
+        );
+        k9::assert_equal!(
+            format!("{} {}", conflicts[0].0, conflicts[0].1),
+            "brightness 50% brightness 80%"
+        );
+        assert!(device.take_state_conflicts().is_empty());
+    }
+
+    #[test]
+    fn control_response_is_applied() {
+        use crate::platform_api::DeviceCapabilityKind;
+
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        device.set_http_device_state(
+            serde_json::from_value(serde_json::json!({
+                "sku": "H6000",
+                "device": "AA:BB:CC:DD:EE:FF:42:2A",
+                "capabilities": [{
+                    "type": "devices.capabilities.on_off",
+                    "instance": "powerSwitch",
+                    "state": {"value": 1},
+                }, {
+                    "type": "devices.capabilities.range",
+                    "instance": "brightness",
+                    "state": {"value": 80},
+                }],
+            }))
+            .unwrap(),
+        );
+
+        let resp = |instance: &str, value, status: &str| ControlDeviceResponseCapability {
+            kind: DeviceCapabilityKind::Range,
+            instance: instance.to_string(),
+            value,
+            state: serde_json::json!({"status": status}),
+        };
+
+        // The device clamped the requested brightness
+        device.apply_control_response(&resp("brightness", serde_json::json!(40), "success"));
+        k9::assert_equal!(device.device_state().unwrap().brightness, 40);
+
+        // A failed control leaves the prior state in place
+        device.apply_control_response(&resp("brightness", serde_json::json!(10), "failure"));
+        k9::assert_equal!(device.device_state().unwrap().brightness, 40);
+
+        // Values that weren't previously reported are added
+        device.apply_control_response(&resp(
+            "colorTemperatureK",
+            serde_json::json!(3000),
+            "success",
+        ));
+        k9::assert_equal!(device.device_state().unwrap().kelvin, 3000);
+    }
+
+    #[test]
+    fn refresh_after_power_on() {
+        let mut device = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert!(!device.take_refresh_after_power_on());
+
+        device.request_refresh_after_power_on();
+        assert!(device.take_refresh_after_power_on());
+        // Only the first poll after the power on acts on it
+        assert!(!device.take_refresh_after_power_on());
+    }
+
+    #[test]
+    fn white_color_temperature() {
+        // The quirk for this light supports 2000-9000K
+        let device = Device::new("H6003", "AA:BB:CC:DD:EE:FF:42:2A");
+        assert_eq!(device.white_color_temperature(5000), Some(5000));
+        assert_eq!(device.white_color_temperature(10000), Some(9000));
+
+        // Humidifiers have no color temperature support
+        let device = Device::new("H7160", "AA:BB:CC:DD:EE:FF:42:2B");
+        assert_eq!(device.white_color_temperature(5000), None);
     }
 }
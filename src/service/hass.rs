@@ -1,12 +1,14 @@
 use crate::hass_mqtt::climate::mqtt_set_temperature;
+use crate::hass_mqtt::cover::mqtt_cover_command;
 use crate::hass_mqtt::enumerator::{enumerate_all_entites, enumerate_entities_for_device};
 use crate::hass_mqtt::humidifier::{mqtt_device_set_work_mode, mqtt_humidifier_set_target};
 use crate::hass_mqtt::instance::EntityList;
-use crate::hass_mqtt::number::mqtt_number_command;
-use crate::hass_mqtt::select::mqtt_set_mode_scene;
-use crate::lan_api::DeviceColor;
+use crate::hass_mqtt::number::{mqtt_number_command, mqtt_range_number_command};
+use crate::hass_mqtt::select::{mqtt_set_mode, mqtt_set_mode_scene};
+use crate::hass_mqtt::switch::mqtt_keep_warm_command;
+use crate::lan_api::{truthy, DeviceColor};
 use crate::opt_env_var;
-use crate::platform_api::{from_json, DeviceType};
+use crate::platform_api::from_json;
 use crate::service::device::Device as ServiceDevice;
 use crate::service::state::StateHandle;
 use crate::temperature::TemperatureScale;
@@ -14,11 +16,34 @@ use anyhow::Context;
 use async_channel::Receiver;
 use mosquitto_rs::router::{MqttRouter, Params, Payload, State};
 use mosquitto_rs::{Client, Event, QoS};
+use parking_lot::Mutex;
 use serde::{Deserialize, Serialize};
+use serde_json::Value as JsonValue;
+use std::collections::{BTreeMap, BTreeSet, HashMap};
 use std::sync::Arc;
 use std::time::Duration;
 
 const HASS_REGISTER_DELAY: tokio::time::Duration = tokio::time::Duration::from_secs(15);
+/// How long to wait after hass announces that it is online before
+/// re-announcing our entities
+const HASS_BIRTH_DELAY: Duration = Duration::from_secs(2);
+const DEFAULT_HASS_DISCOVERY_DELAY_MS: u64 = 100;
+const DEFAULT_HASS_DISCOVERY_BATCH_SIZE: usize = 10;
+/// How long to keep collecting discovery configs before publishing
+/// them; the window restarts each time another config arrives
+const HASS_DISCOVERY_DEBOUNCE: Duration = Duration::from_millis(500);
+/// Published to a per-entity discovery topic to have hass move the
+/// entity over to the device-based discovery config that follows
+const MIGRATE_DISCOVERY_PAYLOAD: &str = r#"{"migrate_discovery": true}"#;
+const DEFAULT_MQTT_CONNECT_TIMEOUT_SECS: u64 = 300;
+const MQTT_CONNECT_INITIAL_BACKOFF: Duration = Duration::from_secs(2);
+const MQTT_CONNECT_MAX_BACKOFF: Duration = Duration::from_secs(60);
+/// Emulated transitions step at most this often, and use at most
+/// this many steps, to bound the number of requests that they make
+const TRANSITION_MIN_STEP_INTERVAL: Duration = Duration::from_secs(1);
+const TRANSITION_MAX_STEPS: u32 = 10;
+/// The longest transition that hass allows
+const TRANSITION_MAX_DURATION: Duration = Duration::from_secs(6553);
 
 #[derive(clap::Parser, Debug)]
 pub struct HassArguments {
@@ -56,6 +81,41 @@ pub struct HassArguments {
     /// variable.
     #[arg(long, global = true)]
     temperature_scale: Option<String>,
+
+    /// How many milliseconds to wait per entity config published to
+    /// home assistant during discovery. The wait happens between
+    /// batches, so a batch of 10 configs is followed by a wait of 10
+    /// times this value. A random jitter of up to half of the wait
+    /// is added.
+    /// Increase this if your broker or home assistant is overwhelmed
+    /// at startup.
+    /// You may also set this via the GOVEE_HASS_DISCOVERY_DELAY_MS
+    /// environment variable. If unspecified, uses 100.
+    #[arg(long, global = true)]
+    hass_discovery_delay_ms: Option<u64>,
+
+    /// How many entity configs to publish to home assistant at a
+    /// time during discovery.
+    /// You may also set this via the GOVEE_HASS_DISCOVERY_BATCH_SIZE
+    /// environment variable. If unspecified, uses 10.
+    #[arg(long, global = true)]
+    hass_discovery_batch_size: Option<usize>,
+
+    /// Publish a single device-based discovery config for each device,
+    /// containing all of its entities, rather than one config per
+    /// entity. This requires Home Assistant 2024.11 or later.
+    /// You may also set this via the GOVEE_HASS_DEVICE_DISCOVERY
+    /// environment variable.
+    #[arg(long, global = true)]
+    hass_device_discovery: bool,
+
+    /// How many seconds to keep retrying the initial connection to
+    /// the mqtt broker before giving up. Useful when the broker is
+    /// started at the same time as govee2mqtt.
+    /// You may also set this via the GOVEE_MQTT_CONNECT_TIMEOUT_SECS
+    /// environment variable. If unspecified, uses 300.
+    #[arg(long, global = true)]
+    mqtt_connect_timeout_secs: Option<u64>,
 }
 
 impl HassArguments {
@@ -96,6 +156,51 @@ impl HassArguments {
         }
     }
 
+    pub fn mqtt_bind_address(&self) -> Option<&str> {
+        self.mqtt_bind_address.as_deref()
+    }
+
+    pub fn hass_discovery_prefix(&self) -> &str {
+        &self.hass_discovery_prefix
+    }
+
+    pub fn hass_discovery_delay(&self) -> anyhow::Result<Duration> {
+        let ms = match self.hass_discovery_delay_ms {
+            Some(ms) => ms,
+            None => opt_env_var("GOVEE_HASS_DISCOVERY_DELAY_MS")?
+                .unwrap_or(DEFAULT_HASS_DISCOVERY_DELAY_MS),
+        };
+        Ok(Duration::from_millis(ms))
+    }
+
+    pub fn hass_discovery_batch_size(&self) -> anyhow::Result<usize> {
+        let size = match self.hass_discovery_batch_size {
+            Some(size) => size,
+            None => opt_env_var("GOVEE_HASS_DISCOVERY_BATCH_SIZE")?
+                .unwrap_or(DEFAULT_HASS_DISCOVERY_BATCH_SIZE),
+        };
+        if size == 0 {
+            anyhow::bail!("The hass discovery batch size must be at least 1");
+        }
+        Ok(size)
+    }
+
+    pub fn mqtt_connect_timeout(&self) -> anyhow::Result<Duration> {
+        let secs = match self.mqtt_connect_timeout_secs {
+            Some(secs) => secs,
+            None => opt_env_var("GOVEE_MQTT_CONNECT_TIMEOUT_SECS")?
+                .unwrap_or(DEFAULT_MQTT_CONNECT_TIMEOUT_SECS),
+        };
+        Ok(Duration::from_secs(secs))
+    }
+
+    pub fn hass_device_discovery(&self) -> anyhow::Result<bool> {
+        if let Some(v) = opt_env_var::<String>("GOVEE_HASS_DEVICE_DISCOVERY")? {
+            return Ok(self.hass_device_discovery || truthy(&v)?);
+        }
+        Ok(self.hass_device_discovery)
+    }
+
     pub fn temperature_scale(&self) -> anyhow::Result<TemperatureScale> {
         match &self.temperature_scale {
             Some(s) => Ok(s.parse()?),
@@ -109,15 +214,51 @@ impl HassArguments {
 #[derive(Clone)]
 pub struct HassClient {
     client: Client,
+    /// The most recently published payload for each of our state
+    /// topics, so that we can log what changed
+    last_state: Arc<Mutex<HashMap<String, String>>>,
+    /// The discovery configs that we have published, keyed by topic,
+    /// so that we can re-announce them when hass restarts
+    discovery_configs: Arc<Mutex<BTreeMap<String, String>>>,
+    /// Discovery configs that are waiting to be published by
+    /// flush_discovery_configs
+    pending_configs: Arc<Mutex<Vec<(String, String)>>>,
+    /// The per-entity discovery topics that we have already migrated
+    /// to device-based discovery and cleared, so that subsequent
+    /// registration passes only need to publish the device configs
+    migrated_topics: Arc<Mutex<BTreeSet<String>>>,
+    /// Tests record what would be published here, rather than
+    /// sending it to a broker
+    #[cfg(test)]
+    published: Option<PublishedMessages>,
 }
 
+/// The topic and payload of each message that a test published
+#[cfg(test)]
+type PublishedMessages = Arc<Mutex<Vec<(String, String)>>>;
+
 impl HassClient {
+    /// Called when hass announces that it has (re)started. Our
+    /// entities are re-announced, or registered for the first time
+    /// if we haven't yet published any configs.
+    async fn on_hass_birth(&self, state: &StateHandle) -> anyhow::Result<()> {
+        if !self.reannounce_to_hass(state).await? {
+            self.register_with_hass(state).await?;
+        }
+        Ok(())
+    }
+
     async fn register_with_hass(&self, state: &StateHandle) -> anyhow::Result<()> {
         let entities = enumerate_all_entites(state).await?;
 
-        // Register the configs
+        // Register the configs. State updates are held back until
+        // this completes, so that we don't report state for entities
+        // that hass doesn't yet know about
         log::trace!("register_with_hass: register entities");
-        entities.publish_config(state, self).await?;
+        state.begin_hass_discovery().await;
+        let result = entities.publish_config(state, self).await;
+        let deferred = state.end_hass_discovery().await;
+        result?;
 
         // Allow hass extra time to register the entities before
         // we mark them as available
@@ -138,21 +279,226 @@ impl HassClient {
         log::trace!("register_with_hass: reporting state");
         entities.notify_state(self).await.context("notify_state")?;
 
+        // and any that changed while discovery was running, which
+        // may include devices that are not in `entities`
+        for device_id in deferred {
+            state.notify_of_state_change(&device_id).await?;
+        }
+
         log::trace!("register_with_hass: done");
 
         Ok(())
     }
 
+    /// Re-publishes the discovery configs that we previously published,
+    /// followed by our availability and current state.
+    /// Returns false if we haven't published any configs yet.
+    async fn reannounce_to_hass(&self, state: &StateHandle) -> anyhow::Result<bool> {
+        let configs = self.discovery_configs.lock().clone();
+        if configs.is_empty() {
+            return Ok(false);
+        }
+
+        log::info!("Re-announcing {} entity configs", configs.len());
+        let batch_size = state.get_hass_discovery_batch_size().await;
+        let delay = state.get_hass_discovery_delay().await;
+        publish_in_batches(
+            configs.into_iter().collect(),
+            batch_size,
+            delay,
+            |topic, payload| self.publish(topic, payload),
+        )
+        .await?;
+
+        self.publish(availability_topic(), "online")
+            .await
+            .context("online -> availability_topic")?;
+        enumerate_all_entites(state)
+            .await?
+            .notify_state(self)
+            .await
+            .context("notify_state")?;
+        Ok(true)
+    }
+
+    /// Queues a discovery config to be published by the next call
+    /// to flush_discovery_configs
+    pub async fn publish_config<P: Serialize>(
+        &self,
+        topic: String,
+        config: P,
+    ) -> anyhow::Result<()> {
+        let payload = serde_json::to_string(&config)?;
+        self.pending_configs.lock().push((topic, payload));
+        Ok(())
+    }
+
+    /// Waits for the queued discovery configs to settle, then
+    /// publishes them in batches, pausing between each batch so
+    /// that we don't overwhelm the broker or hass
+    pub async fn flush_discovery_configs(&self, state: &StateHandle) -> anyhow::Result<()> {
+        let mut queued = self.pending_configs.lock().len();
+        loop {
+            tokio::time::sleep(HASS_DISCOVERY_DEBOUNCE).await;
+            let now_queued = self.pending_configs.lock().len();
+            if now_queued == queued {
+                break;
+            }
+            queued = now_queued;
+        }
+
+        let batch_size = state.get_hass_discovery_batch_size().await;
+        let delay = state.get_hass_discovery_delay().await;
+
+        let mut configs = std::mem::take(&mut *self.pending_configs.lock());
+        let mut superseded = vec![];
+        if state.get_hass_device_discovery().await {
+            let disco = state.get_hass_disco_prefix().await;
+            let entity_topics: Vec<String> =
+                configs.iter().map(|(topic, _)| topic.clone()).collect();
+            configs = device_discovery_configs(&disco, configs)?;
+            // Topics that we already migrated hold no config to hand over
+            let migrated = self.migrated_topics.lock().clone();
+            superseded = entity_topics
+                .into_iter()
+                .filter(|topic| !configs.iter().any(|(t, _)| t == topic))
+                .filter(|topic| !migrated.contains(topic))
+                .collect();
+
+            // hass may still hold the per-entity configs that we
+            // published before device discovery was enabled.
+            // Ask it to hand those entities over to the device
+            // config, rather than creating a second set of them.
+            let migrations = superseded
+                .iter()
+                .map(|topic| (topic.clone(), MIGRATE_DISCOVERY_PAYLOAD.to_string()))
+                .collect();
+            publish_in_batches(migrations, batch_size, delay, |topic, payload| {
+                self.publish(topic, payload)
+            })
+            .await?;
+        }
+
+        // Remember them so that they can be re-announced if hass restarts
+        self.discovery_configs
+            .lock()
+            .extend(configs.iter().cloned());
+
+        publish_in_batches(configs, batch_size, delay, |topic, payload| {
+            self.publish(topic, payload)
+        })
+        .await?;
+
+        // Now that the device configs are in place, clear the
+        // per-entity configs, including any that the broker retained
+        let clears = superseded
+            .iter()
+            .map(|topic| (topic.clone(), String::new()))
+            .collect();
+        publish_in_batches(clears, batch_size, delay, |topic, payload| {
+            self.publish_retained(topic, payload)
+        })
+        .await?;
+
+        self.migrated_topics.lock().extend(superseded);
+        Ok(())
+    }
+
+    /// Marks our entities as unavailable in hass, in preparation
+    /// for shutting down. Uses QoS 1 so that this doesn't return
+    /// until the broker has received the messages.
+    /// The per-device availability topics are retained, so they are
+    /// replaced with "offline" too; otherwise other MQTT clients
+    /// would continue to see those devices as online.
+    pub async fn publish_offline(&self, state: &StateHandle) -> anyhow::Result<()> {
+        let mut topics = vec![availability_topic()];
+        for device in state.devices().await {
+            if !state.is_device_excluded(&device).await {
+                topics.push(device_availability_topic(&device));
+            }
+        }
+
+        for topic in topics {
+            if crate::is_dry_run() {
+                log::info!("dry-run: publish {topic} -> offline");
+                continue;
+            }
+            let retain = topic != availability_topic();
+            self.send(&topic, "offline", QoS::AtLeastOnce, retain)
+                .await
+                .with_context(|| format!("publishing offline to {topic}"))?;
+        }
+        Ok(())
+    }
+
+    /// All of our publishing goes through here
+    async fn send<T: AsRef<str>, P: AsRef<[u8]>>(
+        &self,
+        topic: T,
+        payload: P,
+        qos: QoS,
+        retain: bool,
+    ) -> anyhow::Result<()> {
+        #[cfg(test)]
+        if let Some(published) = &self.published {
+            published.lock().push((
+                topic.as_ref().to_string(),
+                String::from_utf8_lossy(payload.as_ref()).to_string(),
+            ));
+            return Ok(());
+        }
+        self.client.publish(topic, payload, qos, retain).await?;
+        Ok(())
+    }
+
+    /// Logs the difference between payload and the payload that
+    /// we previously published to the same state topic.
+    /// Discovery configs and other topics are ignored.
+    fn log_state_change(&self, topic: &str, payload: &str) {
+        if !topic.starts_with("gv2mqtt/") {
+            return;
+        }
+        let previous = self
+            .last_state
+            .lock()
+            .insert(topic.to_string(), payload.to_string());
+        if let Some(changes) = describe_state_change(previous.as_deref(), payload) {
+            log::debug!("{topic}: {changes}");
+        }
+    }
+
     pub async fn publish<T: AsRef<str> + std::fmt::Display, P: AsRef<[u8]> + std::fmt::Display>(
         &self,
         topic: T,
         payload: P,
     ) -> anyhow::Result<()> {
+        self.log_state_change(topic.as_ref(), &payload.to_string());
+        if crate::is_dry_run() {
+            log::info!("dry-run: publish {topic} -> {payload}");
+            return Ok(());
+        }
         log::trace!("{topic} -> {payload}");
-        self.client
-            .publish(topic, payload, QoS::AtMostOnce, false)
-            .await?;
-        Ok(())
+        self.send(topic, payload, QoS::AtMostOnce, false).await
+    }
+
+    /// Publishes a message that the broker retains and delivers to
+    /// clients that subscribe later on. An empty payload clears the
+    /// retained message.
+    pub async fn publish_retained<
+        T: AsRef<str> + std::fmt::Display,
+        P: AsRef<[u8]> + std::fmt::Display,
+    >(
+        &self,
+        topic: T,
+        payload: P,
+    ) -> anyhow::Result<()> {
+        self.log_state_change(topic.as_ref(), &payload.to_string());
+        if crate::is_dry_run() {
+            log::info!("dry-run: publish retained {topic} -> {payload}");
+            return Ok(());
+        }
+        log::trace!("{topic} -> {payload} (retained)");
+        self.send(topic, payload, QoS::AtMostOnce, true).await
     }
 
     pub async fn publish_obj<T: AsRef<str> + std::fmt::Display, P: Serialize>(
@@ -161,11 +507,30 @@ impl HassClient {
         payload: P,
     ) -> anyhow::Result<()> {
         let payload = serde_json::to_string(&payload)?;
+        self.log_state_change(topic.as_ref(), &payload);
+        if crate::is_dry_run() {
+            log::info!("dry-run: publish {topic} -> {payload}");
+            return Ok(());
+        }
         log::trace!("{topic} -> {payload}");
-        self.client
-            .publish(topic, payload, QoS::AtMostOnce, false)
-            .await?;
-        Ok(())
+        self.send(topic, payload, QoS::AtMostOnce, false).await
+    }
+
+    /// Publishes whether we have heard from the device recently enough
+    /// for its entities to be considered available
+    pub async fn publish_device_availability(
+        &self,
+        device: &ServiceDevice,
+        state: &StateHandle,
+    ) -> anyhow::Result<()> {
+        let available = device.is_available(state.get_availability_timeout_for(device).await);
+        // Retained, so that hass sees it after restarting, even when
+        // nothing else prompts us to publish it again
+        self.publish_retained(
+            device_availability_topic(device),
+            if available { "online" } else { "offline" },
+        )
+        .await
     }
 
     pub async fn advise_hass_of_light_state(
@@ -181,6 +546,44 @@ impl HassClient {
     }
 }
 
+/// Describes how a published state payload differs from the
+/// previous payload for the same topic. JSON objects are compared
+/// field by field, so that only the changed fields are reported.
+/// Returns None if nothing changed.
+fn describe_state_change(previous: Option<&str>, current: &str) -> Option<String> {
+    let Some(previous) = previous else {
+        return Some(format!("initial state {current}"));
+    };
+    if previous == current {
+        return None;
+    }
+
+    let parse = |s: &str| serde_json::from_str::<JsonValue>(s).ok();
+    if let (Some(JsonValue::Object(prev)), Some(JsonValue::Object(cur))) =
+        (parse(previous), parse(current))
+    {
+        let null = JsonValue::Null;
+        let mut keys: Vec<&String> = prev.keys().chain(cur.keys()).collect();
+        keys.sort();
+        keys.dedup();
+        let changes: Vec<String> = keys
+            .into_iter()
+            .filter_map(|key| {
+                let old = prev.get(key).unwrap_or(&null);
+                let new = cur.get(key).unwrap_or(&null);
+                (old != new).then(|| format!("{key}: {old} -> {new}"))
+            })
+            .collect();
+        return if changes.is_empty() {
+            None
+        } else {
+            Some(changes.join(", "))
+        };
+    }
+
+    Some(format!("{previous} -> {current}"))
+}
+
 pub fn topic_safe_string(s: &str) -> String {
     let mut result = String::new();
     for c in s.chars() {
@@ -211,6 +614,10 @@ pub fn light_state_topic(device: &ServiceDevice) -> String {
     format!("gv2mqtt/light/{id}/state", id = topic_safe_id(device))
 }
 
+pub fn light_attributes_topic(device: &ServiceDevice) -> String {
+    format!("gv2mqtt/light/{id}/attributes", id = topic_safe_id(device))
+}
+
 pub fn light_segment_state_topic(device: &ServiceDevice, segment: u32) -> String {
     format!(
         "gv2mqtt/light/{id}/state/{segment}",
@@ -224,6 +631,13 @@ pub fn availability_topic() -> String {
     "gv2mqtt/availability".to_string()
 }
 
+/// Used together with availability_topic, so that the entities
+/// of a device that we haven't heard from within its availability
+/// timeout are marked unavailable
+pub fn device_availability_topic(device: &ServiceDevice) -> String {
+    format!("gv2mqtt/{id}/availability", id = topic_safe_id(device))
+}
+
 pub fn oneclick_topic() -> String {
     "gv2mqtt/oneclick".to_string()
 }
@@ -232,11 +646,25 @@ pub fn purge_cache_topic() -> String {
     "gv2mqtt/purge-caches".to_string()
 }
 
+pub fn resync_topic() -> String {
+    "gv2mqtt/resync".to_string()
+}
+
+pub fn scenes_available_topic(device: &ServiceDevice) -> String {
+    format!("gv2mqtt/{id}/scene/available", id = topic_safe_id(device))
+}
+
 #[derive(Deserialize)]
 pub struct IdParameter {
     pub id: String,
 }
 
+#[derive(Deserialize)]
+pub struct IdAndInst {
+    pub id: String,
+    pub instance: String,
+}
+
 /// Someone clicked the "Request Platform API State" button
 async fn mqtt_request_platform_data(
     Params(IdParameter { id }): Params<IdParameter>,
@@ -250,13 +678,135 @@ async fn mqtt_request_platform_data(
     Ok(())
 }
 
+/// The color in a light command. hass sends r, g and b when the
+/// light is in rgb mode, and x and y when it is in xy mode
+#[derive(Deserialize, Debug, Clone)]
+struct HassLightColor {
+    r: Option<u8>,
+    g: Option<u8>,
+    b: Option<u8>,
+    x: Option<f64>,
+    y: Option<f64>,
+}
+
+impl HassLightColor {
+    fn to_device_color(&self) -> anyhow::Result<DeviceColor> {
+        match (self.r, self.g, self.b, self.x, self.y) {
+            (Some(r), Some(g), Some(b), _, _) => Ok(DeviceColor { r, g, b }),
+            (_, _, _, Some(x), Some(y)) => Ok(DeviceColor::from_xy(x, y)),
+            _ => anyhow::bail!("light color {self:?} has neither rgb nor xy values"),
+        }
+    }
+}
+
 #[derive(Deserialize, Debug, Clone)]
 struct HassLightCommand {
     state: String,
     color_temp: Option<u32>,
-    color: Option<DeviceColor>,
+    color: Option<HassLightColor>,
     effect: Option<String>,
     brightness: Option<u8>,
+    /// The duration of the transition, in seconds
+    transition: Option<f64>,
+}
+
+/// Returns the number of steps to use for an emulated transition
+/// of the given duration, and the interval between them
+fn transition_schedule(duration: Duration) -> (u32, Duration) {
+    let steps = (duration.as_secs_f64() / TRANSITION_MIN_STEP_INTERVAL.as_secs_f64()) as u32;
+    let steps = steps.clamp(1, TRANSITION_MAX_STEPS);
+    (steps, duration / steps)
+}
+
+/// Converts the transition requested by hass into a Duration,
+/// capped at TRANSITION_MAX_DURATION. Returns None if there is
+/// no transition, or if the value isn't a usable number of seconds.
+fn transition_duration(secs: f64) -> Option<Duration> {
+    match Duration::try_from_secs_f64(secs) {
+        Ok(duration) if !duration.is_zero() => Some(duration.min(TRANSITION_MAX_DURATION)),
+        Ok(_) => None,
+        Err(err) if secs > 0. => {
+            // The value is too large to represent; treat it as the longest
+            log::debug!("transition of {secs} seconds: {err}");
+            Some(TRANSITION_MAX_DURATION)
+        }
+        Err(err) => {
+            log::warn!("Ignoring transition of {secs} seconds: {err}");
+            None
+        }
+    }
+}
+
+/// Linearly interpolates between from and to
+fn interpolate(from: u8, to: u8, step: u32, steps: u32) -> u8 {
+    let from = from as f64;
+    let to = to as f64;
+    (from + (to - from) * step as f64 / steps as f64).round() as u8
+}
+
+/// Govee devices have no way to transition to a new brightness or
+/// color over a specified time, so we emulate it by stepping the
+/// brightness and color towards their targets. This stops short of
+/// the targets, leaving it to the caller to apply the final values.
+/// Returns false if another command arrived for the device in the
+/// meantime, in which case the transition was abandoned part way
+/// and the caller should leave the device to the newer command.
+async fn emulate_transition(
+    state: &StateHandle,
+    device: &ServiceDevice,
+    command: &HassLightCommand,
+    generation: u64,
+    duration: Duration,
+) -> anyhow::Result<bool> {
+    let device_state = device.device_state();
+    let is_on = device_state
+        .as_ref()
+        .map(|s| s.light_on.unwrap_or(s.on))
+        .unwrap_or(false);
+    let from_brightness = match &device_state {
+        Some(s) if is_on => s.brightness,
+        _ => 0,
+    };
+    // When turning on without a brightness, fade up to the
+    // brightness that the light had before it was turned off
+    let to_brightness = command
+        .brightness
+        .or_else(|| (!is_on).then(|| device.last_nonzero_brightness.unwrap_or(100)));
+    let from_color = device_state.as_ref().map(|s| s.color).unwrap_or_default();
+    let to_color = match &command.color {
+        Some(color) => Some(color.to_device_color()?),
+        None => None,
+    };
+
+    let (steps, interval) = transition_schedule(duration);
+    log::info!("Emulating a {duration:?} transition for {device} in {steps} steps");
+
+    for step in 1..steps {
+        if !state.is_current_light_command(&device.id, generation).await {
+            log::info!("Transition for {device} was superseded by a newer command");
+            return Ok(false);
+        }
+        if let Some(to) = to_brightness {
+            state
+                .device_set_brightness(device, interpolate(from_brightness, to, step, steps))
+                .await
+                .context("emulate_transition: state.device_set_brightness")?;
+        }
+        if let Some(to) = &to_color {
+            state
+                .device_set_color_rgb(
+                    device,
+                    interpolate(from_color.r, to.r, step, steps),
+                    interpolate(from_color.g, to.g, step, steps),
+                    interpolate(from_color.b, to.b, step, steps),
+                )
+                .await
+                .context("emulate_transition: state.device_set_color_rgb")?;
+        }
+        tokio::time::sleep(interval).await;
+    }
+
+    Ok(state.is_current_light_command(&device.id, generation).await)
 }
 
 /// HASS is sending a command to a light
@@ -269,8 +819,9 @@ async fn mqtt_light_command(
 
     let command: HassLightCommand = serde_json::from_str(&payload)?;
     log::info!("Command for {device}: {payload}");
+    let generation = state.begin_light_command(&device.id).await;
 
-    let is_light = device.device_type() == DeviceType::Light;
+    let is_light = device.power_is_light();
 
     if command.state == "OFF" {
         if is_light {
@@ -287,6 +838,18 @@ async fn mqtt_light_command(
     } else {
         let mut power_on = true;
 
+        let transition = command
+            .transition
+            .filter(|_| command.effect.is_none())
+            .and_then(transition_duration);
+        if let Some(duration) = transition {
+            if state.get_emulate_transitions().await
+                && !emulate_transition(&state, &device, &command, generation, duration).await?
+            {
+                return Ok(());
+            }
+        }
+
         if let Some(brightness) = command.brightness {
             state
                 .device_set_brightness(&device, brightness)
@@ -308,15 +871,29 @@ async fn mqtt_light_command(
         }
 
         if let Some(color) = &command.color {
-            state
-                .device_set_color_rgb(&device, color.r, color.g, color.b)
-                .await
-                .context("mqtt_light_command: state.device_set_color_rgb")?;
+            let color = color.to_device_color()?;
+            match state.white_color_temperature_for_device(&device).await {
+                Some(kelvin) if color.is_white() => {
+                    // Pure white renders more accurately using the
+                    // white LEDs than by mixing rgb
+                    state
+                        .device_set_color_temperature(&device, kelvin)
+                        .await
+                        .context("mqtt_light_command: state.device_set_color_temperature")?;
+                }
+                _ => {
+                    state
+                        .device_set_color_rgb(&device, color.r, color.g, color.b)
+                        .await
+                        .context("mqtt_light_command: state.device_set_color_rgb")?;
+                }
+            }
             power_on = false;
         }
         if let Some(color_temp) = command.color_temp {
+            let kelvin = state.hass_mired_to_kelvin(&device, color_temp).await;
             state
-                .device_set_color_temperature(&device, mired_to_kelvin(color_temp))
+                .device_set_color_temperature(&device, kelvin)
                 .await
                 .context("mqtt_light_command: state.device_set_color_temperature")?;
             power_on = false;
@@ -384,6 +961,7 @@ async fn mqtt_light_segment_command(
             // client.set_segment_brightness(&info, segment, 0).await?;
         }
         if let Some(color) = &command.color {
+            let color = color.to_device_color()?;
             client
                 .set_segment_rgb(&info, segment, color.r, color.g, color.b)
                 .await?;
@@ -407,6 +985,48 @@ async fn mqtt_purge_caches(State(state): State<StateHandle>) -> anyhow::Result<(
         .context("register_with_hass")
 }
 
+/// Re-polls all devices and re-publishes all of our discovery
+/// configs and state. Requests that arrive while a resync is
+/// already underway are folded into a single further resync that
+/// runs once that one is done, so that their changes are seen.
+async fn mqtt_resync(State(state): State<StateHandle>) -> anyhow::Result<()> {
+    if !state.begin_resync().await {
+        log::info!("mqtt_resync: a resync is already in progress; will run again after it");
+        return Ok(());
+    }
+    loop {
+        let result = resync_all(&state).await;
+        if !state.end_resync().await {
+            return result;
+        }
+        if let Err(err) = result {
+            log::error!("mqtt_resync: {err:#}");
+        }
+        log::info!("mqtt_resync: running again for a request received meanwhile");
+    }
+}
+
+async fn resync_all(state: &StateHandle) -> anyhow::Result<()> {
+    let devices = state.devices().await;
+    log::info!("mqtt_resync: polling {} devices", devices.len());
+    for device in &devices {
+        if state.is_device_excluded(device).await {
+            continue;
+        }
+        if let Err(err) = state.poll_device_now(device).await {
+            log::error!("mqtt_resync: while polling {device}: {err:#}");
+        }
+    }
+
+    state
+        .get_hass_client()
+        .await
+        .expect("have hass client")
+        .register_with_hass(state)
+        .await
+        .context("register_with_hass")
+}
+
 async fn mqtt_oneclick(
     Payload(name): Payload<String>,
     State(state): State<StateHandle>,
@@ -417,10 +1037,9 @@ async fn mqtt_oneclick(
         .get_undoc_client()
         .await
         .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))?;
-    let items = undoc.parse_one_clicks().await?;
-    let item = items
-        .iter()
-        .find(|item| item.name == name)
+    let item = undoc
+        .find_one_click(&name)
+        .await?
         .ok_or_else(|| anyhow::anyhow!("didn't find item {name}"))?;
 
     let iot = state
@@ -431,10 +1050,37 @@ async fn mqtt_oneclick(
     iot.activate_one_click(&item).await
 }
 
-#[derive(Deserialize)]
-struct IdAndInst {
-    id: String,
-    instance: String,
+/// Activates a scene by name, for use by mqtt clients other than hass
+async fn mqtt_scene_activate(
+    Payload(scene): Payload<String>,
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_scene_activate: {id}: {scene}");
+    let device = state.resolve_device_for_control(&id).await?;
+
+    state
+        .device_set_scene(&device, scene.trim())
+        .await
+        .context("mqtt_scene_activate: state.device_set_scene")
+}
+
+/// Publishes the list of scenes that are available for the device,
+/// for use by mqtt clients other than hass
+async fn mqtt_scene_list(
+    Params(IdParameter { id }): Params<IdParameter>,
+    State(state): State<StateHandle>,
+) -> anyhow::Result<()> {
+    log::info!("mqtt_scene_list: {id}");
+    let device = state.resolve_device_read_only(&id).await?;
+    let scenes = state.device_list_scenes(&device).await?;
+
+    state
+        .get_hass_client()
+        .await
+        .ok_or_else(|| anyhow::anyhow!("no hass client"))?
+        .publish_obj(scenes_available_topic(&device), scenes)
+        .await
 }
 
 async fn mqtt_switch_command(
@@ -482,22 +1128,160 @@ pub fn kelvin_to_mired(kelvin: u32) -> u32 {
     }
 }
 
-/// HASS is advising us that its status has changed
+/// The mired range that is advertised for every light when color
+/// temperatures are normalized; 6500K to 2000K
+pub const NORMALIZED_MIN_MIREDS: u32 = 153;
+pub const NORMALIZED_MAX_MIREDS: u32 = 500;
+
+/// Maps a kelvin value within the device range onto a percentage,
+/// where 0 is the warmest and 100 the coolest that the device can do
+pub fn kelvin_to_percent(kelvin: u32, (min, max): (u32, u32)) -> f64 {
+    if max <= min {
+        return 100.;
+    }
+    let kelvin = kelvin.clamp(min, max);
+    100. * (kelvin - min) as f64 / (max - min) as f64
+}
+
+/// The inverse of kelvin_to_percent
+pub fn percent_to_kelvin(percent: f64, (min, max): (u32, u32)) -> u32 {
+    let percent = percent.clamp(0., 100.);
+    min + ((max.saturating_sub(min)) as f64 * percent / 100.).round() as u32
+}
+
+/// Expresses a percentage as a position on the normalized mired scale
+pub fn percent_to_normalized_mired(percent: f64) -> u32 {
+    let span = (NORMALIZED_MAX_MIREDS - NORMALIZED_MIN_MIREDS) as f64;
+    NORMALIZED_MAX_MIREDS - (span * percent.clamp(0., 100.) / 100.).round() as u32
+}
+
+/// The inverse of percent_to_normalized_mired
+pub fn normalized_mired_to_percent(mired: u32) -> f64 {
+    let mired = mired.clamp(NORMALIZED_MIN_MIREDS, NORMALIZED_MAX_MIREDS);
+    let span = (NORMALIZED_MAX_MIREDS - NORMALIZED_MIN_MIREDS) as f64;
+    100. * (NORMALIZED_MAX_MIREDS - mired) as f64 / span
+}
+
+/// Combines per-entity discovery configs into a single device-based
+/// discovery config for each device, with each entity as one of its
+/// components. Configs that don't identify a device are left as-is.
+fn device_discovery_configs(
+    disco_prefix: &str,
+    configs: Vec<(String, String)>,
+) -> anyhow::Result<Vec<(String, String)>> {
+    let mut result = vec![];
+    let mut devices: BTreeMap<String, JsonValue> = BTreeMap::new();
+
+    for (topic, payload) in configs {
+        // The topic is {disco_prefix}/{integration}/{unique_id}/config
+        let parts: Option<(&str, &str)> = topic
+            .strip_prefix(disco_prefix)
+            .and_then(|rest| rest.strip_prefix('/'))
+            .and_then(|rest| rest.strip_suffix("/config"))
+            .and_then(|rest| rest.split_once('/'));
+        let mut config: JsonValue = serde_json::from_str(&payload)?;
+        let identifier = config
+            .pointer("/device/identifiers/0")
+            .and_then(|id| id.as_str())
+            .map(topic_safe_string);
+
+        let (Some((integration, unique_id)), Some(identifier), Some(obj)) =
+            (parts, identifier, config.as_object_mut())
+        else {
+            result.push((topic, payload));
+            continue;
+        };
+
+        let device = obj.remove("device").unwrap_or_default();
+        let origin = obj.remove("origin").unwrap_or_default();
+        obj.insert("platform".to_string(), integration.into());
+
+        let entry = devices.entry(identifier).or_insert_with(|| {
+            serde_json::json!({
+                "device": device,
+                "origin": origin,
+                "components": {},
+            })
+        });
+        entry["components"][unique_id] = config;
+    }
+
+    for (identifier, config) in devices {
+        result.push((
+            format!("{disco_prefix}/device/{identifier}/config"),
+            serde_json::to_string(&config)?,
+        ));
+    }
+
+    Ok(result)
+}
+
+/// How long to pause after publishing a batch of batch_len configs.
+/// delay is applied per config, so that the overall publishing rate
+/// doesn't depend upon the batch size.
+fn batch_pause(delay: Duration, batch_len: usize) -> Duration {
+    delay.saturating_mul(batch_len as u32)
+}
+
+/// Publishes configs batch_size at a time, pausing between batches
+/// for delay per config in the batch, plus a random jitter of up to
+/// half of that pause
+async fn publish_in_batches<F, Fut>(
+    configs: Vec<(String, String)>,
+    batch_size: usize,
+    delay: Duration,
+    mut publish: F,
+) -> anyhow::Result<()>
+where
+    F: FnMut(String, String) -> Fut,
+    Fut: std::future::Future<Output = anyhow::Result<()>>,
+{
+    let total = configs.len();
+    let mut published = 0;
+    for (idx, batch) in configs.chunks(batch_size.max(1)).enumerate() {
+        if idx > 0 {
+            let pause = batch_pause(delay, batch.len());
+            let jitter = fastrand::u64(0..=pause.as_millis() as u64 / 2);
+            tokio::time::sleep(pause + Duration::from_millis(jitter)).await;
+        }
+        for (topic, payload) in batch {
+            publish(topic.clone(), payload.clone())
+                .await
+                .with_context(|| format!("publishing {topic}"))?;
+        }
+        published += batch.len();
+        log::info!(
+            "Published batch of {} entity configs ({published} of {total})",
+            batch.len()
+        );
+    }
+    Ok(())
+}
+
+/// HASS is advising us that its status has changed.
+/// It publishes "online" as its birth message when it starts up,
+/// at which point it has forgotten our non-retained configs.
 async fn mqtt_homeassitant_status(
     Payload(status): Payload<String>,
     State(state): State<StateHandle>,
 ) -> anyhow::Result<()> {
+    if !status.trim().eq_ignore_ascii_case("online") {
+        log::info!("Home Assistant status changed: {status}");
+        return Ok(());
+    }
+
     let client = state
         .get_hass_client()
         .await
         .expect("hass client to be present");
 
-    log::info!("Home Assistant status changed: {status}, waiting {HASS_REGISTER_DELAY:?} before re-registering entities");
-    tokio::time::sleep(HASS_REGISTER_DELAY).await;
-
-    client.register_with_hass(&state).await?;
+    log::info!(
+        "Home Assistant status changed: {status}, waiting {HASS_BIRTH_DELAY:?} \
+         before re-announcing entities"
+    );
+    tokio::time::sleep(HASS_BIRTH_DELAY).await;
 
-    Ok(())
+    client.on_hass_birth(&state).await
 }
 
 async fn run_mqtt_loop(
@@ -520,6 +1304,11 @@ async fn run_mqtt_loop(
             .route(format!("{disco_prefix}/status"), mqtt_homeassitant_status)
             .await?;
 
+        if state.is_discovery_only().await {
+            log::info!("Discovery-only mode: not subscribing to control topics");
+            return register_and_build(state, router).await;
+        }
+
         router
             .route("gv2mqtt/light/:id/command", mqtt_light_command)
             .await?;
@@ -532,9 +1321,13 @@ async fn run_mqtt_loop(
         router
             .route("gv2mqtt/switch/:id/command/:instance", mqtt_switch_command)
             .await?;
+        router
+            .route("gv2mqtt/switch/:id/keep-warm", mqtt_keep_warm_command)
+            .await?;
 
         router.route(oneclick_topic(), mqtt_oneclick).await?;
         router.route(purge_cache_topic(), mqtt_purge_caches).await?;
+        router.route(resync_topic(), mqtt_resync).await?;
         router
             .route(
                 "gv2mqtt/:id/request-platform-data",
@@ -547,6 +1340,15 @@ async fn run_mqtt_loop(
                 mqtt_number_command,
             )
             .await?;
+        router
+            .route(
+                "gv2mqtt/number/:id/set-range/:instance",
+                mqtt_range_number_command,
+            )
+            .await?;
+        router
+            .route("gv2mqtt/cover/:id/command/:instance", mqtt_cover_command)
+            .await?;
         router
             .route("gv2mqtt/humidifier/:id/set-mode", mqtt_device_set_work_mode)
             .await?;
@@ -568,13 +1370,30 @@ async fn run_mqtt_loop(
         router
             .route("gv2mqtt/:id/set-mode-scene", mqtt_set_mode_scene)
             .await?;
+        router
+            .route("gv2mqtt/:id/set-mode/:instance", mqtt_set_mode)
+            .await?;
+        router
+            .route("gv2mqtt/:id/scene/activate", mqtt_scene_activate)
+            .await?;
+        router
+            .route("gv2mqtt/:id/scene/list", mqtt_scene_list)
+            .await?;
 
+        register_and_build(state, router).await
+    }
+
+    /// Publishes our entity configs to hass, then returns the router
+    async fn register_and_build(
+        state: &StateHandle,
+        router: MqttRouter<StateHandle>,
+    ) -> anyhow::Result<Arc<MqttRouter<StateHandle>>> {
         tokio::time::sleep(HASS_REGISTER_DELAY).await;
         state
             .get_hass_client()
             .await
             .expect("have hass client")
-            .register_with_hass(&state)
+            .register_with_hass(state)
             .await
             .context("register_with_hass")?;
 
@@ -590,17 +1409,26 @@ async fn run_mqtt_loop(
                 let router = router.clone();
                 let state = state.clone();
                 tokio::spawn(async move {
-                    if let Err(err) = router.dispatch(msg.clone(), state.clone()).await {
-                        log::error!("While dispatching {msg:?}: {err:#}");
+                    let error = match router.dispatch(msg.clone(), state.clone()).await {
+                        Ok(()) => None,
+                        Err(err) => {
+                            log::error!("While dispatching {msg:?}: {err:#}");
+                            Some(format!("{err:#}"))
+                        }
+                    };
+                    if let Err(err) = record_control_result(&state, &msg.topic, error).await {
+                        log::error!("While recording control result for {msg:?}: {err:#}");
                     }
                 });
             }
             Event::Disconnected(reason) => {
                 log::warn!("MQTT disconnected with reason={reason}");
+                state.set_mqtt_connected(false).await;
                 need_rebuild = true;
             }
             Event::Connected(status) => {
                 log::info!("MQTT connected with status={status}");
+                state.set_mqtt_connected(status.0 == 0).await;
                 if need_rebuild {
                     router = rebuild_router(&client, &state).await?;
                 }
@@ -613,6 +1441,85 @@ async fn run_mqtt_loop(
     Ok(())
 }
 
+/// If the topic is a command for a device, records the outcome
+/// of the command against the device and reports it to hass
+async fn record_control_result(
+    state: &StateHandle,
+    topic: &str,
+    error: Option<String>,
+) -> anyhow::Result<()> {
+    let Some(device_id) = state.device_id_for_topic(topic).await else {
+        return Ok(());
+    };
+    let device = state
+        .device_by_id(&device_id)
+        .await
+        .expect("device to exist");
+    state
+        .device_mut(&device.sku, &device.id)
+        .await
+        .set_last_control_result(error);
+    state.notify_of_state_change(&device_id).await
+}
+
+/// Returns the delay to use after a failed connection attempt
+/// that was preceded by a delay of `delay`
+fn next_connect_backoff(delay: Duration) -> Duration {
+    (delay * 2).min(MQTT_CONNECT_MAX_BACKOFF)
+}
+
+/// Connect to the broker, retrying with exponential backoff so that
+/// we can ride out the broker starting up at the same time as us.
+/// Gives up once the configured connect timeout has elapsed, or
+/// immediately if the broker rejects our credentials.
+async fn connect_with_backoff(
+    client: &Client,
+    mqtt_host: &str,
+    mqtt_port: u16,
+    args: &HassArguments,
+) -> anyhow::Result<()> {
+    let deadline = tokio::time::Instant::now() + args.mqtt_connect_timeout()?;
+    let mut delay = MQTT_CONNECT_INITIAL_BACKOFF;
+    let mut attempt = 1;
+
+    loop {
+        log::info!("Connecting to mqtt broker {mqtt_host}:{mqtt_port} (attempt {attempt})");
+        let err = match client
+            .connect(
+                mqtt_host,
+                mqtt_port.into(),
+                Duration::from_secs(120),
+                args.mqtt_bind_address(),
+            )
+            .await
+        {
+            Ok(_) => return Ok(()),
+            Err(err @ mosquitto_rs::Error::RejectedConnection(_)) => {
+                return Err(err)
+                    .with_context(|| format!("connecting to mqtt broker {mqtt_host}:{mqtt_port}"));
+            }
+            Err(err) => err,
+        };
+
+        if tokio::time::Instant::now() + delay > deadline {
+            return Err(err).with_context(|| {
+                format!(
+                    "connecting to mqtt broker {mqtt_host}:{mqtt_port}; \
+                     giving up after {attempt} attempts"
+                )
+            });
+        }
+
+        log::info!(
+            "Failed to connect to mqtt broker {mqtt_host}:{mqtt_port}: {err:#}. \
+             Retrying in {delay:?}"
+        );
+        tokio::time::sleep(delay).await;
+        delay = next_connect_backoff(delay);
+        attempt += 1;
+    }
+}
+
 pub async fn spawn_hass_integration(
     state: StateHandle,
     args: &HassArguments,
@@ -623,6 +1530,15 @@ pub async fn spawn_hass_integration(
     )?;
 
     state.set_temperature_scale(args.temperature_scale()?).await;
+    state
+        .set_hass_discovery_delay(args.hass_discovery_delay()?)
+        .await;
+    state
+        .set_hass_discovery_batch_size(args.hass_discovery_batch_size()?)
+        .await;
+    state
+        .set_hass_device_discovery(args.hass_device_discovery()?)
+        .await;
 
     let mqtt_host = args.mqtt_host()?;
     let mqtt_username = args.mqtt_username()?;
@@ -637,20 +1553,19 @@ pub async fn spawn_hass_integration(
         );
     }
     client.set_username_and_password(mqtt_username.as_deref(), mqtt_password.as_deref())?;
-    client
-        .connect(
-            &mqtt_host,
-            mqtt_port.into(),
-            Duration::from_secs(120),
-            args.mqtt_bind_address.as_deref(),
-        )
-        .await
-        .with_context(|| format!("connecting to mqtt broker {mqtt_host}:{mqtt_port}"))?;
+    connect_with_backoff(&client, &mqtt_host, mqtt_port, args).await?;
+    state.set_mqtt_connected(true).await;
     let subscriber = client.subscriber().expect("to own the subscriber");
 
     state
         .set_hass_client(HassClient {
             client: client.clone(),
+            last_state: Default::default(),
+            discovery_configs: Default::default(),
+            pending_configs: Default::default(),
+            migrated_topics: Default::default(),
+            #[cfg(test)]
+            published: None,
         })
         .await;
 
@@ -694,3 +1609,544 @@ fn test_camel_case_to_space_separated() {
         "Oscillation Toggle"
     );
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::hass_mqtt::instance::EntityInstance;
+    use crate::hass_mqtt::number::RangeNumber;
+    use crate::platform_api::mock::{devices_from_fixture, MockControl, MockGoveeApiClient};
+    use crate::platform_api::{DeviceCapabilityKind, DeviceCapabilityState, HttpDeviceState};
+    use serde_json::json;
+
+    #[test]
+    fn normalized_color_temperature() {
+        let narrow = (2700, 6500);
+        let wide = (2000, 9000);
+
+        // Both ends of the slider map to the ends of each range
+        for range in [narrow, wide] {
+            assert_eq!(
+                percent_to_kelvin(normalized_mired_to_percent(NORMALIZED_MAX_MIREDS), range),
+                range.0
+            );
+            assert_eq!(
+                percent_to_kelvin(normalized_mired_to_percent(NORMALIZED_MIN_MIREDS), range),
+                range.1
+            );
+        }
+
+        // The middle of the slider is the middle of each range, give
+        // or take the resolution of the mired scale
+        let middle = percent_to_normalized_mired(50.);
+        for (range, expect) in [(narrow, 4600), (wide, 5500)] {
+            let kelvin = percent_to_kelvin(normalized_mired_to_percent(middle), range);
+            assert!(kelvin.abs_diff(expect) <= 25, "{kelvin} vs {expect}");
+        }
+
+        // A reported value is presented at the same slider position
+        assert_eq!(
+            percent_to_normalized_mired(kelvin_to_percent(4600, narrow)),
+            middle
+        );
+        assert_eq!(
+            percent_to_normalized_mired(kelvin_to_percent(1000, narrow)),
+            500
+        );
+    }
+
+    const DEVICE_ID: &str = "9D:FA:85:EB:D3:00:8B:FF";
+
+    const LIST_DEVICES: &str = include_str!("../../test-data/list_devices.json");
+
+    /// Returns a State holding the devices from the specified
+    /// list_devices fixture, backed by a mock platform client
+    async fn state_with_mock_client(fixture: &str) -> (StateHandle, MockGoveeApiClient) {
+        let client = MockGoveeApiClient::with_devices(devices_from_fixture(fixture));
+        (client.service_state().await, client)
+    }
+
+    /// Returns a HassClient that records what it publishes
+    fn recording_hass_client() -> (HassClient, PublishedMessages) {
+        let published = PublishedMessages::default();
+        let hass = HassClient {
+            client: Client::with_auto_id().unwrap(),
+            last_state: Default::default(),
+            discovery_configs: Default::default(),
+            pending_configs: Default::default(),
+            migrated_topics: Default::default(),
+            published: Some(published.clone()),
+        };
+        (hass, published)
+    }
+
+    #[tokio::test]
+    async fn offline_covers_device_availability() {
+        let (state, client) = state_with_mock_client(LIST_DEVICES).await;
+        let (hass, published) = recording_hass_client();
+
+        hass.publish_offline(&state).await.unwrap();
+
+        let published = published.lock().clone();
+        k9::assert_equal!(published[0], (availability_topic(), "offline".to_string()));
+        k9::assert_equal!(published.len(), client.devices.len() + 1);
+        for device in state.devices().await {
+            assert!(
+                published.contains(&(device_availability_topic(&device), "offline".to_string()))
+            );
+        }
+    }
+
+    #[tokio::test]
+    async fn device_discovery_migrates_entity_configs() {
+        let state = Arc::new(crate::service::state::State::new());
+        state
+            .set_hass_disco_prefix("homeassistant".to_string())
+            .await;
+        state.set_hass_device_discovery(true).await;
+        let (hass, published) = recording_hass_client();
+
+        let entity = "homeassistant/light/gv2mqtt-AA/config".to_string();
+        hass.publish_config(
+            entity.clone(),
+            json!({"unique_id": "gv2mqtt-AA", "device": {"identifiers": ["gv2mqtt-AA"]}}),
+        )
+        .await
+        .unwrap();
+        hass.flush_discovery_configs(&state).await.unwrap();
+
+        let topics = published.lock().clone();
+        k9::assert_equal!(topics.len(), 3);
+        k9::assert_equal!(
+            topics[0],
+            (entity.clone(), MIGRATE_DISCOVERY_PAYLOAD.to_string())
+        );
+        k9::assert_equal!(topics[1].0, "homeassistant/device/gv2mqtt-aa/config");
+        k9::assert_equal!(topics[2], (entity.clone(), String::new()));
+
+        // A later registration pass only publishes the device config
+        published.lock().clear();
+        hass.publish_config(
+            entity,
+            json!({"unique_id": "gv2mqtt-AA", "device": {"identifiers": ["gv2mqtt-AA"]}}),
+        )
+        .await
+        .unwrap();
+        hass.flush_discovery_configs(&state).await.unwrap();
+        let topics = published.lock().clone();
+        k9::assert_equal!(topics.len(), 1);
+        k9::assert_equal!(topics[0].0, "homeassistant/device/gv2mqtt-aa/config");
+    }
+
+    #[tokio::test]
+    async fn birth_message_republishes_discovery() {
+        let (state, _client) = state_with_mock_client(LIST_DEVICES).await;
+        state
+            .set_hass_disco_prefix("homeassistant".to_string())
+            .await;
+        let (hass, published) = recording_hass_client();
+        state.set_hass_client(hass.clone()).await;
+        let configs = |published: &[(String, String)]| {
+            published
+                .iter()
+                .filter(|(topic, _)| topic.ends_with("/config"))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+
+        // Only "online" means that hass has restarted
+        mqtt_homeassitant_status(Payload("offline".to_string()), State(state.clone()))
+            .await
+            .unwrap();
+        assert!(published.lock().is_empty());
+
+        // Nothing was registered yet, so this falls back to
+        // the full registration
+        hass.on_hass_birth(&state).await.unwrap();
+        let registered = configs(&std::mem::take(&mut *published.lock()));
+        assert!(!registered.is_empty());
+
+        // Now the stored configs are published again, followed
+        // by our availability
+        hass.on_hass_birth(&state).await.unwrap();
+        let republished = std::mem::take(&mut *published.lock());
+        let mut sorted = registered.clone();
+        sorted.sort();
+        k9::assert_equal!(configs(&republished), sorted);
+        assert!(republished
+            .iter()
+            .any(|(topic, payload)| *topic == availability_topic() && payload == "online"));
+    }
+
+    #[tokio::test]
+    async fn range_number_reports_the_value_that_was_set() {
+        const H7141_ID: &str = "AA:BB:CC:DD:EE:FF:00:11";
+        let client = MockGoveeApiClient::with_devices(
+            devices_from_fixture(include_str!("../../test-data/list_devices_issue4.json"))
+                .into_iter()
+                .filter(|info| info.sku == "H7141")
+                .collect(),
+        );
+        let state = client.service_state().await;
+        state
+            .device_mut("H7141", H7141_ID)
+            .await
+            .set_http_device_state(HttpDeviceState {
+                sku: "H7141".to_string(),
+                device: H7141_ID.to_string(),
+                capabilities: vec![DeviceCapabilityState {
+                    kind: DeviceCapabilityKind::Range,
+                    instance: "humidity".to_string(),
+                    state: json!({"value": 50}),
+                }],
+            });
+
+        let device = state.device_by_id(H7141_ID).await.unwrap();
+        let cap = device
+            .http_device_info
+            .as_ref()
+            .and_then(|info| info.capability_by_instance("humidity"))
+            .unwrap();
+        let number = RangeNumber::new(&device, &state, cap).unwrap();
+
+        mqtt_range_number_command(
+            Payload("60".to_string()),
+            Params(IdAndInst {
+                id: H7141_ID.to_string(),
+                instance: "humidity".to_string(),
+            }),
+            State(state.clone()),
+        )
+        .await
+        .unwrap();
+
+        // The state topic carries the value that was just set,
+        // rather than the previously polled value
+        let (hass, published) = recording_hass_client();
+        number.notify_state(&hass).await.unwrap();
+        k9::assert_equal!(
+            published.lock().clone(),
+            vec![(
+                "gv2mqtt/number/AABBCCDDEEFF0011/range/humidity/state".to_string(),
+                "60".to_string()
+            )]
+        );
+    }
+
+    #[tokio::test]
+    async fn switch_command_uses_platform_api() {
+        let (state, client) = state_with_mock_client(LIST_DEVICES).await;
+
+        for (instance, command) in [("powerSwitch", "ON"), ("gradientToggle", "OFF")] {
+            mqtt_switch_command(
+                Payload(command.to_string()),
+                Params(IdAndInst {
+                    id: DEVICE_ID.to_string(),
+                    instance: instance.to_string(),
+                }),
+                State(state.clone()),
+            )
+            .await
+            .unwrap();
+        }
+
+        k9::assert_equal!(
+            client.controls(),
+            vec![
+                MockControl {
+                    device: DEVICE_ID.to_string(),
+                    instance: "powerSwitch".to_string(),
+                    value: json!(1),
+                },
+                MockControl {
+                    device: DEVICE_ID.to_string(),
+                    instance: "gradientToggle".to_string(),
+                    value: json!(0),
+                },
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn control_result_is_recorded() {
+        let (state, _client) = state_with_mock_client(LIST_DEVICES).await;
+
+        record_control_result(&state, "homeassistant/status", None)
+            .await
+            .unwrap();
+        assert!(state
+            .device_by_id(DEVICE_ID)
+            .await
+            .unwrap()
+            .last_control_result
+            .is_none());
+
+        record_control_result(
+            &state,
+            "gv2mqtt/switch/9DFA85EBD3008BFF/command/powerSwitch",
+            Some("device offline".to_string()),
+        )
+        .await
+        .unwrap();
+        let result = state
+            .device_by_id(DEVICE_ID)
+            .await
+            .unwrap()
+            .last_control_result
+            .unwrap();
+        k9::assert_equal!(result.error.as_deref(), Some("device offline"));
+
+        record_control_result(&state, "gv2mqtt/light/9DFA85EBD3008BFF/command", None)
+            .await
+            .unwrap();
+        let result = state
+            .device_by_id(DEVICE_ID)
+            .await
+            .unwrap()
+            .last_control_result
+            .unwrap();
+        k9::assert_equal!(result.error, None);
+    }
+
+    #[tokio::test]
+    async fn scene_activate_by_name() {
+        let (state, _client) = state_with_mock_client(LIST_DEVICES).await;
+        let mut client = MockGoveeApiClient::with_devices(vec![]);
+        client.scenes = vec!["Sunrise".to_string(), "Aurora".to_string()];
+        state.set_platform_client(client.clone()).await;
+
+        mqtt_scene_activate(
+            Payload("aurora".to_string()),
+            Params(IdParameter {
+                id: "9DFA85EBD3008BFF".to_string(),
+            }),
+            State(state.clone()),
+        )
+        .await
+        .unwrap();
+
+        k9::assert_equal!(
+            client.controls(),
+            vec![MockControl {
+                device: DEVICE_ID.to_string(),
+                instance: "lightScene".to_string(),
+                value: json!("Aurora"),
+            }]
+        );
+
+        assert!(mqtt_scene_activate(
+            Payload("Bogus".to_string()),
+            Params(IdParameter {
+                id: DEVICE_ID.to_string(),
+            }),
+            State(state.clone()),
+        )
+        .await
+        .is_err());
+    }
+
+    #[test]
+    fn device_based_discovery() {
+        let device = |id: &str| {
+            json!({
+                "name": "Light",
+                "manufacturer": "Govee",
+                "model": "H6000",
+                "identifiers": [format!("gv2mqtt-{id}")],
+            })
+        };
+        let origin = json!({"name": "govee2mqtt"});
+        let entity = |id: &str, unique_id: &str| {
+            json!({
+                "unique_id": unique_id,
+                "device": device(id),
+                "origin": origin,
+            })
+            .to_string()
+        };
+
+        let configs = vec![
+            (
+                "homeassistant/light/gv2mqtt-AA/config".to_string(),
+                entity("AA", "gv2mqtt-AA"),
+            ),
+            (
+                "homeassistant/sensor/sensor-AA-status/config".to_string(),
+                entity("AA", "sensor-AA-status"),
+            ),
+            (
+                "homeassistant/light/gv2mqtt-BB/config".to_string(),
+                entity("BB", "gv2mqtt-BB"),
+            ),
+            (
+                "homeassistant/button/no-device/config".to_string(),
+                json!({"unique_id": "no-device"}).to_string(),
+            ),
+        ];
+
+        let result: Vec<(String, JsonValue)> = device_discovery_configs("homeassistant", configs)
+            .unwrap()
+            .into_iter()
+            .map(|(topic, payload)| (topic, serde_json::from_str(&payload).unwrap()))
+            .collect();
+
+        k9::assert_equal!(
+            result,
+            vec![
+                (
+                    "homeassistant/button/no-device/config".to_string(),
+                    json!({"unique_id": "no-device"})
+                ),
+                (
+                    "homeassistant/device/gv2mqtt-aa/config".to_string(),
+                    json!({
+                        "device": device("AA"),
+                        "origin": origin,
+                        "components": {
+                            "gv2mqtt-AA": {"unique_id": "gv2mqtt-AA", "platform": "light"},
+                            "sensor-AA-status": {
+                                "unique_id": "sensor-AA-status",
+                                "platform": "sensor"
+                            },
+                        },
+                    })
+                ),
+                (
+                    "homeassistant/device/gv2mqtt-bb/config".to_string(),
+                    json!({
+                        "device": device("BB"),
+                        "origin": origin,
+                        "components": {
+                            "gv2mqtt-BB": {"unique_id": "gv2mqtt-BB", "platform": "light"},
+                        },
+                    })
+                ),
+            ]
+        );
+    }
+
+    #[tokio::test]
+    async fn discovery_is_published_in_batches() {
+        let configs: Vec<(String, String)> = (0..25)
+            .map(|n| {
+                (
+                    format!("homeassistant/light/gv2mqtt-{n}/config"),
+                    n.to_string(),
+                )
+            })
+            .collect();
+
+        let published = Arc::new(Mutex::new(vec![]));
+        publish_in_batches(configs.clone(), 10, Duration::ZERO, |topic, payload| {
+            let published = published.clone();
+            async move {
+                published.lock().push((topic, payload));
+                Ok(())
+            }
+        })
+        .await
+        .unwrap();
+
+        let published = published.lock().clone();
+        k9::assert_equal!(published, configs);
+    }
+
+    #[test]
+    fn batch_pause_keeps_per_config_rate() {
+        let delay = Duration::from_millis(100);
+        k9::assert_equal!(batch_pause(delay, 1), delay);
+        k9::assert_equal!(batch_pause(delay, 10), Duration::from_secs(1));
+        k9::assert_equal!(batch_pause(Duration::ZERO, 10), Duration::ZERO);
+    }
+
+    #[test]
+    fn state_change_description() {
+        k9::assert_equal!(
+            describe_state_change(None, "ON"),
+            Some("initial state ON".to_string())
+        );
+        k9::assert_equal!(describe_state_change(Some("ON"), "ON"), None);
+        k9::assert_equal!(
+            describe_state_change(Some("ON"), "OFF"),
+            Some("ON -> OFF".to_string())
+        );
+        k9::assert_equal!(
+            describe_state_change(
+                Some(r#"{"state":"ON","brightness":50,"effect":"Sunrise"}"#),
+                r#"{"state":"ON","brightness":75,"color_temp":200}"#
+            ),
+            Some(
+                "brightness: 50 -> 75, color_temp: null -> 200, effect: \"Sunrise\" -> null"
+                    .to_string()
+            )
+        );
+        // Differences in formatting alone are not a change
+        k9::assert_equal!(
+            describe_state_change(Some(r#"{"a":1,"b":2}"#), r#"{"b":2,"a":1}"#),
+            None
+        );
+    }
+
+    #[tokio::test]
+    async fn newer_command_supersedes_transition() {
+        let (state, client) = state_with_mock_client(LIST_DEVICES).await;
+        let device = state.device_by_id(DEVICE_ID).await.unwrap();
+        let command: HassLightCommand =
+            serde_json::from_str(r#"{"state": "ON", "brightness": 80, "transition": 5}"#).unwrap();
+
+        let first = state.begin_light_command(&device.id).await;
+        assert!(state.is_current_light_command(&device.id, first).await);
+        let second = state.begin_light_command(&device.id).await;
+        assert!(!state.is_current_light_command(&device.id, first).await);
+        assert!(state.is_current_light_command(&device.id, second).await);
+
+        let completed =
+            emulate_transition(&state, &device, &command, first, Duration::from_secs(5))
+                .await
+                .unwrap();
+        assert!(!completed);
+        k9::assert_equal!(client.controls().len(), 0);
+    }
+
+    #[test]
+    fn transition_steps() {
+        k9::assert_equal!(
+            transition_schedule(Duration::from_secs(5)),
+            (5, Duration::from_secs(1))
+        );
+        k9::assert_equal!(
+            transition_schedule(Duration::from_secs(60)),
+            (10, Duration::from_secs(6))
+        );
+        k9::assert_equal!(
+            transition_schedule(Duration::from_millis(500)),
+            (1, Duration::from_millis(500))
+        );
+
+        k9::assert_equal!(transition_duration(0.), None);
+        k9::assert_equal!(transition_duration(-1.), None);
+        k9::assert_equal!(transition_duration(f64::NAN), None);
+        k9::assert_equal!(transition_duration(2.5), Some(Duration::from_millis(2500)));
+        k9::assert_equal!(transition_duration(1e6), Some(TRANSITION_MAX_DURATION));
+        k9::assert_equal!(transition_duration(1e300), Some(TRANSITION_MAX_DURATION));
+        k9::assert_equal!(
+            transition_duration(f64::INFINITY),
+            Some(TRANSITION_MAX_DURATION)
+        );
+
+        k9::assert_equal!(interpolate(0, 100, 1, 4), 25);
+        k9::assert_equal!(interpolate(100, 0, 3, 4), 25);
+        k9::assert_equal!(interpolate(10, 10, 2, 4), 10);
+    }
+
+    #[test]
+    fn connect_backoff() {
+        let mut delay = MQTT_CONNECT_INITIAL_BACKOFF;
+        let mut delays = vec![];
+        for _ in 0..7 {
+            delays.push(delay.as_secs());
+            delay = next_connect_backoff(delay);
+        }
+        assert_eq!(delays, vec![2, 4, 8, 16, 32, 60, 60]);
+    }
+}
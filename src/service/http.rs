@@ -1,13 +1,14 @@
+use crate::platform_api::HttpDeviceState;
 use crate::service::coordinator::Coordinator;
 use crate::service::device::{Device, DeviceState};
 use crate::service::state::StateHandle;
 use anyhow::Context;
-use axum::extract::{Path, State};
+use axum::extract::{Path, Query, State};
 use axum::http::StatusCode;
 use axum::response::{IntoResponse, Response};
-use axum::routing::get;
+use axum::routing::{get, post};
 use axum::{Json, Router};
-use serde::Serialize;
+use serde::{Deserialize, Serialize};
 use std::net::IpAddr;
 use tower_http::services::ServeDir;
 
@@ -207,10 +208,10 @@ async fn activate_one_click(
         .await
         .ok_or_else(|| anyhow::anyhow!("Undoc API client is not available"))
         .map_err(generic)?;
-    let items = undoc.parse_one_clicks().await.map_err(generic)?;
-    let item = items
-        .iter()
-        .find(|item| item.name == name)
+    let item = undoc
+        .find_one_click(&name)
+        .await
+        .map_err(generic)?
         .ok_or_else(|| anyhow::anyhow!("didn't find item {name}"))
         .map_err(not_found)?;
 
@@ -229,8 +230,114 @@ async fn redirect_to_index() -> Response {
     axum::response::Redirect::to("/assets/index.html").into_response()
 }
 
-pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()> {
+async fn health(State(state): State<StateHandle>) -> Response {
+    let mqtt_connected = state.is_mqtt_connected().await;
+    let mut response = Json(serde_json::json!({
+        "status": if mqtt_connected { "ok" } else { "mqtt_disconnected" },
+        "devices": state.devices().await.len(),
+        "mqtt_connected": mqtt_connected,
+        "state_conflicts": state.get_state_conflict_count().await,
+    }))
+    .into_response();
+    if !mqtt_connected {
+        *response.status_mut() = StatusCode::SERVICE_UNAVAILABLE;
+    }
+    response
+}
+
+#[derive(Deserialize)]
+struct WebhookParams {
+    token: Option<String>,
+}
+
+/// Compares the tokens without short-circuiting, so that the
+/// response time doesn't reveal how much of the token is correct
+fn tokens_match(expected: &str, provided: &str) -> bool {
+    expected.len() == provided.len()
+        && expected
+            .bytes()
+            .zip(provided.bytes())
+            .fold(0, |acc, (a, b)| acc | (a ^ b))
+            == 0
+}
+
+/// Receives a device state update pushed to us by the Govee
+/// platform API, as registered via --webhook-url
+async fn govee_webhook(
+    State(state): State<StateHandle>,
+    Query(params): Query<WebhookParams>,
+    Json(http_state): Json<HttpDeviceState>,
+) -> Result<Response, Response> {
+    let authorized = match (state.get_webhook_token().await, &params.token) {
+        (Some(expected), Some(provided)) => tokens_match(&expected, provided),
+        _ => false,
+    };
+    if !authorized {
+        return Err(response_with_code(
+            StatusCode::UNAUTHORIZED,
+            "webhook request has a missing or invalid token",
+        ));
+    }
+
+    let device = state
+        .device_by_id(&http_state.device)
+        .await
+        .ok_or_else(|| not_found(format!("device {} not found", http_state.device)))?;
+    log::trace!("webhook update for {device}: {http_state:?}");
+
+    {
+        let mut device = state.device_mut(&device.sku, &device.id).await;
+        device.set_http_device_state(http_state);
+        // We have a current state, so there is no need to poll
+        // again until the poll interval has elapsed
+        device.set_last_polled();
+    }
+    state
+        .notify_of_state_change(&device.id)
+        .await
+        .map_err(generic)?;
+
+    Ok(response_with_code(StatusCode::OK, "ok"))
+}
+
+fn webhook_router() -> Router<StateHandle> {
+    Router::new().route("/govee/webhook", post(govee_webhook))
+}
+
+/// Runs an HTTP server that accepts only the webhook, for when
+/// --webhook-port differs from --http-port, so that the webhook
+/// can be exposed without exposing the rest of the HTTP API
+pub async fn run_webhook_server(state: StateHandle, port: u16) -> anyhow::Result<()> {
+    let app = webhook_router().with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+        .await
+        .with_context(|| format!("run_webhook_server: binding to port {port}"))?;
+    log::info!("webhook server addr is {:?}", listener.local_addr()?);
+    axum::serve(listener, app)
+        .await
+        .context("webhook server stopped")
+}
+
+/// Runs a minimal HTTP server that reports on our health,
+/// for use with container liveness probes. It is separate from
+/// the main HTTP server so that it can be exposed independently.
+pub async fn run_health_server(state: StateHandle, port: u16) -> anyhow::Result<()> {
     let app = Router::new()
+        .route("/health", get(health))
+        .with_state(state);
+
+    let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
+        .await
+        .with_context(|| format!("run_health_server: binding to port {port}"))?;
+    log::info!("health server addr is {:?}", listener.local_addr()?);
+    axum::serve(listener, app)
+        .await
+        .context("health server stopped")
+}
+
+pub async fn run_http_server(state: StateHandle, port: u16, webhook: bool) -> anyhow::Result<()> {
+    let mut app = Router::new()
         .route("/api/devices", get(list_devices))
         .route("/api/device/:id/power/on", get(device_power_on))
         .route("/api/device/:id/power/off", get(device_power_off))
@@ -248,8 +355,11 @@ pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()
         .route("/api/oneclicks", get(list_one_clicks))
         .route("/api/oneclick/activate/:scene", get(activate_one_click))
         .route("/", get(redirect_to_index))
-        .nest_service("/assets", ServeDir::new("assets"))
-        .with_state(state);
+        .nest_service("/assets", ServeDir::new("assets"));
+    if webhook {
+        app = app.merge(webhook_router());
+    }
+    let app = app.with_state(state);
 
     let listener = tokio::net::TcpListener::bind(("0.0.0.0", port))
         .await
@@ -262,3 +372,127 @@ pub async fn run_http_server(state: StateHandle, port: u16) -> anyhow::Result<()
 
     Ok(())
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use std::sync::Arc;
+
+    async fn health_of(state: &StateHandle) -> (StatusCode, serde_json::Value) {
+        let response = health(State(state.clone())).await;
+        let status = response.status();
+        let body = axum::body::to_bytes(response.into_body(), usize::MAX)
+            .await
+            .unwrap();
+        (status, serde_json::from_slice(&body).unwrap())
+    }
+
+    fn webhook_params(token: &str) -> Query<WebhookParams> {
+        Query(WebhookParams {
+            token: Some(token.to_string()),
+        })
+    }
+
+    #[tokio::test]
+    async fn webhook_updates_state() {
+        let state = Arc::new(crate::service::state::State::new());
+        state.set_webhook_token("secret".to_string()).await;
+        drop(state.device_mut("H6000", "AA:BB:CC:DD:EE:FF:42:2A").await);
+
+        let http_state: HttpDeviceState = serde_json::from_value(serde_json::json!({
+            "sku": "H6000",
+            "device": "AA:BB:CC:DD:EE:FF:42:2A",
+            "capabilities": [{
+                "type": "devices.capabilities.on_off",
+                "instance": "powerSwitch",
+                "state": {"value": 1},
+            }],
+        }))
+        .unwrap();
+        let response = govee_webhook(
+            State(state.clone()),
+            webhook_params("secret"),
+            Json(http_state),
+        )
+        .await
+        .unwrap();
+        k9::assert_equal!(response.status(), StatusCode::OK);
+
+        let device = state.device_by_id("AA:BB:CC:DD:EE:FF:42:2A").await.unwrap();
+        assert!(device.last_polled.is_some());
+        k9::assert_equal!(device.device_state().map(|s| s.on), Some(true));
+
+        let unknown: HttpDeviceState = serde_json::from_value(serde_json::json!({
+            "sku": "H6000",
+            "device": "11:22:33:44:55:66:77:88",
+            "capabilities": [],
+        }))
+        .unwrap();
+        let response = govee_webhook(
+            State(state.clone()),
+            webhook_params("secret"),
+            Json(unknown),
+        )
+        .await
+        .unwrap_err();
+        k9::assert_equal!(response.status(), StatusCode::NOT_FOUND);
+    }
+
+    #[tokio::test]
+    async fn webhook_rejects_bad_token() {
+        let state = Arc::new(crate::service::state::State::new());
+        drop(state.device_mut("H6000", "AA:BB:CC:DD:EE:FF:42:2A").await);
+        let http_state = || -> HttpDeviceState {
+            serde_json::from_value(serde_json::json!({
+                "sku": "H6000",
+                "device": "AA:BB:CC:DD:EE:FF:42:2A",
+                "capabilities": [{
+                    "type": "devices.capabilities.on_off",
+                    "instance": "powerSwitch",
+                    "state": {"value": 1},
+                }],
+            }))
+            .unwrap()
+        };
+
+        // No token has been registered, so nothing is accepted
+        let response = govee_webhook(State(state.clone()), webhook_params(""), Json(http_state()))
+            .await
+            .unwrap_err();
+        k9::assert_equal!(response.status(), StatusCode::UNAUTHORIZED);
+
+        state.set_webhook_token("secret".to_string()).await;
+        for params in [
+            Query(WebhookParams { token: None }),
+            webhook_params("wrong!"),
+            webhook_params("secre"),
+        ] {
+            let response = govee_webhook(State(state.clone()), params, Json(http_state()))
+                .await
+                .unwrap_err();
+            k9::assert_equal!(response.status(), StatusCode::UNAUTHORIZED);
+        }
+
+        let device = state.device_by_id("AA:BB:CC:DD:EE:FF:42:2A").await.unwrap();
+        assert!(device.last_polled.is_none());
+        assert!(device.device_state().is_none());
+    }
+
+    #[tokio::test]
+    async fn health_reflects_mqtt_connection() {
+        let state = Arc::new(crate::service::state::State::new());
+        drop(state.device_mut("H6000", "AA:BB:CC:DD:EE:FF:42:2A").await);
+
+        let (status, body) = health_of(&state).await;
+        k9::assert_equal!(status, StatusCode::SERVICE_UNAVAILABLE);
+        k9::assert_equal!(body["mqtt_connected"], false);
+        k9::assert_equal!(body["devices"], 1);
+        k9::assert_equal!(body["state_conflicts"], 0);
+
+        state.set_mqtt_connected(true).await;
+        let (status, body) = health_of(&state).await;
+        k9::assert_equal!(status, StatusCode::OK);
+        k9::assert_equal!(body["status"], "ok");
+        k9::assert_equal!(body["mqtt_connected"], true);
+    }
+}
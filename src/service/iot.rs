@@ -2,12 +2,17 @@ use crate::ble::{Base64HexBytes, GoveeBlePacket, HumidifierAutoMode, NotifyHumid
 use crate::lan_api::{DeviceColor, DeviceStatus};
 use crate::platform_api::from_json;
 use crate::service::state::StateHandle;
-use crate::undoc_api::{ms_timestamp, DeviceEntry, LoginAccountResponse, ParsedOneClick};
+use crate::undoc_api::{
+    ms_timestamp, DeviceEntry, LoginAccountResponse, ParsedOneClick, UndocApiArguments,
+};
 use crate::Args;
 use anyhow::Context;
 use async_channel::Receiver;
 use mosquitto_rs::{Event, QoS};
+use openssl::asn1::{Asn1Time, Asn1TimeRef};
+use openssl::x509::{X509Ref, X509};
 use serde::Deserialize;
+use std::path::Path;
 use std::time::Duration;
 use tokio::time::timeout;
 
@@ -227,12 +232,90 @@ impl IotClient {
     }
 }
 
+/// How long to wait before retrying after failing to obtain
+/// fresh IoT credentials. Each failure doubles the delay, up to
+/// the maximum, so that we don't hammer the rate limited login
+/// endpoint during a prolonged outage
+const CREDENTIAL_RETRY_INITIAL_BACKOFF: Duration = Duration::from_secs(60);
+const CREDENTIAL_RETRY_MAX_BACKOFF: Duration = Duration::from_secs(3600);
+
+fn next_credential_retry_backoff(delay: Duration) -> Duration {
+    (delay * 2).min(CREDENTIAL_RETRY_MAX_BACKOFF)
+}
+
 pub async fn start_iot_client(
     args: &Args,
     state: StateHandle,
     acct: Option<LoginAccountResponse>,
 ) -> anyhow::Result<()> {
-    let client = args.undoc_args.api_client()?;
+    let undoc_args = args.undoc_args.clone();
+    let (client, subscriptions, acct) = connect_iot(&undoc_args, &state, acct).await?;
+
+    tokio::spawn(async move {
+        let (mut client, mut subscriptions, mut acct) = (client, subscriptions, acct);
+        loop {
+            match run_iot_subscriber(
+                subscriptions,
+                state.clone(),
+                client,
+                acct,
+                &undoc_args.govee_iot_cert,
+            )
+            .await
+            {
+                Ok(IotLoopExit::Terminated) => break,
+                Ok(IotLoopExit::CredentialsExpired) => {}
+                Err(err) => {
+                    log::error!("IoT loop failed: {err:#}");
+                    break;
+                }
+            }
+
+            // The credentials were rejected; fetch new ones and
+            // reconnect, retrying until we succeed
+            let mut delay = CREDENTIAL_RETRY_INITIAL_BACKOFF;
+            loop {
+                match undoc_args.api_client() {
+                    Ok(undoc) => {
+                        undoc.invalidate_iot_key();
+                        undoc.invalidate_account_login();
+                    }
+                    Err(err) => {
+                        log::error!("Unable to invalidate the cached IoT credentials: {err:#}");
+                    }
+                }
+                match connect_iot(&undoc_args, &state, None).await {
+                    Ok(connection) => {
+                        (client, subscriptions, acct) = connection;
+                        break;
+                    }
+                    Err(err) => {
+                        log::error!(
+                            "Failed to reconnect to IoT with fresh credentials: {err:#}. \
+                             Will retry in {delay:?}"
+                        );
+                        tokio::time::sleep(delay).await;
+                        delay = next_credential_retry_backoff(delay);
+                    }
+                }
+            }
+        }
+        log::info!("IoT loop terminated");
+        Ok::<(), anyhow::Error>(())
+    });
+
+    Ok(())
+}
+
+/// Obtains the IoT credentials, writes them out to the key and cert
+/// files, then connects to the IoT broker and registers the resulting
+/// client with the state
+async fn connect_iot(
+    undoc_args: &UndocApiArguments,
+    state: &StateHandle,
+    acct: Option<LoginAccountResponse>,
+) -> anyhow::Result<(mosquitto_rs::Client, Receiver<Event>, LoginAccountResponse)> {
+    let client = undoc_args.api_client()?;
     let acct = match acct {
         Some(a) => a,
         None => client.login_account_cached().await?,
@@ -250,12 +333,12 @@ pub async fn start_iot_client(
         let pem = priv_key
             .private_key_to_pem_pkcs8()
             .context("to_pem_pkcs8")?;
-        std::fs::write(&args.undoc_args.govee_iot_key, &pem)?;
+        std::fs::write(&undoc_args.govee_iot_key, &pem)?;
     }
     for cert in container.cert_bags(&res.p12_pass).context("cert_bags")? {
-        let cert = openssl::x509::X509::from_der(&cert).context("x509 from der")?;
+        let cert = X509::from_der(&cert).context("x509 from der")?;
         let pem = cert.to_pem().context("cert.to_pem")?;
-        std::fs::write(&args.undoc_args.govee_iot_cert, &pem)?;
+        std::fs::write(&undoc_args.govee_iot_cert, &pem)?;
     }
 
     let client = mosquitto_rs::Client::with_id(
@@ -267,12 +350,13 @@ pub async fn start_iot_client(
         true,
     )
     .context("new client")?;
+    let amazon_root_ca = undoc_args.amazon_root_ca()?;
     client
         .configure_tls(
-            Some(&args.undoc_args.amazon_root_ca),
+            Some(&amazon_root_ca),
             None::<&std::path::Path>,
-            Some(&args.undoc_args.govee_iot_cert),
-            Some(&args.undoc_args.govee_iot_key),
+            Some(&undoc_args.govee_iot_cert),
+            Some(&undoc_args.govee_iot_key),
             None,
         )
         .context("configure_tls")?;
@@ -294,15 +378,47 @@ pub async fn start_iot_client(
         })
         .await;
 
-    tokio::spawn(async move {
-        if let Err(err) = run_iot_subscriber(subscriptions, state, client, acct).await {
-            log::error!("IoT loop failed: {err:#}");
-        }
-        log::info!("IoT loop terminated");
-        Ok::<(), anyhow::Error>(())
-    });
+    Ok((client, subscriptions, acct))
+}
 
-    Ok(())
+enum IotLoopExit {
+    Terminated,
+    CredentialsExpired,
+}
+
+// From mosq_err_t in mosquitto.h
+const MOSQ_ERR_AUTH: i32 = 11;
+
+/// Returns true if the event indicates that the broker rejected
+/// our credentials, which happens when the IoT certificate expires.
+/// TLS and connection refused errors are not included: they are
+/// also caused by transient network problems, and treating them as
+/// expired credentials would trigger a full account login each time.
+/// An expired certificate is detected by iot_certificate_expired.
+fn is_credential_failure(event: &Event) -> bool {
+    match event {
+        // The CONNACK codes for "bad username or password" and
+        // "not authorized" from the MQTT 3.1.1 spec
+        Event::Connected(status) => matches!(status.0, 4 | 5),
+        Event::Disconnected(reason) => reason.0 == MOSQ_ERR_AUTH,
+        Event::Message(_) => false,
+    }
+}
+
+/// Returns true if the certificate is no longer valid at now.
+/// AWS rejects an expired client certificate during the TLS
+/// handshake, which looks just like a network problem from here,
+/// so we have to check the certificate itself.
+fn certificate_expired(cert: &X509Ref, now: &Asn1TimeRef) -> anyhow::Result<bool> {
+    Ok(cert.not_after().compare(now)? != std::cmp::Ordering::Greater)
+}
+
+/// Returns true if the IoT certificate in the PEM file has expired
+fn iot_certificate_expired(cert_path: &Path) -> anyhow::Result<bool> {
+    let pem = std::fs::read(cert_path).with_context(|| format!("reading {cert_path:?}"))?;
+    let cert = X509::from_pem(&pem).with_context(|| format!("parsing {cert_path:?}"))?;
+    let now = Asn1Time::days_from_now(0)?;
+    certificate_expired(&cert, &now)
 }
 
 #[derive(Deserialize, Debug)]
@@ -375,8 +491,26 @@ async fn run_iot_subscriber(
     state: StateHandle,
     client: mosquitto_rs::Client,
     acct: LoginAccountResponse,
-) -> anyhow::Result<()> {
+    cert_path: &Path,
+) -> anyhow::Result<IotLoopExit> {
     while let Ok(event) = subscriptions.recv().await {
+        if is_credential_failure(&event) {
+            log::warn!("IoT credentials were rejected ({event:?}); will refresh them");
+            return Ok(IotLoopExit::CredentialsExpired);
+        }
+        if let Event::Disconnected(reason) = &event {
+            match iot_certificate_expired(cert_path) {
+                Ok(true) => {
+                    log::warn!(
+                        "IoT disconnected ({reason:?}) and the IoT certificate \
+                         has expired; will refresh the credentials"
+                    );
+                    return Ok(IotLoopExit::CredentialsExpired);
+                }
+                Ok(false) => {}
+                Err(err) => log::warn!("Unable to check the IoT certificate: {err:#}"),
+            }
+        }
         match event {
             Event::Message(msg) => {
                 let payload = String::from_utf8_lossy(&msg.payload);
@@ -507,5 +641,81 @@ async fn run_iot_subscriber(
             }
         }
     }
-    Ok(())
+    Ok(IotLoopExit::Terminated)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use mosquitto_rs::{ConnectionStatus, ReasonCode};
+
+    #[test]
+    fn credential_failure_detection() {
+        // Successful connections and clean or transient disconnects
+        // are not credential problems
+        assert!(!is_credential_failure(&Event::Connected(ConnectionStatus(
+            0
+        ))));
+        assert!(!is_credential_failure(&Event::Disconnected(ReasonCode(0))));
+        assert!(!is_credential_failure(&Event::Disconnected(ReasonCode(
+            7 // MOSQ_ERR_CONN_LOST
+        ))));
+        assert!(!is_credential_failure(&Event::Disconnected(ReasonCode(
+            5 // MOSQ_ERR_CONN_REFUSED
+        ))));
+        assert!(!is_credential_failure(&Event::Disconnected(ReasonCode(
+            8 // MOSQ_ERR_TLS
+        ))));
+        assert!(!is_credential_failure(&Event::Disconnected(ReasonCode(
+            23 // MOSQ_ERR_TLS_HANDSHAKE
+        ))));
+
+        // Rejected credentials
+        assert!(is_credential_failure(&Event::Connected(ConnectionStatus(
+            4
+        ))));
+        assert!(is_credential_failure(&Event::Connected(ConnectionStatus(
+            5
+        ))));
+        assert!(is_credential_failure(&Event::Disconnected(ReasonCode(
+            MOSQ_ERR_AUTH
+        ))));
+    }
+
+    #[test]
+    fn certificate_expiry() {
+        use openssl::ec::{EcGroup, EcKey};
+        use openssl::hash::MessageDigest;
+        use openssl::nid::Nid;
+        use openssl::pkey::PKey;
+
+        let group = EcGroup::from_curve_name(Nid::X9_62_PRIME256V1).unwrap();
+        let key = PKey::from_ec_key(EcKey::generate(&group).unwrap()).unwrap();
+        let mut builder = X509::builder().unwrap();
+        builder.set_pubkey(&key).unwrap();
+        builder
+            .set_not_before(&Asn1Time::from_unix(1_600_000_000).unwrap())
+            .unwrap();
+        builder
+            .set_not_after(&Asn1Time::from_unix(1_700_000_000).unwrap())
+            .unwrap();
+        builder.sign(&key, MessageDigest::sha256()).unwrap();
+        let cert = builder.build();
+
+        let at = |secs| Asn1Time::from_unix(secs).unwrap();
+        assert!(!certificate_expired(&cert, &at(1_650_000_000)).unwrap());
+        assert!(certificate_expired(&cert, &at(1_700_000_000)).unwrap());
+        assert!(certificate_expired(&cert, &at(1_750_000_000)).unwrap());
+    }
+
+    #[test]
+    fn credential_retry_backoff() {
+        let mut delay = CREDENTIAL_RETRY_INITIAL_BACKOFF;
+        let mut delays = vec![];
+        for _ in 0..8 {
+            delays.push(delay.as_secs());
+            delay = next_credential_retry_backoff(delay);
+        }
+        assert_eq!(delays, vec![60, 120, 240, 480, 960, 1920, 3600, 3600]);
+    }
 }
@@ -3,5 +3,6 @@ pub mod device;
 pub mod hass;
 pub mod http;
 pub mod iot;
+pub mod product_names;
 pub mod quirks;
 pub mod state;
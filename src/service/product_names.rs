@@ -0,0 +1,33 @@
+use once_cell::sync::Lazy;
+use std::collections::HashMap;
+
+/// Maps a SKU to the name under which the product is sold.
+/// This is used to produce a friendlier default name for a device
+/// that has no name assigned in the Govee App.
+/// To add a product, add an entry to product_names.json.
+static PRODUCT_NAMES: Lazy<HashMap<String, String>> = Lazy::new(|| {
+    serde_json::from_str(include_str!("product_names.json"))
+        .expect("product_names.json to be valid")
+});
+
+/// Returns the product name for the SKU, if known
+pub fn product_name(sku: &str) -> Option<&'static str> {
+    PRODUCT_NAMES
+        .get(&sku.to_ascii_uppercase())
+        .map(|name| name.as_str())
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn lookup() {
+        k9::assert_equal!(product_name("H6072"), Some("RGBIC Floor Lamp"));
+        k9::assert_equal!(product_name("h6072"), Some("RGBIC Floor Lamp"));
+        k9::assert_equal!(product_name("H0000"), None);
+        assert!(PRODUCT_NAMES
+            .keys()
+            .all(|sku| *sku == sku.to_ascii_uppercase()));
+    }
+}
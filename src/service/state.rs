@@ -1,35 +1,112 @@
 use crate::ble::{Base64HexBytes, SetHumidifierMode, SetHumidifierNightlightParams};
-use crate::lan_api::{Client as LanClient, DeviceStatus as LanDeviceStatus, LanDevice};
-use crate::platform_api::{DeviceCapability, GoveeApiClient};
+use crate::brightness::BrightnessCurve;
+use crate::hass_mqtt::light::LightScenes;
+use crate::hass_mqtt::select::DEDICATED_SCENE_INSTANCES;
+use crate::hass_mqtt::switch::PowerEntity;
+use crate::lan_api::{
+    Client as LanClient, DeviceColor, DeviceStatus as LanDeviceStatus, LanDevice,
+};
+use crate::platform_api::{
+    ApiError, ControlDeviceResponseCapability, DeviceCapability, DeviceType, GoveeApiClientTrait,
+};
 use crate::service::coordinator::Coordinator;
-use crate::service::device::Device;
-use crate::service::hass::{topic_safe_id, HassClient};
+use crate::service::device::{Device, SentValue};
+use crate::service::hass::{
+    kelvin_to_mired, kelvin_to_percent, mired_to_kelvin, normalized_mired_to_percent,
+    percent_to_kelvin, percent_to_normalized_mired, topic_safe_id, HassClient,
+};
 use crate::service::iot::IotClient;
 use crate::temperature::{TemperatureScale, TemperatureValue};
 use crate::undoc_api::GoveeUndocumentedApi;
 use anyhow::Context;
 use serde_json::Value as JsonValue;
-use std::collections::HashMap;
+use std::collections::{BTreeSet, HashMap};
 use std::sync::Arc;
 use std::time::Instant;
 use tokio::sync::{MappedMutexGuard, Mutex, MutexGuard, Semaphore};
 use tokio::time::{sleep, Duration};
 
+/// How often devices are polled, unless configured otherwise
+pub const DEFAULT_POLL_INTERVAL_SECS: u64 = 900;
+pub const DEFAULT_BOIL_THRESHOLD_C: f64 = 2.0;
+
+/// How long to wait after controlling a device before polling it,
+/// to give its reported state a chance to catch up with the command
+const POLL_AFTER_CONTROL_DELAY: Duration = Duration::from_secs(5);
+
+/// How long a background poll waits for a LAN device to respond.
+/// The polls are made one device at a time, so an offline device
+/// would otherwise hold up the others for the full query timeout.
+const LAN_POLL_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// Tracks the hass discovery passes that are in progress, which
+/// may overlap when hass restarts during startup, along with the
+/// devices whose state notifications are waiting for them to end
+#[derive(Default)]
+struct HassDiscoveryPasses {
+    in_progress: usize,
+    deferred: BTreeSet<String>,
+}
+
+/// Whether a resync is running, and whether another was
+/// requested while it was
+#[derive(Default)]
+struct ResyncState {
+    in_progress: bool,
+    pending: bool,
+}
+
 #[derive(Default)]
 pub struct State {
     devices_by_id: Mutex<HashMap<String, Device>>,
     semaphore_by_id: Mutex<HashMap<String, Arc<Semaphore>>>,
     lan_client: Mutex<Option<LanClient>>,
-    platform_client: Mutex<Option<GoveeApiClient>>,
+    platform_client: Mutex<Option<Arc<dyn GoveeApiClientTrait>>>,
     undoc_client: Mutex<Option<GoveeUndocumentedApi>>,
     iot_client: Mutex<Option<IotClient>>,
     hass_client: Mutex<Option<HassClient>>,
     hass_discovery_prefix: Mutex<String>,
     temperature_scale: Mutex<TemperatureScale>,
+    brightness_curve: Mutex<BrightnessCurve>,
+    white_color_temperature: Mutex<Option<u32>>,
+    normalize_color_temperature: Mutex<bool>,
+    hass_discovery_delay: Mutex<Duration>,
+    hass_discovery_batch_size: Mutex<usize>,
+    hass_device_discovery: Mutex<bool>,
+    hass_discovery: Mutex<HassDiscoveryPasses>,
+    poll_interval: Mutex<Option<chrono::Duration>>,
+    availability_timeout: Mutex<Option<chrono::Duration>>,
+    device_type_availability_timeouts: Mutex<Vec<(DeviceType, chrono::Duration)>>,
+    mqtt_connected: Mutex<bool>,
+    discovery_only: Mutex<bool>,
+    included_devices: Mutex<Vec<String>>,
+    excluded_devices: Mutex<Vec<String>>,
+    light_scenes: Mutex<LightScenes>,
+    power_entity: Mutex<PowerEntity>,
+    xy_color: Mutex<bool>,
+    emulate_transitions: Mutex<bool>,
+    state_conflicts: Mutex<u64>,
+    boil_threshold: Mutex<Option<f64>>,
+    resync: Mutex<ResyncState>,
+    light_command_generation: Mutex<HashMap<String, u64>>,
+    webhook_token: Mutex<Option<String>>,
 }
 
 pub type StateHandle = Arc<State>;
 
+/// Returns true if the device is the one identified by label,
+/// which is its name, computed name, id or label, ignoring case.
+fn device_matches_label(d: &Device, label: &str) -> bool {
+    d.name().eq_ignore_ascii_case(label)
+        || d.id.eq_ignore_ascii_case(label)
+        || topic_safe_id(d).eq_ignore_ascii_case(label)
+        || d.ip_addr()
+            .map(|ip| ip.to_string().eq_ignore_ascii_case(label))
+            .unwrap_or(false)
+        || d.computed_name().eq_ignore_ascii_case(label)
+        || d.sku_name().eq_ignore_ascii_case(label)
+}
+
 impl State {
     pub fn new() -> Self {
         Self::default()
@@ -43,6 +120,326 @@ impl State {
         *self.temperature_scale.lock().await
     }
 
+    /// Returns the temperature scale for the device; either its
+    /// own override or the globally configured scale
+    pub async fn temperature_scale_for_device(&self, device: &Device) -> TemperatureScale {
+        match device.temperature_scale {
+            Some(scale) => scale,
+            None => self.get_temperature_scale().await,
+        }
+    }
+
+    pub async fn set_brightness_curve(&self, curve: BrightnessCurve) {
+        *self.brightness_curve.lock().await = curve;
+    }
+
+    /// Returns the brightness curve for the device; either its
+    /// own override or the globally configured curve
+    pub async fn brightness_curve_for_device(&self, device: &Device) -> BrightnessCurve {
+        match device.brightness_curve {
+            Some(curve) => curve,
+            None => *self.brightness_curve.lock().await,
+        }
+    }
+
+    /// Sets the color temperature to use in place of rgb when
+    /// white is requested; None means to always use rgb
+    pub async fn set_white_color_temperature(&self, kelvin: Option<u32>) {
+        *self.white_color_temperature.lock().await = kelvin;
+    }
+
+    /// Returns the color temperature that should be used to render
+    /// white on the device, if it supports color temperature and
+    /// that behavior is enabled
+    pub async fn white_color_temperature_for_device(&self, device: &Device) -> Option<u32> {
+        let kelvin = (*self.white_color_temperature.lock().await)?;
+        device.white_color_temperature(kelvin)
+    }
+
+    /// When enabled, every light presents the same color temperature
+    /// range to hass, mapped as a percentage onto the range of each
+    /// device, so that lights in a group move together
+    pub async fn set_normalize_color_temperature(&self, normalize: bool) {
+        *self.normalize_color_temperature.lock().await = normalize;
+    }
+
+    pub async fn get_normalize_color_temperature(&self) -> bool {
+        *self.normalize_color_temperature.lock().await
+    }
+
+    /// Converts a color temperature reported by the device into the
+    /// mired value that should be reported to hass
+    pub async fn kelvin_to_hass_mired(&self, device: &Device, kelvin: u32) -> u32 {
+        match device.get_color_temperature_range() {
+            Some(range) if self.get_normalize_color_temperature().await => {
+                percent_to_normalized_mired(kelvin_to_percent(kelvin, range))
+            }
+            _ => kelvin_to_mired(kelvin),
+        }
+    }
+
+    /// Converts a mired value received from hass into the color
+    /// temperature that should be sent to the device
+    pub async fn hass_mired_to_kelvin(&self, device: &Device, mired: u32) -> u32 {
+        match device.get_color_temperature_range() {
+            Some(range) if self.get_normalize_color_temperature().await => {
+                percent_to_kelvin(normalized_mired_to_percent(mired), range)
+            }
+            _ => mired_to_kelvin(mired),
+        }
+    }
+
+    pub async fn set_poll_interval(&self, interval: chrono::Duration) {
+        self.poll_interval.lock().await.replace(interval);
+    }
+
+    /// Returns the configured interval at which devices are polled
+    pub async fn get_poll_interval(&self) -> chrono::Duration {
+        self.poll_interval
+            .lock()
+            .await
+            .unwrap_or_else(|| chrono::Duration::seconds(DEFAULT_POLL_INTERVAL_SECS as i64))
+    }
+
+    pub async fn set_availability_timeout(&self, timeout: Option<chrono::Duration>) {
+        *self.availability_timeout.lock().await = timeout;
+    }
+
+    /// Returns the default period after which a device that we haven't
+    /// heard from is marked unavailable, if any
+    pub async fn get_availability_timeout(&self) -> Option<chrono::Duration> {
+        *self.availability_timeout.lock().await
+    }
+
+    /// Overrides the default availability timeout for devices
+    /// of device_type
+    pub async fn set_device_type_availability_timeout(
+        &self,
+        device_type: DeviceType,
+        timeout: chrono::Duration,
+    ) {
+        let mut timeouts = self.device_type_availability_timeouts.lock().await;
+        timeouts.retain(|(t, _)| *t != device_type);
+        timeouts.push((device_type, timeout));
+    }
+
+    /// Returns the availability timeout for device, for use when it
+    /// doesn't have its own. This is resolved each time, rather than
+    /// when the timeouts are configured, so that the timeout for its
+    /// type also applies to a device that is discovered later on.
+    pub async fn get_availability_timeout_for(&self, device: &Device) -> Option<chrono::Duration> {
+        let device_type = device.device_type();
+        let type_timeout = self
+            .device_type_availability_timeouts
+            .lock()
+            .await
+            .iter()
+            .find_map(|(t, timeout)| (*t == device_type).then_some(*timeout));
+        match type_timeout {
+            Some(timeout) => Some(timeout),
+            None => self.get_availability_timeout().await,
+        }
+    }
+
+    pub async fn set_mqtt_connected(&self, connected: bool) {
+        *self.mqtt_connected.lock().await = connected;
+    }
+
+    /// Returns true if we are currently connected to the hass mqtt broker
+    pub async fn is_mqtt_connected(&self) -> bool {
+        *self.mqtt_connected.lock().await
+    }
+
+    pub async fn set_light_scenes(&self, light_scenes: LightScenes) {
+        *self.light_scenes.lock().await = light_scenes;
+    }
+
+    pub async fn get_light_scenes(&self) -> LightScenes {
+        *self.light_scenes.lock().await
+    }
+
+    pub async fn set_power_entity(&self, power_entity: PowerEntity) {
+        *self.power_entity.lock().await = power_entity;
+    }
+
+    pub async fn get_power_entity(&self) -> PowerEntity {
+        *self.power_entity.lock().await
+    }
+
+    pub async fn set_xy_color(&self, xy_color: bool) {
+        *self.xy_color.lock().await = xy_color;
+    }
+
+    /// Returns true if lights should advertise and report xy color
+    /// in addition to rgb
+    pub async fn get_xy_color(&self) -> bool {
+        *self.xy_color.lock().await
+    }
+
+    pub async fn set_emulate_transitions(&self, emulate: bool) {
+        *self.emulate_transitions.lock().await = emulate;
+    }
+
+    /// Returns true if light transitions requested by hass should be
+    /// emulated by stepping the brightness and color
+    pub async fn get_emulate_transitions(&self) -> bool {
+        *self.emulate_transitions.lock().await
+    }
+
+    pub async fn set_webhook_token(&self, token: String) {
+        self.webhook_token.lock().await.replace(token);
+    }
+
+    /// Returns the token that was included in the webhook url
+    /// registered with Govee, and which must accompany every
+    /// webhook request
+    pub async fn get_webhook_token(&self) -> Option<String> {
+        self.webhook_token.lock().await.clone()
+    }
+
+    /// Returns the number of times that a polled value differed
+    /// from the value that we had most recently sent to the device
+    pub async fn get_state_conflict_count(&self) -> u64 {
+        *self.state_conflicts.lock().await
+    }
+
+    /// Records a value sent to the device via the Platform API, so
+    /// that the next poll can detect whether it was overridden
+    async fn note_sent_value(&self, device: &Device, value: SentValue) {
+        self.device_mut(&device.sku, &device.id)
+            .await
+            .note_sent_value(value);
+    }
+
+    pub async fn set_boil_threshold(&self, celsius: f64) {
+        self.boil_threshold.lock().await.replace(celsius);
+    }
+
+    /// Returns how many degrees Celsius below its target temperature
+    /// a kettle may be while still being considered to be boiling
+    pub async fn get_boil_threshold(&self) -> f64 {
+        self.boil_threshold
+            .lock()
+            .await
+            .unwrap_or(DEFAULT_BOIL_THRESHOLD_C)
+    }
+
+    pub async fn set_discovery_only(&self, discovery_only: bool) {
+        *self.discovery_only.lock().await = discovery_only;
+    }
+
+    /// Returns true if we should only publish discovery configs
+    /// and state to hass, without accepting any control commands
+    pub async fn is_discovery_only(&self) -> bool {
+        *self.discovery_only.lock().await
+    }
+
+    /// Sets the ids or names of the devices to manage and to ignore.
+    /// An empty include list means that every device is included.
+    pub async fn set_device_filter(&self, included: Vec<String>, excluded: Vec<String>) {
+        *self.included_devices.lock().await = included;
+        *self.excluded_devices.lock().await = excluded;
+    }
+
+    /// Returns true if the device should be ignored: it is neither
+    /// polled nor published to hass
+    pub async fn is_device_excluded(&self, device: &Device) -> bool {
+        let included = self.included_devices.lock().await;
+        if !included.is_empty() && !included.iter().any(|l| device_matches_label(device, l)) {
+            return true;
+        }
+        self.excluded_devices
+            .lock()
+            .await
+            .iter()
+            .any(|l| device_matches_label(device, l))
+    }
+
+    pub async fn set_hass_discovery_delay(&self, delay: Duration) {
+        *self.hass_discovery_delay.lock().await = delay;
+    }
+
+    pub async fn get_hass_discovery_delay(&self) -> Duration {
+        *self.hass_discovery_delay.lock().await
+    }
+
+    pub async fn set_hass_discovery_batch_size(&self, size: usize) {
+        *self.hass_discovery_batch_size.lock().await = size;
+    }
+
+    pub async fn get_hass_discovery_batch_size(&self) -> usize {
+        *self.hass_discovery_batch_size.lock().await
+    }
+
+    pub async fn set_hass_device_discovery(&self, enabled: bool) {
+        *self.hass_device_discovery.lock().await = enabled;
+    }
+
+    /// Returns true if we should publish one device-based discovery
+    /// config per device, rather than one config per entity
+    pub async fn get_hass_device_discovery(&self) -> bool {
+        *self.hass_device_discovery.lock().await
+    }
+
+    /// Marks the start of a hass discovery pass. State notifications
+    /// are deferred until every pass has ended
+    pub async fn begin_hass_discovery(&self) {
+        self.hass_discovery.lock().await.in_progress += 1;
+    }
+
+    /// Marks the end of a hass discovery pass. When it was the last
+    /// one in progress, returns the ids of the devices whose state
+    /// notifications were deferred while discovery was running
+    pub async fn end_hass_discovery(&self) -> Vec<String> {
+        let mut disco = self.hass_discovery.lock().await;
+        disco.in_progress = disco.in_progress.saturating_sub(1);
+        if disco.in_progress > 0 {
+            return vec![];
+        }
+        std::mem::take(&mut disco.deferred).into_iter().collect()
+    }
+
+    /// Marks a resync as being in progress. Returns false if one
+    /// was already in progress, in which case the request is noted
+    /// so that the running resync goes around once more when it ends
+    pub async fn begin_resync(&self) -> bool {
+        let mut resync = self.resync.lock().await;
+        if resync.in_progress {
+            resync.pending = true;
+            return false;
+        }
+        resync.in_progress = true;
+        true
+    }
+
+    /// Marks the end of a resync. Returns true if another resync was
+    /// requested while it was running, in which case the resync
+    /// remains in progress and the caller should run it again
+    pub async fn end_resync(&self) -> bool {
+        let mut resync = self.resync.lock().await;
+        if std::mem::take(&mut resync.pending) {
+            return true;
+        }
+        resync.in_progress = false;
+        false
+    }
+
+    /// Records the arrival of a light command for the device, which
+    /// supersedes any emulated transition that is still running for
+    /// it. Returns the generation number of the new command.
+    pub async fn begin_light_command(&self, id: &str) -> u64 {
+        let mut generations = self.light_command_generation.lock().await;
+        let generation = generations.entry(id.to_string()).or_default();
+        *generation += 1;
+        *generation
+    }
+
+    /// Returns true if no other light command has arrived for the
+    /// device since the one with the specified generation number
+    pub async fn is_current_light_command(&self, id: &str, generation: u64) -> bool {
+        self.light_command_generation.lock().await.get(id).copied() == Some(generation)
+    }
+
     pub async fn set_hass_disco_prefix(&self, prefix: String) {
         *self.hass_discovery_prefix.lock().await = prefix;
     }
@@ -66,6 +463,18 @@ impl State {
         self.devices_by_id.lock().await.values().cloned().collect()
     }
 
+    /// Returns the id of the device whose topic-safe id appears
+    /// as a component of the mqtt topic, if any
+    pub async fn device_id_for_topic(&self, topic: &str) -> Option<String> {
+        let devices = self.devices_by_id.lock().await;
+        topic.split('/').find_map(|component| {
+            devices
+                .values()
+                .find(|d| topic_safe_id(d) == component)
+                .map(|d| d.id.to_string())
+        })
+    }
+
     /// Returns an immutable copy of the specified Device
     pub async fn device_by_id(&self, id: &str) -> Option<Device> {
         let devices = self.devices_by_id.lock().await;
@@ -128,20 +537,10 @@ impl State {
             return Some(device.clone());
         }
 
-        for d in devices.values() {
-            if d.name().eq_ignore_ascii_case(label)
-                || d.id.eq_ignore_ascii_case(label)
-                || topic_safe_id(d).eq_ignore_ascii_case(label)
-                || d.ip_addr()
-                    .map(|ip| ip.to_string().eq_ignore_ascii_case(label))
-                    .unwrap_or(false)
-                || d.computed_name().eq_ignore_ascii_case(label)
-            {
-                return Some(d.clone());
-            }
-        }
-
-        None
+        devices
+            .values()
+            .find(|d| device_matches_label(d, label))
+            .cloned()
     }
 
     pub async fn set_hass_client(&self, client: HassClient) {
@@ -168,11 +567,18 @@ impl State {
         self.lan_client.lock().await.clone()
     }
 
-    pub async fn set_platform_client(&self, client: GoveeApiClient) {
-        self.platform_client.lock().await.replace(client);
+    /// Returns the LAN client along with the LAN device, if the
+    /// device can be controlled via the LAN API
+    async fn lan_control<'a>(&self, device: &'a Device) -> Option<(LanClient, &'a LanDevice)> {
+        let lan_dev = device.lan_device.as_ref()?;
+        Some((self.get_lan_client().await?, lan_dev))
+    }
+
+    pub async fn set_platform_client<C: GoveeApiClientTrait + 'static>(&self, client: C) {
+        self.platform_client.lock().await.replace(Arc::new(client));
     }
 
-    pub async fn get_platform_client(&self) -> Option<GoveeApiClient> {
+    pub async fn get_platform_client(&self) -> Option<Arc<dyn GoveeApiClientTrait>> {
         self.platform_client.lock().await.clone()
     }
 
@@ -185,6 +591,41 @@ impl State {
         self.undoc_client.lock().await.clone()
     }
 
+    /// Polls the device via the best available API, regardless of
+    /// when it was last polled
+    pub async fn poll_device_now(self: &Arc<Self>, device: &Device) -> anyhow::Result<()> {
+        if device.is_ble_only_device() == Some(true) {
+            // We can't poll this device, we have no ble support
+            return Ok(());
+        }
+
+        let needs_platform = device.needs_platform_poll();
+
+        // Don't interrogate via HTTP if we can use the LAN.
+        // If we have LAN and the device doesn't respond, it is likely
+        // offline and there is little sense in burning up request
+        // quota to the platform API for it
+        if let (Some(lan_device), false) = (&device.lan_device, needs_platform) {
+            if !self.poll_lan_status(lan_device, LAN_POLL_TIMEOUT).await? {
+                log::trace!("LAN-available device {device} didn't respond; it's likely offline.");
+            }
+            // Mark it as polled even if it didn't respond, so that an
+            // offline device isn't queried again on every pass
+            self.device_mut(&device.sku, &device.id)
+                .await
+                .set_last_polled();
+            return Ok(());
+        }
+
+        if !needs_platform && self.poll_iot_api(device).await? {
+            return Ok(());
+        }
+
+        self.poll_platform_api(device).await?;
+
+        Ok(())
+    }
+
     pub async fn poll_iot_api(self: &Arc<Self>, device: &Device) -> anyhow::Result<bool> {
         if let Some(iot) = self.get_iot_client().await {
             if let Some(info) = device.undoc_device_info.clone() {
@@ -228,11 +669,34 @@ impl State {
                     .context("get_device_state")?;
                 log::trace!("updated state for {device}");
 
-                {
+                // Reflect a scene that was activated via the Govee app
+                let active_scene = match client.active_scene_name(info, &http_state).await {
+                    Ok(scene) => scene,
+                    Err(err) => {
+                        log::warn!("Unable to resolve the active scene for {device}: {err:#}");
+                        None
+                    }
+                };
+
+                let conflicts = {
                     let mut device = self.device_mut(&device.sku, &device.id).await;
                     device.set_http_device_state(http_state);
+                    if let Some(scene) = &active_scene {
+                        device.set_active_scene(Some(scene));
+                    }
                     device.set_last_polled();
+                    device.take_state_conflicts()
+                };
+                // The polled state is published below regardless, but
+                // note when it differs from what we last sent, as that
+                // means the device rejected it or the app changed it
+                for (sent, polled) in &conflicts {
+                    log::debug!(
+                        "Device {id} state conflict: sent {sent} but polled {polled}",
+                        id = device.id
+                    );
                 }
+                *self.state_conflicts.lock().await += conflicts.len() as u64;
                 self.notify_of_state_change(&device.id)
                     .await
                     .context("state.notify_of_state_change")?;
@@ -246,6 +710,33 @@ impl State {
         Ok(false)
     }
 
+    /// Query the current status of a LAN device, publishing it to
+    /// hass if it changed. Returns false if the device didn't respond.
+    pub async fn poll_lan_status(
+        self: &Arc<Self>,
+        device: &LanDevice,
+        timeout: Duration,
+    ) -> anyhow::Result<bool> {
+        let Some(client) = self.get_lan_client().await else {
+            return Ok(false);
+        };
+        let status = match client.query_status_with_timeout(device, timeout).await {
+            Ok(status) => status,
+            Err(err) => {
+                log::trace!("LAN status query for {}: {err:#}", device.device);
+                return Ok(false);
+            }
+        };
+        let changed = self
+            .device_mut(&device.sku, &device.device)
+            .await
+            .set_lan_device_status(status);
+        if changed {
+            self.notify_of_state_change(&device.device).await?;
+        }
+        Ok(true)
+    }
+
     async fn poll_lan_api<F: Fn(&LanDeviceStatus) -> bool>(
         self: &Arc<Self>,
         device: &LanDevice,
@@ -277,13 +768,12 @@ impl State {
         device: &Device,
         capability: &DeviceCapability,
         value: V,
-    ) -> anyhow::Result<()> {
+    ) -> anyhow::Result<ControlDeviceResponseCapability> {
         let value: JsonValue = value.into();
         if let Some(client) = self.get_platform_client().await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to send {value:?} control to {device}");
-                client.control_device(info, capability, value).await?;
-                return Ok(());
+                return client.control_device(info, capability, value).await;
             }
         }
 
@@ -311,9 +801,9 @@ impl State {
                 )
             })?;
 
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some((lan_client, lan_dev)) = self.lan_control(device).await {
             log::info!("Using LAN API to set {device} light power state");
-            lan_dev.send_turn(on).await?;
+            lan_client.send_turn(lan_dev, on).await?;
             self.poll_lan_api(lan_dev, |status| status.on == on).await?;
             return Ok(());
         }
@@ -323,6 +813,7 @@ impl State {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} light power state");
                     iot.set_power_state(&info.entry, on).await?;
+                    self.note_power_on(device, on).await;
                     return Ok(());
                 }
             }
@@ -332,6 +823,7 @@ impl State {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} light {instance_name} state");
                 client.set_toggle_state(info, instance_name, on).await?;
+                self.note_power_on(device, on).await;
                 return Ok(());
             }
         }
@@ -344,9 +836,9 @@ impl State {
         device: &Device,
         on: bool,
     ) -> anyhow::Result<()> {
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some((lan_client, lan_dev)) = self.lan_control(device).await {
             log::info!("Using LAN API to set {device} power state");
-            lan_dev.send_turn(on).await?;
+            lan_client.send_turn(lan_dev, on).await?;
             self.poll_lan_api(lan_dev, |status| status.on == on).await?;
             return Ok(());
         }
@@ -356,6 +848,7 @@ impl State {
                 if let Some(info) = &device.undoc_device_info {
                     log::info!("Using IoT API to set {device} power state");
                     iot.set_power_state(&info.entry, on).await?;
+                    self.note_power_on(device, on).await;
                     return Ok(());
                 }
             }
@@ -364,7 +857,12 @@ impl State {
         if let Some(client) = self.get_platform_client().await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} power state");
-                client.set_power_state(info, on).await?;
+                let result = client.set_power_state(info, on).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .apply_control_response(&result);
+                self.note_power_on(device, on).await;
+                self.note_sent_value(device, SentValue::Power(on)).await;
                 return Ok(());
             }
         }
@@ -372,11 +870,28 @@ impl State {
         anyhow::bail!("Unable to control power state for {device}");
     }
 
+    /// The device restores its prior brightness and color when it
+    /// is powered on, but doesn't necessarily tell us about them.
+    /// Flag it so that poll_after_control fetches them once the
+    /// device has settled, rather than polling immediately here.
+    async fn note_power_on(&self, device: &Device, on: bool) {
+        if on {
+            self.device_mut(&device.sku, &device.id)
+                .await
+                .request_refresh_after_power_on();
+        }
+    }
+
     pub async fn device_set_brightness(
         self: &Arc<Self>,
         device: &Device,
         percent: u8,
     ) -> anyhow::Result<()> {
+        let percent = self
+            .brightness_curve_for_device(device)
+            .await
+            .apply(percent);
+
         if self
             .try_humidifier_set_nightlight(device, |p| {
                 p.brightness = percent;
@@ -387,9 +902,9 @@ impl State {
             return Ok(());
         }
 
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some((lan_client, lan_dev)) = self.lan_control(device).await {
             log::info!("Using LAN API to set {device} brightness");
-            lan_dev.send_brightness(percent).await?;
+            lan_client.send_brightness(lan_dev, percent).await?;
             self.poll_lan_api(lan_dev, |status| status.brightness == percent)
                 .await?;
             return Ok(());
@@ -408,7 +923,12 @@ impl State {
         if let Some(client) = self.get_platform_client().await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} brightness");
-                client.set_brightness(info, percent).await?;
+                let result = client.set_brightness(info, percent).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .apply_control_response(&result);
+                self.note_sent_value(device, SentValue::Brightness(percent))
+                    .await;
                 return Ok(());
             }
         }
@@ -420,10 +940,15 @@ impl State {
         device: &Device,
         kelvin: u32,
     ) -> anyhow::Result<()> {
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some((lan_client, lan_dev)) = self.lan_control(device).await {
             log::info!("Using LAN API to set {device} color temperature");
-            lan_dev.send_color_temperature_kelvin(kelvin).await?;
-            self.poll_lan_api(lan_dev, |status| status.color_temperature_kelvin == kelvin)
+            let value = device
+                .lan_color_temperature_encoding()
+                .encode(kelvin, device.lan_color_temperature_range());
+            lan_client
+                .send_color_temperature_kelvin(lan_dev, value)
+                .await?;
+            self.poll_lan_api(lan_dev, |status| status.color_temperature_kelvin == value)
                 .await?;
             self.device_mut(&device.sku, &device.id)
                 .await
@@ -444,7 +969,12 @@ impl State {
         if let Some(client) = self.get_platform_client().await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} color temperature");
-                client.set_color_temperature(info, kelvin).await?;
+                let result = client.set_color_temperature(info, kelvin).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .apply_control_response(&result);
+                self.note_sent_value(device, SentValue::ColorTemperature(kelvin))
+                    .await;
                 self.device_mut(&device.sku, &device.id)
                     .await
                     .set_active_scene(None);
@@ -526,10 +1056,10 @@ impl State {
             return Ok(());
         }
 
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some((lan_client, lan_dev)) = self.lan_control(device).await {
             let color = crate::lan_api::DeviceColor { r, g, b };
             log::info!("Using LAN API to set {device} color");
-            lan_dev.send_color_rgb(color).await?;
+            lan_client.send_color_rgb(lan_dev, color).await?;
             self.poll_lan_api(lan_dev, |status| status.color == color)
                 .await?;
             self.device_mut(&device.sku, &device.id)
@@ -551,7 +1081,12 @@ impl State {
         if let Some(client) = self.get_platform_client().await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} color");
-                client.set_color_rgb(info, r, g, b).await?;
+                let result = client.set_color_rgb(info, r, g, b).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .apply_control_response(&result);
+                self.note_sent_value(device, SentValue::Color(DeviceColor { r, g, b }))
+                    .await;
                 self.device_mut(&device.sku, &device.id)
                     .await
                     .set_active_scene(None);
@@ -567,8 +1102,22 @@ impl State {
         };
 
         let iot_available = self.get_iot_client().await.is_some();
+        let powered_on = self
+            .device_mut(&device.sku, &device.id)
+            .await
+            .take_refresh_after_power_on();
 
         if device.pollable_via_iot() && iot_available {
+            // The IoT API pushes changes to us, but the push for a
+            // power on doesn't reliably include the brightness and
+            // color that the device restored, so ask for them
+            if powered_on {
+                sleep(POLL_AFTER_CONTROL_DELAY).await;
+                log::info!("Requesting {device} state after power on");
+                if let Err(err) = self.poll_iot_api(&device).await {
+                    log::error!("Polling {device} failed: {err:#}");
+                }
+            }
             return;
         }
         if device.pollable_via_lan() {
@@ -579,7 +1128,7 @@ impl State {
         // by the platform API isn't guaranteed to be
         // coherent with the command we just issued
         // right away :-/
-        sleep(Duration::from_secs(5)).await;
+        sleep(POLL_AFTER_CONTROL_DELAY).await;
 
         log::info!("Polling {device} to get latest state after control");
         if let Err(err) = self.poll_platform_api(&device).await {
@@ -615,6 +1164,122 @@ impl State {
         Ok(vec![])
     }
 
+    /// Lists the scenes for the effect list of the light and the
+    /// Mode/Scene select, leaving out the DIY scenes and snapshots
+    /// that are presented by their own select entities
+    pub async fn device_list_main_scenes(&self, device: &Device) -> anyhow::Result<Vec<String>> {
+        let mut scenes = self.device_list_scenes(device).await?;
+        for (instance, _name) in DEDICATED_SCENE_INSTANCES {
+            let dedicated = self
+                .device_list_scenes_for_instance(device, instance)
+                .await?;
+            scenes.retain(|scene| !dedicated.contains(scene));
+        }
+        Ok(scenes)
+    }
+
+    /// Lists the scenes provided by a single scene capability
+    /// instance, such as diyScene or snapshot
+    pub async fn device_list_scenes_for_instance(
+        &self,
+        device: &Device,
+        instance: &str,
+    ) -> anyhow::Result<Vec<String>> {
+        if let Some(client) = self.get_platform_client().await {
+            if let Some(info) = &device.http_device_info {
+                return Ok(sort_and_dedup_scenes(
+                    client.list_scene_names_for_instance(info, instance).await?,
+                ));
+            }
+        }
+        Ok(vec![])
+    }
+
+    pub async fn device_set_range_value(
+        self: &Arc<Self>,
+        device: &Device,
+        instance_name: &str,
+        value: f64,
+    ) -> anyhow::Result<()> {
+        if let Some(client) = self.get_platform_client().await {
+            if let Some(info) = &device.http_device_info {
+                log::info!("Using Platform API to set {device} {instance_name} to {value}");
+                let result = client.set_range_value(info, instance_name, value).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .apply_control_response(&result);
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("Unable to set {instance_name} for {device}");
+    }
+
+    /// Probes each of the segments that the device advertises by
+    /// setting it to its current color, and returns the indices of
+    /// the segments that accepted it. Segments whose color is not
+    /// known are not probed, as we have no color that would leave
+    /// them unchanged; they are assumed to be responsive.
+    /// This costs one API call per probed segment.
+    pub async fn probe_segments(self: &Arc<Self>, device: &Device) -> anyhow::Result<Vec<u32>> {
+        let client = self
+            .get_platform_client()
+            .await
+            .ok_or_else(|| anyhow::anyhow!("probe segments for {device}: no Platform API"))?;
+        let info = device
+            .http_device_info
+            .as_ref()
+            .ok_or_else(|| anyhow::anyhow!("HTTP device info is missing"))?;
+        let segments = info
+            .supports_segmented_rgb()
+            .ok_or_else(|| anyhow::anyhow!("{device} has no segments"))?;
+
+        let mut responsive = vec![];
+        for n in segments {
+            let Some(rgb) = device.segment_state(n).and_then(|seg| seg.rgb) else {
+                log::debug!("{device} segment {n} has no known color; not probing it");
+                responsive.push(n);
+                continue;
+            };
+            let [_, r, g, b] = rgb.to_be_bytes();
+            match client.set_segment_rgb(info, n, r, g, b).await {
+                Ok(response) if response.succeeded() => responsive.push(n),
+                Ok(response) => {
+                    log::debug!("{device} segment {n} was rejected: {:?}", response.state);
+                }
+                Err(err) if is_segment_rejection(&err) => {
+                    log::debug!("{device} segment {n} was rejected: {err:#}");
+                }
+                Err(err) => {
+                    // Don't mistake an outage or rate limiting for an
+                    // unresponsive segment; keep the advertised range
+                    return Err(err.context(format!("probing segment {n} of {device}")));
+                }
+            }
+        }
+        Ok(responsive)
+    }
+
+    pub async fn device_set_mode(
+        self: &Arc<Self>,
+        device: &Device,
+        instance_name: &str,
+        value: u32,
+    ) -> anyhow::Result<()> {
+        if let Some(client) = self.get_platform_client().await {
+            if let Some(info) = &device.http_device_info {
+                log::info!("Using Platform API to set {device} {instance_name} to {value}");
+                let result = client.set_mode(info, instance_name, value).await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .apply_control_response(&result);
+                return Ok(());
+            }
+        }
+
+        anyhow::bail!("Unable to set {instance_name} for {device}");
+    }
+
     pub async fn device_set_target_temperature(
         self: &Arc<Self>,
         device: &Device,
@@ -624,9 +1289,12 @@ impl State {
         if let Some(client) = self.get_platform_client().await {
             if let Some(info) = &device.http_device_info {
                 log::info!("Using Platform API to set {device} target temperature to {target}");
-                client
+                let result = client
                     .set_target_temperature(info, instance_name, target)
                     .await?;
+                self.device_mut(&device.sku, &device.id)
+                    .await
+                    .apply_control_response(&result);
                 return Ok(());
             }
         }
@@ -646,7 +1314,22 @@ impl State {
             if let Some(client) = self.get_platform_client().await {
                 if let Some(info) = &device.http_device_info {
                     log::info!("Using Platform API to set {device} to scene {scene}");
-                    client.set_scene_by_name(info, scene).await?;
+                    match client.set_scene_by_name(info, scene).await {
+                        Ok(_) => {}
+                        Err(err) if is_unknown_scene_error(&err) => {
+                            // The scene may be a DIY scene that was created after
+                            // we cached the scene list; refresh it and try again.
+                            // Govee doesn't send an IoT message when the scene
+                            // list changes, so this is the only signal that we
+                            // have; only this device's list is invalidated,
+                            // rather than the whole topic via
+                            // cache_invalidate_topic
+                            log::warn!("{err:#}. Refreshing DIY scene list for {device}");
+                            client.invalidate_diy_scenes(info)?;
+                            client.set_scene_by_name(info, scene).await?;
+                        }
+                        Err(err) => return Err(err),
+                    }
                     self.device_mut(&device.sku, &device.id)
                         .await
                         .set_active_scene(Some(scene));
@@ -655,9 +1338,9 @@ impl State {
             }
         }
 
-        if let Some(lan_dev) = &device.lan_device {
+        if let Some((lan_client, lan_dev)) = self.lan_control(device).await {
             log::info!("Using LAN API to set {device} to scene {scene}");
-            lan_dev.set_scene_by_name(scene).await?;
+            lan_client.set_scene_by_name(lan_dev, scene).await?;
 
             self.device_mut(&device.sku, &device.id)
                 .await
@@ -674,6 +1357,21 @@ impl State {
         let Some(canonical_device) = self.device_by_id(&device_id).await else {
             anyhow::bail!("cannot find device {device_id}!?");
         };
+        if self.is_device_excluded(&canonical_device).await {
+            return Ok(());
+        }
+
+        {
+            let mut disco = self.hass_discovery.lock().await;
+            if disco.in_progress > 0 {
+                // The state will be reported once discovery is complete
+                log::trace!(
+                    "defer state notification for {canonical_device} during hass discovery"
+                );
+                disco.deferred.insert(canonical_device.id.clone());
+                return Ok(());
+            }
+        }
 
         if let Some(hass) = self.get_hass_client().await {
             hass.advise_hass_of_light_state(&canonical_device, self)
@@ -684,8 +1382,354 @@ impl State {
     }
 }
 
+/// Sorts the scene names, ignoring case, and removes names that
+/// differ only in case. `scenes` is expected to be ordered by the
+/// priority of the source that produced each name; the sort is
+/// stable, so the name from the highest priority source is kept.
 pub fn sort_and_dedup_scenes(mut scenes: Vec<String>) -> Vec<String> {
     scenes.sort_by_key(|s| s.to_ascii_lowercase());
-    scenes.dedup();
+    scenes.dedup_by(|a, b| a.eq_ignore_ascii_case(b));
     scenes
 }
+
+/// Returns true if the scene was not found, which may mean that our
+/// cached scene list is stale. Other failures, such as rate limits
+/// or network errors, won't be helped by refreshing the list.
+fn is_unknown_scene_error(err: &anyhow::Error) -> bool {
+    is_invalid_parameter_error(err)
+}
+
+/// Returns true if the error means that the device doesn't have the
+/// segment. Govee answers a request for a segment that doesn't exist
+/// with HTTP 400, and our own checks fail with InvalidParameter.
+fn is_segment_rejection(err: &anyhow::Error) -> bool {
+    match ApiError::from_err(err) {
+        Some(ApiError::InvalidParameter(_)) => true,
+        Some(ApiError::Http(failed)) => failed.status() == reqwest::StatusCode::BAD_REQUEST,
+        _ => false,
+    }
+}
+
+fn is_invalid_parameter_error(err: &anyhow::Error) -> bool {
+    matches!(ApiError::from_err(err), Some(ApiError::InvalidParameter(_)))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::platform_api::mock::{devices_from_fixture, MockGoveeApiClient};
+    use crate::platform_api::{DeviceCapabilityKind, DeviceCapabilityState, HttpDeviceState};
+
+    #[test]
+    fn unknown_scene_error() {
+        let unknown: anyhow::Error = ApiError::InvalidParameter("Scene 'Foo'".to_string()).into();
+        assert!(is_unknown_scene_error(&unknown));
+        assert!(is_unknown_scene_error(
+            &unknown.context("set_scene_by_name")
+        ));
+        assert!(!is_unknown_scene_error(&anyhow::anyhow!(
+            "Unable to communicate with the Govee API"
+        )));
+    }
+
+    #[tokio::test]
+    async fn device_filter() {
+        let state = State::new();
+        let kitchen = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        let hall = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2B");
+        assert!(!state.is_device_excluded(&kitchen).await);
+
+        state
+            .set_device_filter(vec![], vec!["aa:bb:cc:dd:ee:ff:42:2b".to_string()])
+            .await;
+        assert!(!state.is_device_excluded(&kitchen).await);
+        assert!(state.is_device_excluded(&hall).await);
+
+        state
+            .set_device_filter(vec!["AA:BB:CC:DD:EE:FF:42:2A".to_string()], vec![])
+            .await;
+        assert!(!state.is_device_excluded(&kitchen).await);
+        assert!(state.is_device_excluded(&hall).await);
+
+        // Exclusion wins over inclusion
+        state
+            .set_device_filter(
+                vec!["AA:BB:CC:DD:EE:FF:42:2A".to_string()],
+                vec!["AA:BB:CC:DD:EE:FF:42:2A".to_string()],
+            )
+            .await;
+        assert!(state.is_device_excluded(&kitchen).await);
+    }
+
+    #[tokio::test]
+    async fn state_notifications_wait_for_discovery() {
+        let state = Arc::new(State::new());
+        drop(state.device_mut("H6000", "AA:BB:CC:DD:EE:FF:42:2A").await);
+
+        // Two overlapping passes, as happens when hass restarts
+        // while we are still registering
+        state.begin_hass_discovery().await;
+        state.begin_hass_discovery().await;
+        state
+            .notify_of_state_change("AA:BB:CC:DD:EE:FF:42:2A")
+            .await
+            .unwrap();
+        k9::assert_equal!(state.end_hass_discovery().await, Vec::<String>::new());
+
+        // Still deferred, as the other pass is running
+        state
+            .notify_of_state_change("AA:BB:CC:DD:EE:FF:42:2A")
+            .await
+            .unwrap();
+        k9::assert_equal!(
+            state.end_hass_discovery().await,
+            vec!["AA:BB:CC:DD:EE:FF:42:2A".to_string()]
+        );
+        k9::assert_equal!(state.end_hass_discovery().await, Vec::<String>::new());
+    }
+
+    #[tokio::test]
+    async fn device_type_availability_timeout() {
+        let hour = chrono::Duration::seconds(3600);
+        let minute = chrono::Duration::seconds(60);
+        let state = State::new();
+        state.set_availability_timeout(Some(hour)).await;
+
+        // Configured before the device is known to us
+        state
+            .set_device_type_availability_timeout(DeviceType::Light, minute)
+            .await;
+
+        let light = Device::new("H6000", "AA:BB:CC:DD:EE:FF:42:2A");
+        k9::assert_equal!(light.device_type(), DeviceType::Light);
+        k9::assert_equal!(
+            state.get_availability_timeout_for(&light).await,
+            Some(minute)
+        );
+
+        let mut kettle = Device::new("H7171", "AA:BB:CC:DD:EE:FF:42:2B");
+        let mut info = crate::platform_api::mock::devices_from_fixture(include_str!(
+            "../../test-data/list_devices.json"
+        ))[0]
+            .clone();
+        info.device_type = DeviceType::Kettle;
+        kettle.set_http_device_info(info);
+        k9::assert_equal!(
+            state.get_availability_timeout_for(&kettle).await,
+            Some(hour)
+        );
+    }
+
+    #[tokio::test]
+    async fn main_scenes_exclude_dedicated() {
+        let info =
+            devices_from_fixture(include_str!("../../test-data/list_devices.json"))[0].clone();
+        let mut client = MockGoveeApiClient::with_devices(vec![info.clone()]);
+        client.scenes = ["", "Aurora", "Fade", "Sunrise", "Sunset"]
+            .iter()
+            .map(|s| s.to_string())
+            .collect();
+        let state = client.service_state().await;
+        let device = state.device_by_id(&info.device).await.unwrap();
+
+        k9::assert_equal!(
+            state.device_list_main_scenes(&device).await.unwrap(),
+            vec!["", "Aurora"]
+        );
+        k9::assert_equal!(
+            state
+                .device_list_scenes_for_instance(&device, "snapshot")
+                .await
+                .unwrap(),
+            vec!["Sunrise", "Sunset"]
+        );
+    }
+
+    #[test]
+    fn scene_dedup_ignores_case() {
+        let scenes = sort_and_dedup_scenes(
+            [
+                "Rustling Leaves",
+                "Rainbow",
+                "Aurora",
+                "Rustling leaves",
+                "rainbow",
+            ]
+            .iter()
+            .map(|s| s.to_string())
+            .collect(),
+        );
+        k9::assert_equal!(scenes, vec!["Aurora", "Rainbow", "Rustling Leaves"]);
+    }
+
+    #[tokio::test]
+    async fn device_temperature_scale() {
+        let state = State::new();
+        state
+            .set_temperature_scale(TemperatureScale::Fahrenheit)
+            .await;
+
+        let mut device = Device::new("H7171", "AA:BB:CC:DD:EE:FF:00:55");
+        k9::assert_equal!(
+            state.temperature_scale_for_device(&device).await,
+            TemperatureScale::Fahrenheit
+        );
+
+        device.set_temperature_scale(TemperatureScale::Celsius);
+        k9::assert_equal!(
+            state.temperature_scale_for_device(&device).await,
+            TemperatureScale::Celsius
+        );
+    }
+
+    #[tokio::test]
+    async fn resync_requests_are_coalesced() {
+        let state = State::new();
+        assert!(state.begin_resync().await);
+        assert!(!state.begin_resync().await);
+        assert!(!state.begin_resync().await);
+        // The requests that arrived meanwhile cause one more run
+        assert!(state.end_resync().await);
+        assert!(!state.begin_resync().await);
+        assert!(state.end_resync().await);
+        assert!(!state.end_resync().await);
+        assert!(state.begin_resync().await);
+        assert!(!state.end_resync().await);
+    }
+
+    const SEGMENT_RGB: u32 = 0x102030;
+
+    /// Records that the segments of the device have SEGMENT_RGB as
+    /// their color, and returns the updated device
+    async fn set_segment_colors(state: &StateHandle, device: &Device, segments: &[u32]) -> Device {
+        state
+            .device_mut(&device.sku, &device.id)
+            .await
+            .set_http_device_state(HttpDeviceState {
+                sku: device.sku.clone(),
+                device: device.id.clone(),
+                capabilities: vec![DeviceCapabilityState {
+                    kind: DeviceCapabilityKind::SegmentColorSetting,
+                    instance: "segmentedColorRgb".to_string(),
+                    state: serde_json::json!({
+                        "value": [{"segment": segments, "rgb": SEGMENT_RGB}]
+                    }),
+                }],
+            });
+        state.device_by_id(&device.id).await.unwrap()
+    }
+
+    /// Returns a State holding the segmented light from the
+    /// list_devices_2 fixture with a known color for each segment,
+    /// along with its advertised segments
+    async fn segmented_light_state() -> (StateHandle, MockGoveeApiClient, Device, Vec<u32>) {
+        let client = MockGoveeApiClient::with_devices(devices_from_fixture(include_str!(
+            "../../test-data/list_devices_2.json"
+        )));
+        let state = client.service_state().await;
+        let device = state.device_by_id(&client.devices[0].device).await.unwrap();
+        let advertised: Vec<u32> = device
+            .http_device_info
+            .as_ref()
+            .and_then(|info| info.supports_segmented_rgb())
+            .unwrap()
+            .collect();
+        let device = set_segment_colors(&state, &device, &advertised).await;
+        (state, client, device, advertised)
+    }
+
+    #[tokio::test]
+    async fn probe_segments_sets_each_segment() {
+        let (state, client, device, advertised) = segmented_light_state().await;
+
+        // The mock accepts every request, so every segment responds
+        let responsive = state.probe_segments(&device).await.unwrap();
+        k9::assert_equal!(&responsive, &advertised);
+
+        let controls = client.controls();
+        k9::assert_equal!(controls.len(), advertised.len());
+        assert!(controls.iter().all(|c| c.instance == "segmentedColorRgb"
+            && c.value["rgb"] == serde_json::json!(SEGMENT_RGB)));
+    }
+
+    #[tokio::test]
+    async fn probe_segments_skips_segments_without_a_color() {
+        let (state, client, device, advertised) = segmented_light_state().await;
+        let device = set_segment_colors(&state, &device, &advertised[1..]).await;
+
+        // The first segment is kept, but isn't painted some
+        // arbitrary color to probe it
+        let responsive = state.probe_segments(&device).await.unwrap();
+        k9::assert_equal!(&responsive, &advertised);
+        k9::assert_equal!(client.controls().len(), advertised.len() - 1);
+        assert!(client
+            .controls()
+            .iter()
+            .all(|c| c.value["segment"] != serde_json::json!([advertised[0]])));
+    }
+
+    #[tokio::test]
+    async fn probe_segments_drops_segments_that_govee_rejects() {
+        use crate::platform_api::GoveeApiClient;
+        use wiremock::matchers::{body_partial_json, method, path};
+        use wiremock::{Mock, MockServer, ResponseTemplate};
+
+        let (state, _client, device, advertised) = segmented_light_state().await;
+        let last = *advertised.last().unwrap();
+
+        let server = MockServer::start().await;
+        Mock::given(method("POST"))
+            .and(path("/router/api/v1/device/control"))
+            .and(body_partial_json(serde_json::json!({
+                "payload": {"capability": {"value": {"segment": [last]}}}
+            })))
+            .respond_with(ResponseTemplate::new(400).set_body_raw(
+                r#"{"code": 400, "msg": "Parameter value out of range"}"#,
+                "application/json",
+            ))
+            .expect(1)
+            .mount(&server)
+            .await;
+        Mock::given(method("POST"))
+            .and(path("/router/api/v1/device/control"))
+            .respond_with(ResponseTemplate::new(200).set_body_raw(
+                r#"{"requestId": "uuid", "code": 200, "msg": "success",
+                    "capability": {"type": "devices.capabilities.segment_color_setting",
+                    "instance": "segmentedColorRgb", "value": 0,
+                    "state": {"status": "success"}}}"#,
+                "application/json",
+            ))
+            .mount(&server)
+            .await;
+        state
+            .set_platform_client(GoveeApiClient::with_server("test-api-key", server.uri()))
+            .await;
+
+        let responsive = state.probe_segments(&device).await.unwrap();
+        k9::assert_equal!(&responsive, &advertised[..advertised.len() - 1]);
+    }
+
+    #[tokio::test]
+    async fn probe_segments_stops_on_transient_error() {
+        let (state, client, device, advertised) = segmented_light_state().await;
+
+        // A rejected segment is left out
+        client.fail_next_control(ApiError::InvalidParameter("segment".to_string()));
+        let responsive = state.probe_segments(&device).await.unwrap();
+        k9::assert_equal!(&responsive, &advertised[1..]);
+
+        // but rate limiting or an outage doesn't tell us anything
+        // about the segments, so probing gives up
+        client.fail_next_control(ApiError::InvalidParameter("segment".to_string()));
+        client.fail_next_control(MockGoveeApiClient::http_error(
+            reqwest::StatusCode::TOO_MANY_REQUESTS,
+        ));
+        let err = state.probe_segments(&device).await.unwrap_err();
+        assert!(ApiError::from_err(&err).unwrap().is_transient());
+
+        client.fail_next_control(MockGoveeApiClient::http_error(
+            reqwest::StatusCode::SERVICE_UNAVAILABLE,
+        ));
+        assert!(state.probe_segments(&device).await.is_err());
+    }
+}
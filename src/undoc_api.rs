@@ -6,6 +6,7 @@ use crate::platform_api::{
     from_json, http_response_body, DeviceCapability, DeviceCapabilityKind, DeviceParameters,
     EnumOption,
 };
+use anyhow::Context;
 use reqwest::Method;
 use serde::de::DeserializeOwned;
 use serde::{Deserialize, Serialize};
@@ -45,6 +46,12 @@ impl<T: std::fmt::Debug> std::fmt::Debug for Redacted<T> {
     }
 }
 
+impl<T: std::fmt::Debug> Redacted<T> {
+    pub fn new(value: T) -> Self {
+        Self(value)
+    }
+}
+
 impl<T: std::fmt::Debug> std::ops::Deref for Redacted<T> {
     type Target = T;
     fn deref(&self) -> &T {
@@ -66,7 +73,7 @@ pub fn ms_timestamp() -> String {
         .to_string()
 }
 
-#[derive(clap::Parser, Debug)]
+#[derive(clap::Parser, Debug, Clone)]
 pub struct UndocApiArguments {
     /// The email address you registered with Govee.
     /// If not passed here, it will be read from
@@ -88,11 +95,18 @@ pub struct UndocApiArguments {
     #[arg(long, global = true, default_value = "/dev/shm/govee.iot.cert")]
     pub govee_iot_cert: PathBuf,
 
-    /// Where to find the AWS root CA certificate
-    #[arg(long, global = true, default_value = "AmazonRootCA1.pem")]
-    pub amazon_root_ca: PathBuf,
+    /// Where to find the AWS root CA certificate.
+    /// If unspecified, looks for AmazonRootCA1.pem in the current
+    /// directory, alongside the executable and in some well-known
+    /// system locations, falling back to a built-in copy.
+    #[arg(long, global = true)]
+    pub amazon_root_ca: Option<PathBuf>,
 }
 
+/// A copy of the AWS root CA, for use when it cannot be found on disk
+const AMAZON_ROOT_CA_PEM: &str = include_str!("../AmazonRootCA1.pem");
+const AMAZON_ROOT_CA_FILE_NAME: &str = "AmazonRootCA1.pem";
+
 impl UndocApiArguments {
     pub fn opt_email(&self) -> anyhow::Result<Option<String>> {
         match &self.govee_email {
@@ -126,6 +140,47 @@ impl UndocApiArguments {
         })
     }
 
+    /// Returns the path to the AWS root CA certificate.
+    /// If none was specified and it cannot be found in any of the
+    /// usual places, the built-in copy is written alongside the
+    /// IoT key and that path is returned.
+    pub fn amazon_root_ca(&self) -> anyhow::Result<PathBuf> {
+        if let Some(path) = &self.amazon_root_ca {
+            return Ok(path.clone());
+        }
+
+        let mut candidates = vec![PathBuf::from(AMAZON_ROOT_CA_FILE_NAME)];
+        if let Some(dir) = std::env::current_exe()
+            .ok()
+            .and_then(|exe| exe.parent().map(|dir| dir.to_path_buf()))
+        {
+            candidates.push(dir.join(AMAZON_ROOT_CA_FILE_NAME));
+        }
+        candidates.push(PathBuf::from("/app").join(AMAZON_ROOT_CA_FILE_NAME));
+        // The name used by the ca-certificates package on Debian
+        // and derived distributions
+        candidates.push(PathBuf::from("/etc/ssl/certs/Amazon_Root_CA_1.pem"));
+
+        if let Some(path) = candidates.into_iter().find(|p| p.exists()) {
+            log::debug!("Using AWS root CA from {}", path.display());
+            return Ok(path);
+        }
+
+        self.write_builtin_amazon_root_ca()
+    }
+
+    fn write_builtin_amazon_root_ca(&self) -> anyhow::Result<PathBuf> {
+        let path = self
+            .govee_iot_key
+            .parent()
+            .unwrap_or_else(|| std::path::Path::new("."))
+            .join(AMAZON_ROOT_CA_FILE_NAME);
+        std::fs::write(&path, AMAZON_ROOT_CA_PEM)
+            .with_context(|| format!("writing built-in AWS root CA to {}", path.display()))?;
+        log::debug!("Using built-in AWS root CA, written to {}", path.display());
+        Ok(path)
+    }
+
     pub fn api_client(&self) -> anyhow::Result<GoveeUndocumentedApi> {
         let email = self.email()?;
         let password = self.password()?;
@@ -199,6 +254,10 @@ impl GoveeUndocumentedApi {
         crate::cache::invalidate_key("undoc-api", "account-info").ok();
     }
 
+    pub fn invalidate_iot_key(&self) {
+        crate::cache::invalidate_key("undoc-api", "iot-key").ok();
+    }
+
     async fn login_account_impl(&self) -> anyhow::Result<CacheComputeResult<LoginAccountResponse>> {
         let response = reqwest::Client::builder()
             .timeout(Duration::from_secs(30))
@@ -250,7 +309,31 @@ impl GoveeUndocumentedApi {
         Ok(value.into_inner())
     }
 
+    /// Returns the device list, which includes the room assignments.
+    /// The list is fetched every time, but the most recent list is kept
+    /// for a day and used in place of the current list if the fetch
+    /// fails, so that a brief outage doesn't lose the room assignments.
     pub async fn get_device_list(&self, token: &str) -> anyhow::Result<DevicesResponse> {
+        let value: JsonValue = cache_get(
+            CacheGetOptions {
+                topic: "undoc-api",
+                key: "device-list",
+                soft_ttl: Duration::ZERO,
+                hard_ttl: ONE_DAY,
+                negative_ttl: Duration::from_secs(60),
+                allow_stale: true,
+            },
+            async {
+                let value = self.get_device_list_impl(token).await?;
+                Ok(CacheComputeResult::Value(value))
+            },
+        )
+        .await?;
+
+        from_json(&serde_json::to_vec(&value)?)
+    }
+
+    async fn get_device_list_impl(&self, token: &str) -> anyhow::Result<JsonValue> {
         let response = reqwest::Client::builder()
             .timeout(Duration::from_secs(30))
             .build()?
@@ -272,9 +355,11 @@ impl GoveeUndocumentedApi {
             self.invalidate_account_login();
         }
 
-        let resp: DevicesResponse = http_response_body(response).await?;
+        let value: JsonValue = http_response_body(response).await?;
+        // Don't cache a response that we can't parse
+        let _: DevicesResponse = from_json(&serde_json::to_vec(&value)?)?;
 
-        Ok(resp)
+        Ok(value)
     }
 
     pub fn invalidate_community_login(&self) {
@@ -449,6 +534,20 @@ impl GoveeUndocumentedApi {
         .await
     }
 
+    /// Finds the named one-click shortcut. The shortcut list is cached
+    /// for a day, so if the name isn't found, the list is fetched again
+    /// in case the shortcut was recently created in the Govee app.
+    pub async fn find_one_click(&self, name: &str) -> anyhow::Result<Option<ParsedOneClick>> {
+        let find = |items: Vec<ParsedOneClick>| items.into_iter().find(|item| item.name == name);
+
+        if let Some(item) = find(self.parse_one_clicks().await?) {
+            return Ok(Some(item));
+        }
+
+        crate::cache::invalidate_key("undoc-api", "one-click-shortcuts")?;
+        Ok(find(self.parse_one_clicks().await?))
+    }
+
     pub async fn parse_one_clicks(&self) -> anyhow::Result<Vec<ParsedOneClick>> {
         let token = self.login_community().await?;
         let res = self.get_saved_one_click_shortcuts(&token).await?;
@@ -922,6 +1021,31 @@ mod test {
     use super::*;
     use crate::platform_api::from_json;
 
+    #[test]
+    fn amazon_root_ca() {
+        let dir = std::env::temp_dir().join(format!("govee-test-{}", Uuid::new_v4().simple()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let args = UndocApiArguments {
+            govee_email: None,
+            govee_password: None,
+            govee_iot_key: dir.join("govee.iot.key"),
+            govee_iot_cert: dir.join("govee.iot.cert"),
+            amazon_root_ca: Some(PathBuf::from("/path/to/ca.pem")),
+        };
+
+        k9::assert_equal!(
+            args.amazon_root_ca().unwrap(),
+            PathBuf::from("/path/to/ca.pem")
+        );
+
+        let path = args.write_builtin_amazon_root_ca().unwrap();
+        k9::assert_equal!(path, dir.join("AmazonRootCA1.pem"));
+        let pem = std::fs::read_to_string(&path).unwrap();
+        assert!(pem.starts_with("-----BEGIN CERTIFICATE-----"), "{pem}");
+
+        std::fs::remove_dir_all(&dir).ok();
+    }
+
     #[test]
     fn get_device_scenes() {
         let resp: DevicesResponse =